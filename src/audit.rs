@@ -0,0 +1,97 @@
+//! Audit log of the command lines run in sessions, with who ran them, when,
+//! for how long and how they ended, for keeping track of what was done on
+//! shared lab Pis. Unlike transcripts, output and typed input are left out.
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::Once;
+use std::time::{Duration, SystemTime};
+
+use crate::transcript;
+use crate::transport::TransportKind;
+
+/// Where command lines are logged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditLog {
+    /// Appended to a file, a line per command line
+    File(PathBuf),
+    /// Sent to syslog with the authpriv facility
+    Syslog,
+}
+
+impl AuditLog {
+    /// The log a value of `audit_log` in the config means, `syslog` or the
+    /// path of a file
+    pub(crate) fn from_config(value: PathBuf) -> AuditLog {
+        match value.as_os_str() == "syslog" {
+            true => AuditLog::Syslog,
+            false => AuditLog::File(value),
+        }
+    }
+}
+
+/// A command line that completed
+pub(crate) struct Entry<'a> {
+    pub(crate) started: SystemTime,
+    pub(crate) duration: Duration,
+    pub(crate) status: i32,
+    pub(crate) user: &'a str,
+    pub(crate) transport: TransportKind,
+    pub(crate) command: &'a str,
+}
+
+/// Log a command line. Files are opened for each line, so several sessions
+/// can log to the same file and it can be rotated.
+pub(crate) fn record(log: &AuditLog, entry: &Entry) -> io::Result<()> {
+    /* Lines of multi-line commands stay on one line of the log */
+    let fields = format!(
+        "user={} transport={} exit={} time={:.3}s command={}",
+        entry.user,
+        format!("{:?}", entry.transport).to_lowercase(),
+        entry.status,
+        entry.duration.as_secs_f64(),
+        entry.command.replace('\\', "\\\\").replace('\n', "\\n")
+    );
+    match log {
+        AuditLog::File(path) => {
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .mode(0o600)
+                .open(path)?;
+            let line = format!("{} {}\n", transcript::timestamp(entry.started), fields);
+            file.write_all(line.as_bytes())
+        }
+        AuditLog::Syslog => {
+            syslog(&fields);
+            Ok(())
+        }
+    }
+}
+
+fn syslog(message: &str) {
+    static OPEN: Once = Once::new();
+    OPEN.call_once(|| unsafe {
+        libc::openlog(c"pieshell".as_ptr(), libc::LOG_PID, libc::LOG_AUTHPRIV);
+    });
+    /* NUL bytes can't be passed on */
+    let message =
+        std::ffi::CString::new(message.replace('\0', "")).unwrap_or_else(|_| c"".to_owned());
+    unsafe {
+        libc::syslog(libc::LOG_INFO, c"%s".as_ptr(), message.as_ptr());
+    }
+}
+
+/// The name a session is logged under: who logged in, or else who runs
+/// the shell
+pub(crate) fn user_name(login: Option<&str>) -> String {
+    if let Some(user) = login {
+        return user.to_owned();
+    }
+    match std::env::var("USER") {
+        Ok(user) => user,
+        Err(_) => format!("uid{}", unsafe { libc::getuid() }),
+    }
+}
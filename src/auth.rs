@@ -0,0 +1,535 @@
+//! Backends checking who logs in to a session, selected with `auth` in the
+//! profile of a transport. The serial console and the network can use
+//! different ones, e.g. passwords on the UART and one-time codes over TCP.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::transport::TransportKind;
+
+/// Where the file backend looks for `user:hash` lines by default
+const CREDENTIALS_FILE: &str = "/etc/pieshell/credentials";
+/// Where the TOTP backend looks for `user:secret` lines by default
+const TOTP_FILE: &str = "/etc/pieshell/totp";
+
+/// Seconds each one-time code is valid for, and how many steps the clock of
+/// the phone may be off by
+const TOTP_STEP: u64 = 30;
+const TOTP_SKEW: i64 = 1;
+
+/// Wait after the first failed login, doubled with each one after it
+const FAILURE_DELAY: Duration = Duration::from_secs(1);
+const MAX_FAILURE_DELAY: Duration = Duration::from_secs(60);
+
+/// Failed logins in a row on each transport and until when logins wait.
+/// This is kept across sessions, so connecting again doesn't help guessing.
+static FAILURES: Mutex<Option<HashMap<TransportKind, (u32, Instant)>>> = Mutex::new(None);
+
+/// The time step of the last one-time code accepted for each user. Codes of
+/// that step or earlier are refused, so a code seen by someone else can't be
+/// used again.
+static USED_STEPS: Mutex<Option<HashMap<String, u64>>> = Mutex::new(None);
+
+/// How a session checks who logs in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    /// Anyone reaching the transport gets a prompt
+    None,
+    /// Passwords checked against crypt(3) hashes in a credentials file
+    File,
+    /// Passwords checked by PAM, with the `pieshell` service. Needs the `pam`
+    /// feature.
+    Pam,
+    /// One-time codes of an authenticator app, from secrets in a file
+    Totp,
+}
+
+/// A backend checking what a user answers when logging in
+pub(crate) trait Authenticator {
+    /// What is asked for after the user name
+    fn prompt(&self) -> &'static str;
+
+    /// Whether the answer proves who the user is. Fails if the backend
+    /// can't check at all, e.g. because its file is missing.
+    fn verify(&self, user: &str, answer: &str) -> Result<bool, String>;
+}
+
+/// The backend of a method, or `None` if sessions don't log in.
+/// `credentials` overrides the file of the file and TOTP backends.
+pub(crate) fn authenticator(
+    method: AuthMethod,
+    credentials: Option<&Path>,
+) -> Option<Box<dyn Authenticator>> {
+    let file = |default: &str| credentials.map_or_else(|| PathBuf::from(default), Path::to_owned);
+    match method {
+        AuthMethod::None => None,
+        AuthMethod::File => Some(Box::new(CredentialsFile {
+            path: file(CREDENTIALS_FILE),
+        })),
+        #[cfg(feature = "pam")]
+        AuthMethod::Pam => Some(Box::new(pam::Pam)),
+        /* Settings don't allow PAM without the feature */
+        #[cfg(not(feature = "pam"))]
+        AuthMethod::Pam => None,
+        AuthMethod::Totp => Some(Box::new(Totp {
+            path: file(TOTP_FILE),
+        })),
+    }
+}
+
+/// How long logins on a transport must wait after failures
+pub(crate) fn login_delay(kind: TransportKind) -> Duration {
+    let failures = FAILURES.lock().unwrap_or_else(|error| error.into_inner());
+    match failures.as_ref().and_then(|failures| failures.get(&kind)) {
+        Some((_, until)) => until.saturating_duration_since(Instant::now()),
+        None => Duration::ZERO,
+    }
+}
+
+/// Count a failed login on a transport, making the next one wait longer
+pub(crate) fn login_failed(kind: TransportKind) {
+    let mut failures = FAILURES.lock().unwrap_or_else(|error| error.into_inner());
+    let (count, until) = failures
+        .get_or_insert_with(HashMap::new)
+        .entry(kind)
+        .or_insert((0, Instant::now()));
+    *count += 1;
+    let delay = FAILURE_DELAY.saturating_mul(1 << (*count - 1).min(16));
+    *until = Instant::now() + delay.min(MAX_FAILURE_DELAY);
+}
+
+pub(crate) fn login_succeeded(kind: TransportKind) {
+    let mut failures = FAILURES.lock().unwrap_or_else(|error| error.into_inner());
+    if let Some(failures) = failures.as_mut() {
+        failures.remove(&kind);
+    }
+}
+
+/// Find the entry of a user in a file of `user:value` lines. Blank lines and
+/// lines starting with `#` are skipped.
+fn lookup(path: &Path, user: &str) -> Result<Option<String>, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+    let value = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| *name == user)
+        .map(|(_, value)| value.to_owned());
+    Ok(value)
+}
+
+/// Hashes as in /etc/shadow, made with e.g. `mkpasswd -m sha-512`
+struct CredentialsFile {
+    path: PathBuf,
+}
+
+#[link(name = "crypt")]
+extern "C" {
+    fn crypt(phrase: *const libc::c_char, setting: *const libc::c_char) -> *mut libc::c_char;
+}
+
+/// crypt(3) returns a static buffer, which sessions in other threads would
+/// overwrite
+static CRYPT: Mutex<()> = Mutex::new(());
+
+impl Authenticator for CredentialsFile {
+    fn prompt(&self) -> &'static str {
+        "Password: "
+    }
+
+    fn verify(&self, user: &str, answer: &str) -> Result<bool, String> {
+        let hash = match lookup(&self.path, user)? {
+            Some(hash) => hash,
+            None => return Ok(false),
+        };
+        let (phrase, setting) = match (CString::new(answer), CString::new(hash.as_str())) {
+            (Ok(phrase), Ok(setting)) => (phrase, setting),
+            _ => return Ok(false),
+        };
+
+        let _guard = CRYPT.lock().unwrap_or_else(|error| error.into_inner());
+        let hashed = unsafe { crypt(phrase.as_ptr(), setting.as_ptr()) };
+        /* Invalid hashes give NULL or a string starting with '*' */
+        if hashed.is_null() {
+            return Ok(false);
+        }
+        let hashed = unsafe { CStr::from_ptr(hashed) }.to_bytes();
+        Ok(!hashed.starts_with(b"*") && constant_time_eq(hashed, hash.as_bytes()))
+    }
+}
+
+/// Compare without returning early at the first difference, so the time
+/// taken doesn't tell how much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a
+        .iter()
+        .zip(b)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    difference == 0
+}
+
+/// Time-based one-time codes as in RFC 6238, with the defaults of
+/// authenticator apps: HMAC-SHA1, 30 seconds and 6 digits. Secrets are in
+/// base32, as in the QR codes the apps scan.
+struct Totp {
+    path: PathBuf,
+}
+
+impl Authenticator for Totp {
+    fn prompt(&self) -> &'static str {
+        "Code: "
+    }
+
+    fn verify(&self, user: &str, answer: &str) -> Result<bool, String> {
+        let secret = match lookup(&self.path, user)? {
+            Some(secret) => secret,
+            None => return Ok(false),
+        };
+        let key = base32_decode(&secret)
+            .ok_or_else(|| format!("{}: invalid secret of {}", self.path.display(), user))?;
+        let code: u32 = match answer.trim().parse() {
+            Ok(code) if answer.trim().len() == 6 => code,
+            _ => return Ok(false),
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|error| error.to_string())?
+            .as_secs();
+        let step = (now / TOTP_STEP) as i64;
+        let matched = (-TOTP_SKEW..=TOTP_SKEW)
+            .map(|skew| (step + skew) as u64)
+            .find(|&step| totp_code(&key, step) == code);
+        let matched = match matched {
+            Some(matched) => matched,
+            None => return Ok(false),
+        };
+
+        let mut used = USED_STEPS.lock().unwrap_or_else(|error| error.into_inner());
+        let last = used
+            .get_or_insert_with(HashMap::new)
+            .entry(user.to_owned())
+            .or_insert(0);
+        if *last >= matched {
+            return Ok(false);
+        }
+        *last = matched;
+        Ok(true)
+    }
+}
+
+/// The code for a time step, the dynamic truncation of RFC 4226
+fn totp_code(key: &[u8], step: u64) -> u32 {
+    let mac = hmac_sha1(key, &step.to_be_bytes());
+    let offset = (mac[19] & 0xf) as usize;
+    let bits = u32::from_be_bytes([
+        mac[offset],
+        mac[offset + 1],
+        mac[offset + 2],
+        mac[offset + 3],
+    ]);
+    (bits & 0x7fff_ffff) % 1_000_000
+}
+
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    match key.len() > BLOCK {
+        true => block[..20].copy_from_slice(&sha1_smol::Sha1::from(key).digest().bytes()),
+        false => block[..key.len()].copy_from_slice(key),
+    }
+
+    let mut inner = sha1_smol::Sha1::new();
+    inner.update(&block.map(|byte| byte ^ 0x36));
+    inner.update(message);
+    let mut outer = sha1_smol::Sha1::new();
+    outer.update(&block.map(|byte| byte ^ 0x5c));
+    outer.update(&inner.digest().bytes());
+    outer.digest().bytes()
+}
+
+/// Decode base32 as in RFC 4648, ignoring case, spaces and padding
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    match bytes.is_empty() {
+        true => None,
+        false => Some(bytes),
+    }
+}
+
+/// PAM, loaded when it is first used so the shell runs on systems without
+/// it. The service is `pieshell`, configured in /etc/pam.d/pieshell, e.g.
+/// with `@include common-auth`. Checking the passwords of other users needs
+/// the shell to run as root.
+#[cfg(feature = "pam")]
+mod pam {
+    use std::ffi::{c_void, CString};
+    use std::ptr;
+
+    use super::Authenticator;
+    use crate::SHELL_NAME;
+
+    const PAM_SUCCESS: libc::c_int = 0;
+    const PAM_BUF_ERR: libc::c_int = 5;
+    const PAM_PROMPT_ECHO_OFF: libc::c_int = 1;
+    const PAM_PROMPT_ECHO_ON: libc::c_int = 2;
+
+    #[repr(C)]
+    struct Message {
+        style: libc::c_int,
+        text: *const libc::c_char,
+    }
+
+    #[repr(C)]
+    struct Response {
+        text: *mut libc::c_char,
+        code: libc::c_int,
+    }
+
+    type Converse = extern "C" fn(
+        libc::c_int,
+        *mut *const Message,
+        *mut *mut Response,
+        *mut c_void,
+    ) -> libc::c_int;
+
+    #[repr(C)]
+    struct Conversation {
+        converse: Converse,
+        data: *mut c_void,
+    }
+
+    type Start = unsafe extern "C" fn(
+        *const libc::c_char,
+        *const libc::c_char,
+        *const Conversation,
+        *mut *mut c_void,
+    ) -> libc::c_int;
+    type Call = unsafe extern "C" fn(*mut c_void, libc::c_int) -> libc::c_int;
+
+    pub(super) struct Pam;
+
+    impl Authenticator for Pam {
+        fn prompt(&self) -> &'static str {
+            "Password: "
+        }
+
+        fn verify(&self, user: &str, answer: &str) -> Result<bool, String> {
+            let (user, password) = match (CString::new(user), CString::new(answer)) {
+                (Ok(user), Ok(password)) => (user, password),
+                _ => return Ok(false),
+            };
+            let library = unsafe { libc::dlopen(c"libpam.so.0".as_ptr(), libc::RTLD_NOW) };
+            if library.is_null() {
+                return Err(String::from("PAM is not installed"));
+            }
+            let symbol = |name: &std::ffi::CStr| unsafe { libc::dlsym(library, name.as_ptr()) };
+            let symbols = [
+                symbol(c"pam_start"),
+                symbol(c"pam_authenticate"),
+                symbol(c"pam_acct_mgmt"),
+                symbol(c"pam_end"),
+            ];
+            if symbols.iter().any(|symbol| symbol.is_null()) {
+                unsafe { libc::dlclose(library) };
+                return Err(String::from("PAM is missing functions"));
+            }
+            let start: Start = unsafe { std::mem::transmute(symbols[0]) };
+            let authenticate: Call = unsafe { std::mem::transmute(symbols[1]) };
+            let account: Call = unsafe { std::mem::transmute(symbols[2]) };
+            let end: Call = unsafe { std::mem::transmute(symbols[3]) };
+
+            /* Every question of the modules is answered with the password */
+            let conversation = Conversation {
+                converse,
+                data: password.as_ptr() as *mut c_void,
+            };
+            let service = CString::new(SHELL_NAME).expect("name has no NUL");
+            let mut handle = ptr::null_mut();
+            let mut status =
+                unsafe { start(service.as_ptr(), user.as_ptr(), &conversation, &mut handle) };
+            if status == PAM_SUCCESS {
+                status = unsafe { authenticate(handle, 0) };
+            }
+            if status == PAM_SUCCESS {
+                /* Expired and locked accounts can't log in either */
+                status = unsafe { account(handle, 0) };
+            }
+            if !handle.is_null() {
+                unsafe { end(handle, status) };
+            }
+            unsafe { libc::dlclose(library) };
+            Ok(status == PAM_SUCCESS)
+        }
+    }
+
+    extern "C" fn converse(
+        count: libc::c_int,
+        messages: *mut *const Message,
+        responses: *mut *mut Response,
+        data: *mut c_void,
+    ) -> libc::c_int {
+        let count = count.max(0) as usize;
+        /* PAM frees the responses, so they come from malloc */
+        let answers =
+            unsafe { libc::calloc(count, std::mem::size_of::<Response>()) } as *mut Response;
+        if answers.is_null() {
+            return PAM_BUF_ERR;
+        }
+        for i in 0..count {
+            let message = unsafe { &**messages.add(i) };
+            if matches!(message.style, PAM_PROMPT_ECHO_OFF | PAM_PROMPT_ECHO_ON) {
+                unsafe { (*answers.add(i)).text = libc::strdup(data as *const libc::c_char) };
+            }
+        }
+        unsafe { *responses = answers };
+        PAM_SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The secret of the test vectors of RFC 4226 and RFC 6238
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn computes_hmac_sha1() {
+        /* Test cases 1, 2 and 6 of RFC 2202 */
+        assert_eq!(
+            hex(&hmac_sha1(&[0x0b; 20], b"Hi There")),
+            "b617318655057264e28bc0b6fb378c8ef146be00"
+        );
+        assert_eq!(
+            hex(&hmac_sha1(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+        assert_eq!(
+            hex(&hmac_sha1(
+                &[0xaa; 80],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+    }
+
+    #[test]
+    fn computes_hotp_codes() {
+        /* Appendix D of RFC 4226 */
+        let codes = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, code) in codes.into_iter().enumerate() {
+            assert_eq!(
+                totp_code(RFC_SECRET, counter as u64),
+                code,
+                "counter {}",
+                counter
+            );
+        }
+    }
+
+    #[test]
+    fn computes_totp_codes() {
+        /* The SHA-1 vectors of appendix B of RFC 6238, cut to 6 digits */
+        let vectors = [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(
+                totp_code(RFC_SECRET, time / TOTP_STEP),
+                code,
+                "time {}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_base32() {
+        /* Section 10 of RFC 4648 */
+        let vectors = [
+            ("MY======", "f"),
+            ("MZXQ====", "fo"),
+            ("MZXW6===", "foo"),
+            ("MZXW6YQ=", "foob"),
+            ("MZXW6YTB", "fooba"),
+            ("MZXW6YTBOI======", "foobar"),
+        ];
+        for (text, decoded) in vectors {
+            assert_eq!(base32_decode(text).as_deref(), Some(decoded.as_bytes()));
+        }
+        assert_eq!(
+            base32_decode("mzxw 6ytb oi").as_deref(),
+            Some(&b"foobar"[..])
+        );
+        assert_eq!(base32_decode(""), None);
+        assert_eq!(base32_decode("MZXW1"), None);
+    }
+
+    #[test]
+    fn compares_whole_values() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn refuses_codes_used_before() {
+        let path = std::env::temp_dir().join(format!("pieshell-totp-{}", std::process::id()));
+        /* The base32 of the RFC secret */
+        fs::write(&path, "alice:GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\n").unwrap();
+        let totp = Totp { path: path.clone() };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let code = format!("{:06}", totp_code(RFC_SECRET, now / TOTP_STEP));
+
+        let first = totp.verify("alice", &code);
+        let replayed = totp.verify("alice", &code);
+        let other = totp.verify("bob", &code);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(first, Ok(true));
+        assert_eq!(replayed, Ok(false));
+        assert_eq!(other, Ok(false));
+    }
+}
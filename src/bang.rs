@@ -0,0 +1,138 @@
+//! History expansion of typed command lines, as in bash: `!!` is the last
+//! command, `!N` the one numbered N by `history`, `!-N` the Nth last one,
+//! `!PREFIX` the last one starting with PREFIX and `!$` the last word of
+//! the last one. `^OLD^NEW` at the start of a line runs the last command
+//! with OLD replaced by NEW. Nothing is expanded in single quotes, after a
+//! backslash or before a space, `=`, `(` or a closing double quote.
+
+use std::io;
+
+use crate::options::ShellOptions;
+use crate::session::Session;
+use crate::SHELL_NAME;
+
+/// Characters ending the prefix of `!PREFIX`
+const PREFIX_END: [char; 11] = [' ', '\t', '\n', ';', '&', '|', '<', '>', '(', ')', '"'];
+
+impl Session {
+    /// Expand the history references in a typed line, showing the line that
+    /// runs if it changed. Returns `None` if a reference can't be expanded,
+    /// after telling why.
+    pub(crate) fn expand_history(&mut self, input: &str) -> io::Result<Option<String>> {
+        if !self.options.contains(ShellOptions::HISTEXPAND) {
+            return Ok(Some(input.to_owned()));
+        }
+        match expand(input, &self.history) {
+            Ok(Some(expanded)) => {
+                self.write_output(format!("{}\n", expanded).as_bytes())?;
+                Ok(Some(expanded))
+            }
+            Ok(None) => Ok(Some(input.to_owned())),
+            Err(error) => {
+                self.print_error(&format!("{}: {}", SHELL_NAME, error));
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Expand a line. Returns `None` if there is nothing to expand, or an error
+/// naming the reference that wasn't found.
+fn expand(line: &str, history: &[String]) -> Result<Option<String>, String> {
+    if let Some(rest) = line.strip_prefix('^') {
+        return substitute(rest, history).map(Some);
+    }
+
+    let mut expanded = String::with_capacity(line.len());
+    let mut changed = false;
+    let mut single_quoted = false;
+    let mut double_quoted = false;
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if !single_quoted => {
+                expanded.push(c);
+                if let Some((_, escaped)) = chars.next() {
+                    expanded.push(escaped);
+                }
+                continue;
+            }
+            '\'' if !double_quoted => single_quoted = !single_quoted,
+            '"' if !single_quoted => double_quoted = !double_quoted,
+            '!' if !single_quoted => {
+                if let Some((event, length)) = event(&line[i + 1..], history)? {
+                    expanded.push_str(&event);
+                    changed = true;
+                    /* Skip the rest of the reference */
+                    let end = i + 1 + length;
+                    while chars.as_str().len() > line.len() - end {
+                        chars.next();
+                    }
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        expanded.push(c);
+    }
+    Ok(changed.then_some(expanded))
+}
+
+/// The text a reference after `!` stands for, with the length of the
+/// reference. `None` if the `!` doesn't start one.
+fn event(reference: &str, history: &[String]) -> Result<Option<(String, usize)>, String> {
+    let not_found = |length: usize| format!("!{}: event not found", &reference[..length]);
+    let last = |back: usize| {
+        history
+            .len()
+            .checked_sub(back)
+            .and_then(|index| history.get(index))
+    };
+
+    let first = match reference.chars().next() {
+        None | Some(' ' | '\t' | '\n' | '=' | '(' | '"') => return Ok(None),
+        Some(c) => c,
+    };
+    let (event, length) = match first {
+        '!' => (last(1).cloned(), 1),
+        '$' => {
+            let word = last(1).and_then(|line| line.split_whitespace().next_back());
+            (word.map(str::to_owned), 1)
+        }
+        '-' | '0'..='9' => {
+            let back = first == '-';
+            let digits = &reference[usize::from(back)..];
+            let count = digits.chars().take_while(char::is_ascii_digit).count();
+            let length = usize::from(back) + count;
+            let number = match digits[..count].parse::<usize>() {
+                Ok(number) if number > 0 => number,
+                _ => return Err(not_found(length)),
+            };
+            let line = match back {
+                true => last(number),
+                false => history.get(number - 1),
+            };
+            (line.cloned(), length)
+        }
+        _ => {
+            let length = reference.find(PREFIX_END).unwrap_or(reference.len());
+            let prefix = &reference[..length];
+            let line = history.iter().rev().find(|line| line.starts_with(prefix));
+            (line.cloned(), length)
+        }
+    };
+    match event {
+        Some(event) => Ok(Some((event, length))),
+        None => Err(not_found(length)),
+    }
+}
+
+/// `^OLD^NEW^`: the last command with the first OLD replaced by NEW
+fn substitute(rest: &str, history: &[String]) -> Result<String, String> {
+    let (old, new) = rest.split_once('^').unwrap_or((rest, ""));
+    let new = new.strip_suffix('^').unwrap_or(new);
+    match history.last() {
+        Some(line) if !old.is_empty() && line.contains(old) => Ok(line.replacen(old, new, 1)),
+        _ => Err(format!("^{}^{}: substitution failed", old, new)),
+    }
+}
@@ -0,0 +1,285 @@
+//! Brace expansion, which turns `file{1..3}.txt` into `file1.txt file2.txt
+//! file3.txt` and `/tmp/{in,out}` into `/tmp/in /tmp/out`. It comes before
+//! the other expansions, so only braces typed unquoted count.
+
+use crate::parser::{Word, WordPart};
+
+/// Most words a word expands to, in a sequence or in all its braces
+/// together. Words expanding to more are kept as they are instead of running
+/// the shell out of memory.
+const WORD_LIMIT: u64 = 100_000;
+
+/// A character typed unquoted, or any other part of a word
+#[derive(Clone)]
+enum Item {
+    Char(char),
+    Part(WordPart),
+}
+
+/// The words a word expands to. Words without braces to expand, with braces
+/// holding neither a comma nor a sequence like `1..5`, or expanding to more
+/// than `WORD_LIMIT` words are kept.
+pub(crate) fn expand(word: &Word) -> Vec<Word> {
+    let mut items = Vec::new();
+    for part in &word.parts {
+        match part {
+            WordPart::Literal(text) => items.extend(text.chars().map(Item::Char)),
+            part => items.push(Item::Part(part.clone())),
+        }
+    }
+    match items.iter().any(|item| matches!(item, Item::Char('{'))) {
+        true => match expand_items(&items) {
+            Some(expanded) => expanded.iter().map(|items| to_word(items)).collect(),
+            None => vec![word.clone()],
+        },
+        false => vec![word.clone()],
+    }
+}
+
+/// The expansions of the items, or `None` if there are more than
+/// `WORD_LIMIT` of them
+fn expand_items(items: &[Item]) -> Option<Vec<Vec<Item>>> {
+    for (start, item) in items.iter().enumerate() {
+        if !matches!(item, Item::Char('{')) {
+            continue;
+        }
+        let end = match closing(items, start) {
+            Some(end) => end,
+            None => continue,
+        };
+        let inside = &items[start + 1..end];
+        let alternatives: Vec<Vec<Item>> = match split(inside) {
+            alternatives if alternatives.len() > 1 => {
+                let mut expanded = Vec::new();
+                for alternative in alternatives {
+                    expanded.extend(expand_items(alternative)?);
+                    if expanded.len() as u64 > WORD_LIMIT {
+                        return None;
+                    }
+                }
+                expanded
+            }
+            _ => match sequence(inside) {
+                Some(sequence) => sequence
+                    .into_iter()
+                    .map(|text| text.chars().map(Item::Char).collect())
+                    .collect(),
+                None => continue,
+            },
+        };
+
+        let prefix = &items[..start];
+        let suffixes = expand_items(&items[end + 1..])?;
+        /* Each is at most `WORD_LIMIT` long, so the product fits */
+        if alternatives.len() as u64 * suffixes.len() as u64 > WORD_LIMIT {
+            return None;
+        }
+        let mut expanded = Vec::new();
+        for alternative in &alternatives {
+            for suffix in &suffixes {
+                let mut items = prefix.to_vec();
+                items.extend(alternative.iter().cloned());
+                items.extend(suffix.iter().cloned());
+                expanded.push(items);
+            }
+        }
+        return Some(expanded);
+    }
+    Some(vec![items.to_vec()])
+}
+
+/// Where the brace opened at `start` closes, if it does
+fn closing(items: &[Item], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, item) in items.iter().enumerate().skip(start) {
+        match item {
+            Item::Char('{') => depth += 1,
+            Item::Char('}') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The inside of braces split at the commas not in nested braces
+fn split(inside: &[Item]) -> Vec<&[Item]> {
+    let mut alternatives = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, item) in inside.iter().enumerate() {
+        match item {
+            Item::Char('{') => depth += 1,
+            Item::Char('}') => depth -= 1,
+            Item::Char(',') if depth == 0 => {
+                alternatives.push(&inside[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    alternatives.push(&inside[start..]);
+    alternatives
+}
+
+/// The words of a sequence like `1..5`, `01..10`, `10..1..3` or `a..e`
+fn sequence(inside: &[Item]) -> Option<Vec<String>> {
+    let text = inside
+        .iter()
+        .map(|item| match item {
+            Item::Char(c) => Some(*c),
+            Item::Part(_) => None,
+        })
+        .collect::<Option<String>>()?;
+    let fields: Vec<&str> = text.split("..").collect();
+    let (first, last, step) = match fields[..] {
+        [first, last] => (first, last, None),
+        [first, last, step] => (first, last, Some(step.parse::<i64>().ok()?)),
+        _ => return None,
+    };
+    let step = step.map_or(1, i64::unsigned_abs).max(1);
+
+    if let (Ok(from), Ok(to)) = (first.parse::<i64>(), last.parse::<i64>()) {
+        /* A leading zero pads every number to the same width */
+        let padded = |number: &str| {
+            let digits = number.trim_start_matches('-');
+            digits.len() > 1 && digits.starts_with('0')
+        };
+        let width = match padded(first) || padded(last) {
+            true => first.len().max(last.len()),
+            false => 0,
+        };
+        return Some(
+            numbers(from, to, step)?
+                .map(|number| match number < 0 {
+                    true => format!("-{:0>1$}", number.unsigned_abs(), width.saturating_sub(1)),
+                    false => format!("{:0>1$}", number, width),
+                })
+                .collect(),
+        );
+    }
+
+    let mut first = first.chars();
+    let mut last = last.chars();
+    match (first.next(), first.next(), last.next(), last.next()) {
+        (Some(from), None, Some(to), None)
+            if from.is_ascii_alphabetic() && to.is_ascii_alphabetic() =>
+        {
+            Some(
+                numbers(from as i64, to as i64, step)?
+                    .filter_map(|code| char::from_u32(code as u32))
+                    .map(String::from)
+                    .collect(),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// From `from` to `to` in steps of `step`, counting down if `to` is lower.
+/// Fails if there are more than `WORD_LIMIT` numbers.
+fn numbers(from: i64, to: i64, step: u64) -> Option<impl Iterator<Item = i64>> {
+    let count = (from.abs_diff(to) / step).checked_add(1)?;
+    if count > WORD_LIMIT {
+        return None;
+    }
+    /* The numbers lie between `from` and `to`, so they can't overflow */
+    Some((0..count).map(move |i| match from <= to {
+        true => from.wrapping_add_unsigned(i * step),
+        false => from.wrapping_sub_unsigned(i * step),
+    }))
+}
+
+fn to_word(items: &[Item]) -> Word {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    for item in items {
+        match item {
+            Item::Char(c) => literal.push(*c),
+            Item::Part(part) => {
+                if !literal.is_empty() {
+                    parts.push(WordPart::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(part.clone());
+            }
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(WordPart::Literal(literal));
+    }
+    Word { parts }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expanded(text: &str) -> Vec<String> {
+        let word = Word {
+            parts: vec![WordPart::Literal(text.to_owned())],
+        };
+        expand(&word)
+            .into_iter()
+            .map(|word| match &word.parts[..] {
+                [] => String::new(),
+                [WordPart::Literal(text)] => text.clone(),
+                parts => panic!("expected a literal, got {:?}", parts),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn expands_lists() {
+        assert_eq!(expanded("/tmp/{in,out}"), ["/tmp/in", "/tmp/out"]);
+        assert_eq!(expanded("{a,b{1,2}}c"), ["ac", "b1c", "b2c"]);
+        assert_eq!(expanded("{a,}"), ["a", ""]);
+        assert_eq!(expanded("{a}"), ["{a}"]);
+        assert_eq!(expanded("{a,b"), ["{a,b"]);
+    }
+
+    #[test]
+    fn expands_sequences() {
+        assert_eq!(expanded("{1..3}"), ["1", "2", "3"]);
+        assert_eq!(expanded("{3..1}"), ["3", "2", "1"]);
+        assert_eq!(expanded("{1..10..4}"), ["1", "5", "9"]);
+        assert_eq!(expanded("{10..1..-4}"), ["10", "6", "2"]);
+        assert_eq!(expanded("{08..10}"), ["08", "09", "10"]);
+        assert_eq!(expanded("{-01..1}"), ["-01", "000", "001"]);
+        assert_eq!(expanded("{a..c}"), ["a", "b", "c"]);
+        assert_eq!(expanded("{1..a}"), ["{1..a}"]);
+    }
+
+    #[test]
+    fn keeps_sequences_at_the_edges_of_the_integers() {
+        assert_eq!(
+            expanded("{9223372036854775806..9223372036854775807}"),
+            ["9223372036854775806", "9223372036854775807"]
+        );
+        assert_eq!(
+            expanded("{-9223372036854775808..-9223372036854775807}"),
+            ["-9223372036854775808", "-9223372036854775807"]
+        );
+        assert_eq!(
+            expanded("{1..9223372036854775807..9223372036854775807}"),
+            ["1"]
+        );
+        assert_eq!(expanded("{0..1..-9223372036854775808}"), ["0"]);
+    }
+
+    #[test]
+    fn keeps_words_expanding_to_too_many_words() {
+        for word in [
+            String::from("{-9223372036854775808..9223372036854775807}"),
+            "{1..5}".repeat(12),
+            "{a,b}".repeat(40),
+            format!("{{{}}}", ["{1..99999}"; 3].join(",")),
+        ] {
+            assert_eq!(expanded(&word), [word.as_str()]);
+        }
+        assert_eq!(expanded(&"{a,b}".repeat(16)).len(), 1 << 16);
+    }
+}
@@ -0,0 +1,198 @@
+//! Bundles for bug reports about the shell itself, written by `report-bug`.
+//! A tarball holds the version, the settings with secrets redacted, recent
+//! messages of the shell, the latest commands with the output of the last
+//! one, and what board it runs on, so it can be attached to an issue as is.
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::diagnostics;
+use crate::session::Session;
+
+/// How many of the latest commands are included
+const COMMANDS: usize = 50;
+
+/// Words in names of variables whose values are left out
+const SECRET_WORDS: [&str; 6] = ["PASS", "SECRET", "TOKEN", "KEY", "CREDENTIAL", "AUTH"];
+
+/// Size of the blocks of a tar archive
+const BLOCK: usize = 512;
+
+/// Files describing the board and system, with the name they get in the
+/// bundle
+const BOARD_FILES: [(&str, &str); 5] = [
+    ("/proc/device-tree/model", "model"),
+    ("/proc/cpuinfo", "cpuinfo"),
+    ("/proc/meminfo", "meminfo"),
+    ("/proc/uptime", "uptime"),
+    ("/etc/os-release", "os-release"),
+];
+
+/// Write a bundle about the session to `path`. Only the user can read it.
+pub(crate) fn write(session: &Session, path: &Path) -> io::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let mut files = vec![
+        ("version.txt", version().into_bytes()),
+        ("settings.txt", settings(session).into_bytes()),
+        ("log.txt", lines(diagnostics::recent()).into_bytes()),
+        ("commands.txt", commands(session).into_bytes()),
+        (
+            "last-output.txt",
+            session.last_output.iter().copied().collect(),
+        ),
+        ("board.txt", board().into_bytes()),
+    ];
+    files.retain(|(_, contents)| !contents.is_empty());
+
+    let mut tarball = Vec::new();
+    for (name, contents) in &files {
+        append(
+            &mut tarball,
+            &format!("pieshell-report/{}", name),
+            contents,
+            now,
+        );
+    }
+    /* The archive ends with two empty blocks */
+    tarball.resize(tarball.len() + 2 * BLOCK, 0);
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(&tarball))
+}
+
+fn lines(lines: Vec<String>) -> String {
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+fn version() -> String {
+    let features: Vec<&str> = [
+        ("async", cfg!(feature = "async")),
+        ("pam", cfg!(feature = "pam")),
+        ("sqlite", cfg!(feature = "sqlite")),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| *name)
+    .collect();
+    format!(
+        "pieshell {}\nfeatures: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        features.join(" ")
+    )
+}
+
+/// The settings of the session, without the values of variables that look
+/// like secrets
+fn settings(session: &Session) -> String {
+    let mut settings = session.settings().clone();
+    for (name, value) in settings.env.iter_mut() {
+        if is_secret(name) {
+            *value = String::from("<redacted>");
+        }
+    }
+    format!(
+        "transport: {:?}\nstatus of last command: {}\n{:#?}\n",
+        session.kind(),
+        session.last_status,
+        settings
+    )
+}
+
+/// The latest commands, with values assigned to variables that look like
+/// secrets left out, as in `export API_TOKEN=...`
+fn commands(session: &Session) -> String {
+    let skipped = session.history.len().saturating_sub(COMMANDS);
+    let commands = session.history[skipped..]
+        .iter()
+        .map(|line| {
+            line.split(' ')
+                .map(|word| match word.split_once('=') {
+                    Some((name, _)) if is_secret(name) => format!("{}=<redacted>", name),
+                    _ => word.to_owned(),
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect();
+    lines(commands)
+}
+
+fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    SECRET_WORDS.iter().any(|word| name.contains(word))
+}
+
+/// The files describing the board, one after the other under their names.
+/// The serial number of the Pi is left out.
+fn board() -> String {
+    let mut board = String::new();
+    for (path, name) in BOARD_FILES {
+        if let Ok(contents) = fs::read(path) {
+            /* The model in the device tree ends with a NUL */
+            let contents = String::from_utf8_lossy(&contents);
+            let contents: String = contents
+                .trim_end_matches('\0')
+                .lines()
+                .filter(|line| !line.starts_with("Serial"))
+                .map(|line| format!("{}\n", line))
+                .collect();
+            board.push_str(&format!("== {}\n{}\n", name, contents));
+        }
+    }
+
+    let mut uname: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uname) } == 0 {
+        let field = |field: &[libc::c_char]| {
+            let bytes: Vec<u8> = field
+                .iter()
+                .take_while(|c| **c != 0)
+                .map(|c| *c as u8)
+                .collect();
+            String::from_utf8_lossy(&bytes).into_owned()
+        };
+        board.push_str(&format!(
+            "== uname\n{} {} {}\n",
+            field(&uname.sysname),
+            field(&uname.release),
+            field(&uname.machine)
+        ));
+    }
+    board
+}
+
+/// Append a file to a tar archive, with a ustar header
+fn append(tarball: &mut Vec<u8>, name: &str, contents: &[u8], mtime: u64) {
+    let mut header = [0u8; BLOCK];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name.as_bytes());
+    field(100, b"0000600\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", contents.len()).as_bytes());
+    field(136, format!("{:011o}\0", mtime).as_bytes());
+    /* The checksum is counted as spaces */
+    field(148, b"        ");
+    field(156, b"0");
+    field(257, b"ustar\0");
+    field(263, b"00");
+
+    let checksum: u32 = header.iter().map(|byte| *byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    tarball.extend_from_slice(&header);
+    tarball.extend_from_slice(contents);
+    let padding = (BLOCK - contents.len() % BLOCK) % BLOCK;
+    tarball.resize(tarball.len() + padding, 0);
+}
@@ -0,0 +1,2838 @@
+//! Commands implemented inside the shell, as they change the state of the
+//! session and can't be run as separate processes.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::iter;
+use std::ops::RangeInclusive;
+use std::os::fd::OwnedFd;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bugreport;
+use crate::completion::SessionCompleter;
+use crate::condition;
+use crate::config::CdSpell;
+use crate::exec::{self, Jump, Timer};
+use crate::gpio as pins;
+use crate::history;
+use crate::i2c as bus;
+use crate::limits;
+use crate::options;
+use crate::parser;
+use crate::pinout;
+use crate::printf;
+use crate::pwm as channels;
+use crate::session::{self, Session};
+use crate::size::{self, Units};
+use crate::snapshot;
+use crate::spi::{self as spi_bus, Transfer};
+use crate::sysinfo;
+use crate::transport::Serial;
+use crate::traps;
+use crate::xmodem;
+use crate::{ShellError, StatusCode, SHELL_NAME};
+
+/// A builtin gets the session it runs in and its arguments, including its own
+/// name, and returns an exit status
+pub type Builtin = fn(&mut Session, &[&str]) -> i32;
+
+/// A builtin added by the program embedding the shell
+pub type CustomBuiltin = Arc<dyn Fn(&mut Session, &[&str]) -> i32 + Send + Sync>;
+
+/// Number of programs `history --stats` shows
+const HISTORY_TOP: usize = 10;
+
+const BUILTINS: [(&str, Builtin); 50] = [
+    (".", source),
+    ("[", test),
+    ("break", break_),
+    ("cd", cd),
+    ("clip", clip),
+    ("complete", complete),
+    ("continue", continue_),
+    ("converse", converse),
+    ("dirs", dirs),
+    ("echo", echo),
+    ("env", env),
+    ("eval", eval),
+    ("exec", exec),
+    ("exit", exit),
+    ("export", export),
+    ("gpio", gpio),
+    ("hash", hash),
+    ("history", history),
+    ("i2c", i2c),
+    ("jobs", jobs),
+    ("kill", kill),
+    ("log", log),
+    ("memstats", memstats),
+    ("numfmt", numfmt),
+    ("once", once),
+    ("pinout", pinout),
+    ("popd", popd),
+    ("printf", printf),
+    ("pushd", pushd),
+    ("pwm", pwm),
+    ("read", read),
+    ("rehash", rehash),
+    ("report-bug", report_bug),
+    ("return", return_),
+    ("rx", rx),
+    ("session", session_),
+    ("set", set),
+    ("source", source),
+    ("spi", spi),
+    ("sx", sx),
+    ("sysinfo", sysinfo),
+    ("test", test),
+    ("time", time),
+    ("timeout", timeout),
+    ("trap", trap),
+    ("type", type_),
+    ("ulimit", ulimit),
+    ("umask", umask),
+    ("wait", wait),
+    ("which", which),
+];
+
+/// How long `converse` waits for data before checking its timeout again
+const CONVERSE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a command name runs
+enum Resolved {
+    Function,
+    Builtin,
+    Program { path: PathBuf, cached: bool },
+}
+
+pub fn find(name: &str) -> Option<Builtin> {
+    BUILTINS
+        .iter()
+        .find(|(builtin_name, _)| *builtin_name == name)
+        .map(|(_, builtin)| *builtin)
+}
+
+/// Names of all builtins, for completing commands
+pub(crate) fn names() -> impl Iterator<Item = &'static str> {
+    BUILTINS.iter().map(|(name, _)| *name)
+}
+
+/// Whether a command name runs a builtin rather than a program
+pub(crate) fn is_builtin(session: &Session, name: &str) -> bool {
+    session.custom_builtins.contains_key(name) || find(name).is_some()
+}
+
+/// Find what a command name runs, the same way the executor does
+fn resolve(session: &mut Session, name: &str) -> Result<Resolved, ShellError> {
+    if session.functions.contains_key(name) {
+        return Ok(Resolved::Function);
+    }
+    if is_builtin(session, name) {
+        return Ok(Resolved::Builtin);
+    }
+
+    let cached = session.path_cache.programs.contains_key(name);
+    let path = session.find_program(name)?;
+    Ok(Resolved::Program { path, cached })
+}
+
+/// `break [N]`: leave the innermost N loops
+fn break_(session: &mut Session, args: &[&str]) -> i32 {
+    match loop_levels(session, args) {
+        Some(levels) => {
+            session.jump = Some(Jump::Break(levels));
+            0
+        }
+        None => 1,
+    }
+}
+
+/// `continue [N]`: go on with the next round of the Nth innermost loop
+fn continue_(session: &mut Session, args: &[&str]) -> i32 {
+    match loop_levels(session, args) {
+        Some(levels) => {
+            session.jump = Some(Jump::Continue(levels));
+            0
+        }
+        None => 1,
+    }
+}
+
+/// How many loops `break` and `continue` leave. More than are running leaves
+/// them all.
+fn loop_levels(session: &mut Session, args: &[&str]) -> Option<usize> {
+    if session.loop_depth == 0 {
+        session.print_error(&format!(
+            "{}: {}: only meaningful in a loop",
+            SHELL_NAME, args[0]
+        ));
+        return None;
+    }
+
+    let levels = match args.get(1..).unwrap_or_default() {
+        [] => 1,
+        [levels] => match levels.parse::<usize>() {
+            Ok(levels) if levels > 0 => levels,
+            _ => {
+                session.print_error(&format!(
+                    "{}: {}: {}: loop count out of range",
+                    SHELL_NAME, args[0], levels
+                ));
+                return None;
+            }
+        },
+        _ => {
+            session.print_error(&format!("{}: {}: too many arguments", SHELL_NAME, args[0]));
+            return None;
+        }
+    };
+    Some(levels.min(session.loop_depth))
+}
+
+/// `cd [DIR|-]`: change the working directory of the session. A relative
+/// DIR not starting with `.` is looked for in the directories of `CDPATH`
+/// too, and with `cd_spell` in the config a typo in DIR is corrected.
+fn cd(session: &mut Session, args: &[&str]) -> i32 {
+    let target = match args.get(1) {
+        None => match session.var("HOME") {
+            Some(home) => PathBuf::from(home),
+            None => {
+                session.print_error(&format!("{}: cd: HOME not set", SHELL_NAME));
+                return 1;
+            }
+        },
+        Some(&"-") => match &session.previous_dir {
+            Some(dir) => dir.clone(),
+            None => {
+                session.print_error(&format!("{}: cd: OLDPWD not set", SHELL_NAME));
+                return 1;
+            }
+        },
+        Some(dir) => match cdpath_dir(session, dir) {
+            Some(found) => {
+                /* Like other shells, say where a CDPATH search led */
+                if session
+                    .write_output(format!("{}\n", found.display()).as_bytes())
+                    .is_err()
+                {
+                    return 1;
+                }
+                found
+            }
+            None => session.cwd.join(dir),
+        },
+    };
+
+    let shown = args
+        .get(1)
+        .map_or(target.display().to_string(), |dir| dir.to_string());
+    let spell = session.settings().cd_spell;
+    let corrected = match spell {
+        CdSpell::Off => None,
+        _ if target.exists() => None,
+        _ => correct_spelling(&target),
+    };
+    match (spell, corrected) {
+        (CdSpell::Correct, Some(dir)) => {
+            let shown = dir.display().to_string();
+            if session
+                .write_output(format!("{}\n", shown).as_bytes())
+                .is_err()
+            {
+                return 1;
+            }
+            match enter_dir(session, "cd", &shown, &dir) {
+                true => 0,
+                false => 1,
+            }
+        }
+        (_, corrected) => match enter_dir(session, "cd", &shown, &target) {
+            true => 0,
+            false => {
+                if let Some(dir) = corrected {
+                    session.print_error(&format!(
+                        "{}: cd: did you mean {}?",
+                        SHELL_NAME,
+                        dir.display()
+                    ));
+                }
+                1
+            }
+        },
+    }
+}
+
+/// The directory of `CDPATH` holding `dir`, if `dir` is relative and
+/// doesn't start with `.` or `..`. An empty entry stands for the working
+/// directory, which is only returned if `dir` isn't there.
+fn cdpath_dir(session: &Session, dir: &str) -> Option<PathBuf> {
+    let relative = Path::new(dir);
+    match relative.components().next() {
+        Some(Component::Normal(_)) => {}
+        _ => return None,
+    }
+    let cdpath = session.var("CDPATH").filter(|cdpath| !cdpath.is_empty())?;
+    for entry in cdpath.split(':') {
+        let found = match entry {
+            "" if session.cwd.join(relative).is_dir() => return None,
+            "" => continue,
+            entry => session.cwd.join(entry).join(relative),
+        };
+        if found.is_dir() {
+            return Some(found);
+        }
+    }
+    None
+}
+
+/// The directory `dir` probably means, when some of its components don't
+/// exist but are a letter wrong, missing or extra, or have two letters
+/// swapped, from a directory that does, like `/hoem/pi` for `/home/pi`
+fn correct_spelling(dir: &Path) -> Option<PathBuf> {
+    let mut corrected = PathBuf::new();
+    for component in dir.components() {
+        let next = corrected.join(component);
+        if next.exists() {
+            corrected = next;
+            continue;
+        }
+        let name = component.as_os_str().to_str()?;
+        let found = fs::read_dir(&corrected)
+            .ok()?
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|entry| one_typo(name, entry))
+            .min()?;
+        corrected.push(found);
+    }
+    Some(corrected)
+}
+
+/// Whether `typed` is `name` with one letter wrong, missing or extra, or
+/// two neighbouring letters swapped
+fn one_typo(typed: &str, name: &str) -> bool {
+    let typed: Vec<char> = typed.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let prefix = iter::zip(&typed, &name).take_while(|(a, b)| a == b).count();
+    let (typed, name) = (&typed[prefix..], &name[prefix..]);
+    match (typed.len(), name.len()) {
+        (0, 0) => false,
+        (a, b) if a == b => {
+            typed[1..] == name[1..]
+                || (a >= 2 && typed[0] == name[1] && typed[1] == name[0] && typed[2..] == name[2..])
+        }
+        (a, b) if a == b + 1 => typed[1..] == *name,
+        (a, b) if a + 1 == b => *typed == name[1..],
+        _ => false,
+    }
+}
+
+/// Make a directory the working directory of the session, as `cd` does for
+/// `builtin`. Errors name the directory as `shown`. Returns false if it
+/// isn't a directory or is outside the restricted directory.
+fn enter_dir(session: &mut Session, builtin: &str, shown: &str, target: &Path) -> bool {
+    let sandbox = session
+        .settings()
+        .restricted
+        .as_ref()
+        .and_then(|restricted| restricted.directory.clone());
+    match target.canonicalize() {
+        Ok(dir)
+            if sandbox
+                .as_ref()
+                .is_some_and(|sandbox| !dir.starts_with(sandbox)) =>
+        {
+            session.print_error(&format!(
+                "{}: {}: {}: restricted",
+                SHELL_NAME, builtin, shown
+            ));
+            false
+        }
+        Ok(dir) if dir.is_dir() => {
+            let previous = std::mem::replace(&mut session.cwd, dir);
+            session.previous_dir = Some(previous);
+            true
+        }
+        Ok(_) => {
+            session.print_error(&format!(
+                "{}: {}: {}: Not a directory",
+                SHELL_NAME, builtin, shown
+            ));
+            false
+        }
+        Err(error) => {
+            session.print_error(&format!(
+                "{}: {}: {}: {}",
+                SHELL_NAME, builtin, shown, error
+            ));
+            false
+        }
+    }
+}
+
+/// `pushd [DIR | +N | -N]`: save the working directory on the directory
+/// stack and change to DIR, or swap the two on top without arguments. `+N`
+/// and `-N` rotate the stack, so the Nth entry of `dirs` counted from the
+/// left or right comes on top. The stack is shown afterwards.
+fn pushd(session: &mut Session, args: &[&str]) -> i32 {
+    let entered = match args.get(1..).unwrap_or_default() {
+        [] => match session.dir_stack.first().cloned() {
+            Some(dir) => {
+                let previous = session.cwd.clone();
+                let entered = enter_dir(session, "pushd", &dir.display().to_string(), &dir);
+                if entered {
+                    session.dir_stack[0] = previous;
+                }
+                entered
+            }
+            None => {
+                session.print_error(&format!("{}: pushd: no other directory", SHELL_NAME));
+                return 1;
+            }
+        },
+        [arg] if stack_index(arg).is_some() => {
+            let index = match stack_entry(session, "pushd", arg) {
+                Some(index) => index,
+                None => return 1,
+            };
+            let mut dirs = stack(session);
+            dirs.rotate_left(index);
+            let top = dirs.remove(0);
+            let entered = enter_dir(session, "pushd", &top.display().to_string(), &top);
+            if entered {
+                session.dir_stack = dirs;
+            }
+            entered
+        }
+        [dir] => {
+            let previous = session.cwd.clone();
+            let target = session.cwd.join(dir);
+            let entered = enter_dir(session, "pushd", dir, &target);
+            if entered {
+                session.dir_stack.insert(0, previous);
+            }
+            entered
+        }
+        _ => {
+            session.print_error(&format!(
+                "{}: pushd: usage: pushd [DIR | +N | -N]",
+                SHELL_NAME
+            ));
+            return 2;
+        }
+    };
+    match entered {
+        true => show_dirs(session, &DirsFormat::default()),
+        false => 1,
+    }
+}
+
+/// `popd [+N | -N]`: drop the top of the directory stack and change to the
+/// directory below it, or drop the Nth entry of `dirs` counted from the
+/// left or right. The stack is shown afterwards.
+fn popd(session: &mut Session, args: &[&str]) -> i32 {
+    let index = match args.get(1..).unwrap_or_default() {
+        [] => 0,
+        [arg] if stack_index(arg).is_some() => match stack_entry(session, "popd", arg) {
+            Some(index) => index,
+            None => return 1,
+        },
+        _ => {
+            session.print_error(&format!("{}: popd: usage: popd [+N | -N]", SHELL_NAME));
+            return 2;
+        }
+    };
+    if session.dir_stack.is_empty() {
+        session.print_error(&format!("{}: popd: directory stack empty", SHELL_NAME));
+        return 1;
+    }
+
+    match index {
+        0 => {
+            let dir = session.dir_stack[0].clone();
+            if !enter_dir(session, "popd", &dir.display().to_string(), &dir) {
+                return 1;
+            }
+            session.dir_stack.remove(0);
+        }
+        index => {
+            session.dir_stack.remove(index - 1);
+        }
+    }
+    show_dirs(session, &DirsFormat::default())
+}
+
+/// How `dirs` shows the directory stack
+#[derive(Default)]
+struct DirsFormat {
+    /// Full paths instead of ones starting with `~`
+    long: bool,
+    /// One entry per line
+    lines: bool,
+    /// One entry per line with its position
+    numbered: bool,
+}
+
+/// `dirs [-clpv] [+N | -N]`: show the directory stack, the working
+/// directory first, with the home directory as `~`. `-l` shows full paths,
+/// `-p` an entry per line and `-v` numbers them. `+N` and `-N` show only the
+/// Nth entry counted from the left or right, and `-c` clears the stack.
+fn dirs(session: &mut Session, args: &[&str]) -> i32 {
+    let mut format = DirsFormat::default();
+    let mut entry = None;
+    for arg in &args[1..] {
+        if stack_index(arg).is_some() {
+            match stack_entry(session, "dirs", arg) {
+                Some(index) => entry = Some(index),
+                None => return 1,
+            }
+            continue;
+        }
+        match arg.strip_prefix('-') {
+            Some(flags) if !flags.is_empty() && flags.chars().all(|c| "clpv".contains(c)) => {
+                for flag in flags.chars() {
+                    match flag {
+                        'c' => session.dir_stack.clear(),
+                        'l' => format.long = true,
+                        'p' => format.lines = true,
+                        _ => format.numbered = true,
+                    }
+                }
+            }
+            _ => {
+                session.print_error(&format!(
+                    "{}: dirs: usage: dirs [-clpv] [+N | -N]",
+                    SHELL_NAME
+                ));
+                return 2;
+            }
+        }
+    }
+
+    match entry {
+        Some(index) => {
+            let dir = display_dir(session, &stack(session)[index], format.long);
+            match session.write_output(format!("{}\n", dir).as_bytes()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            }
+        }
+        None => show_dirs(session, &format),
+    }
+}
+
+fn show_dirs(session: &mut Session, format: &DirsFormat) -> i32 {
+    let dirs: Vec<String> = stack(session)
+        .iter()
+        .map(|dir| display_dir(session, dir, format.long))
+        .collect();
+    let listing = match (format.numbered, format.lines) {
+        (true, _) => dirs
+            .iter()
+            .enumerate()
+            .map(|(i, dir)| format!("{:2}  {}\n", i, dir))
+            .collect(),
+        (false, true) => dirs.iter().map(|dir| format!("{}\n", dir)).collect(),
+        (false, false) => format!("{}\n", dirs.join(" ")),
+    };
+    match session.write_output(listing.as_bytes()) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// The working directory followed by the directory stack, as `dirs` shows
+/// them
+fn stack(session: &Session) -> Vec<PathBuf> {
+    iter::once(session.cwd.clone())
+        .chain(session.dir_stack.iter().cloned())
+        .collect()
+}
+
+/// A directory with the home directory written as `~`, unless `long`
+fn display_dir(session: &Session, dir: &Path, long: bool) -> String {
+    let home = session.var("HOME").filter(|home| !home.is_empty());
+    match home.and_then(|home| dir.strip_prefix(home).ok().map(Path::to_owned)) {
+        Some(rest) if !long && rest.as_os_str().is_empty() => String::from("~"),
+        Some(rest) if !long => format!("~/{}", rest.display()),
+        _ => dir.display().to_string(),
+    }
+}
+
+/// The number of `+N` or `-N`, and whether it counts from the left
+fn stack_index(arg: &str) -> Option<(usize, bool)> {
+    let (digits, from_left) = match arg.strip_prefix('+') {
+        Some(digits) => (digits, true),
+        None => (arg.strip_prefix('-')?, false),
+    };
+    match digits.chars().all(|c| c.is_ascii_digit()) {
+        true => digits.parse().ok().map(|number| (number, from_left)),
+        false => None,
+    }
+}
+
+/// The position in `dirs` that `+N` or `-N` names, if the stack has it
+fn stack_entry(session: &mut Session, builtin: &str, arg: &str) -> Option<usize> {
+    let length = session.dir_stack.len() + 1;
+    let index = match stack_index(arg)? {
+        (number, true) if number < length => Some(number),
+        (number, false) if number < length => Some(length - 1 - number),
+        _ => None,
+    };
+    if index.is_none() {
+        session.print_error(&format!(
+            "{}: {}: {}: directory stack index out of range",
+            SHELL_NAME, builtin, arg
+        ));
+    }
+    index
+}
+
+/// `clip [[<] FILE]`: copy a file, or the output of the last command, to the
+/// clipboard of the terminal
+fn clip(session: &mut Session, args: &[&str]) -> i32 {
+    /* There is no redirection of builtin input, so "< FILE" is taken to
+    name the file */
+    let file = match args.get(1..).unwrap_or_default() {
+        [] => None,
+        [file] | ["<", file] => Some(file),
+        _ => {
+            session.print_error(&format!("{}: clip: usage: clip [[<] FILE]", SHELL_NAME));
+            return 2;
+        }
+    };
+
+    let data = match file {
+        Some(file) => match fs::read(session.cwd.join(file)) {
+            Ok(data) => data,
+            Err(error) => {
+                session.print_error(&format!("{}: clip: {}: {}", SHELL_NAME, file, error));
+                return 1;
+            }
+        },
+        None => Vec::from(session.last_output.clone()),
+    };
+
+    match session.copy_to_clipboard(&data) {
+        Ok(()) => 0,
+        Err(error) => {
+            session.print_error(&format!("{}: clip: {}", SHELL_NAME, error));
+            1
+        }
+    }
+}
+
+/// A step of `converse`
+enum Step<'a> {
+    Send(&'a str),
+    Expect(&'a str),
+}
+
+/// `converse DEVICE [--baud N] [--timeout SECONDS] [--send TEXT | --expect
+/// TEXT]...`: script a device on a serial port, like a modem or GPS module.
+/// Lines are sent ending with a carriage return, and each expected text must
+/// arrive within the timeout. What the device sends is shown.
+fn converse(session: &mut Session, args: &[&str]) -> i32 {
+    let mut device = None;
+    let mut baud = 115_200;
+    let mut timeout = Duration::from_secs(5);
+    let mut steps = Vec::new();
+
+    let mut rest = args[1..].iter();
+    while let Some(&arg) = rest.next() {
+        let value = match (arg, rest.clone().next()) {
+            ("--baud" | "--timeout" | "--send" | "--expect", Some(value)) => {
+                rest.next();
+                *value
+            }
+            (arg, _) if device.is_none() && !arg.starts_with("--") => {
+                device = Some(arg);
+                continue;
+            }
+            _ => return converse_usage(session),
+        };
+        match arg {
+            "--baud" => match value.parse::<u32>() {
+                Ok(value) if value > 0 => baud = value,
+                _ => {
+                    session.print_error(&format!(
+                        "{}: converse: '{}' is not a valid baud rate",
+                        SHELL_NAME, value
+                    ));
+                    return 2;
+                }
+            },
+            "--timeout" => match value
+                .parse::<f64>()
+                .ok()
+                .filter(|seconds| *seconds > 0.0)
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            {
+                Some(limit) => timeout = limit,
+                None => {
+                    session.print_error(&format!(
+                        "{}: converse: '{}' is not a positive number of seconds",
+                        SHELL_NAME, value
+                    ));
+                    return 2;
+                }
+            },
+            "--send" => steps.push(Step::Send(value)),
+            _ if value.is_empty() => return converse_usage(session),
+            _ => steps.push(Step::Expect(value)),
+        }
+    }
+    let device = match device {
+        Some(device) => device,
+        None => return converse_usage(session),
+    };
+
+    let path = session.cwd.join(device);
+    let mut serial = match Serial::open(&path, baud, CONVERSE_INTERVAL) {
+        Ok(serial) => serial,
+        Err(error) => {
+            session.print_error(&format!("{}: converse: {}: {}", SHELL_NAME, device, error));
+            return 1;
+        }
+    };
+
+    /* Data received since the last expected text */
+    let mut received = Vec::new();
+    for step in steps {
+        let result = match step {
+            Step::Send(text) => serial.write_all(format!("{}\r", text).as_bytes()),
+            Step::Expect(text) => {
+                /* A deadline too far away to be a point in time never passes */
+                let deadline = Instant::now().checked_add(timeout);
+                loop {
+                    if let Some(position) = received
+                        .windows(text.len())
+                        .position(|window| window == text.as_bytes())
+                    {
+                        received.drain(..position + text.len());
+                        break Ok(());
+                    }
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        session.print_error(&format!(
+                            "{}: converse: timed out waiting for '{}'",
+                            SHELL_NAME, text
+                        ));
+                        return 1;
+                    }
+
+                    let mut buf = [0u8; 256];
+                    match serial.read(&mut buf) {
+                        Ok(length) => {
+                            received.extend_from_slice(&buf[..length]);
+                            if let Err(error) = session.write_output(&buf[..length]) {
+                                break Err(error);
+                            }
+                        }
+                        Err(error) => break Err(error),
+                    }
+                }
+            }
+        };
+        if let Err(error) = result {
+            session.print_error(&format!("{}: converse: {}: {}", SHELL_NAME, device, error));
+            return 1;
+        }
+    }
+    0
+}
+
+fn converse_usage(session: &mut Session) -> i32 {
+    session.print_error(&format!(
+        "{}: converse: usage: converse DEVICE [--baud N] [--timeout SECONDS] \
+         [--send TEXT | --expect TEXT]...",
+        SHELL_NAME
+    ));
+    2
+}
+
+/// `echo [-neE] [ARG]...`: write the arguments, joined by spaces. `-n` leaves
+/// out the newline and `-e` replaces escapes like `\t`.
+fn echo(session: &mut Session, args: &[&str]) -> i32 {
+    let mut newline = true;
+    let mut escapes = false;
+    let mut words = &args[1..];
+    while let Some(flags) = words.first().and_then(|word| word.strip_prefix('-')) {
+        if flags.is_empty() || !flags.chars().all(|flag| "neE".contains(flag)) {
+            break;
+        }
+        for flag in flags.chars() {
+            match flag {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        words = &words[1..];
+    }
+
+    let text = words.join(" ");
+    let mut output = Vec::new();
+    match escapes {
+        /* `\c` also drops the newline */
+        true => newline &= !printf::unescape(&text, &mut output),
+        false => output.extend_from_slice(text.as_bytes()),
+    }
+    if newline {
+        output.push(b'\n');
+    }
+
+    match session.write_output(&output) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// `env [ARG]...`: list the environment commands of the session get.
+/// With arguments, the `env` program is run with them, as it changes the
+/// environment only for the command it starts.
+fn env(session: &mut Session, args: &[&str]) -> i32 {
+    if args.len() > 1 {
+        let path = match session.find_program("env") {
+            Ok(path) => path,
+            Err(error) => {
+                session.report(&error);
+                return error.exit_code();
+            }
+        };
+        let command: Vec<String> = iter::once(path.display().to_string())
+            .chain(args[1..].iter().map(|arg| arg.to_string()))
+            .map(|arg| exec::quote(&arg))
+            .collect();
+        return session.run_nested("env", &command.join(" "));
+    }
+
+    let listing: String = session
+        .environment()
+        .iter()
+        .map(|(name, value)| format!("{}={}\n", name, value))
+        .collect();
+    match session.write_output(listing.as_bytes()) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// `eval [ARG]...`: run the arguments, joined by spaces, as a command
+fn eval(session: &mut Session, args: &[&str]) -> i32 {
+    let source = args[1..].join(" ");
+    session.run_nested("eval", &source)
+}
+
+/// `exec [COMMAND [ARG]...]`: replace the shell with COMMAND, e.g. to hand
+/// the console over to bash. The executor runs it with its redirections,
+/// see `Session::exec`.
+fn exec(session: &mut Session, args: &[&str]) -> i32 {
+    session.exec(args, Vec::new(), Vec::new())
+}
+
+/// `exit [N]`: end the session with exit status N, or that of the last
+/// command. The status of the first session is that of the shell process.
+fn exit(session: &mut Session, args: &[&str]) -> i32 {
+    session.exit_requested = true;
+    match args.get(1..).unwrap_or_default() {
+        [] => session.last_status,
+        /* Exit statuses are a single byte */
+        [status] => match status.parse::<i32>() {
+            Ok(status) => status & 0xff,
+            Err(_) => {
+                session.print_error(&format!(
+                    "{}: exit: {}: numeric argument required",
+                    SHELL_NAME, status
+                ));
+                2
+            }
+        },
+        _ => {
+            /* Like other shells, stay in the session */
+            session.exit_requested = false;
+            session.print_error(&format!("{}: exit: too many arguments", SHELL_NAME));
+            1
+        }
+    }
+}
+
+/// `export [NAME[=VALUE]]...`: set variables passed to commands started from
+/// the session, or list them when called without arguments. A name alone
+/// passes on a variable set in the shell.
+fn export(session: &mut Session, args: &[&str]) -> i32 {
+    if args.len() == 1 {
+        let mut variables: Vec<String> = session
+            .env
+            .iter()
+            .map(|(name, value)| format!("export {}=\"{}\"\n", name, value))
+            .collect();
+        variables.sort();
+        return match session.write_output(variables.concat().as_bytes()) {
+            Ok(()) => 0,
+            Err(_) => 1,
+        };
+    }
+
+    let mut status = 0;
+    for arg in &args[1..] {
+        match arg.split_once('=') {
+            Some((name, value)) if parser::is_valid_name(name) => {
+                session.vars.remove(name);
+                session.env.insert(name.to_owned(), value.to_owned());
+            }
+            None if parser::is_valid_name(arg) => {
+                if let Some(value) = session.vars.remove(*arg) {
+                    session.env.insert(arg.to_string(), value);
+                }
+            }
+            _ => {
+                session.print_error(&format!(
+                    "{}: export: '{}': not a valid identifier",
+                    SHELL_NAME, arg
+                ));
+                status = 1;
+            }
+        }
+    }
+
+    status
+}
+
+/// `gpio status | read PIN | write PIN 0|1 | toggle PIN | mode PIN MODE`:
+/// show or change the GPIO pins of the header. Writing or toggling a pin
+/// makes it an output. Modes are `in`, `out` and `alt0` to `alt5`.
+fn gpio(session: &mut Session, args: &[&str]) -> i32 {
+    if !session.settings().hardware {
+        session.print_error(&format!(
+            "{}: gpio: hardware builtins are disabled in the config",
+            SHELL_NAME
+        ));
+        return 1;
+    }
+    let pin = |session: &mut Session, pin: &str| match pin.parse::<u8>() {
+        Ok(pin) if pins::PINS.contains(&pin) => Some(pin),
+        _ => {
+            session.print_error(&format!(
+                "{}: gpio: {}: invalid pin, pins are 0 to 27",
+                SHELL_NAME, pin
+            ));
+            None
+        }
+    };
+
+    let output = match args.get(1..).unwrap_or_default() {
+        ["status"] => pins::status(),
+        ["read", number] => match pin(session, number) {
+            Some(number) => pins::read(number).map(|high| format!("{}\n", u8::from(high))),
+            None => return 2,
+        },
+        ["write", number, level] => {
+            let (number, high) = match (pin(session, number), *level) {
+                (Some(number), "0") => (number, false),
+                (Some(number), "1") => (number, true),
+                (Some(_), level) => {
+                    session.print_error(&format!(
+                        "{}: gpio: {}: invalid level, levels are 0 and 1",
+                        SHELL_NAME, level
+                    ));
+                    return 2;
+                }
+                (None, _) => return 2,
+            };
+            pins::write(number, high).map(|()| String::new())
+        }
+        ["toggle", number] => match pin(session, number) {
+            Some(number) => pins::toggle(number).map(|high| format!("{}\n", u8::from(high))),
+            None => return 2,
+        },
+        ["mode", number, mode] => {
+            let (number, mode) = match (pin(session, number), pins::mode_by_name(mode)) {
+                (Some(number), Some(mode)) => (number, mode),
+                (Some(_), None) => {
+                    session.print_error(&format!(
+                        "{}: gpio: {}: invalid mode, modes are in, out and alt0 to alt5",
+                        SHELL_NAME, mode
+                    ));
+                    return 2;
+                }
+                (None, _) => return 2,
+            };
+            pins::set_mode(number, mode).map(|()| String::new())
+        }
+        _ => {
+            session.print_error(&format!(
+                "{}: gpio: usage: gpio status | read PIN | write PIN 0|1 | toggle PIN | \
+                 mode PIN MODE",
+                SHELL_NAME
+            ));
+            return 2;
+        }
+    };
+
+    match output {
+        Ok(output) => match session.write_output(output.as_bytes()) {
+            Ok(()) => 0,
+            Err(_) => 1,
+        },
+        Err(error) => {
+            session.print_error(&format!("{}: gpio: {}", SHELL_NAME, error));
+            1
+        }
+    }
+}
+
+/// `complete -W WORDS NAME...`, `complete -F FUNCTION NAME...`: complete
+/// the arguments of commands with the words, split at whitespace, or with
+/// the `COMPREPLY` a function sets, see `SessionCompleter`. `complete -r
+/// [NAME]...` removes completers, all of them without names, and `complete`
+/// and `complete -p` list them.
+fn complete(session: &mut Session, args: &[&str]) -> i32 {
+    let (completer, names) = match &args[1..] {
+        [] | ["-p"] => {
+            let mut listing: Vec<String> = session
+                .completers
+                .iter()
+                .map(|(name, completer)| match completer {
+                    SessionCompleter::Words(words) => format!(
+                        "complete -W {} {}\n",
+                        exec::quote(&words.join(" ")),
+                        exec::quote(name)
+                    ),
+                    SessionCompleter::Function(function) => {
+                        format!("complete -F {} {}\n", function, exec::quote(name))
+                    }
+                })
+                .collect();
+            listing.sort();
+            return match session.write_output(listing.concat().as_bytes()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            };
+        }
+        ["-r"] => {
+            session.completers.clear();
+            return 0;
+        }
+        ["-r", names @ ..] => {
+            let mut status = 0;
+            for name in names {
+                if session.completers.remove(*name).is_none() {
+                    session.print_error(&format!(
+                        "{}: complete: {}: no completion specification",
+                        SHELL_NAME, name
+                    ));
+                    status = 1;
+                }
+            }
+            return status;
+        }
+        ["-W", words, names @ ..] if !names.is_empty() => {
+            let words = words.split_whitespace().map(str::to_owned).collect();
+            (SessionCompleter::Words(words), names)
+        }
+        ["-F", function, names @ ..] if !names.is_empty() => {
+            (SessionCompleter::Function((*function).to_owned()), names)
+        }
+        _ => {
+            session.print_error(&format!(
+                "{}: complete: usage: complete [-p] | -W WORDS NAME... | -F FUNCTION NAME... | \
+                 -r [NAME]...",
+                SHELL_NAME
+            ));
+            return 2;
+        }
+    };
+
+    for name in names {
+        session
+            .completers
+            .insert((*name).to_owned(), completer.clone());
+    }
+    0
+}
+
+/// `hash [-r] [NAME]...`: look up programs and remember where they are, or
+/// list the remembered ones when called without arguments. `-r` forgets them.
+fn hash(session: &mut Session, args: &[&str]) -> i32 {
+    match args.get(1..).unwrap_or_default() {
+        [] => {
+            if session.path_cache.programs.is_empty() {
+                return match session.write_output(b"hash: hash table empty\n") {
+                    Ok(()) => 0,
+                    Err(_) => 1,
+                };
+            }
+            let mut programs: Vec<String> = session
+                .path_cache
+                .programs
+                .iter()
+                .map(|(name, path)| format!("{}\t{}\n", name, path.display()))
+                .collect();
+            programs.sort();
+            match session.write_output(programs.concat().as_bytes()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            }
+        }
+        ["-r"] => rehash(session, args),
+        names => {
+            let mut status = 0;
+            for name in names {
+                if let Err(error) = session.find_program(name) {
+                    session.print_error(&format!("{}: hash: {}", SHELL_NAME, error));
+                    status = 1;
+                }
+            }
+            status
+        }
+    }
+}
+
+/// `log [on [FILE] | off]`: start or stop appending a transcript of the
+/// session to FILE, or the file of the config. Without arguments, tells
+/// where it goes.
+fn log(session: &mut Session, args: &[&str]) -> i32 {
+    let message = match args.get(1..).unwrap_or_default() {
+        [] => match session.transcript_path() {
+            Some(path) => format!("logging to {}\n", path.display()),
+            None => String::from("not logging\n"),
+        },
+        ["on", file @ ..] if file.len() <= 1 => {
+            let path = match file.first() {
+                Some(file) => session.cwd.join(file),
+                None => match &session.settings().transcript {
+                    Some(path) => path.clone(),
+                    None => {
+                        session.print_error(&format!(
+                            "{}: log: no file given and none in the config",
+                            SHELL_NAME
+                        ));
+                        return 2;
+                    }
+                },
+            };
+            if let Err(error) = session.start_transcript(&path) {
+                session.print_error(&format!(
+                    "{}: log: {}: {}",
+                    SHELL_NAME,
+                    path.display(),
+                    error
+                ));
+                return 1;
+            }
+            format!("logging to {}\n", path.display())
+        }
+        ["off"] => match session.stop_transcript() {
+            Some(path) => format!("stopped logging to {}\n", path.display()),
+            None => String::from("not logging\n"),
+        },
+        _ => {
+            session.print_error(&format!(
+                "{}: log: usage: log [on [FILE] | off]",
+                SHELL_NAME
+            ));
+            return 2;
+        }
+    };
+    match session.write_output(message.as_bytes()) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// `memstats`: show the memory used by the shell process and by the parts
+/// of this session that have a cap
+fn memstats(session: &mut Session, _args: &[&str]) -> i32 {
+    /* The resident set size is counted for the whole process, shared by all
+    sessions */
+    let rss = fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("VmRSS:"))
+                .map(|value| value.trim().to_owned())
+        })
+        .unwrap_or_else(|| String::from("unknown"));
+
+    let settings = session.settings();
+    let history = session::history_memory(&session.history);
+    let (jobs, job_output) = session.jobs.memory();
+    let human = |bytes: usize| size::format(bytes as u64, Units::Iec);
+    let report = format!(
+        "process rss  {}\n\
+         history      {} of {} ({} entries)\n\
+         scrollback   {} of {}\n\
+         jobs         {} buffered for {} jobs, {} each at most\n",
+        rss,
+        human(history),
+        human(settings.history_memory),
+        session.history.len(),
+        human(session.last_output.len()),
+        human(settings.scrollback_memory),
+        human(job_output),
+        jobs,
+        human(settings.job_memory),
+    );
+
+    match session.write_output(report.as_bytes()) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// `numfmt [--from=UNITS] [--to=UNITS] NUMBER...`: convert sizes in bytes
+/// to and from sizes like `1.5G`, for the units `iec`, `si` and, for
+/// `--from` only, `auto`
+fn numfmt(session: &mut Session, args: &[&str]) -> i32 {
+    let mut from = None;
+    let mut to = None;
+    let mut numbers = Vec::new();
+    for arg in &args[1..] {
+        if let Some(name) = arg.strip_prefix("--from=") {
+            from = match Units::from_name(name) {
+                Some(units) => Some(units),
+                None => return numfmt_invalid(session, "units", name),
+            };
+        } else if let Some(name) = arg.strip_prefix("--to=") {
+            to = match Units::from_name(name) {
+                Some(Units::Auto) | None => return numfmt_invalid(session, "units", name),
+                Some(units) => Some(units),
+            };
+        } else if arg.starts_with("--") {
+            return numfmt_usage(session);
+        } else {
+            numbers.push(*arg);
+        }
+    }
+    if numbers.is_empty() {
+        return numfmt_usage(session);
+    }
+
+    let mut output = String::new();
+    for number in numbers {
+        let bytes = match from {
+            Some(units) => size::parse(number, units),
+            None => number.parse().ok(),
+        };
+        let bytes = match bytes {
+            Some(bytes) => bytes,
+            None => return numfmt_invalid(session, "number", number),
+        };
+        match to {
+            Some(units) => output.push_str(&size::format(bytes, units)),
+            None => output.push_str(&bytes.to_string()),
+        }
+        output.push('\n');
+    }
+
+    match session.write_output(output.as_bytes()) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn numfmt_invalid(session: &mut Session, what: &str, text: &str) -> i32 {
+    session.print_error(&format!(
+        "{}: numfmt: invalid {} '{}'",
+        SHELL_NAME, what, text
+    ));
+    2
+}
+
+fn numfmt_usage(session: &mut Session) -> i32 {
+    session.print_error(&format!(
+        "{}: numfmt: usage: numfmt [--from=iec|si|auto] [--to=iec|si] NUMBER...",
+        SHELL_NAME
+    ));
+    2
+}
+
+/// `once KEY COMMAND [ARG]...`: run the command with its arguments as given,
+/// unless a command was run with the same key before. Then its output and
+/// exit status are given again, so clients can retry without running a step
+/// twice.
+fn once(session: &mut Session, args: &[&str]) -> i32 {
+    let (key, command) = match args {
+        [_, key, command @ ..] if !command.is_empty() => (*key, command),
+        _ => {
+            session.print_error(&format!(
+                "{}: once: usage: once KEY COMMAND [ARG]...",
+                SHELL_NAME
+            ));
+            return 2;
+        }
+    };
+
+    if let Some(receipt) = session.receipts.find(key) {
+        return match session.write_output(&receipt.output) {
+            Ok(()) => receipt.status,
+            Err(_) => 1,
+        };
+    }
+
+    let outer = session.receipts.start();
+    let status = session.run_nested_args("once", command);
+    /* A command cut short didn't finish its work, so retrying runs it */
+    let key = match session.timed_out || session.exit_requested {
+        true => None,
+        false => Some(key),
+    };
+    session.receipts.finish(key, status, outer);
+    status
+}
+
+/// `pinout`: show the pin header of the board, with the mode and level of
+/// the GPIO pins unless hardware builtins are disabled
+fn pinout(session: &mut Session, args: &[&str]) -> i32 {
+    if args.len() > 1 {
+        session.print_error(&format!("{}: pinout: usage: pinout", SHELL_NAME));
+        return 2;
+    }
+    let model = pinout::model();
+    let states = match session.settings().hardware {
+        true => Some(pins::states()),
+        false => None,
+    };
+    let diagram = pinout::diagram(
+        model.as_deref(),
+        states.as_ref().and_then(|states| states.as_deref().ok()),
+    );
+    if session.write_output(diagram.as_bytes()).is_err() {
+        return 1;
+    }
+    match states {
+        Some(Err(error)) => {
+            session.print_error(&format!("{}: pinout: {}", SHELL_NAME, error));
+            1
+        }
+        _ => 0,
+    }
+}
+
+/// `printf FORMAT [ARG]...`: write the arguments as the format says, using
+/// the format again while arguments are left
+fn printf(session: &mut Session, args: &[&str]) -> i32 {
+    let format = match args.get(1) {
+        Some(format) => format,
+        None => {
+            session.print_error(&format!(
+                "{}: printf: usage: printf FORMAT [ARG]...",
+                SHELL_NAME
+            ));
+            return 2;
+        }
+    };
+
+    let formatted = match printf::printf(format, &args[2..]) {
+        Ok(formatted) => formatted,
+        Err(error) => {
+            session.print_error(&format!("{}: printf: {}", SHELL_NAME, error));
+            return 1;
+        }
+    };
+    if session.write_output(&formatted.output).is_err() {
+        return 1;
+    }
+    for error in &formatted.errors {
+        session.print_error(&format!("{}: printf: {}", SHELL_NAME, error));
+    }
+    match formatted.errors.is_empty() {
+        true => 0,
+        false => 1,
+    }
+}
+
+/// `pwm set CHANNEL FREQ DUTY | off CHANNEL | status`: drive or stop the
+/// hardware PWM channels 0 and 1. The frequency is in Hz and the duty cycle
+/// in percent, e.g. `pwm set 0 50 7.5` for a servo in the middle.
+fn pwm(session: &mut Session, args: &[&str]) -> i32 {
+    if !session.settings().hardware {
+        session.print_error(&format!(
+            "{}: pwm: hardware builtins are disabled in the config",
+            SHELL_NAME
+        ));
+        return 1;
+    }
+    let channel = |session: &mut Session, channel: &str| match channel.parse::<usize>() {
+        Ok(channel) if channel < channels::CHANNELS.len() => Some(channel),
+        _ => {
+            session.print_error(&format!(
+                "{}: pwm: {}: invalid channel, channels are 0 and 1",
+                SHELL_NAME, channel
+            ));
+            None
+        }
+    };
+    let invalid = |session: &mut Session, what: &str, text: &str| {
+        session.print_error(&format!("{}: pwm: {}: invalid {}", SHELL_NAME, text, what));
+        2
+    };
+
+    let output = match args.get(1..).unwrap_or_default() {
+        ["status"] => channels::status(),
+        ["set", number, frequency, duty] => {
+            let number = match channel(session, number) {
+                Some(number) => number,
+                None => return 2,
+            };
+            let frequency = match frequency.parse::<f64>() {
+                Ok(frequency) if frequency > 0.0 && frequency.is_finite() => frequency,
+                _ => return invalid(session, "frequency", frequency),
+            };
+            let duty_cycle = match duty.trim_end_matches('%').parse::<f64>() {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => percent / 100.0,
+                _ => return invalid(session, "duty cycle, from 0 to 100 percent", duty),
+            };
+            channels::set(number, frequency, duty_cycle).map(|()| String::new())
+        }
+        ["off", number] => match channel(session, number) {
+            Some(number) => channels::off(number).map(|()| String::new()),
+            None => return 2,
+        },
+        _ => {
+            session.print_error(&format!(
+                "{}: pwm: usage: pwm set CHANNEL FREQ DUTY | off CHANNEL | status",
+                SHELL_NAME
+            ));
+            return 2;
+        }
+    };
+
+    match output {
+        Ok(output) => match session.write_output(output.as_bytes()) {
+            Ok(()) => 0,
+            Err(_) => 1,
+        },
+        Err(error) => {
+            session.print_error(&format!("{}: pwm: {}", SHELL_NAME, error));
+            1
+        }
+    }
+}
+
+/// `read [-rs] [-p PROMPT] [NAME]...`: read a line typed by the user into
+/// variables, split at whitespace with the rest going to the last one.
+/// Without names, the line goes to REPLY. Backslashes escape the next
+/// character unless `-r` is given, and `-s` hides what is typed.
+fn read(session: &mut Session, args: &[&str]) -> i32 {
+    let mut prompt = "";
+    let mut raw = false;
+    let mut secret = false;
+    let mut rest = args[1..].iter();
+    let mut names = Vec::new();
+    while let Some(&arg) = rest.next() {
+        match arg {
+            "-r" => raw = true,
+            "-s" => secret = true,
+            "-rs" | "-sr" => (raw, secret) = (true, true),
+            "-p" => match rest.next() {
+                Some(text) => prompt = text,
+                None => return read_usage(session),
+            },
+            _ if arg.starts_with('-') && names.is_empty() => return read_usage(session),
+            _ if parser::is_valid_name(arg) => names.push(arg),
+            _ => {
+                session.print_error(&format!(
+                    "{}: read: '{}': not a valid identifier",
+                    SHELL_NAME, arg
+                ));
+                return 2;
+            }
+        }
+    }
+    if names.is_empty() {
+        names.push("REPLY");
+    }
+
+    /* Without -r, a backslash at the end continues the line on the next */
+    let mut line = String::new();
+    let continuation = session.continuation_prompt();
+    let mut question = prompt;
+    /* A last line without a newline is still read, but fails */
+    let mut complete = true;
+    loop {
+        /* Input redirected for a loop, as in `while read line; do ...; done
+        < file`, is read instead of the transport */
+        let answer = match (&session.exec_fds[0], secret) {
+            (Some(file), _) => read_line(file).map(|line| {
+                line.map(|(line, ended)| {
+                    complete &= ended;
+                    line
+                })
+            }),
+            (None, true) => session.ask_secret(question),
+            (None, false) => session.ask(question),
+        };
+        let answer = match answer {
+            Ok(Some(answer)) if !session::is_end_of_input(&answer) => answer,
+            _ => return 1,
+        };
+        let continued = !raw && answer.ends_with('\\') && !answer.ends_with("\\\\");
+        line.push_str(&answer);
+        if !continued {
+            break;
+        }
+        line.pop();
+        question = &continuation;
+    }
+
+    let fields = split_fields(&line, names.len(), raw);
+    for (name, value) in names
+        .iter()
+        .zip(fields.iter().map(String::as_str).chain(iter::repeat("")))
+    {
+        session.assign(name, value);
+    }
+    match complete {
+        true => 0,
+        false => 1,
+    }
+}
+
+/// Read a line from a redirected standard input, and whether it ended with
+/// a newline. It is read a byte at a time, so the rest of the input is left
+/// for the commands after `read`.
+fn read_line(input: &OwnedFd) -> io::Result<Option<(String, bool)>> {
+    let mut input = File::from(input.try_clone()?);
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    let ended = loop {
+        match input.read(&mut byte)? {
+            0 if line.is_empty() => return Ok(None),
+            0 => break false,
+            _ if byte[0] == b'\n' => break true,
+            _ => line.push(byte[0]),
+        }
+    };
+    Ok(Some((String::from_utf8_lossy(&line).into_owned(), ended)))
+}
+
+/// Split a line read by `read` into at most `count` fields at whitespace,
+/// the last one keeping the rest of the line
+fn split_fields(line: &str, count: usize, raw: bool) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.trim_start().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if !raw => field.extend(chars.next()),
+            ' ' | '\t' if fields.len() + 1 < count => {
+                fields.push(std::mem::take(&mut field));
+                while chars.next_if(|c| matches!(c, ' ' | '\t')).is_some() {}
+            }
+            _ => field.push(c),
+        }
+    }
+    /* The last field keeps inner whitespace but not what trails it */
+    fields.push(field.trim_end().to_owned());
+    fields
+}
+
+fn read_usage(session: &mut Session) -> i32 {
+    session.print_error(&format!(
+        "{}: read: usage: read [-rs] [-p PROMPT] [NAME]...",
+        SHELL_NAME
+    ));
+    2
+}
+
+/// `return [N]`: end the function or sourced file running, with exit status
+/// N or that of the last command
+fn return_(session: &mut Session, args: &[&str]) -> i32 {
+    if session.function_depth == 0 && session.sourcing.is_empty() {
+        session.print_error(&format!(
+            "{}: return: can only return from a function or sourced file",
+            SHELL_NAME
+        ));
+        return 1;
+    }
+
+    let status = match args.get(1..).unwrap_or_default() {
+        [] => session.last_status,
+        /* Exit statuses are a single byte */
+        [status] => match status.parse::<i32>() {
+            Ok(status) => status & 0xff,
+            Err(_) => {
+                session.print_error(&format!(
+                    "{}: return: {}: numeric argument required",
+                    SHELL_NAME, status
+                ));
+                return 2;
+            }
+        },
+        _ => {
+            session.print_error(&format!("{}: return: too many arguments", SHELL_NAME));
+            return 1;
+        }
+    };
+    session.returning = true;
+    status
+}
+
+/// `rehash`: forget where programs were found, e.g. after installing some
+fn rehash(session: &mut Session, _args: &[&str]) -> i32 {
+    session.path_cache.programs.clear();
+    0
+}
+
+/// `report-bug [FILE]`: write a tarball about the shell and this session to
+/// attach to a bug report, to a file in /tmp by default
+fn report_bug(session: &mut Session, args: &[&str]) -> i32 {
+    let path = match args.get(1..) {
+        Some([]) | None => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs());
+            PathBuf::from(format!("/tmp/pieshell-report-{}.tar", now))
+        }
+        Some([file]) => session.cwd.join(file),
+        Some(_) => {
+            session.print_error(&format!(
+                "{}: report-bug: usage: report-bug [FILE]",
+                SHELL_NAME
+            ));
+            return 2;
+        }
+    };
+
+    if let Err(error) = bugreport::write(session, &path) {
+        session.print_error(&format!(
+            "{}: report-bug: {}: {}",
+            SHELL_NAME,
+            path.display(),
+            error
+        ));
+        return 1;
+    }
+    let message = format!(
+        "Wrote {}, please check it for anything private before attaching it\n",
+        path.display()
+    );
+    match session.write_output(message.as_bytes()) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// `history [--search TEXT | --stats]`: list the commands entered in this
+/// session, the ones kept by the history backend containing TEXT, or counts
+/// over all kept ones
+fn history(session: &mut Session, args: &[&str]) -> i32 {
+    let listing = match args.get(1..) {
+        Some([]) | None => session
+            .history
+            .iter()
+            .enumerate()
+            .map(|(i, line)| format!("{:5}  {}\n", i + 1, line))
+            .collect(),
+        Some(["--search", text]) => match history_search(session, text) {
+            Ok(lines) => lines.iter().map(|line| format!("{}\n", line)).collect(),
+            Err(error) => {
+                session.print_error(&format!("{}: history: {}", SHELL_NAME, error));
+                return 1;
+            }
+        },
+        Some(["--stats"]) => match history_stats(session) {
+            Ok(stats) => stats,
+            Err(error) => {
+                session.print_error(&format!("{}: history: {}", SHELL_NAME, error));
+                return 1;
+            }
+        },
+        Some(_) => {
+            session.print_error(&format!(
+                "{}: history: usage: history [--search TEXT | --stats]",
+                SHELL_NAME
+            ));
+            return 2;
+        }
+    };
+
+    match session.write_output(listing.as_bytes()) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// Entries containing `text`, from the backend if the history is kept
+fn history_search(session: &mut Session, text: &str) -> Result<Vec<String>, String> {
+    let limit = session.settings().history_size;
+    match &mut session.history_store {
+        Some(store) => store.search(text, limit),
+        None => Ok(session
+            .history
+            .iter()
+            .filter(|line| line.contains(text))
+            .cloned()
+            .collect()),
+    }
+}
+
+fn history_stats(session: &mut Session) -> Result<String, String> {
+    let stats = match &mut session.history_store {
+        Some(store) => store.stats(HISTORY_TOP)?,
+        None => {
+            let mut tally = history::Tally::default();
+            for line in &session.history {
+                tally.add(line);
+            }
+            tally.finish(HISTORY_TOP)
+        }
+    };
+    let mut report = format!(
+        "entries   {}\ndistinct  {}\n",
+        stats.entries, stats.distinct
+    );
+    for (program, uses) in stats.commands {
+        report.push_str(&format!("{:8}  {}\n", uses, program));
+    }
+    Ok(report)
+}
+
+/// `i2c [-b BUS] detect | read ADDRESS REGISTER [COUNT] | write ADDRESS
+/// REGISTER BYTE...`: find the devices on an I2C bus, bus 1 by default, or
+/// read or write their registers. Numbers can be given in hex as `0x48`.
+fn i2c(session: &mut Session, args: &[&str]) -> i32 {
+    if !session.settings().hardware {
+        session.print_error(&format!(
+            "{}: i2c: hardware builtins are disabled in the config",
+            SHELL_NAME
+        ));
+        return 1;
+    }
+    let (index, args) = match args.get(1..).unwrap_or_default() {
+        ["-b", index, args @ ..] => (Some(*index), args),
+        args => (None, args),
+    };
+    let number = |session: &mut Session, what: &str, text: &str, range: RangeInclusive<u32>| {
+        let value = match text.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => text.parse(),
+        };
+        match value {
+            Ok(value) if range.contains(&value) => Some(value),
+            _ => {
+                session.print_error(&format!("{}: i2c: {}: invalid {}", SHELL_NAME, text, what));
+                None
+            }
+        }
+    };
+    let index = match index.map(|index| number(session, "bus", index, 0..=0xff)) {
+        None => bus::DEFAULT_BUS,
+        Some(Some(index)) => index as u8,
+        Some(None) => return 2,
+    };
+
+    let output = match args {
+        ["detect"] => bus::detect(index),
+        ["read", address, register, count @ ..] if count.len() <= 1 => {
+            let address = number(session, "address", address, 0..=0x7f);
+            let register = number(session, "register", register, 0..=0xff);
+            let count = match count.first() {
+                Some(count) => number(session, "count", count, 1..=256),
+                None => Some(1),
+            };
+            match (address, register, count) {
+                (Some(address), Some(register), Some(count)) => {
+                    bus::read(index, address as u16, register as u8, count as usize)
+                        .map(|bytes| bus::hex_table(register as u8, &bytes))
+                }
+                _ => return 2,
+            }
+        }
+        ["write", address, register, bytes @ ..] if !bytes.is_empty() => {
+            let address = number(session, "address", address, 0..=0x7f);
+            let register = number(session, "register", register, 0..=0xff);
+            let bytes: Option<Vec<u8>> = bytes
+                .iter()
+                .map(|byte| number(session, "byte", byte, 0..=0xff).map(|byte| byte as u8))
+                .collect();
+            match (address, register, bytes) {
+                (Some(address), Some(register), Some(bytes)) => {
+                    bus::write(index, address as u16, register as u8, &bytes)
+                        .map(|()| String::new())
+                }
+                _ => return 2,
+            }
+        }
+        _ => {
+            session.print_error(&format!(
+                "{}: i2c: usage: i2c [-b BUS] detect | read ADDRESS REGISTER [COUNT] | \
+                 write ADDRESS REGISTER BYTE...",
+                SHELL_NAME
+            ));
+            return 2;
+        }
+    };
+
+    match output {
+        Ok(output) => match session.write_output(output.as_bytes()) {
+            Ok(()) => 0,
+            Err(_) => 1,
+        },
+        Err(error) => {
+            session.print_error(&format!("{}: i2c: {}", SHELL_NAME, error));
+            1
+        }
+    }
+}
+
+/// `jobs`: list the background jobs of this session
+fn jobs(session: &mut Session, _args: &[&str]) -> i32 {
+    let listing = session.jobs.list();
+    match session.write_output(listing.as_bytes()) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// `kill [-s SIGNAL | -SIGNAL] PID|%JOB...`, `kill -l`: send a signal, TERM
+/// by default, to processes or to every command of a background job
+fn kill(session: &mut Session, args: &[&str]) -> i32 {
+    let (signal, targets) = match &args[1..] {
+        ["-l"] => {
+            let listing: String = traps::sendable_signals()
+                .iter()
+                .map(|(name, signal)| format!("{:>2}) {}\n", signal, name))
+                .collect();
+            return match session.write_output(listing.as_bytes()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            };
+        }
+        ["-s", signal, targets @ ..] => (Some(*signal), targets),
+        ["--", targets @ ..] => (None, targets),
+        [option, targets @ ..] if option.len() > 1 && option.starts_with('-') => {
+            (Some(&option[1..]), targets)
+        }
+        targets => (None, targets),
+    };
+    if targets.is_empty() {
+        session.print_error(&format!(
+            "{}: kill: usage: kill [-s SIGNAL | -SIGNAL] PID|%JOB... or kill -l",
+            SHELL_NAME
+        ));
+        return 2;
+    }
+    let signal = match signal.map(traps::sendable_signal) {
+        None => libc::SIGTERM,
+        Some(Some(signal)) => signal,
+        Some(None) => {
+            session.print_error(&format!(
+                "{}: kill: {}: invalid signal specification",
+                SHELL_NAME,
+                signal.unwrap_or_default()
+            ));
+            return 1;
+        }
+    };
+
+    let mut status = 0;
+    for target in targets {
+        let pids: Vec<libc::pid_t> = match target.starts_with('%') {
+            true => match session.jobs.pids(target) {
+                Some(pids) if pids.is_empty() => {
+                    session.print_error(&format!(
+                        "{}: kill: {}: job has terminated",
+                        SHELL_NAME, target
+                    ));
+                    status = 1;
+                    continue;
+                }
+                Some(pids) => pids.iter().map(|pid| *pid as libc::pid_t).collect(),
+                None => {
+                    session.print_error(&format!("{}: kill: {}: no such job", SHELL_NAME, target));
+                    status = 1;
+                    continue;
+                }
+            },
+            false => match target.parse() {
+                Ok(pid) => vec![pid],
+                Err(_) => {
+                    session.print_error(&format!(
+                        "{}: kill: {}: arguments must be process or job IDs",
+                        SHELL_NAME, target
+                    ));
+                    status = 1;
+                    continue;
+                }
+            },
+        };
+        for pid in pids {
+            if unsafe { libc::kill(pid, signal) } != 0 {
+                session.print_error(&format!(
+                    "{}: kill: ({}) - {}",
+                    SHELL_NAME,
+                    pid,
+                    io::Error::last_os_error()
+                ));
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+/// `source FILE [ARG]...`, `. FILE [ARG]...`: run the commands in a file in
+/// this session, with the ARGs as `$1` and on. Files that end up sourcing
+/// themselves are stopped.
+fn source(session: &mut Session, args: &[&str]) -> i32 {
+    let file = match args.get(1) {
+        Some(file) => *file,
+        None => {
+            session.print_error(&format!(
+                "{}: {}: usage: {} FILE [ARG]...",
+                SHELL_NAME, args[0], args[0]
+            ));
+            return 2;
+        }
+    };
+
+    let path = session.cwd.join(file);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(error) => {
+            session.print_error(&format!("{}: {}: {}: {}", SHELL_NAME, args[0], file, error));
+            return 1;
+        }
+    };
+
+    let path = path.canonicalize().unwrap_or(path);
+    if session.sourcing.contains(&path) {
+        session.print_error(&format!(
+            "{}: {}: {}: sourced recursively",
+            SHELL_NAME, args[0], file
+        ));
+        return 1;
+    }
+
+    /* Arguments replace `$1` and on while the file runs, as for a
+    function */
+    let positional = match args.len() > 2 {
+        true => {
+            let arguments = args[2..].iter().map(|arg| arg.to_string()).collect();
+            Some(std::mem::replace(&mut session.positional, arguments))
+        }
+        false => None,
+    };
+    session.sourcing.push(path);
+    let status = session.run_nested(args[0], &contents);
+    session.sourcing.pop();
+    if let Some(positional) = positional {
+        session.positional = positional;
+    }
+    session.returning = false;
+    status
+}
+
+/// `spi [-s HZ] [-m MODE] xfer BUS CS HEXBYTES...`: send bytes written in
+/// hex to the device at a chip select of an SPI bus, and show the bytes
+/// received meanwhile. The clock is 1 MHz and the mode 0 by default.
+fn spi(session: &mut Session, args: &[&str]) -> i32 {
+    if !session.settings().hardware {
+        session.print_error(&format!(
+            "{}: spi: hardware builtins are disabled in the config",
+            SHELL_NAME
+        ));
+        return 1;
+    }
+    let mut transfer = Transfer {
+        bus: 0,
+        select: 0,
+        speed: spi_bus::DEFAULT_SPEED,
+        mode: 0,
+    };
+    let mut args = args.get(1..).unwrap_or_default();
+    let usage = |session: &mut Session| {
+        session.print_error(&format!(
+            "{}: spi: usage: spi [-s HZ] [-m MODE] xfer BUS CS HEXBYTES...",
+            SHELL_NAME
+        ));
+        2
+    };
+    let invalid = |session: &mut Session, what: &str, text: &str| {
+        session.print_error(&format!("{}: spi: {}: invalid {}", SHELL_NAME, text, what));
+        2
+    };
+
+    loop {
+        match args {
+            ["-s", speed, rest @ ..] => {
+                transfer.speed = match speed.parse() {
+                    Ok(speed) if speed > 0 => speed,
+                    _ => return invalid(session, "clock speed", speed),
+                };
+                args = rest;
+            }
+            ["-m", mode, rest @ ..] => {
+                transfer.mode = match mode.parse() {
+                    Ok(mode) if mode <= 3 => mode,
+                    _ => return invalid(session, "mode", mode),
+                };
+                args = rest;
+            }
+            _ => break,
+        }
+    }
+    let bytes = match args {
+        ["xfer", bus, select, bytes @ ..] if !bytes.is_empty() => {
+            transfer.bus = match bus.parse() {
+                Ok(bus) => bus,
+                Err(_) => return invalid(session, "bus", bus),
+            };
+            transfer.select = match select.parse() {
+                Ok(select) => select,
+                Err(_) => return invalid(session, "chip select", select),
+            };
+            match spi_bus::parse_hex(bytes) {
+                Some(bytes) => bytes,
+                None => return invalid(session, "hex bytes", &bytes.join(" ")),
+            }
+        }
+        _ => return usage(session),
+    };
+
+    match transfer.run(&bytes) {
+        Ok(received) => {
+            let hex: Vec<String> = received
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            match session.write_output(format!("{}\n", hex.join(" ")).as_bytes()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            }
+        }
+        Err(error) => {
+            session.print_error(&format!("{}: spi: {}", SHELL_NAME, error));
+            1
+        }
+    }
+}
+
+/// `sx [-k] [-y] FILE`: send a file over the UART to the terminal program
+/// at the other end, with XMODEM or with YMODEM for `-y`, which also tells
+/// the name and size. `-k` sends 1024 bytes at a time, as YMODEM always
+/// does. Progress shows in the terminal program, as anything else written
+/// meanwhile would break the transfer.
+fn sx(session: &mut Session, args: &[&str]) -> i32 {
+    let mut long_blocks = false;
+    let mut ymodem = false;
+    let mut rest = args.get(1..).unwrap_or_default();
+    while let [flag @ ("-k" | "-y"), tail @ ..] = rest {
+        match *flag {
+            "-k" => long_blocks = true,
+            _ => ymodem = true,
+        }
+        rest = tail;
+    }
+    let name = match rest {
+        [name] => *name,
+        _ => {
+            session.print_error(&format!("{}: sx: usage: sx [-k] [-y] FILE", SHELL_NAME));
+            return 2;
+        }
+    };
+    let path = session.cwd.join(name);
+    let (mut file, metadata) = match fs::File::open(&path).and_then(|file| {
+        let metadata = file.metadata()?;
+        Ok((file, metadata))
+    }) {
+        Ok((_, metadata)) if metadata.is_dir() => {
+            session.print_error(&format!("{}: sx: {}: is a directory", SHELL_NAME, name));
+            return 1;
+        }
+        Ok(opened) => opened,
+        Err(error) => {
+            session.print_error(&format!("{}: sx: {}: {}", SHELL_NAME, name, error));
+            return 1;
+        }
+    };
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let header = ymodem.then(|| xmodem::Header {
+        name: &file_name,
+        size: metadata.len(),
+        modified: metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |time| time.as_secs()),
+    });
+
+    let result = session.transfer(|reader, writer| {
+        writer.write_all(b"Ready to send, start receiving the file now (Ctrl-C cancels)\r\n")?;
+        xmodem::send_file(
+            reader,
+            writer,
+            &mut io::BufReader::new(&mut file),
+            header,
+            long_blocks || ymodem,
+        )
+    });
+    match result {
+        Ok(sent) => {
+            let report = format!(
+                "sent {} bytes in {} blocks, {} sent again\n",
+                sent.bytes, sent.blocks, sent.retries
+            );
+            match session.write_output(report.as_bytes()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            }
+        }
+        Err(error) => {
+            session.print_error(&format!("{}: sx: {}: {}", SHELL_NAME, name, error));
+            1
+        }
+    }
+}
+
+/// `sysinfo`: show the temperature, throttling, core voltage, clock,
+/// memory, load and uptime of the board
+fn sysinfo(session: &mut Session, args: &[&str]) -> i32 {
+    if args.len() > 1 {
+        session.print_error(&format!("{}: sysinfo: usage: sysinfo", SHELL_NAME));
+        return 2;
+    }
+    match session.write_output(sysinfo::report().as_bytes()) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// `test EXPRESSION`, `[ EXPRESSION ]`: check files, strings and numbers.
+/// Returns 0 if the expression is true, 1 if false and 2 for errors.
+fn test(session: &mut Session, args: &[&str]) -> i32 {
+    let mut expression = &args[1..];
+    if args[0] == "[" {
+        expression = match expression.split_last() {
+            Some((&"]", expression)) => expression,
+            _ => {
+                session.print_error(&format!("{}: [: missing ']'", SHELL_NAME));
+                return 2;
+            }
+        };
+    }
+
+    match condition::evaluate(session, expression) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(error) => {
+            session.print_error(&format!("{}: {}: {}", SHELL_NAME, args[0], error));
+            2
+        }
+    }
+}
+
+/// `trap COMMAND SIGNAL...`: run COMMAND between commands after one of the
+/// signals arrived, or as the session ends for `EXIT`. `trap - SIGNAL...`
+/// removes the traps, `trap` and `trap -p` list them and `trap -l` lists the
+/// signals.
+fn trap(session: &mut Session, args: &[&str]) -> i32 {
+    let (command, signals) = match &args[1..] {
+        [] | ["-p"] => {
+            let listing: String = session
+                .traps
+                .list()
+                .map(|(signal, command)| {
+                    format!(
+                        "trap -- '{}' {}\n",
+                        command.replace('\'', "'\\''"),
+                        traps::signal_name(signal)
+                    )
+                })
+                .collect();
+            return match session.write_output(listing.as_bytes()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            };
+        }
+        ["-l"] => {
+            let listing: String = traps::SIGNALS
+                .iter()
+                .map(|(name, signal)| format!("{:>2}) {}\n", signal, name))
+                .collect();
+            return match session.write_output(listing.as_bytes()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            };
+        }
+        [command, signals @ ..] if !signals.is_empty() => (*command, signals),
+        _ => {
+            session.print_error(&format!(
+                "{}: trap: usage: trap [-lp] [COMMAND SIGNAL...]",
+                SHELL_NAME
+            ));
+            return 2;
+        }
+    };
+
+    let mut status = 0;
+    for name in signals {
+        let signal = match traps::signal_by_name(name) {
+            Some(signal) => signal,
+            None => {
+                session.print_error(&format!(
+                    "{}: trap: {}: invalid signal specification",
+                    SHELL_NAME, name
+                ));
+                status = 1;
+                continue;
+            }
+        };
+        let command = match command {
+            "-" => None,
+            command => Some(command.to_owned()),
+        };
+        session.traps.set(signal, command);
+    }
+    status
+}
+
+/// `time [COMMAND [ARG]...]`: run a command and tell how long it took. A
+/// pipeline starting with `time` is timed as a whole instead, so this only
+/// runs for `time` alone or after variable assignments.
+fn time(session: &mut Session, args: &[&str]) -> i32 {
+    let timer = Timer::start();
+    let status = match args.len() > 1 {
+        true => {
+            let command: Vec<String> = args[1..].iter().map(|arg| exec::quote(arg)).collect();
+            session.run_nested("time", &command.join(" "))
+        }
+        false => 0,
+    };
+    match session.write_output(timer.report().as_bytes()) {
+        Ok(()) => status,
+        Err(_) => 1,
+    }
+}
+
+/// `timeout SECONDS COMMAND [ARG]...`: run a command, killing it with what
+/// it started if it still runs after SECONDS, and then fail with status 124
+fn timeout(session: &mut Session, args: &[&str]) -> i32 {
+    let (seconds, command) = match args {
+        [_, seconds, command @ ..] if !command.is_empty() => (*seconds, command),
+        _ => {
+            session.print_error(&format!(
+                "{}: timeout: usage: timeout SECONDS COMMAND [ARG]...",
+                SHELL_NAME
+            ));
+            return 2;
+        }
+    };
+    /* A limit too far away to be a point in time is no limit at all */
+    let deadline = seconds
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| *seconds > 0.0)
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .and_then(|limit| Instant::now().checked_add(limit));
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => {
+            session.print_error(&format!(
+                "{}: timeout: '{}' is not a positive number of seconds",
+                SHELL_NAME, seconds
+            ));
+            return 2;
+        }
+    };
+
+    /* A deadline of the script running comes first */
+    let outer = session.deadline;
+    session.deadline = Some(outer.map_or(deadline, |outer| outer.min(deadline)));
+    let grouped = std::mem::replace(&mut session.grouped, true);
+    let command: Vec<String> = command.iter().map(|arg| exec::quote(arg)).collect();
+    let status = session.run_nested("timeout", &command.join(" "));
+    session.grouped = grouped;
+    session.deadline = outer;
+
+    let outer_passed = outer.is_some_and(|outer| Instant::now() >= outer);
+    match session.timed_out && !outer_passed {
+        true => {
+            session.timed_out = false;
+            StatusCode::Timeout.code()
+        }
+        false => status,
+    }
+}
+
+/// `type NAME...`: tell what each name runs when used as a command
+fn type_(session: &mut Session, args: &[&str]) -> i32 {
+    let mut status = 0;
+    for name in &args[1..] {
+        let description = match resolve(session, name) {
+            Ok(Resolved::Function) => format!("{} is a function\n", name),
+            Ok(Resolved::Builtin) => format!("{} is a shell builtin\n", name),
+            Ok(Resolved::Program { path, cached: true }) => {
+                format!("{} is hashed ({})\n", name, path.display())
+            }
+            Ok(Resolved::Program { path, .. }) => format!("{} is {}\n", name, path.display()),
+            Err(_) => {
+                session.print_error(&format!("{}: type: {}: not found", SHELL_NAME, name));
+                status = 1;
+                continue;
+            }
+        };
+        if session.write_output(description.as_bytes()).is_err() {
+            return 1;
+        }
+    }
+    status
+}
+
+/// `ulimit [-SH] [-a | -cdfnstuv [LIMIT]]`: show or set resource limits of
+/// the commands of the session, by default the size of files they write in
+/// kilobytes. LIMIT is a number or `unlimited`. Both the soft and the hard
+/// limit are set unless `-S` or `-H` is given, and the soft one is shown.
+fn ulimit(session: &mut Session, args: &[&str]) -> i32 {
+    let usage = |session: &mut Session| {
+        session.print_error(&format!(
+            "{}: ulimit: usage: ulimit [-SH] [-a | -cdfnstuv [LIMIT]]",
+            SHELL_NAME
+        ));
+        2
+    };
+    let (mut soft, mut hard, mut all) = (false, false, false);
+    let mut kinds = Vec::new();
+    let mut operands = Vec::new();
+    for arg in &args[1..] {
+        let options = match arg.strip_prefix('-') {
+            Some(options) if !options.is_empty() => options,
+            _ => {
+                operands.push(*arg);
+                continue;
+            }
+        };
+        for option in options.chars() {
+            match option {
+                'S' => soft = true,
+                'H' => hard = true,
+                'a' => all = true,
+                option => match limits::Kind::by_option(option) {
+                    Some(kind) => kinds.push(kind),
+                    None => return usage(session),
+                },
+            }
+        }
+    }
+    if all {
+        kinds = limits::KINDS.iter().collect();
+    } else if kinds.is_empty() {
+        kinds.extend(limits::Kind::by_option('f'));
+    }
+    let show =
+        |value: Option<u64>| value.map_or(String::from("unlimited"), |value| value.to_string());
+
+    let value = match operands[..] {
+        [] => {
+            let mut listing = String::new();
+            for kind in &kinds {
+                let values = match kind.values(&session.limits) {
+                    Ok(values) => values,
+                    Err(error) => {
+                        session.print_error(&format!(
+                            "{}: ulimit: {}: {}",
+                            SHELL_NAME, kind.description, error
+                        ));
+                        return 1;
+                    }
+                };
+                let value = show(if hard && !soft {
+                    values.hard
+                } else {
+                    values.soft
+                });
+                match kinds.len() {
+                    1 => listing.push_str(&format!("{}\n", value)),
+                    _ => listing.push_str(&format!(
+                        "{:<28}(-{}) {}\n",
+                        kind.description, kind.option, value
+                    )),
+                }
+            }
+            return match session.write_output(listing.as_bytes()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            };
+        }
+        [value] if !all && kinds.len() == 1 => value,
+        _ => return usage(session),
+    };
+
+    let kind = kinds[0];
+    let value = match value {
+        "unlimited" => None,
+        value => match value.parse::<libc::rlim_t>() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                session.print_error(&format!(
+                    "{}: ulimit: {}: invalid number",
+                    SHELL_NAME, value
+                ));
+                return 1;
+            }
+        },
+    };
+    let limit = kind.values(&session.limits).and_then(|current| {
+        let mut values = current;
+        if soft || !hard {
+            values.soft = value;
+        }
+        if hard || !soft {
+            values.hard = value;
+        }
+        kind.limit(values, current)
+    });
+    match limit {
+        Ok(limit) => {
+            limits::set(&mut session.limits, limit);
+            0
+        }
+        Err(error) => {
+            session.print_error(&format!(
+                "{}: ulimit: {}: cannot modify limit: {}",
+                SHELL_NAME, kind.description, error
+            ));
+            1
+        }
+    }
+}
+
+/// `umask [-S] [MODE]`: show or set the mask for files created by the
+/// session and its commands, in octal or with `-S` like `u=rwx,g=rx,o=rx`.
+/// MODE is octal or symbolic, like `g-w` or `u=rwx,go=rx`.
+fn umask(session: &mut Session, args: &[&str]) -> i32 {
+    let (symbolic, mode) = match &args[1..] {
+        [] => (false, None),
+        ["-S"] => (true, None),
+        [mode] => (false, Some(*mode)),
+        ["-S", mode] => (true, Some(*mode)),
+        _ => {
+            session.print_error(&format!("{}: umask: usage: umask [-S] [MODE]", SHELL_NAME));
+            return 2;
+        }
+    };
+    let current = session.umask.unwrap_or_else(process_umask);
+
+    let mode = match mode {
+        Some(mode) => mode,
+        None => {
+            let shown = match symbolic {
+                true => {
+                    let allowed = !current & 0o777;
+                    let classes: Vec<String> = [('u', 6), ('g', 3), ('o', 0)]
+                        .iter()
+                        .map(|(class, shift)| {
+                            let permissions: String = [('r', 4), ('w', 2), ('x', 1)]
+                                .iter()
+                                .filter(|(_, bit)| allowed >> shift & bit != 0)
+                                .map(|(permission, _)| *permission)
+                                .collect();
+                            format!("{}={}", class, permissions)
+                        })
+                        .collect();
+                    classes.join(",")
+                }
+                false => format!("{:04o}", current),
+            };
+            return match session.write_output(format!("{}\n", shown).as_bytes()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            };
+        }
+    };
+
+    let mask = match u32::from_str_radix(mode, 8) {
+        Ok(mask) if mask <= 0o777 => Some(mask),
+        Ok(_) => None,
+        Err(_) => symbolic_umask(mode, current),
+    };
+    match mask {
+        Some(mask) => {
+            session.umask = Some(mask);
+            0
+        }
+        None => {
+            session.print_error(&format!("{}: umask: {}: invalid mode", SHELL_NAME, mode));
+            1
+        }
+    }
+}
+
+/// The mask a symbolic mode like `u=rwx,go-w` leaves of `mask`. It names
+/// the permissions allowed, the opposite of the mask.
+fn symbolic_umask(mode: &str, mask: u32) -> Option<u32> {
+    let mut allowed = !mask & 0o777;
+    for clause in mode.split(',') {
+        let operator = clause.find(['=', '+', '-'])?;
+        let classes = match &clause[..operator] {
+            "" => 0o777,
+            who => who.chars().try_fold(0, |classes, class| match class {
+                'u' => Some(classes | 0o700),
+                'g' => Some(classes | 0o070),
+                'o' => Some(classes | 0o007),
+                'a' => Some(classes | 0o777),
+                _ => None,
+            })?,
+        };
+        /* Operators can follow each other, as in `u=r+w` */
+        let mut rest = &clause[operator..];
+        while let Some(operator) = rest.chars().next() {
+            let end = rest[1..]
+                .find(['=', '+', '-'])
+                .map_or(rest.len(), |end| end + 1);
+            let permissions =
+                rest[1..end]
+                    .chars()
+                    .try_fold(0, |bits, permission| match permission {
+                        'r' => Some(bits | 0o444),
+                        'w' => Some(bits | 0o222),
+                        'x' => Some(bits | 0o111),
+                        _ => None,
+                    })?
+                    & classes;
+            match operator {
+                '=' => allowed = allowed & !classes | permissions,
+                '+' => allowed |= permissions,
+                _ => allowed &= !permissions,
+            }
+            rest = &rest[end..];
+        }
+    }
+    Some(!allowed & 0o777)
+}
+
+/// The file mode creation mask of the shell process, 022 if it can't be
+/// told. It is read rather than set and put back, which could race with
+/// other sessions creating files.
+fn process_umask() -> u32 {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Umask:"))
+                .and_then(|mask| u32::from_str_radix(mask.trim(), 8).ok())
+        })
+        .unwrap_or(0o022)
+}
+
+/// `wait [PID|%JOB]...`: wait until the background jobs given, or all of
+/// them, completed, and return the exit status of the last one given.
+/// Ctrl-C stops waiting, as does the deadline of `timeout`.
+fn wait(session: &mut Session, args: &[&str]) -> i32 {
+    let mut waited = Vec::new();
+    let mut status = 0;
+    for target in &args[1..] {
+        match session.jobs.find(target) {
+            Some(job) => waited.push(Some(job)),
+            None if target.starts_with('%') || target.parse::<u32>().is_ok() => {
+                session.print_error(&format!("{}: wait: {}: no such job", SHELL_NAME, target));
+                waited.push(None);
+            }
+            None => {
+                session.print_error(&format!(
+                    "{}: wait: {}: arguments must be process or job IDs",
+                    SHELL_NAME, target
+                ));
+                return 2;
+            }
+        }
+    }
+    let all = waited.is_empty();
+    if all {
+        waited = session.jobs.running().into_iter().map(Some).collect();
+    }
+
+    for job in waited {
+        let job = match job {
+            Some(job) => job,
+            None => {
+                status = StatusCode::NotFound.code();
+                continue;
+            }
+        };
+        status = loop {
+            if let Some(status) = session.jobs.status(job) {
+                break status;
+            }
+            if session
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                session.timed_out = true;
+                return StatusCode::Timeout.code();
+            }
+            match session.poll_interrupt() {
+                Ok(false) => thread::sleep(exec::POLL_INTERVAL),
+                Ok(true) => {
+                    session.interrupted = true;
+                    return StatusCode::signaled(libc::SIGINT);
+                }
+                Err(_) => return 1,
+            }
+        };
+    }
+    /* Without operands the status is 0, whatever the jobs returned */
+    match all {
+        true => 0,
+        false => status,
+    }
+}
+
+/// `which NAME...`: print the path of the program each name runs, or that it
+/// is a builtin
+fn which(session: &mut Session, args: &[&str]) -> i32 {
+    let mut status = 0;
+    for name in &args[1..] {
+        let line = match resolve(session, name) {
+            Ok(Resolved::Function) => format!("{}: shell function\n", name),
+            Ok(Resolved::Builtin) => format!("{}: shell builtin\n", name),
+            Ok(Resolved::Program { path, .. }) => format!("{}\n", path.display()),
+            Err(_) => {
+                status = 1;
+                continue;
+            }
+        };
+        if session.write_output(line.as_bytes()).is_err() {
+            return 1;
+        }
+    }
+    status
+}
+
+/// `rx FILE`: receive a file sent with XMODEM or YMODEM over the UART, e.g.
+/// with "Send File" of minicom or Tera Term. XMODEM pads files to a
+/// multiple of 128 bytes, YMODEM senders tell their size.
+fn rx(session: &mut Session, args: &[&str]) -> i32 {
+    let name = match args.get(1..).unwrap_or_default() {
+        [name] => *name,
+        _ => {
+            session.print_error(&format!("{}: rx: usage: rx FILE", SHELL_NAME));
+            return 2;
+        }
+    };
+    let path = session.cwd.join(name);
+
+    let result = session.transfer(|reader, writer| {
+        let mut file = io::BufWriter::new(fs::File::create(&path)?);
+        writer.write_all(
+            b"Waiting for the sender, start sending the file now (Ctrl-C cancels)\r\n",
+        )?;
+        let received = xmodem::receive(reader, writer, &mut file).inspect_err(|_| {
+            /* Don't leave half a file behind */
+            let _ = fs::remove_file(&path);
+        })?;
+        file.flush()?;
+        Ok(received)
+    });
+    match result {
+        Ok(received) => {
+            if received.refused_more {
+                session.print_error(&format!(
+                    "{}: rx: only the first file of the batch was received",
+                    SHELL_NAME
+                ));
+            }
+            let protocol = match received.ymodem {
+                true => "YMODEM",
+                false => "XMODEM",
+            };
+            let report = format!("received {} bytes with {}\n", received.bytes, protocol);
+            match session.write_output(report.as_bytes()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            }
+        }
+        Err(error) => {
+            session.print_error(&format!("{}: rx: {}: {}", SHELL_NAME, name, error));
+            1
+        }
+    }
+}
+
+/// `session export` writes the state of the session as JSON, `session import
+/// FILE` sets it up again from such a file
+fn session_(session: &mut Session, args: &[&str]) -> i32 {
+    match args.get(1..).unwrap_or_default() {
+        ["export"] => {
+            let json = snapshot::export(session);
+            match session.write_output(json.as_bytes()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            }
+        }
+        ["import", file] => {
+            let json = match fs::read_to_string(session.cwd.join(file)) {
+                Ok(json) => json,
+                Err(error) => {
+                    session.print_error(&format!("{}: session: {}: {}", SHELL_NAME, file, error));
+                    return 1;
+                }
+            };
+            match snapshot::import(session, &json) {
+                Ok(None) => 0,
+                Ok(Some(warning)) => {
+                    session.print_error(&format!("{}: session: {}", SHELL_NAME, warning));
+                    0
+                }
+                Err(error) => {
+                    session.print_error(&format!("{}: session: {}: {}", SHELL_NAME, file, error));
+                    1
+                }
+            }
+        }
+        _ => {
+            session.print_error(&format!(
+                "{}: session: usage: session export | session import FILE",
+                SHELL_NAME
+            ));
+            2
+        }
+    }
+}
+
+/// `set [-eux] [+eux]`, `set -o NAME [VALUE]`, `set +o NAME`: set or clear
+/// shell options, or list them when called without arguments. Besides the
+/// flags of `options`, there is `script-timeout SECONDS`, the time budget of
+/// a whole script, and `echo`, whether the shell echoes what is typed.
+fn set(session: &mut Session, args: &[&str]) -> i32 {
+    let mut args = &args[1..];
+    if matches!(args, [] | ["-o"] | ["+o"]) {
+        return set_list(session);
+    }
+
+    while let Some((arg, rest)) = args.split_first() {
+        args = rest;
+        let on = arg.starts_with('-');
+        match *arg {
+            "-o" | "+o" => {
+                let name = match args.split_first() {
+                    Some((name, rest)) => {
+                        args = rest;
+                        *name
+                    }
+                    None => {
+                        session.print_error(&format!(
+                            "{}: set: {} requires an option name",
+                            SHELL_NAME, arg
+                        ));
+                        return 1;
+                    }
+                };
+                if name == "echo" {
+                    session.set_echo(on);
+                    continue;
+                }
+                if name == "script-timeout" {
+                    let status = match on {
+                        true => set_script_timeout(session, &mut args),
+                        false => {
+                            session.script_timeout = None;
+                            0
+                        }
+                    };
+                    if status != 0 {
+                        return status;
+                    }
+                    continue;
+                }
+                match options::by_name(name) {
+                    Some(flags) => session.options.set(flags, on),
+                    None => {
+                        session.print_error(&format!(
+                            "{}: set: {}: invalid option name",
+                            SHELL_NAME, name
+                        ));
+                        return 1;
+                    }
+                }
+            }
+            _ if arg.len() > 1 && (arg.starts_with('-') || arg.starts_with('+')) => {
+                for letter in arg[1..].chars() {
+                    match options::by_letter(letter) {
+                        Some(flags) => session.options.set(flags, on),
+                        None => {
+                            session.print_error(&format!(
+                                "{}: set: {}{}: invalid option",
+                                SHELL_NAME,
+                                &arg[..1],
+                                letter
+                            ));
+                            return 1;
+                        }
+                    }
+                }
+            }
+            _ => {
+                session.print_error(&format!("{}: set: {}: invalid option", SHELL_NAME, arg));
+                return 1;
+            }
+        }
+    }
+    0
+}
+
+/// `set -o script-timeout SECONDS`, taking the seconds from `args`
+fn set_script_timeout(session: &mut Session, args: &mut &[&str]) -> i32 {
+    let seconds = match args.split_first() {
+        Some((seconds, rest)) => {
+            *args = rest;
+            *seconds
+        }
+        None => {
+            session.print_error(&format!(
+                "{}: set: script-timeout requires a number of seconds",
+                SHELL_NAME
+            ));
+            return 1;
+        }
+    };
+    match seconds.parse::<u64>() {
+        Ok(seconds) if seconds > 0 => {
+            session.script_timeout = Some(Duration::from_secs(seconds));
+            0
+        }
+        _ => {
+            session.print_error(&format!(
+                "{}: set: script-timeout: '{}' is not a positive number of seconds",
+                SHELL_NAME, seconds
+            ));
+            1
+        }
+    }
+}
+
+fn set_list(session: &mut Session) -> i32 {
+    let mut listing = String::new();
+    for (_, name, flags) in options::FLAGS {
+        let state = match session.options.contains(flags) {
+            true => "on",
+            false => "off",
+        };
+        listing.push_str(&format!("{:<16}{}\n", name, state));
+    }
+    let echo = match session.settings().echo {
+        true => "on",
+        false => "off",
+    };
+    listing.push_str(&format!("{:<16}{}\n", "echo", echo));
+    let timeout = match session.script_timeout {
+        Some(timeout) => timeout.as_secs().to_string(),
+        None => String::from("off"),
+    };
+    listing.push_str(&format!("{:<16}{}\n", "script-timeout", timeout));
+    match session.write_output(listing.as_bytes()) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
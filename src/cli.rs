@@ -0,0 +1,135 @@
+use std::path::PathBuf;
+
+use crate::theme::{ColorPolicy, Theme};
+
+/// Options given on the command line. These take precedence over the config
+/// file.
+pub struct Args {
+    pub color: Option<ColorPolicy>,
+    pub theme: Option<&'static Theme>,
+    pub config: Option<PathBuf>,
+    /// Address to accept TCP connections on instead of using stdio/UART
+    pub listen: Option<String>,
+    /// Speak the telnet protocol on TCP connections
+    pub telnet: bool,
+    /// Serve a session on stdin/stdout
+    pub stdio: bool,
+    /// Serve a session on the UART
+    pub uart: bool,
+    /// Run each line of input with a status line after it, for test rigs
+    pub batch: bool,
+    /// Script to run instead of serving interactive sessions
+    pub script: Option<PathBuf>,
+    /// Arguments after the script, its `$1` and on
+    pub script_args: Vec<String>,
+    /// Serial devices and TCP addresses of machines to run `command` on
+    pub fleet: Vec<String>,
+    /// Command to run with `--fleet`
+    pub command: Option<String>,
+    /// File a transcript of every session is appended to
+    pub log: Option<PathBuf>,
+    /// Run as a systemd service, see `ShellBuilder::service`
+    pub daemon: bool,
+}
+
+impl Args {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+        let mut parsed = Args {
+            color: None,
+            theme: None,
+            config: None,
+            listen: None,
+            telnet: false,
+            stdio: false,
+            uart: false,
+            batch: false,
+            script: None,
+            script_args: Vec::new(),
+            fleet: Vec::new(),
+            command: None,
+            log: None,
+            daemon: false,
+        };
+
+        while let Some(arg) = args.next() {
+            /* Everything after the script is passed to it, options too */
+            if parsed.script.is_some() {
+                parsed.script_args.push(arg);
+                continue;
+            }
+            /* Accept both "--option=value" and "--option value" */
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+                None => (arg, None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or(format!("{} requires a value", name))
+            };
+
+            match name.as_str() {
+                "--color" => parsed.color = Some(value()?.parse()?),
+                "--theme" => {
+                    let theme = value()?;
+                    parsed.theme = Some(Theme::by_name(&theme).ok_or(format!(
+                        "unknown theme '{}' (available: {})",
+                        theme,
+                        Theme::names().collect::<Vec<_>>().join(", ")
+                    ))?);
+                }
+                "--config" => parsed.config = Some(PathBuf::from(value()?)),
+                "--listen" => parsed.listen = Some(value()?),
+                "--telnet" => parsed.telnet = true,
+                "--stdio" => parsed.stdio = true,
+                "--uart" => parsed.uart = true,
+                "--batch" => parsed.batch = true,
+                "--log" => parsed.log = Some(PathBuf::from(value()?)),
+                "--daemon" => parsed.daemon = true,
+                "--fleet" => parsed.fleet.extend(
+                    value()?
+                        .split(',')
+                        .filter(|endpoint| !endpoint.is_empty())
+                        .map(str::to_owned),
+                ),
+                "-c" | "--command" => parsed.command = Some(value()?),
+                _ if name.starts_with('-') => return Err(format!("unknown option '{}'", name)),
+                _ => {
+                    /* The name may contain '=', so use the whole argument */
+                    let script = match inline_value {
+                        Some(value) => format!("{}={}", name, value),
+                        None => name,
+                    };
+                    parsed.script = Some(PathBuf::from(script));
+                }
+            }
+        }
+
+        if parsed.fleet.is_empty() != parsed.command.is_none() {
+            return Err(String::from("--fleet and -c must be given together"));
+        }
+        Ok(parsed)
+    }
+}
+
+pub fn usage() -> String {
+    format!(
+        "usage: {} [--config FILE] [--color always|never|auto] [--theme NAME] \
+         [--stdio] [--uart] [--listen ADDR:PORT [--telnet]] [--batch] \
+         [--log FILE] [--daemon] [SCRIPT [ARG]...]\n       \
+         {} --fleet DEVICE|ADDR:PORT,... -c COMMAND\n\n\
+         Sessions are served on every transport given. Without any, the UART \
+         is used on the Pi and stdio elsewhere. With SCRIPT, the script is run \
+         with the ARGs as $1 and on, its output on the first transport, and \
+         the shell exits. With --batch, no prompt is shown and each line of \
+         input is followed by <<<EXIT STATUS TIMEms>>>. With --log, everything \
+         read and written in sessions is appended to FILE. With --daemon, the \
+         shell runs as a systemd service of Type=notify, stops cleanly on \
+         SIGTERM and starts a new session on the UART whenever one ends. With \
+         --fleet, COMMAND is run on every machine listed and the results are \
+         shown.",
+        crate::SHELL_NAME,
+        crate::SHELL_NAME
+    )
+}
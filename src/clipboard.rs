@@ -0,0 +1,39 @@
+//! Copying text to the clipboard of the terminal at the other end of the
+//! transport with the OSC 52 escape sequence, so output can be copied off the
+//! Pi over a serial line.
+
+/// Largest amount of data sent in one sequence. Terminals limit the length
+/// of escape sequences, xterm for example to 100000 bytes of base64.
+pub const MAX_LENGTH: usize = 74_994;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The escape sequence setting the clipboard to `data`
+pub fn osc52(data: &[u8]) -> Vec<u8> {
+    format!("\x1b]52;c;{}\x07", base64(data)).into_bytes()
+}
+
+fn base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        /* Every 3 bytes become 4 characters, padded with '=' at the end */
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
@@ -0,0 +1,86 @@
+//! Completion of the arguments of commands. Programs embedding the shell
+//! register completers with `ShellBuilder::completer`, sessions with the
+//! `complete` builtin, from a list of words or a function. Arguments of
+//! other commands are completed as paths.
+
+use std::sync::Arc;
+
+use crate::exec;
+use crate::gpio;
+use crate::session::Session;
+
+/// The command line a completer is asked about
+#[derive(Debug, Clone, Copy)]
+pub struct Completion<'a> {
+    /// The words of the command before the one being completed, its name
+    /// first
+    pub words: &'a [&'a str],
+    /// What was typed of the word being completed, possibly nothing
+    pub word: &'a str,
+}
+
+/// Returns what the word being completed could be. Candidates not starting
+/// with what was typed are left out.
+pub type Completer = Arc<dyn Fn(&Session, &Completion) -> Vec<String> + Send + Sync>;
+
+/// A completer registered in a session with `complete`
+#[derive(Debug, Clone)]
+pub(crate) enum SessionCompleter {
+    /// `complete -W WORDS`
+    Words(Vec<String>),
+    /// `complete -F FUNCTION`, called with the command, the word and the
+    /// word before it, as in bash. It sets `COMPREPLY` to the candidates.
+    Function(String),
+}
+
+impl Session {
+    /// Candidates for an argument of a command, if the command has a
+    /// completer. Those of the session take precedence over the ones of the
+    /// embedding program, which take precedence over the shell's own.
+    pub(crate) fn argument_candidates(&mut self, completion: &Completion) -> Option<Vec<String>> {
+        let command = completion.words[0];
+        if let Some(completer) = self.completers.get(command).cloned() {
+            return Some(match completer {
+                SessionCompleter::Words(words) => words,
+                SessionCompleter::Function(function) => self.call_completer(&function, completion),
+            });
+        }
+        if let Some(completer) = self.custom_completers.get(command).cloned() {
+            return Some(completer(self, completion));
+        }
+        builtin_candidates(completion.words)
+    }
+
+    fn call_completer(&mut self, function: &str, completion: &Completion) -> Vec<String> {
+        let previous = completion.words.last().copied().unwrap_or_default();
+        let source = format!(
+            "{} {} {} {}",
+            exec::quote(function),
+            exec::quote(completion.words[0]),
+            exec::quote(completion.word),
+            exec::quote(previous)
+        );
+        self.assign("COMPREPLY", "");
+        self.run_nested("complete", &source);
+        let reply = self.var("COMPREPLY").unwrap_or_default();
+        reply.split_whitespace().map(str::to_owned).collect()
+    }
+}
+
+/// Candidates for the arguments of the shell's own builtins
+fn builtin_candidates(words: &[&str]) -> Option<Vec<String>> {
+    let candidates: Vec<String> = match words {
+        ["gpio"] => ["status", "read", "write", "toggle", "mode"]
+            .map(String::from)
+            .to_vec(),
+        ["gpio", "read" | "write" | "toggle" | "mode"] => {
+            gpio::PINS.map(|pin| pin.to_string()).collect()
+        }
+        ["gpio", "write", _] => vec![String::from("0"), String::from("1")],
+        ["gpio", "mode", _] => ["in", "out", "alt0", "alt1", "alt2", "alt3", "alt4", "alt5"]
+            .map(String::from)
+            .to_vec(),
+        _ => return None,
+    };
+    Some(candidates)
+}
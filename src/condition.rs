@@ -0,0 +1,298 @@
+//! Expressions of the `test` and `[` builtins, like `-f /boot/config.txt` or
+//! `"$count" -gt 3 -a ! -d /mnt/usb`
+
+use std::ffi::CString;
+use std::fs::{self, Metadata};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::Session;
+
+const UNARY: [&str; 22] = [
+    "-b", "-c", "-d", "-e", "-f", "-g", "-G", "-h", "-k", "-L", "-n", "-O", "-p", "-r", "-s", "-S",
+    "-t", "-u", "-w", "-x", "-z", "-N",
+];
+
+const BINARY: [&str; 14] = [
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+/// Evaluate an expression. Fails with a message for expressions that don't
+/// make sense, like comparing words as numbers.
+pub(crate) fn evaluate(session: &Session, args: &[&str]) -> Result<bool, String> {
+    let mut evaluator = Evaluator {
+        session,
+        args,
+        position: 0,
+    };
+    let result = match args.len() {
+        0 => return Ok(false),
+        /* Few arguments always mean the same, whatever they look like, as
+        in `[ -n ]` or `[ ! = x ]` */
+        1 => !args[0].is_empty(),
+        2 if args[0] == "!" => args[1].is_empty(),
+        3 if BINARY.contains(&args[1]) => evaluator.binary(args[0], args[1], args[2])?,
+        _ => {
+            let result = evaluator.or()?;
+            if let Some(arg) = evaluator.args.get(evaluator.position) {
+                return Err(format!("{}: unexpected argument", arg));
+            }
+            result
+        }
+    };
+    Ok(result)
+}
+
+struct Evaluator<'a> {
+    session: &'a Session,
+    args: &'a [&'a str],
+    position: usize,
+}
+
+impl<'a> Evaluator<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.args.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let arg = self
+            .args
+            .get(self.position)
+            .copied()
+            .ok_or_else(|| String::from("argument expected"))?;
+        self.position += 1;
+        Ok(arg)
+    }
+
+    /// `EXPRESSION -o EXPRESSION`, binding loosest
+    fn or(&mut self) -> Result<bool, String> {
+        let mut result = self.and()?;
+        while self.peek() == Some("-o") {
+            self.position += 1;
+            result |= self.and()?;
+        }
+        Ok(result)
+    }
+
+    /// `EXPRESSION -a EXPRESSION`
+    fn and(&mut self) -> Result<bool, String> {
+        let mut result = self.not()?;
+        while self.peek() == Some("-a") {
+            self.position += 1;
+            result &= self.not()?;
+        }
+        Ok(result)
+    }
+
+    /// `! EXPRESSION`
+    fn not(&mut self) -> Result<bool, String> {
+        match self.peek() {
+            Some("!") if self.position + 1 < self.args.len() => {
+                self.position += 1;
+                Ok(!self.not()?)
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<bool, String> {
+        let arg = self.next()?;
+        let following = self.args.get(self.position).copied();
+
+        /* A binary operator after the argument wins, as in `[ -f = -f ]` */
+        if let Some(operator) = following.filter(|operator| BINARY.contains(operator)) {
+            if self.position + 1 < self.args.len() {
+                self.position += 1;
+                let right = self.next()?;
+                return self.binary(arg, operator, right);
+            }
+        }
+        if arg == "(" {
+            let result = self.or()?;
+            return match self.next() {
+                Ok(")") => Ok(result),
+                _ => Err(String::from("')' expected")),
+            };
+        }
+        if UNARY.contains(&arg) && following.is_some() {
+            let operand = self.next()?;
+            return self.unary(arg, operand);
+        }
+        Ok(!arg.is_empty())
+    }
+
+    fn unary(&self, operator: &str, operand: &str) -> Result<bool, String> {
+        match operator {
+            "-n" => return Ok(!operand.is_empty()),
+            "-z" => return Ok(operand.is_empty()),
+            "-t" => {
+                return match integer(operand)? {
+                    0..=2 => Ok(self.session.is_terminal()),
+                    _ => Ok(false),
+                }
+            }
+            _ => {}
+        }
+
+        let path = self.path(operand);
+        /* Symbolic links are tested themselves, everything else follows them */
+        if matches!(operator, "-h" | "-L") {
+            return Ok(fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_symlink()));
+        }
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(false),
+        };
+        let mode = metadata.permissions().mode();
+        let file_type = metadata.file_type();
+        let result = match operator {
+            "-b" => file_type.is_block_device(),
+            "-c" => file_type.is_char_device(),
+            "-d" => file_type.is_dir(),
+            "-e" => true,
+            "-f" => file_type.is_file(),
+            "-g" => mode & 0o2000 != 0,
+            "-G" => metadata.gid() == unsafe { libc::getegid() },
+            "-k" => mode & 0o1000 != 0,
+            "-N" => metadata.mtime() > metadata.atime(),
+            "-O" => metadata.uid() == unsafe { libc::geteuid() },
+            "-p" => file_type.is_fifo(),
+            "-r" => accessible(&path, libc::R_OK),
+            "-s" => metadata.len() > 0,
+            "-S" => file_type.is_socket(),
+            "-u" => mode & 0o4000 != 0,
+            "-w" => accessible(&path, libc::W_OK),
+            _ => accessible(&path, libc::X_OK),
+        };
+        Ok(result)
+    }
+
+    fn binary(&self, left: &str, operator: &str, right: &str) -> Result<bool, String> {
+        let result = match operator {
+            "=" | "==" => left == right,
+            "!=" => left != right,
+            "<" => left < right,
+            ">" => left > right,
+            "-nt" | "-ot" | "-ef" => {
+                let left = fs::metadata(self.path(left)).ok();
+                let right = fs::metadata(self.path(right)).ok();
+                files(&left, operator, &right)
+            }
+            _ => {
+                let (left, right) = (integer(left)?, integer(right)?);
+                match operator {
+                    "-eq" => left == right,
+                    "-ne" => left != right,
+                    "-lt" => left < right,
+                    "-le" => left <= right,
+                    "-gt" => left > right,
+                    _ => left >= right,
+                }
+            }
+        };
+        Ok(result)
+    }
+
+    /// Paths are relative to the working directory of the session
+    fn path(&self, operand: &str) -> PathBuf {
+        self.session.cwd.join(operand)
+    }
+}
+
+/// Compare two files by age or identity. Files that don't exist are older
+/// than those that do.
+fn files(left: &Option<Metadata>, operator: &str, right: &Option<Metadata>) -> bool {
+    match (left, right, operator) {
+        (Some(left), Some(right), "-nt") => modified(left) > modified(right),
+        (Some(left), Some(right), "-ot") => modified(left) < modified(right),
+        (Some(left), Some(right), _) => left.dev() == right.dev() && left.ino() == right.ino(),
+        (Some(_), None, "-nt") | (None, Some(_), "-ot") => true,
+        _ => false,
+    }
+}
+
+fn modified(metadata: &Metadata) -> (i64, i64) {
+    (metadata.mtime(), metadata.mtime_nsec())
+}
+
+fn integer(text: &str) -> Result<i64, String> {
+    text.trim()
+        .parse()
+        .map_err(|_| format!("{}: integer expression expected", text))
+}
+
+/// Whether the user may access a file, as the kernel decides
+fn accessible(path: &Path, mode: libc::c_int) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), mode) == 0 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::transport::{self, TransportKind};
+
+    fn session() -> Session {
+        let (reader, writer, _) = transport::memory_reader_writer(Vec::new());
+        let settings = Config::default()
+            .settings(TransportKind::Stdio)
+            .expect("default settings should be valid");
+        let mut session = Session::new(reader, writer, settings);
+        session.cwd = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        session
+    }
+
+    fn test(expression: &str) -> Result<bool, String> {
+        let args: Vec<&str> = expression.split(' ').collect();
+        evaluate(&session(), &args)
+    }
+
+    #[test]
+    fn gives_few_arguments_a_fixed_meaning() {
+        assert_eq!(evaluate(&session(), &[]), Ok(false));
+        assert_eq!(evaluate(&session(), &[""]), Ok(false));
+        assert_eq!(test("-n"), Ok(true));
+        assert_eq!(test("! -z"), Ok(false));
+        assert_eq!(evaluate(&session(), &["!", ""]), Ok(true));
+        assert_eq!(test("! = x"), Ok(false));
+        assert_eq!(test("-f = -f"), Ok(true));
+    }
+
+    #[test]
+    fn compares_strings_and_integers() {
+        assert_eq!(test("a = a"), Ok(true));
+        assert_eq!(test("a != a"), Ok(false));
+        assert_eq!(test("a < b"), Ok(true));
+        assert_eq!(test("10 -gt 9"), Ok(true));
+        assert_eq!(test("-3 -le -3"), Ok(true));
+        assert_eq!(
+            test("1 -eq x"),
+            Err(String::from("x: integer expression expected"))
+        );
+    }
+
+    #[test]
+    fn combines_expressions() {
+        assert_eq!(test("a = a -a b = c"), Ok(false));
+        assert_eq!(test("a = a -o b = c"), Ok(true));
+        assert_eq!(test("a = b -o b = b -a c = d"), Ok(false));
+        assert_eq!(test("! ( a = b -o c = d )"), Ok(true));
+        assert_eq!(test("( a = a"), Err(String::from("')' expected")));
+        assert_eq!(test("a = a b"), Err(String::from("b: unexpected argument")));
+    }
+
+    #[test]
+    fn tests_files_relative_to_the_working_directory() {
+        assert_eq!(test("-f Cargo.toml"), Ok(true));
+        assert_eq!(test("-d src -a -s src/lib.rs"), Ok(true));
+        assert_eq!(test("-e missing"), Ok(false));
+        assert_eq!(test("-f src"), Ok(false));
+        assert_eq!(test("Cargo.toml -ef ./Cargo.toml"), Ok(true));
+        assert_eq!(test("Cargo.toml -nt missing"), Ok(true));
+        assert_eq!(test("missing -ot Cargo.toml"), Ok(true));
+    }
+}
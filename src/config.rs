@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::audit::AuditLog;
+use crate::auth::AuthMethod;
+use crate::encoding::OutputEncoding;
+use crate::history::HistoryBackend;
+use crate::images::ImagePolicy;
+use crate::prompt::{DEFAULT_CONTINUATION_PROMPT, DEFAULT_PROMPT};
+use crate::theme::{ColorPolicy, Theme};
+use crate::transport::TransportKind;
+
+/// The banner of sessions when none is configured
+const MOTD_FILE: &str = "/etc/motd";
+
+/// The keys the line editor is used with, like the `editing-mode` of
+/// readline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EditingMode {
+    #[default]
+    Emacs,
+    /// Modal, Escape switches from typing to commands like `k` and `dd`
+    Vi,
+}
+
+/// What `cd` does with a directory that doesn't exist but is a typo away
+/// from one, like bash's `cdspell`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CdSpell {
+    #[default]
+    Off,
+    /// Fail and name the directory that was probably meant
+    Suggest,
+    /// Change to the directory that was probably meant and print it
+    Correct,
+}
+
+/// How output to the UART is held back while the terminal can't keep up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    #[default]
+    None,
+    /// With the RTS and CTS lines, GPIO 17 and 16 on the primary UART
+    RtsCts,
+    /// With XOFF and XON, Ctrl-S and Ctrl-Q, which then can't be typed
+    XonXoff,
+}
+
+/// What the terminal at the other end sends to end a line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputNewline {
+    /// `\r`, `\n` or both
+    #[default]
+    Any,
+    /// `\r`, like PuTTY and minicom. `\n` is Ctrl-J.
+    Cr,
+    /// `\n`, with `\r` dropped
+    Lf,
+}
+
+/// Line ending written to the transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Newline {
+    Lf,
+    Crlf,
+}
+
+/// Settings that can be given at the top level of the config file, or in a
+/// `[transport.<name>]` section to only apply to sessions on that transport.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Profile {
+    pub echo: Option<bool>,
+    pub newline: Option<Newline>,
+    /// What ends typed lines, "any" by default
+    pub input_newline: Option<InputNewline>,
+    pub color: Option<ColorPolicy>,
+    pub theme: Option<String>,
+    /// Text printed when a session starts, with variables like
+    /// `{hostname}`, see `motd::render`
+    pub banner: Option<String>,
+    /// File with the banner, `/etc/motd` unless a banner is given
+    pub banner_file: Option<PathBuf>,
+    /// Number of lines shown before pausing long command output
+    pub pager: Option<usize>,
+    /// Seconds without input before the session is closed. Sessions on
+    /// stdio don't time out.
+    pub idle_timeout: Option<u64>,
+    /// Speak the telnet protocol on TCP connections
+    pub telnet: Option<bool>,
+    /// Baud rate of the UART
+    pub baud: Option<u32>,
+    /// Flow control of the UART, "none" by default
+    pub flow_control: Option<FlowControl>,
+    /// Prompt template, see `prompt::render`
+    pub prompt: Option<String>,
+    /// Template of the prompt for more lines of an unfinished command, e.g.
+    /// after a trailing `\` or an `if` without `fi`
+    pub continuation_prompt: Option<String>,
+    /// Number of commands kept in the history
+    pub history_size: Option<usize>,
+    /// File the history is kept in between sessions
+    pub history_file: Option<PathBuf>,
+    /// How the history file is written, "file" by default
+    pub history_backend: Option<HistoryBackend>,
+    /// Commands run when a session starts, `~/.pieshellrc` by default
+    pub rc_file: Option<PathBuf>,
+    /// Readline init file with key bindings for the line editor
+    pub inputrc: Option<PathBuf>,
+    /// Keys of the line editor, unless the inputrc sets them
+    pub editing_mode: Option<EditingMode>,
+    /// Lines in the format of the inputrc, like `"\C-r": history-search-backward`,
+    /// read before the inputrc
+    pub key_bindings: Option<Vec<String>>,
+    /// Correct typos in directories given to `cd`, or only suggest the
+    /// directory meant
+    pub cd_spell: Option<CdSpell>,
+    /// Enable the builtins talking to GPIO, I2C, SPI and PWM peripherals
+    pub hardware: Option<bool>,
+    /// Allow copying to the terminal's clipboard with OSC 52
+    pub clipboard: Option<bool>,
+    /// Pass inline images in command output through or strip them
+    pub images: Option<ImagePolicy>,
+    /// Pass output that isn't valid UTF-8 through or replace it
+    pub output_encoding: Option<OutputEncoding>,
+    /// Offer to run a command again with sudo when it failed for lack of
+    /// permissions
+    pub elevate: Option<bool>,
+    /// Kilobytes the history of a session may use
+    pub history_memory: Option<usize>,
+    /// Kilobytes of output of the last command kept, e.g. for copying it to
+    /// the clipboard
+    pub scrollback_memory: Option<usize>,
+    /// Kilobytes of output buffered for each background job until it is
+    /// shown
+    pub job_memory: Option<usize>,
+    /// Kilobytes of output of a command sent over the transport. The rest
+    /// is saved to a file in /tmp, up to 16 MiB. Commands given the terminal
+    /// on stdio aren't capped.
+    pub output_limit: Option<usize>,
+    /// File mode creation mask for files the session creates, in octal like
+    /// "027"
+    pub umask: Option<String>,
+    /// Variables passed to commands, on top of those of the top level when
+    /// given for a transport
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// How sessions log in before the first prompt
+    pub auth: Option<AuthMethod>,
+    /// File with the credentials of the `file` and `totp` methods
+    pub credentials: Option<PathBuf>,
+    /// Run each line of input with a status line after it, without prompt
+    /// or echo, for test rigs
+    pub batch: Option<bool>,
+    /// Follow each command line with its exit status and duration, like
+    /// `set -o summary`
+    pub summary: Option<bool>,
+    /// File a transcript of each session is appended to, see `log`
+    pub transcript: Option<PathBuf>,
+    /// File each command line is logged to with its time and exit status,
+    /// or "syslog"
+    pub audit_log: Option<PathBuf>,
+    /// Limit what sessions can run, e.g. on a console for field technicians
+    pub restricted: Option<Restricted>,
+}
+
+/// What a restricted session may do. Redirections are refused, as are
+/// commands given by path, assignments before commands and setting
+/// variables like `PATH` and `LD_PRELOAD`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Restricted {
+    /// Names of the builtins and programs in PATH that can be run.
+    /// Functions can always be called, as their commands are checked too.
+    pub commands: Vec<String>,
+    /// Directory the session starts in and can't `cd` out of
+    pub directory: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Transports {
+    pub stdio: Option<Profile>,
+    pub uart: Option<Profile>,
+    pub tcp: Option<Profile>,
+}
+
+/// The contents of `pieshell.toml`
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    pub defaults: Profile,
+    #[serde(default)]
+    pub transport: Transports,
+}
+
+/// The settings in effect for one session, after merging the transport
+/// profile over the top-level defaults over the built-in defaults.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub echo: bool,
+    pub newline: Newline,
+    pub input_newline: InputNewline,
+    pub color: ColorPolicy,
+    pub theme: &'static Theme,
+    pub banner: String,
+    /// Takes the place of `banner` if it can be read
+    pub banner_file: Option<PathBuf>,
+    pub pager: Option<usize>,
+    pub idle_timeout: Option<Duration>,
+    pub telnet: bool,
+    pub baud: u32,
+    pub flow_control: FlowControl,
+    pub prompt: String,
+    pub continuation_prompt: String,
+    pub history_size: usize,
+    pub history_file: Option<PathBuf>,
+    pub history_backend: HistoryBackend,
+    pub rc_file: Option<PathBuf>,
+    pub inputrc: Option<PathBuf>,
+    pub editing_mode: EditingMode,
+    pub key_bindings: Vec<String>,
+    pub cd_spell: CdSpell,
+    pub hardware: bool,
+    pub clipboard: bool,
+    pub images: ImagePolicy,
+    pub output_encoding: OutputEncoding,
+    pub elevate: bool,
+    /// Memory caps in bytes. The oldest history entries and output are
+    /// dropped first.
+    pub history_memory: usize,
+    pub scrollback_memory: usize,
+    pub job_memory: usize,
+    /// Bytes of output of a command sent before it is cut short, if any
+    pub output_limit: Option<usize>,
+    /// Mask for files created by the session and its commands, if it isn't
+    /// the one of the shell process
+    pub umask: Option<u32>,
+    pub env: HashMap<String, String>,
+    pub auth: AuthMethod,
+    pub credentials: Option<PathBuf>,
+    pub batch: bool,
+    pub summary: bool,
+    pub transcript: Option<PathBuf>,
+    pub audit_log: Option<AuditLog>,
+    pub restricted: Option<Restricted>,
+}
+
+impl Config {
+    /// Load the config file given on the command line, or the first one found
+    /// in the default locations. A missing default config is not an error.
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let path = match path.map(Path::to_owned).or_else(find) {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+
+        let contents =
+            fs::read_to_string(&path).map_err(|error| format!("{}: {}", path.display(), error))?;
+        toml::from_str(&contents).map_err(|error| format!("{}: {}", path.display(), error))
+    }
+
+    pub fn profile(&self, kind: TransportKind) -> Option<&Profile> {
+        match kind {
+            TransportKind::Stdio => self.transport.stdio.as_ref(),
+            TransportKind::Uart => self.transport.uart.as_ref(),
+            TransportKind::Tcp => self.transport.tcp.as_ref(),
+        }
+    }
+
+    /// Resolve the settings for a session started on the given transport
+    pub fn settings(&self, kind: TransportKind) -> Result<Settings, String> {
+        let profile = self.profile(kind).cloned().unwrap_or_default();
+        let defaults = &self.defaults;
+
+        let theme = match profile.theme.as_ref().or(defaults.theme.as_ref()) {
+            Some(name) => {
+                Theme::by_name(name).ok_or(format!("unknown theme '{}' in config", name))?
+            }
+            None => Theme::default_theme(),
+        };
+
+        let umask = match profile.umask.as_ref().or(defaults.umask.as_ref()) {
+            Some(mask) => match u32::from_str_radix(mask, 8) {
+                Ok(mask) if mask <= 0o777 => Some(mask),
+                _ => return Err(format!("invalid umask '{}' in config", mask)),
+            },
+            None => None,
+        };
+        let mut environment = defaults.env.clone();
+        environment.extend(profile.env.clone());
+
+        let auth = profile.auth.or(defaults.auth).unwrap_or(AuthMethod::None);
+        if auth == AuthMethod::Pam && !cfg!(feature = "pam") {
+            return Err(String::from(
+                "auth = \"pam\" in config needs pieshell built with the pam feature",
+            ));
+        }
+
+        let history_backend = profile
+            .history_backend
+            .or(defaults.history_backend)
+            .unwrap_or(HistoryBackend::File);
+        if history_backend == HistoryBackend::Sqlite && !cfg!(feature = "sqlite") {
+            return Err(String::from(
+                "history_backend = \"sqlite\" in config needs pieshell built with the sqlite feature",
+            ));
+        }
+
+        /* A sandbox that doesn't exist must not leave sessions unconfined */
+        let mut restricted = profile.restricted.or(defaults.restricted.clone());
+        if let Some(Restricted {
+            directory: Some(directory),
+            ..
+        }) = &mut restricted
+        {
+            *directory = directory.canonicalize().map_err(|error| {
+                format!(
+                    "restricted directory {} in config: {}",
+                    directory.display(),
+                    error
+                )
+            })?;
+        }
+
+        let telnet =
+            kind == TransportKind::Tcp && profile.telnet.or(defaults.telnet).unwrap_or(false);
+        let batch = profile.batch.or(defaults.batch).unwrap_or(false);
+
+        Ok(Settings {
+            /* Serial terminals don't echo locally and telnet clients are asked
+            to leave echo to the shell, everything else echoes by itself.
+            Test rigs only want the output of commands. */
+            echo: !batch
+                && profile
+                    .echo
+                    .or(defaults.echo)
+                    .unwrap_or(kind == TransportKind::Uart || telnet),
+            newline: profile.newline.or(defaults.newline).unwrap_or(Newline::Lf),
+            input_newline: profile
+                .input_newline
+                .or(defaults.input_newline)
+                .unwrap_or_default(),
+            color: profile
+                .color
+                .or(defaults.color)
+                .unwrap_or(ColorPolicy::Auto),
+            theme,
+            banner_file: match (&profile.banner, &defaults.banner) {
+                (None, None) => Some(
+                    profile
+                        .banner_file
+                        .or(defaults.banner_file.clone())
+                        .unwrap_or(PathBuf::from(MOTD_FILE)),
+                ),
+                _ => profile.banner_file.or(defaults.banner_file.clone()),
+            },
+            banner: profile
+                .banner
+                .or(defaults.banner.clone())
+                .unwrap_or(String::from("Welcome to the shell")),
+            pager: profile.pager.or(defaults.pager).filter(|lines| *lines > 0),
+            idle_timeout: profile
+                .idle_timeout
+                .or(defaults.idle_timeout)
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            telnet,
+            baud: profile.baud.or(defaults.baud).unwrap_or(115_200),
+            flow_control: profile
+                .flow_control
+                .or(defaults.flow_control)
+                .unwrap_or_default(),
+            prompt: profile
+                .prompt
+                .or(defaults.prompt.clone())
+                .unwrap_or(String::from(DEFAULT_PROMPT)),
+            continuation_prompt: profile
+                .continuation_prompt
+                .or(defaults.continuation_prompt.clone())
+                .unwrap_or(String::from(DEFAULT_CONTINUATION_PROMPT)),
+            history_size: profile
+                .history_size
+                .or(defaults.history_size)
+                .unwrap_or(500),
+            history_file: profile.history_file.or(defaults.history_file.clone()),
+            history_backend,
+            rc_file: profile.rc_file.or(defaults.rc_file.clone()).or_else(|| {
+                env::var_os("HOME").map(|home| PathBuf::from(home).join(".pieshellrc"))
+            }),
+            inputrc: profile
+                .inputrc
+                .or(defaults.inputrc.clone())
+                .or_else(|| env::var_os("INPUTRC").map(PathBuf::from))
+                .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".inputrc"))),
+            editing_mode: profile
+                .editing_mode
+                .or(defaults.editing_mode)
+                .unwrap_or_default(),
+            key_bindings: profile
+                .key_bindings
+                .or(defaults.key_bindings.clone())
+                .unwrap_or_default(),
+            cd_spell: profile.cd_spell.or(defaults.cd_spell).unwrap_or_default(),
+            hardware: profile.hardware.or(defaults.hardware).unwrap_or(true),
+            clipboard: profile.clipboard.or(defaults.clipboard).unwrap_or(true),
+            images: profile
+                .images
+                .or(defaults.images)
+                .unwrap_or(ImagePolicy::Auto),
+            output_encoding: profile
+                .output_encoding
+                .or(defaults.output_encoding)
+                .unwrap_or(OutputEncoding::Raw),
+            elevate: profile.elevate.or(defaults.elevate).unwrap_or(false),
+            history_memory: profile
+                .history_memory
+                .or(defaults.history_memory)
+                .unwrap_or(64)
+                * 1024,
+            scrollback_memory: profile
+                .scrollback_memory
+                .or(defaults.scrollback_memory)
+                .unwrap_or(256)
+                * 1024,
+            job_memory: profile.job_memory.or(defaults.job_memory).unwrap_or(64) * 1024,
+            output_limit: profile
+                .output_limit
+                .or(defaults.output_limit)
+                .map(|limit| limit * 1024),
+            umask,
+            env: environment,
+            auth,
+            credentials: profile.credentials.or(defaults.credentials.clone()),
+            batch,
+            summary: profile.summary.or(defaults.summary).unwrap_or(false),
+            transcript: profile.transcript.or(defaults.transcript.clone()),
+            audit_log: profile
+                .audit_log
+                .or(defaults.audit_log.clone())
+                .map(AuditLog::from_config),
+            restricted,
+        })
+    }
+}
+
+/// The config file that will be used when none is given, if there is one
+pub fn find() -> Option<PathBuf> {
+    default_paths().into_iter().find(|path| path.is_file())
+}
+
+/// Locations searched for the config file when none is given
+pub fn default_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+        paths.push(PathBuf::from(dir).join("pieshell/pieshell.toml"));
+    } else if let Some(home) = env::var_os("HOME") {
+        paths.push(PathBuf::from(home).join(".config/pieshell/pieshell.toml"));
+    }
+    paths.push(PathBuf::from("/etc/pieshell.toml"));
+
+    paths
+}
@@ -0,0 +1,34 @@
+//! Messages of the shell process itself, e.g. about connections, written to
+//! stderr. The latest ones are kept for `report-bug`, as stderr of a shell
+//! started at boot often goes nowhere.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::SHELL_NAME;
+
+/// How many messages are kept
+const KEPT: usize = 200;
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Write a message to stderr and keep it with the time it was written
+pub(crate) fn log(message: &str) {
+    eprintln!("{}: {}", SHELL_NAME, message);
+
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let mut recent = RECENT.lock().unwrap_or_else(|error| error.into_inner());
+    if recent.len() == KEPT {
+        recent.pop_front();
+    }
+    recent.push_back(format!("{} {}", seconds, message));
+}
+
+/// The kept messages, oldest first, each starting with the Unix time
+pub(crate) fn recent() -> Vec<String> {
+    let recent = RECENT.lock().unwrap_or_else(|error| error.into_inner());
+    recent.iter().cloned().collect()
+}
@@ -0,0 +1,92 @@
+//! The line discipline between the session and its transport, like the
+//! `icrnl` and `onlcr` settings of a terminal: what ends a line typed at the
+//! other end, and how lines end in what is sent to it. Bytes lent to file
+//! transfers and input passed on to pseudo-terminals are left as they are.
+
+use std::io::{self, Write};
+use std::ops::{Deref, DerefMut};
+
+use crate::config::{InputNewline, Newline};
+
+/// Translates the ends of typed lines to `\n`
+pub(crate) struct InputDiscipline {
+    newline: InputNewline,
+    /// The last character was a carriage return
+    after_cr: bool,
+}
+
+impl InputDiscipline {
+    pub(crate) fn new(newline: InputNewline) -> InputDiscipline {
+        InputDiscipline {
+            newline,
+            after_cr: false,
+        }
+    }
+
+    /// The character as the shell sees it, `None` for one that is dropped
+    pub(crate) fn translate(&mut self, c: char) -> Option<char> {
+        let after_cr = std::mem::replace(&mut self.after_cr, c == '\r');
+        match (self.newline, c) {
+            (InputNewline::Any | InputNewline::Cr, '\r') => Some('\n'),
+            /* Terminals sending both end a line once */
+            (InputNewline::Any, '\n') if after_cr => None,
+            (InputNewline::Lf, '\r') => None,
+            (_, c) => Some(c),
+        }
+    }
+}
+
+/// A writer sending `\n` as the transport expects it
+pub(crate) struct Translated<T> {
+    link: T,
+    newline: Newline,
+}
+
+impl<T> Translated<T> {
+    pub(crate) fn new(link: T, newline: Newline) -> Translated<T> {
+        Translated { link, newline }
+    }
+
+    pub(crate) fn set_newline(&mut self, newline: Newline) {
+        self.newline = newline;
+    }
+}
+
+impl<T> Deref for Translated<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.link
+    }
+}
+
+impl<T> DerefMut for Translated<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.link
+    }
+}
+
+impl<T: Write> Write for Translated<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.newline {
+            Newline::Lf => self.link.write(buf),
+            Newline::Crlf => {
+                /* All of it is written, so a line end isn't split from its
+                carriage return */
+                let mut translated = Vec::with_capacity(buf.len() + buf.len() / 16);
+                for &byte in buf {
+                    if byte == b'\n' {
+                        translated.push(b'\r');
+                    }
+                    translated.push(byte);
+                }
+                self.link.write_all(&translated)?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.link.flush()
+    }
+}
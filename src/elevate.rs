@@ -0,0 +1,180 @@
+//! Offering to run a command line again as root with sudo after it failed
+//! for lack of permissions, e.g. when writing to /sys/class/gpio as a normal
+//! user. Enabled with the `elevate` setting.
+
+use std::fs;
+use std::io::{self, Write as _};
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::MetadataExt;
+use std::process::{Command, Stdio};
+
+use crate::builtins;
+use crate::parser::{self, Pipeline, SimpleCommand, Word};
+use crate::session::Session;
+use crate::SHELL_NAME;
+
+pub(crate) const QUESTION: &str = "Permission denied. Run it again with sudo? [y/N] ";
+pub(crate) const PASSWORD_PROMPT: &str = "[sudo] password: ";
+
+/// Error messages of commands that failed for lack of permissions
+const DENIED: [&[u8]; 2] = [b"Permission denied", b"Operation not permitted"];
+
+/// Whether error output tells that permissions were missing
+pub(crate) fn mentions_denied(data: &[u8]) -> bool {
+    DENIED
+        .iter()
+        .any(|message| data.windows(message.len()).any(|window| window == *message))
+}
+
+pub(crate) fn is_yes(answer: Option<&str>) -> bool {
+    matches!(answer.map(str::trim), Some("y" | "Y" | "yes"))
+}
+
+fn is_root() -> bool {
+    /* /proc/self belongs to the effective user of the process */
+    fs::metadata("/proc/self").is_ok_and(|metadata| metadata.uid() == 0)
+}
+
+/// How sudo gets the password of the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Authentication {
+    /// sudo doesn't need it, e.g. because it was given recently
+    None,
+    /// The shell asks for it, as sudo can't reach the user's terminal, and
+    /// passes it to `sudo -S` on standard input
+    Shell,
+    /// sudo asks for it on the terminal the shell runs in
+    Sudo,
+}
+
+/// The pipeline running `line` as root
+pub(crate) fn elevated(line: &str, authentication: Authentication) -> Pipeline {
+    let mut words = vec!["sudo"];
+    match authentication {
+        Authentication::None => words.push("-n"),
+        Authentication::Shell => words.extend(["-S", "-p", ""]),
+        Authentication::Sudo => {}
+    }
+    words.extend(["--", "sh", "-c", line.trim()]);
+    Pipeline {
+        commands: vec![parser::Command::Simple(SimpleCommand {
+            assignments: Vec::new(),
+            words: words.into_iter().map(Word::literal).collect(),
+            redirects: Vec::new(),
+        })],
+        timed: false,
+    }
+}
+
+impl Session {
+    /// Whether to offer running the last line again as root: it failed, some
+    /// error told that permissions were missing, and the shell isn't root
+    /// already. Lines using builtins or functions of the shell can't run under
+    /// sudo.
+    pub(crate) fn should_offer_elevation(&mut self, line: &str) -> bool {
+        if !std::mem::take(&mut self.permission_denied)
+            || !self.settings().elevate
+            || self.settings().restricted.is_some()
+            || self.last_status == 0
+            || is_root()
+        {
+            return false;
+        }
+
+        let ast = match parser::parse(line) {
+            Ok(ast) => ast,
+            Err(_) => return false,
+        };
+        let mut pipelines = Vec::new();
+        for item in &ast.items {
+            pipelines.push(&item.and_or.first);
+            pipelines.extend(item.and_or.rest.iter().map(|(_, pipeline)| pipeline));
+        }
+        let uses_builtin = pipelines
+            .iter()
+            .flat_map(|pipeline| &pipeline.commands)
+            .any(|command| match command {
+                parser::Command::Simple(command) => {
+                    match command.words.first().and_then(Word::as_literal) {
+                        Some(name) => {
+                            builtins::is_builtin(self, &name) || self.functions.contains_key(&name)
+                        }
+                        None => false,
+                    }
+                }
+                /* Compound commands may run builtins anywhere inside */
+                _ => true,
+            });
+        !uses_builtin && self.find_program("sudo").is_ok()
+    }
+
+    /// How sudo will get the password
+    pub(crate) fn authentication(&self) -> Authentication {
+        let cached = Command::new("sudo")
+            .args(["-n", "true"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        match cached {
+            true => Authentication::None,
+            /* Only sessions the shell echoes for aren't on a terminal sudo
+            can open */
+            false if self.settings().echo => Authentication::Shell,
+            false => Authentication::Sudo,
+        }
+    }
+
+    /// Make the password typed in the shell the standard input of the
+    /// elevated line, where `sudo -S` reads it. The commands get end of file
+    /// after it. Returns what to give back to `forget_password`.
+    pub(crate) fn lend_password(&mut self, password: &str) -> io::Result<Option<OwnedFd>> {
+        let (reader, mut writer) = io::pipe()?;
+        writeln!(writer, "{}", password)?;
+        Ok(self.exec_fds[0].replace(reader.into()))
+    }
+
+    /// Restore the standard input replaced by `lend_password`, and drop the
+    /// credentials sudo cached on the way, as every session of the user
+    /// would share them
+    pub(crate) fn forget_password(&mut self, stdin: Option<OwnedFd>) {
+        self.exec_fds[0] = stdin;
+        let forgotten = Command::new("sudo")
+            .arg("-k")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        if let Err(error) = forgotten {
+            self.print_error(&format!("{}: sudo: {}", SHELL_NAME, error));
+        }
+    }
+
+    /// After a line failed for lack of permissions, offer to run it again as
+    /// root
+    pub(crate) fn offer_elevation(&mut self, line: &str) -> io::Result<()> {
+        if !self.should_offer_elevation(line) {
+            return Ok(());
+        }
+
+        if !is_yes(self.ask(QUESTION)?.as_deref()) {
+            return Ok(());
+        }
+
+        let authentication = self.authentication();
+        let pipeline = elevated(line, authentication);
+        if authentication != Authentication::Shell {
+            return self.run_pipeline(&pipeline);
+        }
+
+        let password = match self.ask_secret(PASSWORD_PROMPT)? {
+            Some(password) => password,
+            None => return Ok(()),
+        };
+        let stdin = self.lend_password(&password)?;
+        let result = self.run_pipeline(&pipeline);
+        self.forget_password(stdin);
+        result
+    }
+}
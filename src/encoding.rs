@@ -0,0 +1,81 @@
+//! Command output that isn't UTF-8, like `cat` of a binary or text in
+//! Latin-1. It is passed to the transport byte for byte by default, or made
+//! valid UTF-8 for terminals that get confused by anything else.
+
+use serde::Deserialize;
+
+/// Shown in place of bytes that aren't valid UTF-8
+const REPLACEMENT: &str = "\u{fffd}";
+
+/// What to do with output that isn't valid UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputEncoding {
+    /// Pass the bytes through unchanged
+    Raw,
+    /// Replace invalid bytes with U+FFFD
+    Lossy,
+}
+
+/// Replaces invalid UTF-8 in output. Output may arrive in pieces, so a
+/// character split between calls is kept until the rest of it arrives.
+pub struct LossyFilter {
+    pending: Vec<u8>,
+}
+
+impl LossyFilter {
+    pub fn new() -> LossyFilter {
+        LossyFilter {
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(data);
+
+        let mut filtered = Vec::with_capacity(input.len());
+        let mut rest = &input[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    filtered.extend_from_slice(valid.as_bytes());
+                    return filtered;
+                }
+                Err(error) => {
+                    let (valid, invalid) = rest.split_at(error.valid_up_to());
+                    filtered.extend_from_slice(valid);
+                    match error.error_len() {
+                        Some(length) => {
+                            filtered.extend_from_slice(REPLACEMENT.as_bytes());
+                            rest = &invalid[length..];
+                        }
+                        /* The start of a character, the rest may follow */
+                        None => {
+                            self.pending = invalid.to_vec();
+                            return filtered;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// End the output of a command. The start of a character that never got
+    /// the rest of it is replaced.
+    pub fn finish(&mut self) -> Vec<u8> {
+        match self.pending.is_empty() {
+            true => Vec::new(),
+            false => {
+                self.pending.clear();
+                REPLACEMENT.as_bytes().to_vec()
+            }
+        }
+    }
+}
+
+impl Default for LossyFilter {
+    fn default() -> LossyFilter {
+        LossyFilter::new()
+    }
+}
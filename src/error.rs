@@ -0,0 +1,105 @@
+//! Errors of the shell. Errors that end the shell are returned from `run` to
+//! the program embedding it instead of exiting the process, the others are
+//! reported in the session and set the exit status of the command.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::StatusCode;
+
+#[derive(Debug)]
+pub enum ShellError {
+    /// The input line could not be parsed
+    Parse(String),
+    /// A program given by name was not found in PATH
+    CommandNotFound(String),
+    /// A program given by path does not exist
+    NoSuchFile(String),
+    /// A program was found but could not be started
+    Exec { program: String, error: io::Error },
+    /// A file could not be opened for a redirection, or the file descriptor
+    /// is not supported
+    Redirect { target: String, error: io::Error },
+    /// A variable that isn't set was expanded with `set -u`
+    Unbound(String),
+    /// A command or redirection a restricted session may not use
+    Restricted(String),
+    /// A pipe between commands could not be created
+    Pipe(io::Error),
+    /// A pseudo-terminal for commands could not be allocated
+    Pty(io::Error),
+    /// Invalid command line arguments, with the usage text
+    Usage(String),
+    /// The config file could not be read or is invalid
+    Config(String),
+    /// A transport could not be set up or failed while in use
+    Transport(io::Error),
+}
+
+impl ShellError {
+    /// The exit status caused by the error, for the failed command or the
+    /// whole process
+    pub fn exit_code(&self) -> i32 {
+        self.status_code().code()
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ShellError::CommandNotFound(_) | ShellError::NoSuchFile(_) => StatusCode::NotFound,
+            /* Programs can be missing even though they were found, e.g. when
+            the interpreter of a script doesn't exist */
+            ShellError::Exec { error, .. } if error.kind() == io::ErrorKind::NotFound => {
+                StatusCode::NotFound
+            }
+            ShellError::Exec { .. } => StatusCode::NotExecutable,
+            ShellError::Redirect { .. }
+            | ShellError::Unbound(_)
+            | ShellError::Restricted(_)
+            | ShellError::Pipe(_)
+            | ShellError::Pty(_) => StatusCode::Failure,
+            ShellError::Parse(_) | ShellError::Usage(_) | ShellError::Config(_) => {
+                StatusCode::Usage
+            }
+            ShellError::Transport(_) => StatusCode::Failure,
+        }
+    }
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShellError::Parse(message) => write!(f, "syntax error: {}", message),
+            ShellError::CommandNotFound(program) => write!(f, "{}: command not found", program),
+            ShellError::NoSuchFile(program) => write!(f, "{}: No such file or directory", program),
+            ShellError::Exec { program, error } => write!(f, "{}: {}", program, error),
+            ShellError::Redirect { target, error } => write!(f, "{}: {}", target, error),
+            ShellError::Unbound(name) => write!(f, "{}: unbound variable", name),
+            ShellError::Restricted(what) => write!(f, "{}: restricted", what),
+            ShellError::Pipe(error) => write!(f, "failed to create pipe: {}", error),
+            ShellError::Pty(error) => write!(f, "failed to allocate pseudo-terminal: {}", error),
+            ShellError::Usage(message) => write!(f, "{}", message),
+            ShellError::Config(message) => write!(f, "failed to load config: {}", message),
+            ShellError::Transport(error) => write!(f, "transport error: {}", error),
+        }
+    }
+}
+
+impl Error for ShellError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ShellError::Exec { error, .. }
+            | ShellError::Redirect { error, .. }
+            | ShellError::Pipe(error)
+            | ShellError::Pty(error)
+            | ShellError::Transport(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ShellError {
+    fn from(error: io::Error) -> ShellError {
+        ShellError::Transport(error)
+    }
+}
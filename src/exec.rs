@@ -0,0 +1,2022 @@
+//! Execution of parsed commands: expansion of words, redirections, builtins
+//! and pipelines of external programs.
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, PipeReader, PipeWriter, Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{self, Child, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::braces;
+use crate::builtins;
+use crate::elevate;
+use crate::fields;
+use crate::limits::{self, Limit};
+use crate::options::ShellOptions;
+use crate::parser::{
+    self, AndOr, Ast, Connector, ListItem, Pipeline, Redirect, RedirectKind, SimpleCommand, Word,
+    WordPart,
+};
+use crate::pty::{self, Pty, WindowSize};
+use crate::session::{self, Session};
+use crate::size::{self, Units};
+use crate::traps::Traps;
+use crate::{ShellError, StatusCode, SHELL_NAME};
+
+/// How often running commands are checked for having exited
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Where programs found in PATH are, so PATH is only searched the first time
+/// a program is run
+#[derive(Default)]
+pub(crate) struct PathCache {
+    /// The PATH the programs were found in
+    path_variable: String,
+    pub(crate) programs: HashMap<String, PathBuf>,
+}
+
+/// How deeply `source`, `eval` and the like can nest. This stops a bad rc
+/// file well before it overflows the stack of the session.
+pub(crate) const MAX_DEPTH: usize = 64;
+
+/// Leaving loops early with `break` and `continue`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Jump {
+    /// End this many loops
+    Break(usize),
+    /// End this many loops less one, and go on with the next round of that
+    Continue(usize),
+}
+
+/// Output of a running pipeline
+pub(crate) enum Chunk {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+}
+
+/// Input of the first command of a pipeline
+pub(crate) enum Input {
+    Null,
+    /// A pipe the caller writes to, e.g. to pass on typed input
+    Pipe,
+    /// The terminal the shell runs in. The commands also write to it
+    /// directly, so full-screen programs work.
+    Terminal,
+    /// A new pseudo-terminal of the given size, which the commands also
+    /// write to. The caller relays it to and from the transport.
+    Pty(WindowSize),
+}
+
+/// Where a standard file descriptor of a command points
+enum Target {
+    Null,
+    /// Output relayed to the transport
+    Stdout,
+    /// Errors relayed to the transport
+    Stderr,
+    /// A file or a pipe to another command
+    File(OwnedFd),
+    /// The terminal the shell runs in
+    Terminal,
+    /// The pseudo-terminal of the pipeline
+    Pty(OwnedFd),
+}
+
+impl Target {
+    fn try_clone(&self) -> io::Result<Target> {
+        match self {
+            Target::Null => Ok(Target::Null),
+            Target::Stdout => Ok(Target::Stdout),
+            Target::Stderr => Ok(Target::Stderr),
+            Target::File(fd) => Ok(Target::File(fd.try_clone()?)),
+            Target::Terminal => Ok(Target::Terminal),
+            Target::Pty(fd) => Ok(Target::Pty(fd.try_clone()?)),
+        }
+    }
+}
+
+/// Write ends of the pipes relaying output to the transport
+struct Relay {
+    stdout: PipeWriter,
+    stderr: PipeWriter,
+}
+
+impl Relay {
+    fn stdio(&self, target: Target) -> io::Result<Stdio> {
+        match target {
+            Target::Null => Ok(Stdio::null()),
+            Target::Stdout => Ok(Stdio::from(self.stdout.try_clone()?)),
+            Target::Stderr => Ok(Stdio::from(self.stderr.try_clone()?)),
+            Target::File(fd) | Target::Pty(fd) => Ok(Stdio::from(fd)),
+            Target::Terminal => Ok(Stdio::inherit()),
+        }
+    }
+}
+
+/// The commands of a pipeline that were started
+pub(crate) struct Running {
+    children: Vec<Child>,
+    stdout: Option<PipeReader>,
+    stderr: Option<PipeReader>,
+    /// Input of the first command, if a pipe was asked for
+    pub(crate) input: Option<PipeWriter>,
+    /// The pseudo-terminal of the commands, if one was asked for
+    terminal: Option<Pty>,
+    /// Exit status of the last command, if it didn't start a process
+    status: Option<i32>,
+    /// The commands lead process groups of their own, see `Session::grouped`
+    grouped: bool,
+}
+
+impl Running {
+    /// Process ID of the last command
+    pub(crate) fn pid(&self) -> Option<u32> {
+        self.children.last().map(Child::id)
+    }
+
+    /// Process IDs of all commands, first to last
+    pub(crate) fn pids(&self) -> Vec<u32> {
+        self.children.iter().map(Child::id).collect()
+    }
+
+    /// Whether the commands run on a pseudo-terminal, see `write_terminal`
+    pub(crate) fn has_terminal(&self) -> bool {
+        self.terminal.is_some()
+    }
+
+    /// Tell the commands on a pseudo-terminal that the terminal at the other
+    /// end of the transport changed size
+    pub(crate) fn resize(&self, size: WindowSize) {
+        if let Some(terminal) = &self.terminal {
+            let _ = terminal.resize(size);
+        }
+    }
+
+    /// Pass typed input to the pseudo-terminal of the commands. The terminal
+    /// interrupts the command holding it on Ctrl-C, the other commands of the
+    /// pipeline are interrupted here.
+    pub(crate) fn write_terminal(&mut self, data: &[u8]) -> io::Result<()> {
+        let terminal = match &mut self.terminal {
+            Some(terminal) => terminal,
+            None => return Ok(()),
+        };
+        if data.iter().any(|&byte| terminal.interrupts(byte)) {
+            let holder = terminal.holder();
+            for child in &self.children {
+                if Some(child.id()) != holder {
+                    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+                }
+            }
+        }
+        terminal.write_input(data)
+    }
+
+    /// Read the output of the pipeline in threads, passing it to `send`
+    /// until the pipes are closed or `send` returns false. Output on the
+    /// pseudo-terminal counts as standard output.
+    pub(crate) fn relay_output<F>(&mut self, send: F)
+    where
+        F: Fn(Chunk) -> bool + Clone + Send + 'static,
+    {
+        let terminal = self
+            .terminal
+            .as_ref()
+            .and_then(|terminal| terminal.reader().ok());
+        let pipes = [
+            (
+                self.stdout
+                    .take()
+                    .map(|pipe| File::from(OwnedFd::from(pipe))),
+                true,
+            ),
+            (
+                self.stderr
+                    .take()
+                    .map(|pipe| File::from(OwnedFd::from(pipe))),
+                false,
+            ),
+            (terminal, true),
+        ];
+        for (pipe, is_stdout) in pipes {
+            let mut pipe = match pipe {
+                Some(pipe) => pipe,
+                None => continue,
+            };
+            let send = send.clone();
+            thread::spawn(move || {
+                let mut buf = [0u8; 1024];
+                loop {
+                    let data = match pipe.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(length) => buf[..length].to_vec(),
+                    };
+                    let chunk = match is_stdout {
+                        true => Chunk::Stdout(data),
+                        false => Chunk::Stderr(data),
+                    };
+                    if !send(chunk) {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Check whether every command has exited. Returns the exit status of
+    /// the pipeline, which is that of its last command.
+    pub(crate) fn try_wait(&mut self) -> io::Result<Option<i32>> {
+        let mut last = None;
+        for child in &mut self.children {
+            match child.try_wait()? {
+                Some(status) => last = Some(status),
+                None => return Ok(None),
+            }
+        }
+
+        match (self.status, last) {
+            (Some(status), _) => Ok(Some(status)),
+            (None, Some(status)) => Ok(Some(session::exit_code(status))),
+            (None, None) => Ok(Some(0)),
+        }
+    }
+
+    pub(crate) fn kill(&mut self) {
+        for child in &mut self.children {
+            /* Commands on a pseudo-terminal or run by `timeout` lead a
+            process group of their own, which goes with them along with what
+            they started */
+            if self.terminal.is_some() || self.grouped {
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            }
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Times of a pipeline run with `time`
+pub(crate) struct Timer {
+    started: Instant,
+    /// CPU time of finished child processes when it started
+    user: Duration,
+    system: Duration,
+}
+
+impl Timer {
+    pub(crate) fn start() -> Timer {
+        let (user, system) = children_times();
+        Timer {
+            started: Instant::now(),
+            user,
+            system,
+        }
+    }
+
+    /// The real, user and system time since the start, as bash shows them.
+    /// CPU times are those of every child process that finished meanwhile,
+    /// so background jobs and other sessions ending can add to them.
+    pub(crate) fn report(&self) -> String {
+        let (user, system) = children_times();
+        let format = |duration: Duration| {
+            format!(
+                "{}m{}.{:03}s",
+                duration.as_secs() / 60,
+                duration.as_secs() % 60,
+                duration.subsec_millis()
+            )
+        };
+        format!(
+            "\nreal\t{}\nuser\t{}\nsys\t{}\n",
+            format(self.started.elapsed()),
+            format(user.saturating_sub(self.user)),
+            format(system.saturating_sub(self.system))
+        )
+    }
+}
+
+/// User and system CPU time of the child processes waited for so far
+fn children_times() -> (Duration, Duration) {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) };
+    let duration = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+    (duration(usage.ru_utime), duration(usage.ru_stime))
+}
+
+/// How many Ctrl-C within `PANIC_WINDOW` kill the foreground pipeline, even
+/// when it runs on a pseudo-terminal that ignores them
+const PANIC_INTERRUPTS: usize = 5;
+const PANIC_WINDOW: Duration = Duration::from_secs(2);
+
+/// Watches input typed while a foreground pipeline runs for the keys that
+/// kill it whatever it does with its input: Ctrl-C pressed five times in a
+/// row, or `~k` at the start of a line as with the escapes of ssh
+#[derive(Default)]
+pub(crate) struct DeadMan {
+    interrupts: VecDeque<Instant>,
+    /// Nothing but a newline was typed since the last one
+    line_start: bool,
+    /// A `~` at the start of a line was held back
+    escape: bool,
+}
+
+impl DeadMan {
+    pub(crate) fn new() -> DeadMan {
+        DeadMan {
+            line_start: true,
+            ..DeadMan::default()
+        }
+    }
+
+    /// Look at a typed byte, adding what should reach the pipeline to
+    /// `forward`. Returns true if the pipeline must be killed.
+    pub(crate) fn feed(&mut self, byte: u8, forward: &mut Vec<u8>) -> bool {
+        if byte == 0x3 {
+            let now = Instant::now();
+            self.interrupts
+                .retain(|&pressed| now.duration_since(pressed) < PANIC_WINDOW);
+            self.interrupts.push_back(now);
+            if self.interrupts.len() >= PANIC_INTERRUPTS {
+                return true;
+            }
+        }
+
+        /* `~~` sends a single `~` */
+        if std::mem::take(&mut self.escape) {
+            match byte {
+                b'k' => return true,
+                b'~' => {}
+                _ => forward.push(b'~'),
+            }
+        } else if self.line_start && byte == b'~' {
+            self.escape = true;
+            self.line_start = false;
+            return false;
+        }
+        forward.push(byte);
+        self.line_start = matches!(byte, b'\r' | b'\n');
+        false
+    }
+}
+
+/// Most bytes of a truncated output saved to a file, so a runaway command
+/// can't fill /tmp, which is often kept in memory
+const SAVE_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Keeps the output of a foreground pipeline sent over the transport within
+/// the `output_limit` setting. Past it, the output goes to a file instead,
+/// along with what was sent, so the file has all of it up to `SAVE_LIMIT`.
+/// Commands given the terminal on stdio write to it directly, so their
+/// output isn't capped.
+pub(crate) struct OutputCap {
+    limit: Option<usize>,
+    /// Output sent so far, while below the limit
+    sent: Vec<u8>,
+    truncated: bool,
+    file: Option<File>,
+    /// Bytes written to the file
+    saved: usize,
+}
+
+impl OutputCap {
+    pub(crate) fn new(limit: Option<usize>) -> OutputCap {
+        OutputCap {
+            limit,
+            sent: Vec::new(),
+            truncated: false,
+            file: None,
+            saved: 0,
+        }
+    }
+
+    /// Take output of the pipeline. Returns the part to send, and a notice
+    /// to show once the limit is reached.
+    pub(crate) fn pass<'a>(&mut self, data: &'a [u8]) -> (&'a [u8], Option<String>) {
+        if self.truncated {
+            return (&[], self.save(data));
+        }
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return (data, None),
+        };
+        let room = limit - self.sent.len();
+        if data.len() <= room {
+            self.sent.extend_from_slice(data);
+            return (data, None);
+        }
+
+        self.truncated = true;
+        let sent = std::mem::take(&mut self.sent);
+        let limit = size::format(limit as u64, Units::Iec);
+        let notice = match output_file() {
+            Ok((file, path)) => {
+                self.file = Some(file);
+                let notice = format!(
+                    "\n{}: output truncated after {}, full copy at {}",
+                    SHELL_NAME,
+                    limit,
+                    path.display()
+                );
+                match self.save(&sent).or_else(|| self.save(data)) {
+                    Some(cut) => notice + &cut,
+                    None => notice,
+                }
+            }
+            Err(error) => format!(
+                "\n{}: output truncated after {}, failed to save a full copy: {}",
+                SHELL_NAME, limit, error
+            ),
+        };
+        (&data[..room], Some(notice))
+    }
+
+    /// Append output to the saved copy until it reaches `SAVE_LIMIT`.
+    /// Returns a notice when it does.
+    fn save(&mut self, data: &[u8]) -> Option<String> {
+        let file = self.file.as_mut()?;
+        let part = &data[..data.len().min(SAVE_LIMIT - self.saved)];
+        if file.write_all(part).is_err() {
+            self.file = None;
+            return None;
+        }
+        self.saved += part.len();
+        if part.len() == data.len() {
+            return None;
+        }
+
+        self.file = None;
+        Some(format!(
+            "\n{}: copy cut short after {}",
+            SHELL_NAME,
+            size::format(SAVE_LIMIT as u64, Units::Iec)
+        ))
+    }
+}
+
+/// Create a file only the user can read for output that was cut short,
+/// named `/tmp/pieshell-out-N` after the first free number
+fn output_file() -> io::Result<(File, PathBuf)> {
+    let mut number = 1;
+    loop {
+        let path = env::temp_dir().join(format!("{}-out-{}", SHELL_NAME, number));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+        {
+            Ok(file) => return Ok((file, path)),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => number += 1,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Append to a buffer holding at most `limit` bytes, dropping the oldest
+/// bytes. Returns how many were dropped.
+pub(crate) fn append_capped(buffer: &mut VecDeque<u8>, data: &[u8], limit: usize) -> usize {
+    buffer.extend(data);
+    let excess = buffer.len().saturating_sub(limit);
+    buffer.drain(..excess);
+    excess
+}
+
+/// Whether the pipeline after `connector` runs, given the exit status of the
+/// one before it
+pub(crate) fn should_run(connector: Connector, status: i32) -> bool {
+    match connector {
+        Connector::And => status == 0,
+        Connector::Or => status != 0,
+    }
+}
+
+impl Session {
+    /// Parse a line of input and run it. Errors of the transport are
+    /// returned, all others are reported in the session.
+    pub(crate) fn execute_line(&mut self, input: &str) -> io::Result<()> {
+        self.add_history(input);
+        self.permission_denied = false;
+        self.interrupted = false;
+        let command = input.trim();
+        if command.is_empty() {
+            return Ok(());
+        }
+
+        let started = self.before_command(command);
+        let result = match parser::parse(input) {
+            Ok(ast) => self.execute(&ast),
+            Err(error) => {
+                self.report(&ShellError::Parse(error.message));
+                Ok(())
+            }
+        };
+        self.run_line_traps();
+        self.after_command(command, started);
+        result
+    }
+
+    /// Whether the rest of the commands being run are skipped, e.g. after
+    /// `return` or Ctrl-C
+    pub(crate) fn stopped(&self) -> bool {
+        self.timed_out
+            || self.exit_requested
+            || self.interrupted
+            || self.returning
+            || self.jump.is_some()
+    }
+
+    pub(crate) fn execute(&mut self, ast: &Ast) -> io::Result<()> {
+        for item in &ast.items {
+            self.note_slow_start();
+            self.run_traps();
+            if self.stopped() {
+                break;
+            }
+            if item.background {
+                self.start_job(&item.and_or)?;
+            } else {
+                self.run_and_or(&item.and_or)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the traps of signals that arrived since they last ran. `$?` stays
+    /// the status of the command before.
+    pub(crate) fn run_traps(&mut self) {
+        for command in self.traps.take_pending() {
+            self.run_trap(&command);
+        }
+    }
+
+    /// Run the traps after a line, including the one of `INT` if Ctrl-C
+    /// stopped it
+    pub(crate) fn run_line_traps(&mut self) {
+        if self.interrupted {
+            self.traps.raise(libc::SIGINT);
+        }
+        self.run_traps();
+    }
+
+    /// Run the trap of `EXIT`, as the session or script ends
+    pub(crate) fn run_exit_trap(&mut self) {
+        if let Some(command) = self.traps.take_exit() {
+            let exiting = std::mem::take(&mut self.exit_requested);
+            self.run_trap(&command);
+            self.exit_requested |= exiting;
+        }
+    }
+
+    fn run_trap(&mut self, command: &str) {
+        let status = self.last_status;
+        /* Traps run even after Ctrl-C stopped the line */
+        let interrupted = std::mem::take(&mut self.interrupted);
+        match parser::parse(command) {
+            Ok(ast) => {
+                if let Err(error) = self.execute(&ast) {
+                    eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+                }
+            }
+            Err(error) => self.report(&ShellError::Parse(error.message)),
+        }
+        self.interrupted |= interrupted;
+        if !self.exit_requested {
+            self.last_status = status;
+        }
+    }
+
+    /// Run shell source from within a command, e.g. a sourced file, and
+    /// return the status of its last command. Fails instead of nesting deeper
+    /// than `MAX_DEPTH`.
+    pub(crate) fn run_nested(&mut self, name: &str, source: &str) -> i32 {
+        match parser::parse(source) {
+            Ok(ast) => self.run_nested_ast(name, &ast),
+            Err(error) => {
+                self.report(&ShellError::Parse(error.message));
+                self.last_status
+            }
+        }
+    }
+
+    /// Run a command from within a command, like `run_nested`, with the
+    /// arguments as they are instead of parsing and expanding them
+    pub(crate) fn run_nested_args(&mut self, name: &str, args: &[&str]) -> i32 {
+        let words = args
+            .iter()
+            .map(|arg| Word {
+                parts: vec![WordPart::SingleQuoted((*arg).to_owned())],
+            })
+            .collect();
+        let command = SimpleCommand {
+            assignments: Vec::new(),
+            words,
+            redirects: Vec::new(),
+        };
+        let ast = Ast {
+            items: vec![ListItem {
+                and_or: AndOr {
+                    first: Pipeline {
+                        commands: vec![parser::Command::Simple(command)],
+                        timed: false,
+                    },
+                    rest: Vec::new(),
+                },
+                background: false,
+            }],
+        };
+        self.run_nested_ast(name, &ast)
+    }
+
+    fn run_nested_ast(&mut self, name: &str, ast: &Ast) -> i32 {
+        if self.depth >= MAX_DEPTH {
+            self.print_error(&format!(
+                "{}: {}: maximum nesting depth of {} exceeded",
+                SHELL_NAME, name, MAX_DEPTH
+            ));
+            return StatusCode::Failure.code();
+        }
+
+        self.last_status = StatusCode::Success.code();
+        self.depth += 1;
+        let result = self.execute(ast);
+        self.depth -= 1;
+        match result {
+            Ok(()) => self.last_status,
+            Err(error) => {
+                eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+                StatusCode::Failure.code()
+            }
+        }
+    }
+
+    fn run_and_or(&mut self, and_or: &AndOr) -> io::Result<()> {
+        /* Pipelines followed by `&&` or `||` are tested, so with `set -e`
+        only the last one failing ends the session */
+        let mut ran_last = and_or.rest.is_empty();
+        self.run_tested(&and_or.first, !ran_last)?;
+        for (i, (connector, pipeline)) in and_or.rest.iter().enumerate() {
+            if self.stopped() {
+                break;
+            }
+            if should_run(*connector, self.last_status) {
+                ran_last = i + 1 == and_or.rest.len();
+                self.run_tested(pipeline, !ran_last)?;
+            }
+        }
+        if ran_last {
+            self.check_errexit();
+        }
+        Ok(())
+    }
+
+    /// Run a pipeline, whose status is tested if `tested`
+    fn run_tested(&mut self, pipeline: &Pipeline, tested: bool) -> io::Result<()> {
+        let timer = pipeline.timed.then(Timer::start);
+        self.conditions += usize::from(tested);
+        let result = self.run_pipeline(pipeline);
+        self.conditions -= usize::from(tested);
+        result?;
+        match timer {
+            Some(timer) => self.write_output(timer.report().as_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    /// Run the condition of an `if` or a loop. Its commands failing doesn't
+    /// end the session with `set -e`.
+    fn run_condition(&mut self, condition: &Ast) -> io::Result<()> {
+        self.conditions += 1;
+        let result = self.execute(condition);
+        self.conditions -= 1;
+        result
+    }
+
+    /// With `set -e`, end the session after a command failed, unless its
+    /// status is tested
+    pub(crate) fn check_errexit(&mut self) {
+        if self.options.contains(ShellOptions::ERREXIT)
+            && self.last_status != 0
+            && self.conditions == 0
+        {
+            self.exit_requested = true;
+        }
+    }
+
+    /// Start a pipeline as a background job
+    pub(crate) fn start_job(&mut self, and_or: &AndOr) -> io::Result<()> {
+        if !and_or.rest.is_empty() {
+            self.report(&ShellError::Parse(String::from(
+                "only pipelines can run in the background",
+            )));
+            return Ok(());
+        }
+
+        let running = match self.start_pipeline(&and_or.first, Input::Null) {
+            Ok(running) => running,
+            Err(error) => {
+                self.report(&error);
+                return Ok(());
+            }
+        };
+        let limit = self.settings().job_memory;
+        let (id, pid) = self.jobs.add(running, and_or.to_string(), limit);
+        self.last_job_pid = Some(pid);
+        self.last_status = 0;
+        self.write_output(format!("[{}] {}\n", id, pid).as_bytes())
+    }
+
+    /// Run a pipeline, relaying its output as it arrives. The commands are
+    /// killed if they are still running at the deadline of the session.
+    pub(crate) fn run_pipeline(&mut self, pipeline: &Pipeline) -> io::Result<()> {
+        self.follow_window_size();
+        let running = match self.start_pipeline(pipeline, self.foreground_input()) {
+            /* Without pseudo-terminals, e.g. in a container lacking
+            /dev/pts, typed input still reaches the commands */
+            Err(ShellError::Pty(_)) => self.start_pipeline(pipeline, Input::Pipe),
+            result => result,
+        };
+        let mut running = match running {
+            Ok(running) => running,
+            Err(error) => {
+                self.report(&error);
+                return Ok(());
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        running.relay_output(move |chunk| sender.send(chunk).is_ok());
+
+        /* Input typed meanwhile goes to the commands */
+        let mut stdin = running.input.take();
+        let bridging = stdin.is_some() || running.has_terminal();
+        if bridging {
+            self.set_input_polling(true)?;
+        }
+
+        let mut output = VecDeque::new();
+        let mut cap = OutputCap::new(self.settings().output_limit);
+        let mut dead_man = DeadMan::new();
+        let mut open = true;
+        let status = loop {
+            if open {
+                match receiver.recv_timeout(POLL_INTERVAL) {
+                    Ok(chunk) => self.write_chunk(chunk, &mut output, &mut cap)?,
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => open = false,
+                }
+            } else {
+                /* The pipes can close before the processes exit */
+                thread::sleep(POLL_INTERVAL);
+            }
+            if bridging && self.bridge_input(&mut running, &mut stdin, &mut dead_man)? {
+                running.kill();
+                self.interrupted = true;
+            }
+
+            match running.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) => {}
+                Err(error) => {
+                    running.kill();
+                    break ShellError::Exec {
+                        program: pipeline.to_string(),
+                        error,
+                    }
+                    .exit_code();
+                }
+            }
+
+            if self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                running.kill();
+                self.timed_out = true;
+                break StatusCode::Timeout.code();
+            }
+        };
+
+        if bridging {
+            self.set_input_polling(false)?;
+        }
+
+        /* Output written just before exiting may still be on its way */
+        while let Ok(chunk) = receiver.recv_timeout(POLL_INTERVAL) {
+            self.write_chunk(chunk, &mut output, &mut cap)?;
+        }
+        self.finish_output()?;
+
+        self.last_status = status;
+        /* Like Ctrl-C, a command killed by it stops loops and the rest of
+        the line */
+        self.interrupted |= StatusCode::signal(status) == Some(libc::SIGINT);
+        /* Builtins don't replace the output kept of the last program */
+        if running.pid().is_some() {
+            self.last_output = output;
+        }
+        Ok(())
+    }
+
+    /// Write output of a command, keeping what went to its standard output
+    fn write_chunk(
+        &mut self,
+        chunk: Chunk,
+        output: &mut VecDeque<u8>,
+        cap: &mut OutputCap,
+    ) -> io::Result<()> {
+        match chunk {
+            Chunk::Stdout(data) => {
+                self.write_capped(&data, cap)?;
+                append_capped(output, &data, self.settings().scrollback_memory);
+            }
+            Chunk::Stderr(data) => {
+                self.permission_denied |= elevate::mentions_denied(&data);
+                self.write_capped(&data, cap)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Write output of a foreground command, unless it went past the
+    /// `output_limit` setting
+    pub(crate) fn write_capped(&mut self, data: &[u8], cap: &mut OutputCap) -> io::Result<()> {
+        let (sent, notice) = cap.pass(data);
+        self.write_output(sent)?;
+        if let Some(notice) = notice {
+            self.print_error(&notice);
+        }
+        Ok(())
+    }
+
+    /// Start the commands of a pipeline, connected with pipes. Builtins run
+    /// to completion right away, external programs are left running.
+    pub(crate) fn start_pipeline(
+        &mut self,
+        pipeline: &Pipeline,
+        input: Input,
+    ) -> Result<Running, ShellError> {
+        let (stdout_reader, stdout_writer) = io::pipe().map_err(ShellError::Pipe)?;
+        let (stderr_reader, stderr_writer) = io::pipe().map_err(ShellError::Pipe)?;
+        let relay = Relay {
+            stdout: stdout_writer,
+            stderr: stderr_writer,
+        };
+        let mut running = Running {
+            children: Vec::new(),
+            stdout: Some(stdout_reader),
+            stderr: Some(stderr_reader),
+            input: None,
+            terminal: None,
+            status: None,
+            grouped: self.grouped,
+        };
+
+        /* Where the output of the commands goes if it isn't relayed */
+        let mut terminal = None;
+        let mut stdin = match input {
+            Input::Null => Target::Null,
+            Input::Terminal => {
+                terminal = Some(Target::Terminal);
+                Target::Terminal
+            }
+            Input::Pipe => {
+                let (reader, writer) = io::pipe().map_err(ShellError::Pipe)?;
+                running.input = Some(writer);
+                Target::File(reader.into())
+            }
+            Input::Pty(size) => {
+                let (pty, slave) = Pty::open(size).map_err(ShellError::Pty)?;
+                running.terminal = Some(pty);
+                let stdin = Target::Pty(slave.try_clone().map_err(ShellError::Pipe)?);
+                terminal = Some(Target::Pty(slave));
+                stdin
+            }
+        };
+        let clone_terminal = |terminal: &Option<Target>| match terminal {
+            Some(terminal) => terminal.try_clone().map(Some).map_err(ShellError::Pipe),
+            None => Ok(None),
+        };
+        /* Descriptors redirected with `exec` stay redirected */
+        let exec_fds = |session: &Session, fd: usize| match &session.exec_fds[fd] {
+            Some(file) => file
+                .try_clone()
+                .map(|file| Some(Target::File(file)))
+                .map_err(ShellError::Pipe),
+            None => Ok(None),
+        };
+        if let Some(file) = exec_fds(self, 0)? {
+            stdin = file;
+        }
+
+        for (i, command) in pipeline.commands.iter().enumerate() {
+            let (next_stdin, stdout) = if i + 1 == pipeline.commands.len() {
+                let stdout = match exec_fds(self, 1)? {
+                    Some(file) => file,
+                    None => clone_terminal(&terminal)?.unwrap_or(Target::Stdout),
+                };
+                (Target::Null, stdout)
+            } else {
+                let (reader, writer) = io::pipe().map_err(ShellError::Pipe)?;
+                (Target::File(reader.into()), Target::File(writer.into()))
+            };
+
+            let stderr = match exec_fds(self, 2)? {
+                Some(file) => file,
+                None => clone_terminal(&terminal)?.unwrap_or(Target::Stderr),
+            };
+            let targets = [stdin, stdout, stderr];
+            running.status = match command {
+                parser::Command::Simple(command) => {
+                    self.start_simple(command, targets, &relay, &mut running.children)
+                }
+                parser::Command::Function(function) => {
+                    let body = Arc::new(function.body.clone());
+                    self.functions.insert(function.name.clone(), body);
+                    Some(StatusCode::Success.code())
+                }
+                parser::Command::Group(group) => {
+                    Some(self.run_redirected(&group.redirects, targets, |session| {
+                        session.execute(&group.body).map(|()| session.last_status)
+                    }))
+                }
+                parser::Command::Subshell(group) => {
+                    Some(self.run_redirected(&group.redirects, targets, |session| {
+                        session.subshell(&group.body)
+                    }))
+                }
+                parser::Command::If(parser::If { redirects, .. })
+                | parser::Command::For(parser::For { redirects, .. })
+                | parser::Command::While(parser::While { redirects, .. }) => {
+                    Some(self.run_redirected(redirects, targets, |session| {
+                        session.run_compound(command)
+                    }))
+                }
+            };
+            stdin = next_stdin;
+        }
+
+        /* Only the started processes keep the relay pipes open */
+        drop(relay);
+        Ok(running)
+    }
+
+    /// Start a simple command with its standard input, output and errors
+    /// going to `targets`. Returns the exit status if the command completed
+    /// without starting a process.
+    fn start_simple(
+        &mut self,
+        command: &SimpleCommand,
+        mut targets: [Target; 3],
+        relay: &Relay,
+        children: &mut Vec<Child>,
+    ) -> Option<i32> {
+        /* Process substitutions start first, and the command gets the paths
+        of their pipes in their place */
+        let mut pipes = Vec::new();
+        let substituted;
+        let command = match self.substitute_processes(command, &mut pipes, relay, children) {
+            Ok(Some(command)) => {
+                substituted = command;
+                &substituted
+            }
+            Ok(None) => command,
+            Err(error) => {
+                self.report(&error);
+                return Some(error.exit_code());
+            }
+        };
+        let expanded = self.expand_words(&command.words).and_then(|words| {
+            let assignments = command
+                .assignments
+                .iter()
+                .map(|assignment| {
+                    let value = self.expand_word(&assignment.value)?;
+                    Ok((assignment.name.clone(), value))
+                })
+                .collect::<Result<Vec<(String, String)>, ShellError>>()?;
+            Ok((words, assignments))
+        });
+        let (words, assignments) = match expanded {
+            Ok(expanded) => expanded,
+            Err(error) => {
+                self.report(&error);
+                return Some(error.exit_code());
+            }
+        };
+        if self.options.contains(ShellOptions::XTRACE) {
+            self.trace(&assignments, &words);
+        }
+        if let (Some(_), Some(redirect)) = (&self.settings().restricted, command.redirects.first())
+        {
+            let error = ShellError::Restricted(redirect.to_string());
+            self.report(&error);
+            return Some(error.exit_code());
+        }
+        for redirect in &command.redirects {
+            if let Err(error) = self.redirect(redirect, &mut targets) {
+                self.report(&error);
+                return Some(error.exit_code());
+            }
+        }
+        let stdin_redirected = command
+            .redirects
+            .iter()
+            .any(|redirect| redirect.fd.unwrap_or(redirect.kind.default_fd()) == 0);
+
+        let args: Vec<&str> = words.iter().map(String::as_str).collect();
+        if let Err(error) = self.check_assignments(&assignments, !args.is_empty()) {
+            self.report(&error);
+            return Some(error.exit_code());
+        }
+        let name = match args.first() {
+            Some(name) => *name,
+            None => {
+                /* Without a command, assignments set shell variables */
+                for (name, value) in &assignments {
+                    self.assign(name, value);
+                }
+                return Some(0);
+            }
+        };
+
+        /* Functions and builtins run inside the shell itself. Functions come
+        first, so they can stand in for builtins. */
+        if let Some(body) = self.functions.get(name).cloned() {
+            return Some(self.with_assignments(&assignments, |session| {
+                session.run_inside(
+                    stdin_redirected,
+                    |session| session.call(&body, &args),
+                    targets,
+                )
+            }));
+        }
+        if let Err(error) = self.check_restricted(name) {
+            self.report(&error);
+            return Some(error.exit_code());
+        }
+        if let Some(builtin) = self.custom_builtins.get(name).cloned() {
+            return Some(self.with_assignments(&assignments, |session| {
+                session.run_inside(stdin_redirected, |session| builtin(session, &args), targets)
+            }));
+        }
+        /* In a subshell, `exec` only replaces the subshell. The program runs
+        as a child, and the subshell ends with it. */
+        let replaces_subshell = name == "exec" && args.len() > 1 && self.subshells > 0;
+        let (name, args) = match replaces_subshell {
+            true => (args[1], &args[1..]),
+            false => (name, &args[..]),
+        };
+        if replaces_subshell {
+            self.exit_requested = true;
+            if let Err(error) = self.check_restricted(name) {
+                self.report(&error);
+                return Some(error.exit_code());
+            }
+        }
+        /* `exec` keeps its redirections or hands them to the program
+        replacing the shell, rather than having its output collected */
+        if name == "exec" && !replaces_subshell {
+            let redirected = command
+                .redirects
+                .iter()
+                .map(|redirect| redirect.fd.unwrap_or(redirect.kind.default_fd()) as usize)
+                .collect::<Vec<usize>>();
+            let mut redirects = Vec::new();
+            for (fd, target) in targets.into_iter().enumerate() {
+                if redirected.contains(&fd) {
+                    match target {
+                        Target::File(file) => redirects.push((fd, Some(file))),
+                        _ => redirects.push((fd, None)),
+                    }
+                }
+            }
+            return Some(self.exec(args, assignments, redirects));
+        }
+        if let Some(builtin) = builtins::find(name).filter(|_| !replaces_subshell) {
+            return Some(self.with_assignments(&assignments, |session| {
+                session.run_inside(stdin_redirected, |session| builtin(session, args), targets)
+            }));
+        }
+
+        let path = match self.find_program(name) {
+            Ok(path) => path,
+            Err(error) => {
+                self.report(&error);
+                return Some(error.exit_code());
+            }
+        };
+
+        let mut process = process::Command::new(path);
+        process
+            .args(&args[1..])
+            .current_dir(&self.cwd)
+            .envs(self.exported())
+            .envs(assignments);
+        inherit(&mut process, self.umask, &self.limits);
+        pass_fds(&mut process, &pipes);
+        match spawn(process, targets, relay, self.grouped) {
+            Ok(child) => {
+                children.push(child);
+                None
+            }
+            Err(error) => {
+                let error = ShellError::Exec {
+                    program: name.to_owned(),
+                    error,
+                };
+                self.report(&error);
+                Some(error.exit_code())
+            }
+        }
+    }
+
+    /// Start the process substitutions of a command. Returns the command
+    /// with the paths of their pipes in their place, or None if it has none.
+    /// The ends of the pipes the command uses are added to `pipes`.
+    fn substitute_processes(
+        &mut self,
+        command: &SimpleCommand,
+        pipes: &mut Vec<OwnedFd>,
+        relay: &Relay,
+        children: &mut Vec<Child>,
+    ) -> Result<Option<SimpleCommand>, ShellError> {
+        let substitutes = |word: &Word| {
+            word.parts
+                .iter()
+                .any(|part| matches!(part, WordPart::ProcessSubstitution { .. }))
+        };
+        let targets = command.redirects.iter().map(|redirect| &redirect.target);
+        if !command.words.iter().chain(targets).any(substitutes) {
+            return Ok(None);
+        }
+
+        let mut command = command.clone();
+        let targets = command
+            .redirects
+            .iter_mut()
+            .map(|redirect| &mut redirect.target);
+        for word in command.words.iter_mut().chain(targets) {
+            for part in &mut word.parts {
+                if let WordPart::ProcessSubstitution { commands, writable } = part {
+                    let pipe = self.start_substitution(commands, *writable, relay, children)?;
+                    *part = WordPart::SingleQuoted(format!("/dev/fd/{}", pipe.as_raw_fd()));
+                    pipes.push(pipe);
+                }
+            }
+        }
+        Ok(Some(command))
+    }
+
+    /// Start the pipeline of a process substitution with its output on a
+    /// pipe, or its input if `writable`, and return the other end of the
+    /// pipe. Its processes are waited for along with the command.
+    fn start_substitution(
+        &mut self,
+        commands: &Ast,
+        writable: bool,
+        relay: &Relay,
+        children: &mut Vec<Child>,
+    ) -> Result<OwnedFd, ShellError> {
+        let pipeline = match &commands.items[..] {
+            [item] if !item.background && item.and_or.rest.is_empty() => &item.and_or.first,
+            _ if !writable => return self.substitute_list(commands),
+            /* A list would have to run along with the command writing to
+            it, which only processes can */
+            _ => {
+                return Err(ShellError::Parse(String::from(
+                    "only pipelines can be written to with >(...)",
+                )))
+            }
+        };
+        let (reader, writer) = io::pipe().map_err(ShellError::Pipe)?;
+        let (fd, given, kept) = match writable {
+            true => (0, OwnedFd::from(reader), OwnedFd::from(writer)),
+            false => (1, OwnedFd::from(writer), OwnedFd::from(reader)),
+        };
+        /* The pipe stands in for the transport, as after `exec >FILE` */
+        let saved = self.exec_fds[fd].replace(given);
+        let started = self.start_pipeline(pipeline, Input::Null);
+        self.exec_fds[fd] = saved;
+        let mut running = started?;
+
+        /* What doesn't go to the pipe is relayed with the output of the
+        command */
+        let stdout = relay.stdout.try_clone().map_err(ShellError::Pipe)?;
+        let stderr = relay.stderr.try_clone().map_err(ShellError::Pipe)?;
+        let relay = Arc::new((stdout, stderr));
+        running.relay_output(move |chunk| match chunk {
+            Chunk::Stdout(data) => (&relay.0).write_all(&data).is_ok(),
+            Chunk::Stderr(data) => (&relay.1).write_all(&data).is_ok(),
+        });
+        children.append(&mut running.children);
+        Ok(kept)
+    }
+
+    /// Run the list of a process substitution in a subshell and return a pipe
+    /// with its output. Like other compound commands, it runs before the
+    /// command using it, so its output is collected and written to the pipe
+    /// as the command reads it.
+    fn substitute_list(&mut self, commands: &Ast) -> Result<OwnedFd, ShellError> {
+        let (reader, writer) = io::pipe().map_err(ShellError::Pipe)?;
+        let status = self.last_status;
+        /* The output goes to the pipe even after `exec >FILE` */
+        let saved = self.exec_fds[1].take();
+        self.run_builtin(
+            |session| match session.subshell(commands) {
+                Ok(status) => status,
+                Err(error) => {
+                    eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+                    StatusCode::Failure.code()
+                }
+            },
+            [Target::Null, Target::File(writer.into()), Target::Stderr],
+        );
+        self.exec_fds[1] = saved;
+        self.last_status = status;
+        Ok(reader.into())
+    }
+
+    /// Run `exec` with the descriptors it redirected, to a file or back to
+    /// the transport. With a command, the shell process is replaced by it,
+    /// its standard input, output and errors on the transport of the session
+    /// unless redirected. Without, the redirections stay for the commands
+    /// that follow and the assignments set shell variables. Returns only if
+    /// the shell wasn't replaced.
+    pub(crate) fn exec(
+        &mut self,
+        args: &[&str],
+        assignments: Vec<(String, String)>,
+        redirects: Vec<(usize, Option<OwnedFd>)>,
+    ) -> i32 {
+        let name = match args.get(1) {
+            Some(name) => *name,
+            None => {
+                for (fd, file) in redirects {
+                    self.exec_fds[fd] = file;
+                }
+                for (name, value) in &assignments {
+                    self.assign(name, value);
+                }
+                return StatusCode::Success.code();
+            }
+        };
+
+        if let Err(error) = self.check_restricted(name) {
+            self.report(&error);
+            return error.exit_code();
+        }
+        let path = match self.find_program(name) {
+            Ok(path) => path,
+            Err(error) => {
+                self.report(&error);
+                return error.exit_code();
+            }
+        };
+
+        let mut fds: [Option<OwnedFd>; 3] = Default::default();
+        for (fd, file) in fds.iter_mut().enumerate() {
+            *file = match &self.exec_fds[fd] {
+                Some(exec_fd) => exec_fd.try_clone().ok(),
+                None => None,
+            };
+        }
+        for (fd, file) in redirects {
+            fds[fd] = file;
+        }
+        let console = match self.hand_over() {
+            Ok(console) => console,
+            Err(error) => {
+                self.print_error(&format!("{}: exec: {}", SHELL_NAME, error));
+                return StatusCode::Failure.code();
+            }
+        };
+
+        let mut process = process::Command::new(path);
+        process
+            .arg0(name)
+            .args(&args[2..])
+            .current_dir(&self.cwd)
+            .envs(self.exported())
+            .envs(assignments);
+        inherit(&mut process, self.umask, &self.limits);
+        let [stdin, stdout, stderr] = fds.map(|fd| match (fd, &console) {
+            (Some(fd), _) => Ok(Stdio::from(fd)),
+            (None, Some(console)) => console.try_clone().map(Stdio::from),
+            (None, None) => Ok(Stdio::inherit()),
+        });
+        let error = match (stdin, stdout, stderr) {
+            (Ok(stdin), Ok(stdout), Ok(stderr)) => {
+                process.stdin(stdin).stdout(stdout).stderr(stderr).exec()
+            }
+            (Err(error), _, _) | (_, Err(error), _) | (_, _, Err(error)) => error,
+        };
+        let error = ShellError::Exec {
+            program: name.to_owned(),
+            error,
+        };
+        self.report(&error);
+        error.exit_code()
+    }
+
+    /// Run a function or builtin with the assignments before it passed to
+    /// the commands it starts, until it returns
+    fn with_assignments<F: FnOnce(&mut Session) -> i32>(
+        &mut self,
+        assignments: &[(String, String)],
+        run: F,
+    ) -> i32 {
+        let saved: Vec<(String, Option<String>)> = assignments
+            .iter()
+            .map(|(name, value)| (name.clone(), self.env.insert(name.clone(), value.clone())))
+            .collect();
+        let status = run(self);
+        for (name, value) in saved.into_iter().rev() {
+            match value {
+                Some(value) => self.env.insert(name, value),
+                None => self.env.remove(&name),
+            };
+        }
+        status
+    }
+
+    /// Fail if the session is restricted and `name` isn't one of the commands
+    /// it may run. Paths are never allowed, so only programs in PATH run.
+    fn check_restricted(&self, name: &str) -> Result<(), ShellError> {
+        match &self.settings().restricted {
+            Some(restricted)
+                if name.contains('/')
+                    || !restricted.commands.iter().any(|command| command == name) =>
+            {
+                Err(ShellError::Restricted(name.to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail if the session is restricted and the assignments could change
+    /// what the programs it runs do. Assignments before a command reach the
+    /// programs it starts, like `LD_PRELOAD=x ls`, so none are allowed.
+    /// Without a command, variables that steer programs can't be set, as
+    /// they may already be exported.
+    fn check_assignments(
+        &self,
+        assignments: &[(String, String)],
+        command: bool,
+    ) -> Result<(), ShellError> {
+        if self.settings().restricted.is_none() {
+            return Ok(());
+        }
+        let steers_programs = |name: &str| {
+            name.starts_with("LD_") || matches!(name, "PATH" | "ENV" | "BASH_ENV" | "SHELL")
+        };
+        match assignments
+            .iter()
+            .find(|(name, _)| command || steers_programs(name))
+        {
+            Some((name, _)) => Err(ShellError::Restricted(name.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Run the body of a function with `args` as its arguments, and return the
+    /// status of its last command
+    fn call(&mut self, body: &Ast, args: &[&str]) -> i32 {
+        if self.depth >= MAX_DEPTH {
+            self.print_error(&format!(
+                "{}: {}: maximum nesting depth of {} exceeded",
+                SHELL_NAME, args[0], MAX_DEPTH
+            ));
+            return StatusCode::Failure.code();
+        }
+
+        let arguments = args[1..].iter().map(|arg| arg.to_string()).collect();
+        let caller_arguments = std::mem::replace(&mut self.positional, arguments);
+        /* Loops of the caller can't be left from the function */
+        let caller_loops = std::mem::take(&mut self.loop_depth);
+        self.last_status = StatusCode::Success.code();
+        self.depth += 1;
+        self.function_depth += 1;
+        let result = self.execute(body);
+        self.function_depth -= 1;
+        self.depth -= 1;
+        self.positional = caller_arguments;
+        self.loop_depth = caller_loops;
+        self.returning = false;
+
+        match result {
+            Ok(()) => self.last_status,
+            Err(error) => {
+                eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+                StatusCode::Failure.code()
+            }
+        }
+    }
+
+    /// Run a compound command, e.g. a group or a loop, and return its exit
+    /// status. Its redirections last while the commands in it run, as those
+    /// of `exec` do.
+    fn run_redirected<F>(&mut self, redirects: &[Redirect], mut targets: [Target; 3], run: F) -> i32
+    where
+        F: FnOnce(&mut Session) -> io::Result<i32>,
+    {
+        if let (Some(_), Some(redirect)) = (&self.settings().restricted, redirects.first()) {
+            let error = ShellError::Restricted(redirect.to_string());
+            self.report(&error);
+            return error.exit_code();
+        }
+        for redirect in redirects {
+            if let Err(error) = self.redirect(redirect, &mut targets) {
+                self.report(&error);
+                return error.exit_code();
+            }
+        }
+
+        let mut saved = Vec::new();
+        for redirect in redirects {
+            let fd = redirect.fd.unwrap_or(redirect.kind.default_fd()) as usize;
+            if saved.iter().any(|(saved, _)| *saved == fd) {
+                continue;
+            }
+            let placeholder = match fd {
+                0 => Target::Null,
+                1 => Target::Stdout,
+                _ => Target::Stderr,
+            };
+            let file = match std::mem::replace(&mut targets[fd], placeholder) {
+                Target::File(file) => Some(file),
+                _ => None,
+            };
+            saved.push((fd, std::mem::replace(&mut self.exec_fds[fd], file)));
+        }
+
+        let status = self.run_builtin(
+            |session| match run(session) {
+                Ok(status) => status,
+                Err(error) => {
+                    eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+                    StatusCode::Failure.code()
+                }
+            },
+            targets,
+        );
+        for (fd, file) in saved {
+            self.exec_fds[fd] = file;
+        }
+        status
+    }
+
+    /// Run commands in a subshell. Sessions share the shell process, so
+    /// rather than forking, what the commands can change is restored after
+    /// them: directories, variables, functions, options, limits, traps and
+    /// the redirections of `exec`. `exit` only leaves the subshell, and its
+    /// `EXIT` trap runs as it ends.
+    fn subshell(&mut self, body: &Ast) -> io::Result<i32> {
+        let saved = SubshellState::save(self);
+        /* Loops around the subshell can't be left from it */
+        let loops = std::mem::take(&mut self.loop_depth);
+        self.subshells += 1;
+        let result = self.execute(body);
+        self.run_exit_trap();
+        self.subshells -= 1;
+        self.loop_depth = loops;
+        saved.restore(self);
+        self.exit_requested = false;
+        self.returning = false;
+        self.jump = None;
+        result.map(|()| self.last_status)
+    }
+
+    /// Run an `if`, `for` or `while` command and return its exit status
+    fn run_compound(&mut self, command: &parser::Command) -> io::Result<i32> {
+        match command {
+            parser::Command::If(command) => {
+                for (condition, body) in &command.branches {
+                    self.run_condition(condition)?;
+                    if self.stopped() {
+                        return Ok(self.last_status);
+                    }
+                    if self.last_status == 0 {
+                        self.execute(body)?;
+                        return Ok(self.last_status);
+                    }
+                }
+                match &command.otherwise {
+                    Some(otherwise) => self.execute(otherwise)?,
+                    None => self.last_status = StatusCode::Success.code(),
+                }
+                Ok(self.last_status)
+            }
+            parser::Command::For(command) => {
+                let values = match &command.words {
+                    Some(words) => match self.expand_words(words) {
+                        Ok(values) => values,
+                        Err(error) => {
+                            self.report(&error);
+                            return Ok(error.exit_code());
+                        }
+                    },
+                    None => self.positional.clone(),
+                };
+                let mut status = StatusCode::Success.code();
+                self.loop_depth += 1;
+                for value in values {
+                    self.assign(&command.name, &value);
+                    self.execute(&command.body)?;
+                    status = self.last_status;
+                    if !self.next_round() {
+                        break;
+                    }
+                }
+                self.loop_depth -= 1;
+                Ok(status)
+            }
+            parser::Command::While(command) => {
+                let mut status = StatusCode::Success.code();
+                self.loop_depth += 1;
+                loop {
+                    self.run_condition(&command.condition)?;
+                    if !self.next_round() || (self.last_status == 0) == command.until {
+                        break;
+                    }
+                    self.execute(&command.body)?;
+                    status = self.last_status;
+                    if !self.next_round() {
+                        break;
+                    }
+                }
+                self.loop_depth -= 1;
+                Ok(status)
+            }
+            parser::Command::Simple(_)
+            | parser::Command::Function(_)
+            | parser::Command::Group(_)
+            | parser::Command::Subshell(_) => {
+                unreachable!("not an if, for or while command")
+            }
+        }
+    }
+
+    /// Whether a loop goes on after running its commands, handling `break`
+    /// and `continue`
+    fn next_round(&mut self) -> bool {
+        match self.jump.take() {
+            None | Some(Jump::Continue(1)) => !self.stopped(),
+            Some(Jump::Break(1)) => false,
+            /* Outer loops are left too */
+            Some(Jump::Break(levels)) => {
+                self.jump = Some(Jump::Break(levels - 1));
+                false
+            }
+            Some(Jump::Continue(levels)) => {
+                self.jump = Some(Jump::Continue(levels - 1));
+                false
+            }
+        }
+    }
+
+    /// Find the program to run for a command name, using the cache for
+    /// programs in PATH
+    pub(crate) fn find_program(&mut self, name: &str) -> Result<PathBuf, ShellError> {
+        /* Restricted sessions can't change where programs come from */
+        let path_variable = match &self.settings().restricted {
+            Some(_) => self
+                .settings()
+                .env
+                .get("PATH")
+                .cloned()
+                .or_else(|| env::var("PATH").ok()),
+            None => self.var("PATH"),
+        }
+        .unwrap_or_default();
+        if path_variable != self.path_cache.path_variable {
+            self.path_cache = PathCache {
+                path_variable: path_variable.clone(),
+                programs: HashMap::new(),
+            };
+        }
+
+        /* Programs removed since they were found are searched for again */
+        if let Some(path) = self.path_cache.programs.get(name) {
+            if crate::is_executable(path) {
+                return Ok(path.clone());
+            }
+        }
+
+        let path = crate::find_binary(name, &self.cwd, &path_variable)?;
+        if !name.contains('/') {
+            self.path_cache
+                .programs
+                .insert(name.to_owned(), path.clone());
+        }
+        Ok(path)
+    }
+
+    /// Run a builtin with its output going to `targets`. Output to the
+    /// transport is written as usual, other output is collected and written
+    /// once the builtin returns. Errors always go to the transport.
+    fn run_builtin<F: FnOnce(&mut Session) -> i32>(
+        &mut self,
+        builtin: F,
+        targets: [Target; 3],
+    ) -> i32 {
+        let [_, stdout, _] = targets;
+        if let Target::Stdout | Target::Stderr | Target::Terminal | Target::Pty(_) = stdout {
+            return builtin(self);
+        }
+
+        let previous = self.capture.replace(Vec::new());
+        let status = builtin(self);
+        let output = std::mem::replace(&mut self.capture, previous).unwrap_or_default();
+
+        if let Target::File(fd) = stdout {
+            /* The command reading a pipe is started after the builtin
+            returns, so writing to it must not block */
+            thread::spawn(move || {
+                let _ = File::from(fd).write_all(&output);
+            });
+        }
+        status
+    }
+
+    /// Run a function or builtin like `run_builtin`. Input redirected for it
+    /// stands in for the input redirected with `exec` while it runs, where
+    /// `read` and the commands of functions find it.
+    fn run_inside<F: FnOnce(&mut Session) -> i32>(
+        &mut self,
+        stdin_redirected: bool,
+        builtin: F,
+        mut targets: [Target; 3],
+    ) -> i32 {
+        if !stdin_redirected {
+            return self.run_builtin(builtin, targets);
+        }
+        let file = match std::mem::replace(&mut targets[0], Target::Null) {
+            Target::File(file) => Some(file),
+            _ => None,
+        };
+        let saved = std::mem::replace(&mut self.exec_fds[0], file);
+        let status = self.run_builtin(builtin, targets);
+        self.exec_fds[0] = saved;
+        status
+    }
+
+    fn redirect(&self, redirect: &Redirect, targets: &mut [Target; 3]) -> Result<(), ShellError> {
+        let target = self.expand_word(&redirect.target)?;
+        let bad_descriptor = |fd: String| ShellError::Redirect {
+            target: fd,
+            /* EBADF */
+            error: io::Error::from_raw_os_error(9),
+        };
+
+        /* Only standard input, output and errors can be redirected */
+        let fd = redirect.fd.unwrap_or(redirect.kind.default_fd());
+        if fd > 2 {
+            return Err(bad_descriptor(fd.to_string()));
+        }
+        let fd = fd as usize;
+
+        let path = self.cwd.join(&target);
+        let file = match redirect.kind {
+            RedirectKind::Input => File::open(path),
+            /* The mask of the session is on top of the one of the shell
+            process, which applies to every file it creates */
+            RedirectKind::Output => OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o666 & !self.umask.unwrap_or(0))
+                .open(path),
+            RedirectKind::Append => OpenOptions::new()
+                .append(true)
+                .create(true)
+                .mode(0o666 & !self.umask.unwrap_or(0))
+                .open(path),
+            RedirectKind::DuplicateInput | RedirectKind::DuplicateOutput => {
+                let source = match target.parse::<usize>() {
+                    Ok(source) if source <= 2 => source,
+                    _ => return Err(bad_descriptor(target)),
+                };
+                targets[fd] = targets[source]
+                    .try_clone()
+                    .map_err(|error| ShellError::Redirect { target, error })?;
+                return Ok(());
+            }
+            /* The text is written to a pipe in a thread, so a long one
+            doesn't block until the command reads it */
+            RedirectKind::HereDocument | RedirectKind::HereString => {
+                let mut text = target;
+                if redirect.kind == RedirectKind::HereString {
+                    text.push('\n');
+                }
+                let (reader, writer) = io::pipe().map_err(ShellError::Pipe)?;
+                thread::spawn(move || {
+                    let _ = File::from(OwnedFd::from(writer)).write_all(text.as_bytes());
+                });
+                targets[fd] = Target::File(reader.into());
+                return Ok(());
+            }
+        };
+
+        match file {
+            Ok(file) => {
+                targets[fd] = Target::File(file.into());
+                Ok(())
+            }
+            Err(error) => Err(ShellError::Redirect { target, error }),
+        }
+    }
+
+    /// Expand the words of a command into its arguments, braces first.
+    /// The results of unquoted expansions are split into fields at the
+    /// characters of `IFS`, and unquoted words that expand to nothing are
+    /// left out.
+    pub(crate) fn expand_words(&self, words: &[Word]) -> Result<Vec<String>, ShellError> {
+        let ifs = self
+            .var("IFS")
+            .unwrap_or_else(|| fields::DEFAULT_IFS.to_owned());
+        let mut expanded = Vec::new();
+        for word in &words.iter().flat_map(braces::expand).collect::<Vec<Word>>() {
+            /* `$@` alone, quoted or not, gives every argument as a word of
+            its own */
+            if is_all_arguments(word) {
+                expanded.extend(self.positional.iter().cloned());
+                continue;
+            }
+            let mut pieces = Vec::new();
+            for part in &word.parts {
+                let mut text = String::new();
+                self.expand_parts(std::slice::from_ref(part), &mut text)?;
+                pieces.push((text, matches!(part, WordPart::Parameter(_))));
+            }
+            expanded.extend(fields::split(&pieces, &ifs));
+        }
+        Ok(expanded)
+    }
+
+    /// Expand the parameters and `~` of a word. Fails for parameters that
+    /// aren't set with `set -u`.
+    pub(crate) fn expand_word(&self, word: &Word) -> Result<String, ShellError> {
+        let mut text = String::new();
+        self.expand_parts(&word.parts, &mut text)?;
+        Ok(text)
+    }
+
+    fn expand_parts(&self, parts: &[WordPart], text: &mut String) -> Result<(), ShellError> {
+        for part in parts {
+            match part {
+                WordPart::Literal(literal) | WordPart::SingleQuoted(literal) => {
+                    text.push_str(literal)
+                }
+                WordPart::DoubleQuoted(parts) => self.expand_parts(parts, text)?,
+                WordPart::Parameter(name) => match self.parameter(name) {
+                    Some(value) => text.push_str(&value),
+                    None if self.options.contains(ShellOptions::NOUNSET) => {
+                        return Err(ShellError::Unbound(name.clone()))
+                    }
+                    None => {}
+                },
+                WordPart::Tilde(user) => text.push_str(&self.home_dir(user)),
+                WordPart::ProcessSubstitution { .. } => {
+                    return Err(ShellError::Parse(String::from(
+                        "process substitution only works in arguments and redirections",
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The value of a parameter, or `None` if it is not set
+    fn parameter(&self, name: &str) -> Option<String> {
+        /* `${SIZE@human}` writes a size in bytes for humans and
+        `${SIZE@bytes}` reads it back. Values that aren't sizes are kept. */
+        if let Some((name, transformation)) =
+            name.split_once('@').filter(|(name, _)| !name.is_empty())
+        {
+            let value = self.parameter(name)?;
+            let transformed = match transformation {
+                "human" => value
+                    .parse()
+                    .ok()
+                    .map(|bytes| size::format(bytes, Units::Iec)),
+                _ => size::parse(&value, Units::Iec).map(|bytes| bytes.to_string()),
+            };
+            return Some(transformed.unwrap_or(value));
+        }
+
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "$" => Some(process::id().to_string()),
+            "!" => self.last_job_pid.map(|pid| pid.to_string()),
+            "-" => Some(self.options.letters()),
+            "0" => Some(
+                self.script_name
+                    .clone()
+                    .unwrap_or_else(|| SHELL_NAME.to_owned()),
+            ),
+            "#" => Some(self.positional.len().to_string()),
+            "@" => Some(self.positional.join(" ")),
+            /* Joined by the first character of IFS, as `"$*"` is */
+            "*" => {
+                let separator = match self.var("IFS") {
+                    Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+                    None => String::from(" "),
+                };
+                Some(self.positional.join(&separator))
+            }
+            _ if name.chars().all(|c| c.is_ascii_digit()) => name
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| self.positional.get(n.checked_sub(1)?))
+                .cloned(),
+            _ => self.var(name),
+        }
+    }
+
+    /// Show a command about to run with `set -x`. Like errors, the trace
+    /// goes to the transport even when the output of the command doesn't.
+    fn trace(&mut self, assignments: &[(String, String)], words: &[String]) {
+        let assignments = assignments
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, quote(value)));
+        let words = words.iter().map(|word| quote(word));
+        let line: Vec<String> = assignments.chain(words).collect();
+
+        let capture = self.capture.take();
+        if let Err(error) = self.write_output(format!("+ {}\n", line.join(" ")).as_bytes()) {
+            eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+        }
+        self.capture = capture;
+    }
+
+    /// Home directory of a user, or of the session for an empty name. Unknown
+    /// users are left as they were written.
+    fn home_dir(&self, user: &str) -> String {
+        if user.is_empty() {
+            return self.var("HOME").unwrap_or_else(|| String::from("~"));
+        }
+
+        /* Other users are looked up in the password database */
+        let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+        passwd
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<&str>>())
+            .find(|fields| fields.first() == Some(&user))
+            .and_then(|fields| fields.get(5).map(|home| home.to_string()))
+            .unwrap_or_else(|| format!("~{}", user))
+    }
+}
+
+/// Quote a word for the shell, if it needs quotes to be read back as one
+pub(crate) fn quote(word: &str) -> String {
+    let plain = |c: char| c.is_alphanumeric() || "_-+=.,:/@%^".contains(c);
+    match !word.is_empty() && word.chars().all(plain) {
+        true => word.to_owned(),
+        false => format!("'{}'", word.replace('\'', "'\\''")),
+    }
+}
+
+/// Whether a word is `$@` or `"$@"`
+fn is_all_arguments(word: &Word) -> bool {
+    match &word.parts[..] {
+        [WordPart::Parameter(name)] => name == "@",
+        [WordPart::DoubleQuoted(parts)] => {
+            matches!(&parts[..], [WordPart::Parameter(name)] if name == "@")
+        }
+        _ => false,
+    }
+}
+
+/// What commands in a subshell can change of the session
+struct SubshellState {
+    cwd: PathBuf,
+    previous_dir: Option<PathBuf>,
+    dir_stack: Vec<PathBuf>,
+    env: HashMap<String, String>,
+    vars: HashMap<String, String>,
+    functions: HashMap<String, Arc<Ast>>,
+    positional: Vec<String>,
+    options: ShellOptions,
+    script_timeout: Option<Duration>,
+    umask: Option<u32>,
+    limits: Vec<Limit>,
+    exec_fds: [Option<OwnedFd>; 3],
+    traps: Traps,
+}
+
+impl SubshellState {
+    /// Save the state of the session. Traps are taken rather than copied,
+    /// as subshells start without them.
+    fn save(session: &mut Session) -> SubshellState {
+        SubshellState {
+            cwd: session.cwd.clone(),
+            previous_dir: session.previous_dir.clone(),
+            dir_stack: session.dir_stack.clone(),
+            env: session.env.clone(),
+            vars: session.vars.clone(),
+            functions: session.functions.clone(),
+            positional: session.positional.clone(),
+            options: session.options,
+            script_timeout: session.script_timeout,
+            umask: session.umask,
+            limits: session.limits.clone(),
+            exec_fds: session
+                .exec_fds
+                .each_ref()
+                .map(|fd| fd.as_ref().and_then(|fd| fd.try_clone().ok())),
+            traps: std::mem::take(&mut session.traps),
+        }
+    }
+
+    fn restore(self, session: &mut Session) {
+        session.cwd = self.cwd;
+        session.previous_dir = self.previous_dir;
+        session.dir_stack = self.dir_stack;
+        session.env = self.env;
+        session.vars = self.vars;
+        session.functions = self.functions;
+        session.positional = self.positional;
+        session.options = self.options;
+        session.script_timeout = self.script_timeout;
+        session.umask = self.umask;
+        session.limits = self.limits;
+        session.exec_fds = self.exec_fds;
+        session.traps = self.traps;
+    }
+}
+
+/// Give a program started by a session the file mode creation mask and
+/// resource limits of the session
+fn inherit(process: &mut process::Command, umask: Option<u32>, limits: &[Limit]) {
+    if let Some(mask) = umask {
+        unsafe {
+            process.pre_exec(move || {
+                libc::umask(mask as libc::mode_t);
+                Ok(())
+            })
+        };
+    }
+    if !limits.is_empty() {
+        let limits = limits.to_vec();
+        unsafe { process.pre_exec(move || limits::apply(&limits)) };
+    }
+}
+
+/// Let a program inherit the pipes of its process substitutions, at the
+/// descriptors in their paths
+fn pass_fds(process: &mut process::Command, pipes: &[OwnedFd]) {
+    if pipes.is_empty() {
+        return;
+    }
+    let fds: Vec<RawFd> = pipes.iter().map(AsRawFd::as_raw_fd).collect();
+    unsafe {
+        process.pre_exec(move || {
+            for &fd in &fds {
+                if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        })
+    };
+}
+
+fn spawn(
+    mut process: process::Command,
+    targets: [Target; 3],
+    relay: &Relay,
+    grouped: bool,
+) -> io::Result<Child> {
+    /* Commands on a pseudo-terminal get it as their controlling terminal,
+    so it can interrupt them and they can open /dev/tty */
+    let terminal = targets
+        .iter()
+        .position(|target| matches!(target, Target::Pty(_)));
+    if let Some(fd) = terminal {
+        unsafe { process.pre_exec(move || pty::attach(fd as i32)) };
+    }
+    /* A process group left in the background of the shell's terminal would
+    be stopped when it reads from it */
+    let on_terminal = targets
+        .iter()
+        .any(|target| matches!(target, Target::Terminal));
+    if grouped && terminal.is_none() && !on_terminal {
+        process.process_group(0);
+    }
+
+    let [stdin, stdout, stderr] = targets;
+    process
+        .stdin(relay.stdio(stdin)?)
+        .stdout(relay.stdio(stdout)?)
+        .stderr(relay.stdio(stderr)?)
+        .spawn()
+}
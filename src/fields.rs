@@ -0,0 +1,93 @@
+//! Field splitting, which turns the results of unquoted expansions into
+//! several words at the characters of `IFS`, so `FILES="a b"; ls $FILES`
+//! lists two files and `IFS=:; echo $PATH` prints each directory.
+
+/// `IFS` when it isn't set
+pub(crate) const DEFAULT_IFS: &str = " \t\n";
+
+/// The fields of an expanded word, given as pieces of text and whether each
+/// came from an unquoted expansion. Only those are split. Runs of `IFS`
+/// whitespace separate fields and are dropped at the ends, while each other
+/// `IFS` character ends a field, empty or not. A word giving no text that
+/// wasn't quoted gives no field.
+pub(crate) fn split(pieces: &[(String, bool)], ifs: &str) -> Vec<String> {
+    let is_space = |c: char| ifs.contains(c) && c.is_whitespace();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    /* Whether there is a field to end, which may be empty if quoted */
+    let mut started = false;
+
+    for (text, unquoted) in pieces {
+        if !unquoted || ifs.is_empty() {
+            field.push_str(text);
+            started |= !unquoted || !text.is_empty();
+            continue;
+        }
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if !ifs.contains(c) {
+                field.push(c);
+                started = true;
+                continue;
+            }
+            /* Whitespace around a delimiter that isn't belongs to it */
+            let mut delimits = !is_space(c);
+            while chars.next_if(|&c| is_space(c)).is_some() {}
+            if !delimits && chars.next_if(|&c| ifs.contains(c)).is_some() {
+                delimits = true;
+                while chars.next_if(|&c| is_space(c)).is_some() {}
+            }
+            if started || delimits {
+                fields.push(std::mem::take(&mut field));
+                started = false;
+            }
+        }
+    }
+    if started {
+        fields.push(field);
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unquoted(text: &str, ifs: &str) -> Vec<String> {
+        split(&[(text.to_owned(), true)], ifs)
+    }
+
+    #[test]
+    fn splits_at_whitespace() {
+        assert_eq!(unquoted("a b\t\nc", DEFAULT_IFS), ["a", "b", "c"]);
+        assert_eq!(unquoted("  a  ", DEFAULT_IFS), ["a"]);
+        assert!(unquoted("", DEFAULT_IFS).is_empty());
+        assert!(unquoted(" \t ", DEFAULT_IFS).is_empty());
+    }
+
+    #[test]
+    fn ends_a_field_at_each_other_character() {
+        assert_eq!(unquoted("a::b", ":"), ["a", "", "b"]);
+        assert_eq!(unquoted(":a", ":"), ["", "a"]);
+        assert_eq!(unquoted("a:", ":"), ["a"]);
+        assert_eq!(unquoted("a : b", ": "), ["a", "b"]);
+        assert_eq!(unquoted("a :: b", ": "), ["a", "", "b"]);
+        assert_eq!(unquoted(" : a", ": "), ["", "a"]);
+    }
+
+    #[test]
+    fn keeps_quoted_text_together() {
+        let pieces = [(String::from("pre"), false), (String::from(" x y"), true)];
+        assert_eq!(split(&pieces, DEFAULT_IFS), ["pre", "x", "y"]);
+        let pieces = [(String::from("a "), true), (String::new(), false)];
+        assert_eq!(split(&pieces, " "), ["a", ""]);
+        assert_eq!(split(&[(String::from("a b"), false)], DEFAULT_IFS), ["a b"]);
+        assert_eq!(split(&[(String::new(), false)], DEFAULT_IFS), [""]);
+    }
+
+    #[test]
+    fn splits_nothing_with_an_empty_ifs() {
+        assert_eq!(unquoted("a b", ""), ["a b"]);
+        assert!(unquoted("", "").is_empty());
+    }
+}
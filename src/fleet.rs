@@ -0,0 +1,233 @@
+//! Fleet mode, for classrooms and clusters of Pis: run one command on
+//! several machines running pieshell, attached over serial or reachable over
+//! TCP, and show the results together.
+//!
+//! ```text
+//! pieshell --fleet /dev/ttyUSB0,/dev/ttyUSB1,pi3.local:2323 -c 'uname -a'
+//! ```
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::pty;
+use crate::transport::Serial;
+use crate::{ExitStatus, StatusCode, SHELL_NAME};
+
+/// Time a machine gets to run the command and report back
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a read waits before the deadline is checked again
+const READ_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Start of the lines printed around the output of the command. The rest of
+/// them is sent quoted, so the echo of the command line doesn't match.
+const MARKER: &str = "__pieshell_fleet_";
+
+const IAC: u8 = 255;
+
+/// A link to a machine of the fleet
+enum Connection {
+    Serial(Serial),
+    Tcp(TcpStream),
+}
+
+impl Connection {
+    /// Connect to a serial device given by path, or a TCP address
+    fn open(endpoint: &str, baud: u32) -> io::Result<Connection> {
+        if endpoint.starts_with('/') {
+            let serial = Serial::open(Path::new(endpoint), baud, READ_INTERVAL)?;
+            Ok(Connection::Serial(serial))
+        } else {
+            let stream = TcpStream::connect(endpoint)?;
+            stream.set_read_timeout(Some(READ_INTERVAL))?;
+            Ok(Connection::Tcp(stream))
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Connection::Serial(serial) => serial.write_all(data),
+            Connection::Tcp(stream) => stream.write_all(data),
+        }
+    }
+
+    /// Read what has arrived. Returns an empty buffer if nothing arrived in
+    /// time and `None` when the connection was closed.
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = [0u8; 1024];
+        let length = match self {
+            Connection::Serial(serial) => serial.read(&mut buf)?,
+            Connection::Tcp(stream) => match stream.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(length) => length,
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    0
+                }
+                Err(error) => return Err(error),
+            },
+        };
+        Ok(Some(buf[..length].to_vec()))
+    }
+}
+
+/// What a machine reported
+struct Outcome {
+    status: i32,
+    output: Vec<String>,
+}
+
+/// Run `command` on every endpoint at the same time and print the results.
+/// The shell exits successfully if the command succeeded everywhere.
+pub fn run(endpoints: &[String], command: &str, baud: u32) -> ExitStatus {
+    let threads: Vec<_> = endpoints
+        .iter()
+        .map(|endpoint| {
+            let endpoint = endpoint.clone();
+            let command = command.to_owned();
+            thread::spawn(move || run_on(&endpoint, &command, baud))
+        })
+        .collect();
+
+    let mut succeeded = 0;
+    for (endpoint, thread) in endpoints.iter().zip(threads) {
+        let result = thread
+            .join()
+            .unwrap_or_else(|_| Err(String::from("panicked")));
+        match result {
+            Ok(outcome) => {
+                println!("[{}] exit {}", endpoint, outcome.status);
+                for line in &outcome.output {
+                    println!("{}", line);
+                }
+                if outcome.status == 0 {
+                    succeeded += 1;
+                }
+            }
+            Err(error) => println!("[{}] {}: {}", endpoint, SHELL_NAME, error),
+        }
+    }
+
+    println!("{} of {} succeeded", succeeded, endpoints.len());
+    match succeeded == endpoints.len() {
+        true => ExitStatus::SUCCESS,
+        false => ExitStatus(StatusCode::Failure.code()),
+    }
+}
+
+fn run_on(endpoint: &str, command: &str, baud: u32) -> Result<Outcome, String> {
+    let mut connection = Connection::open(endpoint, baud).map_err(|error| error.to_string())?;
+    let deadline = Instant::now() + TIMEOUT;
+    let mut received = Vec::new();
+
+    /* New sessions drop what arrives before they are ready, so over TCP the
+    command waits for the banner or the prompt */
+    if matches!(connection, Connection::Tcp(_)) {
+        while !session_started(&received) {
+            if Instant::now() >= deadline {
+                return Err(format!("timed out after {}s", TIMEOUT.as_secs()));
+            }
+            match connection.receive() {
+                Ok(Some(data)) => received.extend_from_slice(&data),
+                Ok(None) => return Err(String::from("connection closed")),
+                Err(error) => return Err(error.to_string()),
+            }
+        }
+    }
+
+    /* Ctrl-C drops whatever was typed on the machine before */
+    let line = format!(
+        "\u{3}echo {}\"start\"; {}; echo {}\"end\" $?\r",
+        MARKER, command, MARKER
+    );
+    connection
+        .send(line.as_bytes())
+        .map_err(|error| error.to_string())?;
+
+    while Instant::now() < deadline {
+        match connection.receive() {
+            Ok(Some(data)) => received.extend_from_slice(&data),
+            Ok(None) => return Err(String::from("connection closed")),
+            Err(error) => return Err(error.to_string()),
+        }
+        if let Some(outcome) = parse(&received) {
+            return Ok(outcome);
+        }
+    }
+    Err(format!("timed out after {}s", TIMEOUT.as_secs()))
+}
+
+/// Find the output of the command between the markers, once all of it
+/// arrived
+fn parse(received: &[u8]) -> Option<Outcome> {
+    let text = String::from_utf8_lossy(&strip_telnet(received)).into_owned();
+    let lines: Vec<&str> = text
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .collect();
+    let start_marker = format!("{}start", MARKER);
+    let end_marker = format!("{}end ", MARKER);
+    /* Without echo, the start marker follows the prompt */
+    let start = lines
+        .iter()
+        .position(|line| line.ends_with(&start_marker))?;
+    let end = start
+        + lines[start..]
+            .iter()
+            .position(|line| line.contains(&end_marker))?;
+
+    /* The status is only complete once its line ended */
+    if end + 1 == lines.len() && !text.ends_with('\n') {
+        return None;
+    }
+    let mut output: Vec<String> = lines[start + 1..end]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+    /* Output that didn't end with a newline precedes the end marker */
+    let (last, status) = lines[end].split_once(&end_marker)?;
+    if !last.is_empty() {
+        output.push(last.to_owned());
+    }
+    Some(Outcome {
+        status: status.trim().parse().ok()?,
+        output,
+    })
+}
+
+/// Whether a session sent more than the telnet negotiation and the query
+/// of the window size, which it sends before it is ready for input
+fn session_started(received: &[u8]) -> bool {
+    let text = strip_telnet(received);
+    let rest = text.strip_prefix(pty::SIZE_QUERY).unwrap_or(&text);
+    !rest.is_empty() && !pty::SIZE_QUERY.starts_with(rest)
+}
+
+/// Remove telnet commands from data received from a machine serving
+/// sessions with `--telnet`
+fn strip_telnet(data: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        match (data[i], data.get(i + 1)) {
+            (IAC, Some(&IAC)) => {
+                stripped.push(IAC);
+                i += 2;
+            }
+            /* WILL, WONT, DO and DONT are followed by an option */
+            (IAC, Some(251..=254)) => i += 3,
+            (IAC, _) => i += 2,
+            (byte, _) => {
+                stripped.push(byte);
+                i += 1;
+            }
+        }
+    }
+    stripped
+}
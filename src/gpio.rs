@@ -0,0 +1,102 @@
+//! The GPIO pins of the header, for the `gpio` builtin. Pins are numbered as
+//! by Broadcom, as in `raspi-gpio` and the pinout of the Pi. What is set
+//! stays set after the builtin returns.
+
+use std::io;
+
+use rppal::gpio::{Gpio, IoPin, Level, Mode};
+
+use crate::hardware;
+
+/// The pins on the 40-pin header
+pub(crate) const PINS: std::ops::RangeInclusive<u8> = 0..=27;
+
+/// Find a mode by the name `gpio mode` takes
+pub(crate) fn mode_by_name(name: &str) -> Option<Mode> {
+    let mode = match name {
+        "in" => Mode::Input,
+        "out" => Mode::Output,
+        "alt0" => Mode::Alt0,
+        "alt1" => Mode::Alt1,
+        "alt2" => Mode::Alt2,
+        "alt3" => Mode::Alt3,
+        "alt4" => Mode::Alt4,
+        "alt5" => Mode::Alt5,
+        _ => return None,
+    };
+    Some(mode)
+}
+
+fn mode_name(mode: Mode) -> &'static str {
+    match mode {
+        Mode::Input => "in",
+        Mode::Output => "out",
+        Mode::Alt0 => "alt0",
+        Mode::Alt1 => "alt1",
+        Mode::Alt2 => "alt2",
+        Mode::Alt3 => "alt3",
+        Mode::Alt4 => "alt4",
+        Mode::Alt5 => "alt5",
+    }
+}
+
+/// A pin whose mode can change, left as it is when dropped
+fn io_pin(pin: u8, mode: Mode) -> io::Result<IoPin> {
+    let gpio = Gpio::new().map_err(hardware::gpio_error)?;
+    let mut pin = gpio.get(pin).map_err(hardware::gpio_error)?.into_io(mode);
+    pin.set_reset_on_drop(false);
+    Ok(pin)
+}
+
+/// The level of a pin, whatever its mode
+pub(crate) fn read(pin: u8) -> io::Result<bool> {
+    let gpio = Gpio::new().map_err(hardware::gpio_error)?;
+    let pin = gpio.get(pin).map_err(hardware::gpio_error)?;
+    Ok(pin.read() == Level::High)
+}
+
+pub(crate) fn set_mode(pin: u8, mode: Mode) -> io::Result<()> {
+    io_pin(pin, mode).map(drop)
+}
+
+/// Drive a pin high or low, making it an output
+pub(crate) fn write(pin: u8, high: bool) -> io::Result<()> {
+    let mut pin = io_pin(pin, Mode::Output)?;
+    pin.write(match high {
+        true => Level::High,
+        false => Level::Low,
+    });
+    Ok(())
+}
+
+/// Drive a pin to the other level, making it an output. Returns the new
+/// level.
+pub(crate) fn toggle(pin: u8) -> io::Result<bool> {
+    let mut pin = io_pin(pin, Mode::Output)?;
+    pin.toggle();
+    Ok(pin.is_high())
+}
+
+/// The name of the mode and the level of every pin of the header, in order
+pub(crate) fn states() -> io::Result<Vec<(&'static str, u8)>> {
+    let gpio = Gpio::new().map_err(hardware::gpio_error)?;
+    let mut states = Vec::new();
+    for number in PINS {
+        let pin = gpio.get(number).map_err(hardware::gpio_error)?;
+        let level = match pin.read() {
+            Level::High => 1,
+            Level::Low => 0,
+        };
+        states.push((mode_name(pin.mode()), level));
+    }
+    Ok(states)
+}
+
+/// A table of the mode and level of every pin of the header
+pub(crate) fn status() -> io::Result<String> {
+    let mut table = String::from("PIN  MODE  LEVEL\n");
+    for (number, (mode, level)) in PINS.zip(states()?) {
+        table.push_str(&format!("{:>3}  {:<4}  {}\n", number, mode, level));
+    }
+    Ok(table)
+}
@@ -0,0 +1,127 @@
+//! Errors of the Pi's peripherals. When a device is missing or the user may
+//! not open it, the error tells how to fix that, e.g. which overlay to enable
+//! or which group to join.
+
+use std::io;
+
+use rppal::{gpio, i2c, pwm, spi};
+
+/// A peripheral the shell uses through rppal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Peripheral {
+    Uart,
+    Gpio,
+    /// The I2C bus with the number
+    I2c(u8),
+    /// The SPI bus and chip select with the numbers
+    Spi(u8, u8),
+    /// Both hardware PWM channels, which share one chip
+    Pwm,
+}
+
+impl Peripheral {
+    fn device(self) -> String {
+        match self {
+            Peripheral::Uart => String::from("/dev/serial0"),
+            Peripheral::Gpio => String::from("/dev/gpiomem"),
+            Peripheral::I2c(bus) => format!("/dev/i2c-{}", bus),
+            Peripheral::Spi(bus, select) => format!("/dev/spidev{}.{}", bus, select),
+            Peripheral::Pwm => String::from("/sys/class/pwm/pwmchip0"),
+        }
+    }
+
+    /// How to make a missing device appear
+    fn enable_hint(self) -> &'static str {
+        match self {
+            Peripheral::Uart => {
+                "enable the serial port hardware with raspi-config (Interface Options > Serial \
+                 Port) or enable_uart=1 in /boot/config.txt, then reboot"
+            }
+            Peripheral::Gpio => "GPIO is only available on a Raspberry Pi",
+            Peripheral::I2c(_) => {
+                "enable I2C with raspi-config (Interface Options > I2C) or \
+                 dtparam=i2c_arm=on in /boot/config.txt, then reboot"
+            }
+            Peripheral::Spi(..) => {
+                "enable SPI with raspi-config (Interface Options > SPI) or dtparam=spi=on \
+                 in /boot/config.txt, then reboot; buses past 0 need an spiN overlay"
+            }
+            Peripheral::Pwm => {
+                "enable hardware PWM with dtoverlay=pwm-2chan in /boot/config.txt, then \
+                 reboot"
+            }
+        }
+    }
+
+    /// The group giving access to the device
+    fn group(self) -> &'static str {
+        match self {
+            Peripheral::Uart => "dialout",
+            Peripheral::Gpio => "gpio",
+            Peripheral::I2c(_) => "i2c",
+            Peripheral::Spi(..) => "spi",
+            Peripheral::Pwm => "gpio",
+        }
+    }
+}
+
+/// Add a hint on how to fix the error to it, if it is one of the usual ones
+pub(crate) fn unavailable(peripheral: Peripheral, error: io::Error) -> io::Error {
+    let hint = match error.kind() {
+        io::ErrorKind::NotFound => peripheral.enable_hint().to_owned(),
+        io::ErrorKind::PermissionDenied => format!(
+            "add the user to the {} group with 'sudo usermod -aG {} $USER' and log in again, \
+             or run as root",
+            peripheral.group(),
+            peripheral.group()
+        ),
+        io::ErrorKind::ResourceBusy if peripheral == Peripheral::Uart => String::from(
+            "the serial login console may be using it, disable it with raspi-config \
+             (Interface Options > Serial Port)",
+        ),
+        _ => return error,
+    };
+    io::Error::new(
+        error.kind(),
+        format!("{}: {}; {}", peripheral.device(), error, hint),
+    )
+}
+
+pub(crate) fn gpio_error(error: gpio::Error) -> io::Error {
+    let error = match error {
+        gpio::Error::Io(error) => error,
+        gpio::Error::PermissionDenied(path) => io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("permission denied: {}", path),
+        ),
+        gpio::Error::UnknownModel => {
+            io::Error::new(io::ErrorKind::NotFound, "unknown model, not a Raspberry Pi")
+        }
+        error => io::Error::other(error.to_string()),
+    };
+    unavailable(Peripheral::Gpio, error)
+}
+
+pub(crate) fn i2c_error(bus: u8, error: i2c::Error) -> io::Error {
+    let error = match error {
+        i2c::Error::Io(error) => error,
+        i2c::Error::UnknownModel => {
+            io::Error::new(io::ErrorKind::NotFound, "unknown model, not a Raspberry Pi")
+        }
+        error => io::Error::other(error.to_string()),
+    };
+    unavailable(Peripheral::I2c(bus), error)
+}
+
+pub(crate) fn spi_error(bus: u8, select: u8, error: spi::Error) -> io::Error {
+    let error = match error {
+        spi::Error::Io(error) => error,
+        error => io::Error::new(io::ErrorKind::InvalidInput, error.to_string()),
+    };
+    unavailable(Peripheral::Spi(bus, select), error)
+}
+
+pub(crate) fn pwm_error(error: pwm::Error) -> io::Error {
+    let pwm::Error::Io(error) = error;
+    unavailable(Peripheral::Pwm, error)
+}
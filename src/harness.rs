@@ -0,0 +1,138 @@
+//! Runs sessions on scripted keystrokes instead of a real transport, so the
+//! line editor, parser and command execution can be tested without hardware.
+//!
+//! ```
+//! let transcript = pieshell::harness::Harness::new().line("echo hello").run();
+//! assert!(transcript.contains("hello"));
+//! ```
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::config::{Config, Settings};
+use crate::prompt::PromptProvider;
+use crate::session::Session;
+use crate::transport::{self, TransportKind};
+use crate::{ExitStatus, ShellError};
+
+/// A session to run on scripted input
+pub struct Harness {
+    input: Vec<u8>,
+    settings: Settings,
+    current_dir: Option<PathBuf>,
+    prompt_provider: Option<Arc<dyn PromptProvider>>,
+}
+
+/// Everything a session wrote while it ran
+pub struct Transcript {
+    output: Vec<u8>,
+    result: Result<ExitStatus, ShellError>,
+}
+
+impl Harness {
+    /// A harness using the default stdio settings, without banner or rc file
+    /// and with a plain `$ ` prompt so output is easy to match
+    pub fn new() -> Harness {
+        let mut settings = Config::default()
+            .settings(TransportKind::Stdio)
+            .expect("default settings should be valid");
+        settings.banner = String::new();
+        settings.banner_file = None;
+        settings.rc_file = None;
+        settings.inputrc = None;
+        settings.prompt = String::from("$ ");
+
+        Harness {
+            input: Vec::new(),
+            settings,
+            current_dir: None,
+            prompt_provider: None,
+        }
+    }
+
+    /// Use other settings for the session
+    pub fn settings(mut self, settings: Settings) -> Harness {
+        self.settings = settings;
+        self
+    }
+
+    /// Change the settings used for the session
+    pub fn configure<F: FnOnce(&mut Settings)>(mut self, configure: F) -> Harness {
+        configure(&mut self.settings);
+        self
+    }
+
+    /// Start the session in `dir` instead of the current directory
+    pub fn current_dir<P: Into<PathBuf>>(mut self, dir: P) -> Harness {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// Compute the prompt with `provider` instead of the template
+    pub fn prompt<P: PromptProvider + 'static>(mut self, provider: P) -> Harness {
+        self.prompt_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Type the given keys. Control keys are given as their characters, e.g.
+    /// `"\u{3}"` for Ctrl-C.
+    pub fn keys(mut self, keys: &str) -> Harness {
+        self.input.extend_from_slice(keys.as_bytes());
+        self
+    }
+
+    /// Type a line and press enter
+    pub fn line(self, line: &str) -> Harness {
+        self.keys(line).keys("\r")
+    }
+
+    /// Run the session until all input is consumed
+    pub fn run(self) -> Transcript {
+        let (reader, writer, output) = transport::memory_reader_writer(self.input);
+        let mut session = Session::new(reader, writer, self.settings);
+        if let Some(dir) = self.current_dir {
+            session.cwd = dir;
+        }
+        if let Some(provider) = self.prompt_provider {
+            session.set_prompt_provider(provider);
+        }
+        let result = crate::shell::serve(session);
+
+        let output = transport::lock(&output).clone();
+        Transcript { output, result }
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Harness {
+        Harness::new()
+    }
+}
+
+impl Transcript {
+    /// The status the session ended with, or the error that ended it
+    pub fn result(&self) -> &Result<ExitStatus, ShellError> {
+        &self.result
+    }
+
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn contains(&self, text: &str) -> bool {
+        self.output().contains(text)
+    }
+
+    /// The output without prompts, one entry per line
+    pub fn lines(&self) -> Vec<String> {
+        self.output()
+            .lines()
+            .map(|line| line.trim_start_matches("$ ").to_owned())
+            .filter(|line| !line.is_empty())
+            .collect()
+    }
+}
@@ -0,0 +1,427 @@
+//! Where the history of sessions is kept between them, selected with
+//! `history_backend`. Sessions only hold the latest entries in memory, so
+//! searching and counting all of them is left to the backend.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// How the history is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryBackend {
+    /// Not kept, even with a history file
+    None,
+    /// A line per command in the history file
+    File,
+    /// A SQLite database at the history file, which can be searched without
+    /// reading it all. Needs the `sqlite` feature.
+    Sqlite,
+}
+
+/// Counts over the whole history, for `history --stats`
+pub(crate) struct Stats {
+    pub(crate) entries: usize,
+    pub(crate) distinct: usize,
+    /// The most used programs with how often they were run, most used first
+    pub(crate) commands: Vec<(String, usize)>,
+}
+
+/// Storage of history entries
+pub(crate) trait HistoryStore: Send {
+    /// The latest `count` entries, oldest first
+    fn load(&mut self, count: usize) -> Result<Vec<String>, String>;
+
+    fn append(&mut self, line: &str) -> Result<(), String>;
+
+    /// The latest `limit` entries containing `text`, oldest first
+    fn search(&mut self, text: &str, limit: usize) -> Result<Vec<String>, String>;
+
+    /// Counts with the `top` most used programs
+    fn stats(&mut self, top: usize) -> Result<Stats, String>;
+}
+
+/// The store of a backend, or `None` if the history isn't kept
+pub(crate) fn store(
+    backend: HistoryBackend,
+    path: Option<&Path>,
+) -> Result<Option<Box<dyn HistoryStore>>, String> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    match backend {
+        HistoryBackend::None => Ok(None),
+        HistoryBackend::File => Ok(Some(Box::new(HistoryFile {
+            path: path.to_owned(),
+        }))),
+        #[cfg(feature = "sqlite")]
+        HistoryBackend::Sqlite => Ok(Some(Box::new(sqlite::Database::open(path)?))),
+        /* Settings don't allow SQLite without the feature */
+        #[cfg(not(feature = "sqlite"))]
+        HistoryBackend::Sqlite => Ok(None),
+    }
+}
+
+/// Counts of entries going by, for `stats` without a database
+#[derive(Default)]
+pub(crate) struct Tally {
+    entries: usize,
+    /* Hashes of lines take less memory than the lines */
+    distinct: HashSet<u64>,
+    counts: HashMap<String, usize>,
+}
+
+impl Tally {
+    pub(crate) fn add(&mut self, line: &str) {
+        self.entries += 1;
+        let mut hasher = DefaultHasher::new();
+        line.hash(&mut hasher);
+        self.distinct.insert(hasher.finish());
+        *self.counts.entry(program(line).to_owned()).or_insert(0) += 1;
+    }
+
+    pub(crate) fn finish(self, top: usize) -> Stats {
+        Stats {
+            entries: self.entries,
+            distinct: self.distinct.len(),
+            commands: most_used(self.counts, top),
+        }
+    }
+}
+
+/// The program a history entry runs, counted by `stats`
+fn program(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or_default()
+}
+
+/// The `top` most counted programs, most used first and by name when tied
+fn most_used(counts: HashMap<String, usize>, top: usize) -> Vec<(String, usize)> {
+    let mut commands: Vec<(String, usize)> = counts.into_iter().collect();
+    commands.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    commands.truncate(top);
+    commands
+}
+
+/// A line per entry, appended to as commands are entered. Searches read it
+/// a line at a time.
+struct HistoryFile {
+    path: PathBuf,
+}
+
+impl HistoryFile {
+    /// Every entry in turn, or none if the file doesn't exist yet
+    fn for_each<F: FnMut(String)>(&self, mut f: F) -> Result<(), String> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(format!("{}: {}", self.path.display(), error)),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|error| format!("{}: {}", self.path.display(), error))?;
+            if !line.is_empty() {
+                f(line);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl HistoryStore for HistoryFile {
+    fn load(&mut self, count: usize) -> Result<Vec<String>, String> {
+        let mut lines = VecDeque::new();
+        self.for_each(|line| {
+            lines.push_back(line);
+            if lines.len() > count {
+                lines.pop_front();
+            }
+        })?;
+        Ok(lines.into())
+    }
+
+    fn append(&mut self, line: &str) -> Result<(), String> {
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", line))
+            .map_err(|error| format!("{}: {}", self.path.display(), error))
+    }
+
+    fn search(&mut self, text: &str, limit: usize) -> Result<Vec<String>, String> {
+        let mut found = VecDeque::new();
+        self.for_each(|line| {
+            if line.contains(text) {
+                found.push_back(line);
+                if found.len() > limit {
+                    found.pop_front();
+                }
+            }
+        })?;
+        Ok(found.into())
+    }
+
+    fn stats(&mut self, top: usize) -> Result<Stats, String> {
+        let mut tally = Tally::default();
+        self.for_each(|line| tally.add(&line))?;
+        Ok(tally.finish(top))
+    }
+}
+
+/// The history in a table of a SQLite database, using the system's
+/// libsqlite3
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::collections::HashMap;
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::{most_used, HistoryStore, Stats};
+
+    const SQLITE_OK: libc::c_int = 0;
+    const SQLITE_ROW: libc::c_int = 100;
+    const SQLITE_DONE: libc::c_int = 101;
+    const SQLITE_OPEN_READWRITE: libc::c_int = 0x2;
+    const SQLITE_OPEN_CREATE: libc::c_int = 0x4;
+    const SQLITE_OPEN_FULLMUTEX: libc::c_int = 0x10000;
+    /// Tells SQLite to copy bound text
+    const SQLITE_TRANSIENT: isize = -1;
+
+    /// Milliseconds to wait for other sessions writing to the database
+    const BUSY_TIMEOUT: libc::c_int = 2000;
+
+    const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS history (
+            id INTEGER PRIMARY KEY,
+            line TEXT NOT NULL,
+            time INTEGER NOT NULL
+        )";
+
+    #[repr(C)]
+    struct Sqlite3 {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    struct Statement {
+        _private: [u8; 0],
+    }
+
+    #[link(name = "sqlite3")]
+    extern "C" {
+        fn sqlite3_open_v2(
+            filename: *const libc::c_char,
+            db: *mut *mut Sqlite3,
+            flags: libc::c_int,
+            vfs: *const libc::c_char,
+        ) -> libc::c_int;
+        fn sqlite3_close(db: *mut Sqlite3) -> libc::c_int;
+        fn sqlite3_busy_timeout(db: *mut Sqlite3, ms: libc::c_int) -> libc::c_int;
+        fn sqlite3_errmsg(db: *mut Sqlite3) -> *const libc::c_char;
+        fn sqlite3_prepare_v2(
+            db: *mut Sqlite3,
+            sql: *const libc::c_char,
+            bytes: libc::c_int,
+            statement: *mut *mut Statement,
+            tail: *mut *const libc::c_char,
+        ) -> libc::c_int;
+        fn sqlite3_bind_text(
+            statement: *mut Statement,
+            index: libc::c_int,
+            text: *const libc::c_char,
+            bytes: libc::c_int,
+            destructor: isize,
+        ) -> libc::c_int;
+        fn sqlite3_bind_int64(
+            statement: *mut Statement,
+            index: libc::c_int,
+            value: i64,
+        ) -> libc::c_int;
+        fn sqlite3_step(statement: *mut Statement) -> libc::c_int;
+        fn sqlite3_column_text(statement: *mut Statement, column: libc::c_int) -> *const u8;
+        fn sqlite3_column_bytes(statement: *mut Statement, column: libc::c_int) -> libc::c_int;
+        fn sqlite3_column_int64(statement: *mut Statement, column: libc::c_int) -> i64;
+        fn sqlite3_finalize(statement: *mut Statement) -> libc::c_int;
+    }
+
+    /// A value bound to a `?` of a query
+    enum Value<'a> {
+        Text(&'a str),
+        Integer(i64),
+    }
+
+    pub(super) struct Database {
+        db: *mut Sqlite3,
+    }
+
+    /* The connection is opened serialized, so it can move between threads */
+    unsafe impl Send for Database {}
+
+    impl Database {
+        pub(super) fn open(path: &Path) -> Result<Database, String> {
+            let filename = CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| format!("{}: invalid path", path.display()))?;
+            let mut db = ptr::null_mut();
+            let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_FULLMUTEX;
+            let status = unsafe { sqlite3_open_v2(filename.as_ptr(), &mut db, flags, ptr::null()) };
+            let database = Database { db };
+            if status != SQLITE_OK {
+                return Err(format!("{}: {}", path.display(), database.error()));
+            }
+            unsafe { sqlite3_busy_timeout(db, BUSY_TIMEOUT) };
+            database
+                .query(SCHEMA, &[], |_| {})
+                .map_err(|error| format!("{}: {}", path.display(), error))?;
+            Ok(database)
+        }
+
+        fn error(&self) -> String {
+            match self.db.is_null() {
+                true => String::from("out of memory"),
+                false => unsafe { CStr::from_ptr(sqlite3_errmsg(self.db)) }
+                    .to_string_lossy()
+                    .into_owned(),
+            }
+        }
+
+        /// Run a statement, calling `row` with each row it returns
+        fn query<F: FnMut(&Row)>(
+            &self,
+            sql: &str,
+            values: &[Value],
+            mut row: F,
+        ) -> Result<(), String> {
+            let sql = CString::new(sql).expect("queries have no NUL");
+            let mut statement = ptr::null_mut();
+            let status = unsafe {
+                sqlite3_prepare_v2(self.db, sql.as_ptr(), -1, &mut statement, ptr::null_mut())
+            };
+            if status != SQLITE_OK {
+                return Err(self.error());
+            }
+
+            for (i, value) in values.iter().enumerate() {
+                let index = i as libc::c_int + 1;
+                unsafe {
+                    match value {
+                        Value::Text(text) => sqlite3_bind_text(
+                            statement,
+                            index,
+                            text.as_ptr() as *const libc::c_char,
+                            text.len() as libc::c_int,
+                            SQLITE_TRANSIENT,
+                        ),
+                        Value::Integer(value) => sqlite3_bind_int64(statement, index, *value),
+                    };
+                }
+            }
+
+            let result = loop {
+                match unsafe { sqlite3_step(statement) } {
+                    SQLITE_ROW => row(&Row { statement }),
+                    SQLITE_DONE => break Ok(()),
+                    _ => break Err(self.error()),
+                }
+            };
+            unsafe { sqlite3_finalize(statement) };
+            result
+        }
+    }
+
+    impl Drop for Database {
+        fn drop(&mut self) {
+            unsafe { sqlite3_close(self.db) };
+        }
+    }
+
+    /// A row returned by a query
+    struct Row {
+        statement: *mut Statement,
+    }
+
+    impl Row {
+        fn text(&self, column: libc::c_int) -> String {
+            unsafe {
+                let text = sqlite3_column_text(self.statement, column);
+                let length = sqlite3_column_bytes(self.statement, column) as usize;
+                match text.is_null() {
+                    true => String::new(),
+                    false => String::from_utf8_lossy(std::slice::from_raw_parts(text, length))
+                        .into_owned(),
+                }
+            }
+        }
+
+        fn integer(&self, column: libc::c_int) -> i64 {
+            unsafe { sqlite3_column_int64(self.statement, column) }
+        }
+    }
+
+    impl HistoryStore for Database {
+        fn load(&mut self, count: usize) -> Result<Vec<String>, String> {
+            let mut lines = Vec::new();
+            self.query(
+                "SELECT line FROM history ORDER BY id DESC LIMIT ?",
+                &[Value::Integer(count as i64)],
+                |row| lines.push(row.text(0)),
+            )?;
+            lines.reverse();
+            Ok(lines)
+        }
+
+        fn append(&mut self, line: &str) -> Result<(), String> {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs() as i64);
+            self.query(
+                "INSERT INTO history (line, time) VALUES (?, ?)",
+                &[Value::Text(line), Value::Integer(now)],
+                |_| {},
+            )
+        }
+
+        fn search(&mut self, text: &str, limit: usize) -> Result<Vec<String>, String> {
+            let mut lines = Vec::new();
+            self.query(
+                "SELECT line FROM history WHERE instr(line, ?) > 0 ORDER BY id DESC LIMIT ?",
+                &[Value::Text(text), Value::Integer(limit as i64)],
+                |row| lines.push(row.text(0)),
+            )?;
+            lines.reverse();
+            Ok(lines)
+        }
+
+        fn stats(&mut self, top: usize) -> Result<Stats, String> {
+            let (mut entries, mut distinct) = (0, 0);
+            self.query(
+                "SELECT count(*), count(DISTINCT line) FROM history",
+                &[],
+                |row| {
+                    entries = row.integer(0) as usize;
+                    distinct = row.integer(1) as usize;
+                },
+            )?;
+            /* Lines are split in SQL, so only the counts come back */
+            let mut counts = HashMap::new();
+            self.query(
+                "SELECT substr(ltrim(line), 1, instr(ltrim(line) || ' ', ' ') - 1) AS program,
+                    count(*) FROM history GROUP BY program",
+                &[],
+                |row| {
+                    counts.insert(row.text(0), row.integer(1) as usize);
+                },
+            )?;
+            Ok(Stats {
+                entries,
+                distinct,
+                commands: most_used(counts, top),
+            })
+        }
+    }
+}
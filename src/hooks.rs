@@ -0,0 +1,103 @@
+//! Hooks letting programs embedding the shell follow what its sessions do,
+//! e.g. to log every command to a telemetry system. They are registered with
+//! `ShellBuilder::before_command`, `after_command` and `before_prompt`.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::audit::{self, Entry};
+use crate::diagnostics;
+use crate::options::ShellOptions;
+use crate::session::Session;
+use crate::SHELL_NAME;
+
+/// A command line that completed
+#[derive(Debug, Clone, Copy)]
+pub struct Completed<'a> {
+    pub command: &'a str,
+    /// Exit status of the last command run
+    pub status: i32,
+    /// Time from the start of the line until it completed
+    pub duration: Duration,
+}
+
+pub(crate) type BeforeCommand = Arc<dyn Fn(&Session, &str) + Send + Sync>;
+pub(crate) type AfterCommand = Arc<dyn Fn(&Session, &Completed) + Send + Sync>;
+pub(crate) type BeforePrompt = Arc<dyn Fn(&mut Session) + Send + Sync>;
+
+/// The hooks shared by every session of a shell, called in the order they
+/// were registered
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    pub(crate) before_command: Vec<BeforeCommand>,
+    pub(crate) after_command: Vec<AfterCommand>,
+    pub(crate) before_prompt: Vec<BeforePrompt>,
+}
+
+impl Session {
+    /// Call the hooks for a command line about to run. Returns the time it
+    /// started, to pass to `after_command`.
+    pub(crate) fn before_command(&mut self, command: &str) -> Instant {
+        let hooks = Arc::clone(&self.hooks);
+        for hook in &hooks.before_command {
+            hook(self, command);
+        }
+        Instant::now()
+    }
+
+    /// Call the hooks for a command line that completed
+    pub(crate) fn after_command(&mut self, command: &str, started: Instant) {
+        let completed = Completed {
+            command,
+            status: self.last_status,
+            duration: started.elapsed(),
+        };
+        let hooks = Arc::clone(&self.hooks);
+        for hook in &hooks.after_command {
+            hook(self, &completed);
+        }
+
+        if let Some(log) = &self.settings().audit_log {
+            let user = audit::user_name(self.login_user.as_deref());
+            let entry = Entry {
+                started: SystemTime::now() - completed.duration,
+                duration: completed.duration,
+                status: completed.status,
+                user: &user,
+                transport: self.kind(),
+                command,
+            };
+            if let Err(error) = audit::record(log, &entry) {
+                diagnostics::log(&format!("audit log: {}", error));
+            }
+        }
+
+        if self.options.contains(ShellOptions::SUMMARY) {
+            let summary = format!(
+                "[exit {}, {}]\n",
+                completed.status,
+                format_duration(completed.duration)
+            );
+            if let Err(error) = self.write_output(summary.as_bytes()) {
+                eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+            }
+        }
+    }
+
+    /// Call the hooks run before the prompt is computed
+    pub(crate) fn before_prompt(&mut self) {
+        let hooks = Arc::clone(&self.hooks);
+        for hook in &hooks.before_prompt {
+            hook(self);
+        }
+    }
+}
+
+/// A duration as in `3.2s` or `2m05s`
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds < 60 {
+        true => format!("{:.1}s", duration.as_secs_f64()),
+        false => format!("{}m{:02}s", seconds / 60, seconds % 60),
+    }
+}
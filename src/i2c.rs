@@ -0,0 +1,86 @@
+//! I2C buses for the `i2c` builtin, doing what `i2cdetect`, `i2cget` and
+//! `i2cset` of i2c-tools do on images without them.
+
+use std::io;
+
+use rppal::i2c::I2c;
+
+use crate::hardware;
+
+/// The bus on the header of every Pi since the first revision
+pub(crate) const DEFAULT_BUS: u8 = 1;
+
+/// Addresses that can be scanned, without the reserved ones
+const SCANNED: std::ops::RangeInclusive<u16> = 0x03..=0x77;
+
+fn open(bus: u8, address: Option<u16>) -> io::Result<I2c> {
+    let mut i2c = I2c::with_bus(bus).map_err(|error| hardware::i2c_error(bus, error))?;
+    if let Some(address) = address {
+        i2c.set_slave_address(address)
+            .map_err(|error| hardware::i2c_error(bus, error))?;
+    }
+    Ok(i2c)
+}
+
+/// A table of the addresses with a device answering, as `i2cdetect` shows
+/// it. Addresses where EEPROMs may be are probed by reading, since writing
+/// could change them, the others with a quick write.
+pub(crate) fn detect(bus: u8) -> io::Result<String> {
+    let mut i2c = open(bus, None)?;
+    let mut table = String::from("     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f");
+    for address in 0..0x80u16 {
+        if address % 16 == 0 {
+            table.push_str(&format!("\n{:02x}:", address));
+        }
+        if !SCANNED.contains(&address) {
+            table.push_str("   ");
+            continue;
+        }
+        let found = i2c.set_slave_address(address).is_ok()
+            && match address {
+                0x30..=0x37 | 0x50..=0x5f => i2c.smbus_receive_byte().is_ok(),
+                _ => i2c.smbus_quick_command(false).is_ok(),
+            };
+        match found {
+            true => table.push_str(&format!(" {:02x}", address)),
+            false => table.push_str(" --"),
+        }
+    }
+    table.push('\n');
+    Ok(table)
+}
+
+/// Read `count` bytes from the registers of a device, starting at
+/// `register`
+pub(crate) fn read(bus: u8, address: u16, register: u8, count: usize) -> io::Result<Vec<u8>> {
+    let i2c = open(bus, Some(address))?;
+    let mut buffer = vec![0; count];
+    i2c.write_read(&[register], &mut buffer)
+        .map_err(|error| hardware::i2c_error(bus, error))?;
+    Ok(buffer)
+}
+
+/// Write bytes to the registers of a device, starting at `register`
+pub(crate) fn write(bus: u8, address: u16, register: u8, bytes: &[u8]) -> io::Result<()> {
+    let mut i2c = open(bus, Some(address))?;
+    let mut message = vec![register];
+    message.extend_from_slice(bytes);
+    i2c.write(&message)
+        .map_err(|error| hardware::i2c_error(bus, error))?;
+    Ok(())
+}
+
+/// Bytes as rows of 16 in hex, each row starting with the offset from
+/// `start`
+pub(crate) fn hex_table(start: u8, bytes: &[u8]) -> String {
+    let mut table = String::new();
+    for (i, row) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = row.iter().map(|byte| format!("{:02x}", byte)).collect();
+        table.push_str(&format!(
+            "{:02x}: {}\n",
+            start as usize + i * 16,
+            hex.join(" ")
+        ));
+    }
+    table
+}
@@ -0,0 +1,201 @@
+//! Inline images in command output. Sixel and iTerm image escape sequences
+//! are passed through to terminals that can show them and replaced by a
+//! placeholder everywhere else, where they would show up as pages of noise.
+
+use serde::Deserialize;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Shown in place of a stripped image
+const PLACEHOLDER: &[u8] = b"[image]";
+
+/// Longest escape sequence held back while deciding if it starts an image
+const INTRODUCER_LIMIT: usize = 64;
+
+/// Most bytes of a single image dropped before giving up on finding its end,
+/// so a sequence that is never terminated doesn't swallow all later output
+const IMAGE_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Introducers of iTerm image sequences and whether each starts an image,
+/// as opposed to continuing one
+const ITERM_INTRODUCERS: [(&[u8], bool); 4] = [
+    (b"\x1b]1337;File=", true),
+    (b"\x1b]1337;MultipartFile=", true),
+    (b"\x1b]1337;FilePart=", false),
+    (b"\x1b]1337;FileEnd", false),
+];
+
+/// What to do with image escape sequences in command output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImagePolicy {
+    Passthrough,
+    Strip,
+    /// Pass images through if the transport can show them
+    Auto,
+}
+
+impl ImagePolicy {
+    /// Whether images should be passed through to the transport
+    pub fn resolve(self, supports_images: bool) -> bool {
+        match self {
+            ImagePolicy::Passthrough => true,
+            ImagePolicy::Strip => false,
+            ImagePolicy::Auto => supports_images,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Text,
+    /// Collecting the start of an escape sequence that may introduce an image
+    Introducer,
+    Image,
+    /// Received ESC inside an image, which may start the string terminator
+    ImageEscape,
+}
+
+/// Removes image sequences from output. Output may arrive in pieces, so
+/// sequences split between calls are handled.
+pub struct ImageFilter {
+    state: State,
+    pending: Vec<u8>,
+    /// Bytes of the current image dropped so far
+    discarded: usize,
+}
+
+enum Match {
+    Partial,
+    Image { placeholder: bool },
+    None,
+}
+
+impl ImageFilter {
+    pub fn new() -> ImageFilter {
+        ImageFilter {
+            state: State::Text,
+            pending: Vec::new(),
+            discarded: 0,
+        }
+    }
+
+    pub fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut filtered = Vec::with_capacity(data.len());
+
+        for &byte in data {
+            match self.state {
+                State::Text if byte == ESC => {
+                    self.pending.push(byte);
+                    self.state = State::Introducer;
+                }
+                State::Text => filtered.push(byte),
+                State::Introducer => {
+                    self.pending.push(byte);
+                    let found = match classify(&self.pending) {
+                        Match::Partial if self.pending.len() > INTRODUCER_LIMIT => Match::None,
+                        found => found,
+                    };
+                    match found {
+                        Match::Partial => {}
+                        Match::Image { placeholder } => {
+                            if placeholder {
+                                filtered.extend_from_slice(PLACEHOLDER);
+                            }
+                            self.pending.clear();
+                            self.discarded = 0;
+                            self.state = State::Image;
+                        }
+                        Match::None => {
+                            /* Not an image, so pass on everything held back.
+                            The last byte may start another sequence. */
+                            let last = self.pending.pop();
+                            filtered.append(&mut self.pending);
+                            self.state = State::Text;
+                            if let Some(last) = last {
+                                if last == ESC {
+                                    self.pending.push(last);
+                                    self.state = State::Introducer;
+                                } else {
+                                    filtered.push(last);
+                                }
+                            }
+                        }
+                    }
+                }
+                /* Images end with the string terminator ESC \ or with BEL */
+                State::Image | State::ImageEscape if self.discarded >= IMAGE_LIMIT => {
+                    /* Too long to be an image, show the rest */
+                    self.state = State::Text;
+                    filtered.push(byte);
+                }
+                State::Image if byte == ESC => self.state = State::ImageEscape,
+                State::Image if byte == BEL => self.state = State::Text,
+                State::Image => {}
+                State::ImageEscape if byte == b'\\' => self.state = State::Text,
+                State::ImageEscape => self.state = State::Image,
+            }
+            if matches!(self.state, State::Image | State::ImageEscape) {
+                self.discarded += 1;
+            }
+        }
+
+        filtered
+    }
+
+    /// End the output of a command. An escape sequence held back is passed
+    /// on, and an image that was never terminated is dropped, so the next
+    /// command's output starts unfiltered.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let pending = match self.state {
+            State::Introducer => std::mem::take(&mut self.pending),
+            _ => Vec::new(),
+        };
+        self.state = State::Text;
+        self.discarded = 0;
+        pending
+    }
+}
+
+impl Default for ImageFilter {
+    fn default() -> ImageFilter {
+        ImageFilter::new()
+    }
+}
+
+/// Check if the start of an escape sequence introduces an image
+fn classify(sequence: &[u8]) -> Match {
+    /* Sixel: DCS, numeric parameters separated by ';', then 'q' */
+    if let Some(parameters) = sequence.strip_prefix(b"\x1bP") {
+        return match parameters.split_last() {
+            None => Match::Partial,
+            Some((last, rest))
+                if rest
+                    .iter()
+                    .all(|byte| byte.is_ascii_digit() || *byte == b';') =>
+            {
+                match last {
+                    b'q' => Match::Image { placeholder: true },
+                    b'0'..=b'9' | b';' => Match::Partial,
+                    _ => Match::None,
+                }
+            }
+            Some(_) => Match::None,
+        };
+    }
+
+    for (introducer, placeholder) in ITERM_INTRODUCERS {
+        if sequence == introducer {
+            return Match::Image { placeholder };
+        }
+    }
+    if ITERM_INTRODUCERS
+        .iter()
+        .any(|(introducer, _)| introducer.starts_with(sequence))
+    {
+        return Match::Partial;
+    }
+
+    Match::None
+}
@@ -0,0 +1,665 @@
+//! A subset of the readline init file, `~/.inputrc`, so users coming from
+//! bash keep their key bindings and editor settings.
+//!
+//! Supported are key bindings to the functions of `EditCommand` or to text,
+//! the `editing-mode`, `keymap`, `completion-ignore-case`, `bell-style` and
+//! `enable-bracketed-paste` variables, and the `$if`, `$else`, `$endif` and
+//! `$include` directives. Like readline, anything else is ignored, so one
+//! file serves both. The `key_bindings` of the config are lines in the same
+//! format, read before the file.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub(crate) use crate::config::EditingMode;
+
+/// Name tested by `$if` to apply a section to this shell only
+const APPLICATION: &str = "pieshell";
+
+/// How deeply `$include` can nest, which stops files including themselves
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// What the editor does when something can't be done, like deleting from an
+/// empty line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BellStyle {
+    None,
+    Audible,
+    /// Flash the screen
+    Visible,
+}
+
+/// A set of key bindings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Keymap {
+    Emacs,
+    ViInsert,
+    /// Keys typed after Escape in vi mode
+    ViCommand,
+}
+
+/// A function of the line editor that keys can be bound to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EditCommand {
+    BackwardDeleteChar,
+    UnixLineDiscard,
+    UnixWordRubout,
+    ClearScreen,
+    PreviousHistory,
+    NextHistory,
+    BeginningOfHistory,
+    EndOfHistory,
+    /// Go back to the previous line starting with what was typed
+    HistorySearchBackward,
+    HistorySearchForward,
+    Complete,
+    Abort,
+    EmacsEditingMode,
+    ViEditingMode,
+    ViMovementMode,
+    ViInsertionMode,
+    /// Insert text, bound with `"keys": "text"`
+    Insert(String),
+}
+
+impl EditCommand {
+    /// The command of a readline function name, if it is supported. The
+    /// cursor is always at the end of the line, so functions that only
+    /// differ in where it goes are the same here.
+    fn from_name(name: &str) -> Option<EditCommand> {
+        let command = match name.to_ascii_lowercase().as_str() {
+            "backward-delete-char" => EditCommand::BackwardDeleteChar,
+            "unix-line-discard" | "kill-whole-line" | "backward-kill-line" => {
+                EditCommand::UnixLineDiscard
+            }
+            "unix-word-rubout" | "backward-kill-word" => EditCommand::UnixWordRubout,
+            "clear-screen" => EditCommand::ClearScreen,
+            "previous-history" => EditCommand::PreviousHistory,
+            "next-history" => EditCommand::NextHistory,
+            "beginning-of-history" => EditCommand::BeginningOfHistory,
+            "end-of-history" => EditCommand::EndOfHistory,
+            "history-search-backward" => EditCommand::HistorySearchBackward,
+            "history-search-forward" => EditCommand::HistorySearchForward,
+            "complete" => EditCommand::Complete,
+            "abort" => EditCommand::Abort,
+            "emacs-editing-mode" => EditCommand::EmacsEditingMode,
+            "vi-editing-mode" => EditCommand::ViEditingMode,
+            "vi-movement-mode" => EditCommand::ViMovementMode,
+            "vi-insertion-mode" | "vi-insert-beg" | "vi-append-mode" | "vi-append-eol" => {
+                EditCommand::ViInsertionMode
+            }
+            _ => return None,
+        };
+        Some(command)
+    }
+}
+
+/// Key bindings of the editor without an init file
+fn default_bindings(keymap: Keymap) -> Vec<(&'static str, EditCommand)> {
+    let mut bindings = vec![
+        ("\u{7f}", EditCommand::BackwardDeleteChar),
+        ("\u{8}", EditCommand::BackwardDeleteChar),
+        ("\t", EditCommand::Complete),
+        ("\u{15}", EditCommand::UnixLineDiscard),
+        ("\u{17}", EditCommand::UnixWordRubout),
+        ("\u{c}", EditCommand::ClearScreen),
+        ("\u{1b}[A", EditCommand::PreviousHistory),
+        ("\u{1b}OA", EditCommand::PreviousHistory),
+        ("\u{1b}[B", EditCommand::NextHistory),
+        ("\u{1b}OB", EditCommand::NextHistory),
+    ];
+    match keymap {
+        Keymap::Emacs => bindings.extend([
+            ("\u{10}", EditCommand::PreviousHistory),
+            ("\u{e}", EditCommand::NextHistory),
+            ("\u{1b}<", EditCommand::BeginningOfHistory),
+            ("\u{1b}>", EditCommand::EndOfHistory),
+            ("\u{7}", EditCommand::Abort),
+        ]),
+        Keymap::ViInsert => bindings.push(("\u{1b}", EditCommand::ViMovementMode)),
+        Keymap::ViCommand => {
+            bindings.retain(|(_, command)| *command != EditCommand::Complete);
+            bindings.extend([
+                ("i", EditCommand::ViInsertionMode),
+                ("a", EditCommand::ViInsertionMode),
+                ("I", EditCommand::ViInsertionMode),
+                ("A", EditCommand::ViInsertionMode),
+                ("k", EditCommand::PreviousHistory),
+                ("-", EditCommand::PreviousHistory),
+                ("j", EditCommand::NextHistory),
+                ("+", EditCommand::NextHistory),
+                ("x", EditCommand::BackwardDeleteChar),
+                ("X", EditCommand::BackwardDeleteChar),
+                ("dd", EditCommand::UnixLineDiscard),
+                ("db", EditCommand::UnixWordRubout),
+                ("\u{5}", EditCommand::EmacsEditingMode),
+            ])
+        }
+    }
+    bindings
+}
+
+/// What typed keys are bound to
+pub(crate) struct Lookup {
+    /// The command bound to exactly these keys
+    pub(crate) command: Option<EditCommand>,
+    /// Whether more keys may follow to complete a longer binding
+    pub(crate) longer: bool,
+}
+
+/// Settings and key bindings of the line editor
+#[derive(Debug, Clone)]
+pub(crate) struct Inputrc {
+    pub(crate) editing_mode: EditingMode,
+    pub(crate) completion_ignore_case: bool,
+    pub(crate) bell_style: BellStyle,
+    /// Ask the terminal to mark pasted text, so it is inserted as it is
+    pub(crate) enable_bracketed_paste: bool,
+    /// Bindings from the file, later ones taking precedence
+    bindings: Vec<(Keymap, String, EditCommand)>,
+}
+
+impl Default for Inputrc {
+    fn default() -> Inputrc {
+        Inputrc {
+            editing_mode: EditingMode::Emacs,
+            completion_ignore_case: false,
+            bell_style: BellStyle::Audible,
+            enable_bracketed_paste: true,
+            bindings: Vec::new(),
+        }
+    }
+}
+
+impl Inputrc {
+    /// The editor as the config sets it up, with key bindings given as
+    /// lines of an init file
+    pub(crate) fn configured(editing_mode: EditingMode, key_bindings: &[String]) -> Inputrc {
+        let mut inputrc = Inputrc {
+            editing_mode,
+            ..Inputrc::default()
+        };
+        inputrc.parse(&key_bindings.join("\n"), 0);
+        inputrc
+    }
+
+    /// Read an init file, whose settings and bindings take precedence
+    pub(crate) fn load(&mut self, path: &Path) -> io::Result<()> {
+        self.include(path, 0)
+    }
+
+    fn include(&mut self, path: &Path, depth: usize) -> io::Result<()> {
+        let contents = fs::read_to_string(path)?;
+        self.parse(&contents, depth);
+        Ok(())
+    }
+
+    fn parse(&mut self, contents: &str, depth: usize) {
+        let mut keymap = match self.editing_mode {
+            EditingMode::Emacs => Keymap::Emacs,
+            EditingMode::Vi => Keymap::ViInsert,
+        };
+        /* Whether each enclosing `$if` section applies */
+        let mut conditions: Vec<bool> = Vec::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let active = conditions.iter().all(|condition| *condition);
+
+            if let Some(directive) = line.strip_prefix('$') {
+                let (name, argument) = directive
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((directive, ""));
+                let argument = argument.trim();
+                match name {
+                    "if" => conditions.push(self.test(argument)),
+                    "else" => {
+                        if let Some(condition) = conditions.last_mut() {
+                            *condition = !*condition;
+                        }
+                    }
+                    "endif" => {
+                        conditions.pop();
+                    }
+                    /* Errors of included files are ignored like other lines
+                    that can't be used */
+                    "include" if active && depth < MAX_INCLUDE_DEPTH => {
+                        let _ = self.include(&expand_home(argument), depth + 1);
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            if !active {
+                continue;
+            }
+
+            if let Some(assignment) = line.strip_prefix("set ") {
+                let mut words = assignment.split_whitespace();
+                let name = words.next().unwrap_or_default().to_ascii_lowercase();
+                let value = words.next().unwrap_or_default().to_ascii_lowercase();
+                match (name.as_str(), value.as_str()) {
+                    ("editing-mode", "emacs") => {
+                        self.editing_mode = EditingMode::Emacs;
+                        keymap = Keymap::Emacs;
+                    }
+                    ("editing-mode", "vi") => {
+                        self.editing_mode = EditingMode::Vi;
+                        keymap = Keymap::ViInsert;
+                    }
+                    ("keymap", "emacs" | "emacs-standard") => keymap = Keymap::Emacs,
+                    ("keymap", "vi-insert") => keymap = Keymap::ViInsert,
+                    ("keymap", "vi" | "vi-move" | "vi-command") => keymap = Keymap::ViCommand,
+                    ("completion-ignore-case", value) => {
+                        self.completion_ignore_case = matches!(value, "on" | "1")
+                    }
+                    ("enable-bracketed-paste", value) => {
+                        self.enable_bracketed_paste = matches!(value, "on" | "1")
+                    }
+                    ("bell-style", "none") => self.bell_style = BellStyle::None,
+                    ("bell-style", "audible") => self.bell_style = BellStyle::Audible,
+                    ("bell-style", "visible") => self.bell_style = BellStyle::Visible,
+                    _ => {}
+                }
+                continue;
+            }
+
+            if let Some((keys, command)) = parse_binding(line) {
+                self.bindings.push((keymap, keys, command));
+            }
+        }
+    }
+
+    /// Whether the sections of an `$if` apply
+    fn test(&self, condition: &str) -> bool {
+        if let Some(mode) = condition.strip_prefix("mode=") {
+            let current = match self.editing_mode {
+                EditingMode::Emacs => "emacs",
+                EditingMode::Vi => "vi",
+            };
+            return mode == current;
+        }
+        if let Some(term) = condition.strip_prefix("term=") {
+            /* Either the whole name or the part before the first dash */
+            let current = env::var("TERM").unwrap_or_default();
+            let base = current.split('-').next().unwrap_or_default();
+            return term == current || term == base;
+        }
+        condition.eq_ignore_ascii_case(APPLICATION)
+    }
+
+    /// Find what typed keys are bound to in a keymap
+    pub(crate) fn lookup(&self, keymap: Keymap, keys: &str) -> Lookup {
+        let defaults = default_bindings(keymap);
+        let user = self
+            .bindings
+            .iter()
+            .filter(|(map, _, _)| *map == keymap)
+            .map(|(_, keys, command)| (keys.as_str(), command));
+        let bindings: Vec<(&str, &EditCommand)> = user
+            .rev()
+            .chain(defaults.iter().map(|(keys, command)| (*keys, command)))
+            .collect();
+
+        Lookup {
+            command: bindings
+                .iter()
+                .find(|(bound, _)| *bound == keys)
+                .map(|(_, command)| (*command).clone()),
+            longer: bindings
+                .iter()
+                .any(|(bound, _)| bound.len() > keys.len() && bound.starts_with(keys)),
+        }
+    }
+}
+
+/// Parse a key binding, `"keys": function`, `keyname: function` or either
+/// with `"text"` instead of a function. Unsupported functions are skipped.
+fn parse_binding(line: &str) -> Option<(String, EditCommand)> {
+    let (keys, rest) = match line.strip_prefix('"') {
+        Some(quoted) => {
+            let (keys, rest) = parse_quoted(quoted)?;
+            (keys, rest.trim_start().strip_prefix(':')?)
+        }
+        None => {
+            let (name, rest) = line.split_once(':')?;
+            (parse_key_name(name.trim())?, rest)
+        }
+    };
+    if keys.is_empty() {
+        return None;
+    }
+
+    let value = rest.trim();
+    let command = if let Some(text) = value.strip_prefix('"') {
+        EditCommand::Insert(parse_quoted(text)?.0)
+    } else if let Some(text) = value.strip_prefix('\'') {
+        EditCommand::Insert(text.split_once('\'')?.0.to_owned())
+    } else {
+        EditCommand::from_name(value.split_whitespace().next()?)?
+    };
+    Some((keys, command))
+}
+
+/// Parse the rest of a double-quoted key sequence or text with its escapes,
+/// returning it and what follows the closing quote
+fn parse_quoted(text: &str) -> Option<(String, &str)> {
+    let mut parsed = String::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((parsed, &text[i + 1..])),
+            '\\' => {
+                let rest = &text[i + 1..];
+                let (escaped, length) = parse_escape(rest)?;
+                parsed.push_str(&escaped);
+                /* Skip what the escape consisted of */
+                let end = i + 1 + length;
+                while chars.peek().is_some_and(|(j, _)| *j < end) {
+                    chars.next();
+                }
+            }
+            _ => parsed.push(c),
+        }
+    }
+    None
+}
+
+/// Parse an escape following a backslash. Returns the keys and the number
+/// of bytes the escape took.
+fn parse_escape(text: &str) -> Option<(String, usize)> {
+    if let Some(rest) = text.strip_prefix("C-") {
+        let (key, length) = parse_key(rest)?;
+        return Some((control(key)?.to_string(), 2 + length));
+    }
+    if let Some(rest) = text.strip_prefix("M-") {
+        let (key, length) = parse_key(rest)?;
+        return Some((format!("\u{1b}{}", key), 2 + length));
+    }
+
+    let c = text.chars().next()?;
+    let simple = match c {
+        'e' => Some('\u{1b}'),
+        'a' => Some('\u{7}'),
+        'b' => Some('\u{8}'),
+        'd' => Some('\u{7f}'),
+        'f' => Some('\u{c}'),
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        't' => Some('\t'),
+        'v' => Some('\u{b}'),
+        '\\' | '"' | '\'' => Some(c),
+        _ => None,
+    };
+    if let Some(key) = simple {
+        return Some((key.to_string(), 1));
+    }
+
+    let (digits, radix, skip) = match c {
+        'x' => (&text[1..], 16, 1),
+        '0'..='7' => (text, 8, 0),
+        _ => return Some((c.to_string(), c.len_utf8())),
+    };
+    let max = if radix == 16 { 2 } else { 3 };
+    let length = digits
+        .chars()
+        .take(max)
+        .take_while(|c| c.is_digit(radix))
+        .count();
+    let value = u32::from_str_radix(&digits[..length], radix).ok()?;
+    Some((char::from_u32(value)?.to_string(), skip + length))
+}
+
+/// Parse one key after `\C-` or `\M-`, which may itself be an escape
+fn parse_key(text: &str) -> Option<(String, usize)> {
+    match text.strip_prefix('\\') {
+        Some(rest) => parse_escape(rest).map(|(key, length)| (key, length + 1)),
+        None => text.chars().next().map(|c| (c.to_string(), c.len_utf8())),
+    }
+}
+
+/// Parse a key name like `Control-u`, `Meta-Rubout` or `TAB`
+fn parse_key_name(name: &str) -> Option<String> {
+    let mut rest = name;
+    let mut is_control = false;
+    let mut is_meta = false;
+    loop {
+        let lower = rest.to_ascii_lowercase();
+        if let Some(length) = ["control-", "c-"]
+            .iter()
+            .find(|prefix| lower.starts_with(*prefix))
+            .map(|prefix| prefix.len())
+        {
+            is_control = true;
+            rest = &rest[length..];
+        } else if let Some(length) = ["meta-", "m-"]
+            .iter()
+            .find(|prefix| lower.starts_with(*prefix))
+            .map(|prefix| prefix.len())
+        {
+            is_meta = true;
+            rest = &rest[length..];
+        } else {
+            break;
+        }
+    }
+
+    let key = match rest.to_ascii_lowercase().as_str() {
+        "rubout" | "del" => String::from("\u{7f}"),
+        "escape" | "esc" => String::from("\u{1b}"),
+        "return" | "ret" => String::from("\r"),
+        "newline" | "lfd" => String::from("\n"),
+        "space" | "spc" => String::from(" "),
+        "tab" => String::from("\t"),
+        _ if rest.chars().count() == 1 => rest.to_owned(),
+        _ => return None,
+    };
+    let key = match is_control {
+        true => control(key)?.to_string(),
+        false => key,
+    };
+    match is_meta {
+        true => Some(format!("\u{1b}{}", key)),
+        false => Some(key),
+    }
+}
+
+/// The control character typed with Ctrl and a key
+fn control(key: String) -> Option<char> {
+    match key.as_str() {
+        "?" => Some('\u{7f}'),
+        _ => {
+            let c = key.chars().next().filter(|c| c.is_ascii())?;
+            Some(char::from(c.to_ascii_lowercase() as u8 & 0x1f))
+        }
+    }
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(lines: &[&str]) -> Inputrc {
+        let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        Inputrc::configured(EditingMode::Emacs, &lines)
+    }
+
+    fn bound(inputrc: &Inputrc, keymap: Keymap, keys: &str) -> Option<EditCommand> {
+        inputrc.lookup(keymap, keys).command
+    }
+
+    #[test]
+    fn parses_key_sequences() {
+        let binding = |line| parse_binding(line).map(|(keys, _)| keys);
+        assert_eq!(binding(r#""\C-r": abort"#).as_deref(), Some("\u{12}"));
+        assert_eq!(
+            binding(r#""\M-\C-h": abort"#).as_deref(),
+            Some("\u{1b}\u{8}")
+        );
+        assert_eq!(binding(r#""\e[5~": abort"#).as_deref(), Some("\u{1b}[5~"));
+        assert_eq!(binding(r#""\C-?": abort"#).as_deref(), Some("\u{7f}"));
+        assert_eq!(binding(r#""\x41\101\"": abort"#).as_deref(), Some("AA\""));
+        assert_eq!(binding("Control-u: abort").as_deref(), Some("\u{15}"));
+        assert_eq!(
+            binding("Meta-Rubout: abort").as_deref(),
+            Some("\u{1b}\u{7f}")
+        );
+        assert_eq!(binding("TAB: abort").as_deref(), Some("\t"));
+        assert_eq!(binding("Hyper-x: abort"), None);
+        assert_eq!(binding(r#""": abort"#), None);
+        assert_eq!(binding(r#""\C-r abort"#), None);
+    }
+
+    #[test]
+    fn parses_functions_and_text() {
+        let command = |line| parse_binding(line).map(|(_, command)| command);
+        assert_eq!(
+            command(r#""\e[A": history-search-backward"#),
+            Some(EditCommand::HistorySearchBackward)
+        );
+        assert_eq!(
+            command("C-w: Backward-Kill-Word # comment"),
+            Some(EditCommand::UnixWordRubout)
+        );
+        assert_eq!(
+            command(r#""\C-xg": "git status\r""#),
+            Some(EditCommand::Insert(String::from("git status\r")))
+        );
+        assert_eq!(
+            command(r#""\C-xl": 'ls -l'"#),
+            Some(EditCommand::Insert(String::from("ls -l")))
+        );
+        assert_eq!(command(r#""\C-a": beginning-of-line"#), None);
+    }
+
+    #[test]
+    fn sets_variables() {
+        let inputrc = parsed(&[
+            "set completion-ignore-case on",
+            "set bell-style visible",
+            "set enable-bracketed-paste off",
+            "set no-such-variable on",
+        ]);
+        assert!(inputrc.completion_ignore_case);
+        assert_eq!(inputrc.bell_style, BellStyle::Visible);
+        assert!(!inputrc.enable_bracketed_paste);
+        assert_eq!(
+            parsed(&["set editing-mode vi"]).editing_mode,
+            EditingMode::Vi
+        );
+    }
+
+    #[test]
+    fn binds_keys_in_the_current_keymap() {
+        let inputrc = parsed(&[
+            r#""\C-o": clear-screen"#,
+            "set keymap vi-command",
+            r#""\C-o": abort"#,
+            "set editing-mode vi",
+            r#""\C-t": abort"#,
+        ]);
+        assert_eq!(
+            bound(&inputrc, Keymap::Emacs, "\u{f}"),
+            Some(EditCommand::ClearScreen)
+        );
+        assert_eq!(
+            bound(&inputrc, Keymap::ViCommand, "\u{f}"),
+            Some(EditCommand::Abort)
+        );
+        assert_eq!(
+            bound(&inputrc, Keymap::ViInsert, "\u{14}"),
+            Some(EditCommand::Abort)
+        );
+        assert_eq!(bound(&inputrc, Keymap::Emacs, "\u{14}"), None);
+    }
+
+    #[test]
+    fn prefers_later_bindings_to_defaults() {
+        let inputrc = parsed(&[r#""\C-u": abort"#, r#""\C-u": clear-screen"#]);
+        assert_eq!(
+            bound(&inputrc, Keymap::Emacs, "\u{15}"),
+            Some(EditCommand::ClearScreen)
+        );
+        assert_eq!(
+            bound(&Inputrc::default(), Keymap::Emacs, "\u{15}"),
+            Some(EditCommand::UnixLineDiscard)
+        );
+    }
+
+    #[test]
+    fn looks_up_prefixes_of_longer_bindings() {
+        let inputrc = Inputrc::default();
+        let lookup = inputrc.lookup(Keymap::Emacs, "\u{1b}[");
+        assert_eq!(lookup.command, None);
+        assert!(lookup.longer);
+        let lookup = inputrc.lookup(Keymap::ViCommand, "d");
+        assert!(lookup.longer);
+        assert_eq!(
+            inputrc.lookup(Keymap::ViCommand, "dd").command,
+            Some(EditCommand::UnixLineDiscard)
+        );
+    }
+
+    #[test]
+    fn applies_conditional_sections() {
+        let inputrc = parsed(&[
+            "$if mode=emacs",
+            r#""\C-o": abort"#,
+            "$else",
+            r#""\C-o": clear-screen"#,
+            "$endif",
+            "$if Bash",
+            r#""\C-t": abort"#,
+            "$endif",
+            "$if PieShell",
+            "$if mode=vi",
+            r#""\C-y": abort"#,
+            "$endif",
+            r#""\C-b": abort"#,
+            "$endif",
+        ]);
+        assert_eq!(
+            bound(&inputrc, Keymap::Emacs, "\u{f}"),
+            Some(EditCommand::Abort)
+        );
+        assert_eq!(bound(&inputrc, Keymap::Emacs, "\u{14}"), None);
+        assert_eq!(bound(&inputrc, Keymap::Emacs, "\u{19}"), None);
+        assert_eq!(
+            bound(&inputrc, Keymap::Emacs, "\u{2}"),
+            Some(EditCommand::Abort)
+        );
+    }
+
+    #[test]
+    fn includes_files_up_to_a_depth() {
+        let dir = env::temp_dir().join(format!("pieshell-inputrc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("inputrc");
+        /* A file including itself stops at the depth limit */
+        let contents = format!("\"\\C-o\": abort\n$include {}\n", path.display());
+        fs::write(&path, contents).unwrap();
+
+        let mut inputrc = Inputrc::default();
+        let loaded = inputrc.load(&path);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(loaded.is_ok());
+        assert_eq!(inputrc.bindings.len(), MAX_INCLUDE_DEPTH + 1);
+        assert_eq!(
+            bound(&inputrc, Keymap::Emacs, "\u{f}"),
+            Some(EditCommand::Abort)
+        );
+        assert!(Inputrc::default().load(&dir).is_err());
+    }
+}
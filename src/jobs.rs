@@ -0,0 +1,283 @@
+//! Pipelines started in the background with `&`. Their output is collected
+//! and shown, with a notice when they complete, before the next prompt.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use crate::exec::{self, Chunk, Running, POLL_INTERVAL};
+use crate::StatusCode;
+
+/// Completed jobs `wait` can still find once they were forgotten
+const REAPED_LIMIT: usize = 64;
+
+struct Job {
+    id: usize,
+    /// Tells jobs apart for good, unlike `id`, which is reused
+    serial: usize,
+    command: String,
+    /// Process IDs of the commands of the pipeline
+    pids: Vec<u32>,
+    /// Output not shown yet
+    output: VecDeque<u8>,
+    /// Bytes of output dropped because the buffer was full
+    dropped: usize,
+    /// Exit status, once the job completed
+    status: Option<i32>,
+    /// Whether `jobs` already showed it completed
+    notified: bool,
+}
+
+impl Job {
+    /// The line about the job, as in `[2]- Running  sleep 10`
+    fn notice(&self, marker: char) -> String {
+        let state = match self.status {
+            None => String::from("Running"),
+            Some(0) => String::from("Done"),
+            Some(status) if StatusCode::signal(status).is_some() => String::from("Killed"),
+            Some(status) => format!("Exit {}", status),
+        };
+        format!("[{}]{} {:<8}{}\n", self.id, marker, state, self.command)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    jobs: Vec<Job>,
+    next_id: usize,
+    next_serial: usize,
+    /// Serials, process IDs and exit statuses of completed jobs that were
+    /// forgotten, the latest last
+    reaped: VecDeque<(usize, Vec<u32>, i32)>,
+    /// Called when a job has output or completed, to wake up a session
+    /// waiting for input
+    waker: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+/// The background jobs of a session
+#[derive(Clone, Default)]
+pub(crate) struct Jobs {
+    state: Arc<Mutex<State>>,
+}
+
+impl Jobs {
+    /// Set a function to call whenever there is something to show
+    #[cfg(feature = "async")]
+    pub(crate) fn set_waker(&self, waker: Arc<dyn Fn() + Send + Sync>) {
+        self.lock().waker = Some(waker);
+    }
+
+    /// Keep track of a started pipeline, buffering up to `limit` bytes of its
+    /// output. Returns the job number and process ID to show.
+    pub(crate) fn add(&self, mut running: Running, command: String, limit: usize) -> (usize, u32) {
+        let pid = running.pid().unwrap_or_default();
+        let pids = running.pids();
+        let id = {
+            let mut state = self.lock();
+            /* Numbers are reused once every job has completed */
+            if state.jobs.is_empty() {
+                state.next_id = 1;
+            }
+            let id = state.next_id;
+            state.next_id += 1;
+            let serial = state.next_serial;
+            state.next_serial += 1;
+            state.jobs.push(Job {
+                id,
+                serial,
+                command,
+                pids,
+                output: VecDeque::new(),
+                dropped: 0,
+                status: None,
+                notified: false,
+            });
+            id
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        running.relay_output(move |chunk| sender.send(chunk).is_ok());
+        let jobs = self.clone();
+        thread::spawn(move || loop {
+            match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(Chunk::Stdout(data)) | Ok(Chunk::Stderr(data)) => jobs.update(id, |job| {
+                    job.dropped += exec::append_capped(&mut job.output, &data, limit);
+                }),
+                Err(RecvTimeoutError::Timeout) => {}
+                /* The output is closed, only the exit remains */
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(POLL_INTERVAL),
+            }
+
+            if let Ok(Some(status)) = running.try_wait() {
+                /* Keep the output written just before exiting */
+                while let Ok(Chunk::Stdout(data) | Chunk::Stderr(data)) =
+                    receiver.recv_timeout(POLL_INTERVAL)
+                {
+                    jobs.update(id, |job| {
+                        job.dropped += exec::append_capped(&mut job.output, &data, limit);
+                    });
+                }
+                jobs.update(id, |job| job.status = Some(status));
+                return;
+            }
+        });
+
+        (id, pid)
+    }
+
+    /// Take the output of jobs and notices of completed jobs, forgetting the
+    /// completed ones
+    pub(crate) fn take_messages(&self) -> Vec<u8> {
+        let mut state = self.lock();
+        let markers = markers(&state.jobs);
+        let mut messages = Vec::new();
+        for (job, marker) in state.jobs.iter_mut().zip(markers) {
+            if job.dropped > 0 {
+                let notice = format!("[{}]  {} bytes of output dropped\n", job.id, job.dropped);
+                messages.extend_from_slice(notice.as_bytes());
+                job.dropped = 0;
+            }
+            messages.extend(job.output.drain(..));
+            if job.status.is_some() && !job.notified {
+                messages.extend_from_slice(job.notice(marker).as_bytes());
+            }
+        }
+        let (running, completed) = std::mem::take(&mut state.jobs)
+            .into_iter()
+            .partition(|job| job.status.is_none());
+        state.jobs = running;
+        for job in completed {
+            if state.reaped.len() == REAPED_LIMIT {
+                state.reaped.pop_front();
+            }
+            let status = job.status.unwrap_or_default();
+            state.reaped.push_back((job.serial, job.pids, status));
+        }
+        messages
+    }
+
+    /// A line about every job, for `jobs`. Completed jobs aren't announced
+    /// again before the prompt.
+    pub(crate) fn list(&self) -> String {
+        let mut state = self.lock();
+        let markers = markers(&state.jobs);
+        let mut listing = String::new();
+        for (job, marker) in state.jobs.iter_mut().zip(markers) {
+            listing.push_str(&job.notice(marker));
+            job.notified = job.status.is_some();
+        }
+        listing
+    }
+
+    /// Process IDs of the job a spec like `%2` refers to: `%N` is job N,
+    /// `%+` or `%%` the current job, `%-` the previous one, and `%TEXT` the
+    /// job whose command starts with TEXT. Completed jobs have none.
+    pub(crate) fn pids(&self, spec: &str) -> Option<Vec<u32>> {
+        let state = self.lock();
+        let job = find(&state.jobs, spec)?;
+        match job.status {
+            Some(_) => Some(Vec::new()),
+            None => Some(job.pids.clone()),
+        }
+    }
+
+    /// The job `wait` is given: a spec like `%2`, see `pids`, or the
+    /// process ID of one of its commands. Completed jobs are found by
+    /// process ID until `REAPED_LIMIT` more completed.
+    pub(crate) fn find(&self, target: &str) -> Option<usize> {
+        let state = self.lock();
+        if target.starts_with('%') {
+            return find(&state.jobs, target).map(|job| job.serial);
+        }
+        let pid = target.parse::<u32>().ok()?;
+        match state.jobs.iter().find(|job| job.pids.contains(&pid)) {
+            Some(job) => Some(job.serial),
+            None => state
+                .reaped
+                .iter()
+                .rev()
+                .find(|(_, pids, _)| pids.contains(&pid))
+                .map(|(serial, _, _)| *serial),
+        }
+    }
+
+    /// Jobs that haven't completed, as `find` gives them
+    pub(crate) fn running(&self) -> Vec<usize> {
+        let state = self.lock();
+        state
+            .jobs
+            .iter()
+            .filter(|job| job.status.is_none())
+            .map(|job| job.serial)
+            .collect()
+    }
+
+    /// Exit status of a job `find` gave, once it completed. Jobs forgotten
+    /// altogether count as not found, as in other shells.
+    pub(crate) fn status(&self, serial: usize) -> Option<i32> {
+        let state = self.lock();
+        match state.jobs.iter().find(|job| job.serial == serial) {
+            Some(job) => job.status,
+            None => Some(
+                state
+                    .reaped
+                    .iter()
+                    .find(|(reaped, _, _)| *reaped == serial)
+                    .map_or(StatusCode::NotFound.code(), |(_, _, status)| *status),
+            ),
+        }
+    }
+
+    /// Number of jobs and the bytes of output buffered for them
+    pub(crate) fn memory(&self) -> (usize, usize) {
+        let state = self.lock();
+        let bytes = state.jobs.iter().map(|job| job.output.len()).sum();
+        (state.jobs.len(), bytes)
+    }
+
+    fn update<F: FnOnce(&mut Job)>(&self, id: usize, change: F) {
+        let waker = {
+            let mut state = self.lock();
+            if let Some(job) = state.jobs.iter_mut().find(|job| job.id == id) {
+                change(job);
+            }
+            state.waker.clone()
+        };
+        if let Some(waker) = waker {
+            waker();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The job a spec like `%2` refers to, see `Jobs::pids`
+fn find<'a>(jobs: &'a [Job], spec: &str) -> Option<&'a Job> {
+    let spec = spec.strip_prefix('%')?;
+    let markers = markers(jobs);
+    let mut jobs = jobs.iter().zip(markers);
+    let (job, _) = match spec {
+        "" | "+" | "%" => jobs.find(|(_, marker)| *marker == '+'),
+        "-" => jobs.find(|(_, marker)| *marker == '-'),
+        spec => match spec.parse::<usize>() {
+            Ok(id) => jobs.find(|(job, _)| job.id == id),
+            Err(_) => jobs.find(|(job, _)| job.command.starts_with(spec)),
+        },
+    }?;
+    Some(job)
+}
+
+/// `+` for the job started last and `-` for the one before, as in other
+/// shells
+fn markers(jobs: &[Job]) -> Vec<char> {
+    let mut markers = vec![' '; jobs.len()];
+    for (marker, symbol) in markers.iter_mut().rev().zip(['+', '-']) {
+        *marker = symbol;
+    }
+    markers
+}
@@ -0,0 +1,185 @@
+//! Resource limits of commands, set with `ulimit`. Sessions share the shell
+//! process, so a session's limits are set in each command it starts rather
+//! than in the shell itself.
+
+use std::fs;
+use std::io;
+
+#[cfg(target_env = "gnu")]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+type Resource = libc::c_int;
+
+/// A limit `ulimit` knows, by its option
+pub(crate) struct Kind {
+    pub(crate) option: char,
+    pub(crate) description: &'static str,
+    resource: Resource,
+    /// Bytes or seconds in a unit of the limit as `ulimit` gives it
+    unit: libc::rlim_t,
+}
+
+pub(crate) const KINDS: [Kind; 8] = [
+    Kind {
+        option: 'c',
+        description: "core file size (kbytes)",
+        resource: libc::RLIMIT_CORE,
+        unit: 1024,
+    },
+    Kind {
+        option: 'd',
+        description: "data seg size (kbytes)",
+        resource: libc::RLIMIT_DATA,
+        unit: 1024,
+    },
+    Kind {
+        option: 'f',
+        description: "file size (kbytes)",
+        resource: libc::RLIMIT_FSIZE,
+        unit: 1024,
+    },
+    Kind {
+        option: 'n',
+        description: "open files",
+        resource: libc::RLIMIT_NOFILE,
+        unit: 1,
+    },
+    Kind {
+        option: 's',
+        description: "stack size (kbytes)",
+        resource: libc::RLIMIT_STACK,
+        unit: 1024,
+    },
+    Kind {
+        option: 't',
+        description: "cpu time (seconds)",
+        resource: libc::RLIMIT_CPU,
+        unit: 1,
+    },
+    Kind {
+        option: 'u',
+        description: "max user processes",
+        resource: libc::RLIMIT_NPROC,
+        unit: 1,
+    },
+    Kind {
+        option: 'v',
+        description: "virtual memory (kbytes)",
+        resource: libc::RLIMIT_AS,
+        unit: 1024,
+    },
+];
+
+/// Soft and hard value of a limit, in the units of `ulimit`. None is
+/// unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Values {
+    pub(crate) soft: Option<libc::rlim_t>,
+    pub(crate) hard: Option<libc::rlim_t>,
+}
+
+/// A limit set for the commands of a session
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limit {
+    resource: Resource,
+    limit: libc::rlimit,
+}
+
+impl Kind {
+    pub(crate) fn by_option(option: char) -> Option<&'static Kind> {
+        KINDS.iter().find(|kind| kind.option == option)
+    }
+
+    /// The values set for a session, or else those of the shell process
+    pub(crate) fn values(&self, limits: &[Limit]) -> io::Result<Values> {
+        let limit = match limits.iter().find(|limit| limit.resource == self.resource) {
+            Some(limit) => limit.limit,
+            None => {
+                let mut limit = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                if unsafe { libc::getrlimit(self.resource, &mut limit) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                limit
+            }
+        };
+        Ok(Values {
+            soft: self.in_units(limit.rlim_cur),
+            hard: self.in_units(limit.rlim_max),
+        })
+    }
+
+    /// The limit for a session to set. Fails like setrlimit(2) would in
+    /// the commands, so the error shows up in `ulimit` rather than each
+    /// command failing to start.
+    pub(crate) fn limit(&self, values: Values, current: Values) -> io::Result<Limit> {
+        let exceeds =
+            |value: Option<libc::rlim_t>, bound: Option<libc::rlim_t>| match (value, bound) {
+                (_, None) => false,
+                (None, Some(_)) => true,
+                (Some(value), Some(bound)) => value > bound,
+            };
+        /* EINVAL, and EPERM for raising the hard limit without privileges */
+        if exceeds(values.soft, values.hard) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        if exceeds(values.hard, current.hard) && !may_raise() {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        Ok(Limit {
+            resource: self.resource,
+            limit: libc::rlimit {
+                rlim_cur: self.in_rlim(values.soft),
+                rlim_max: self.in_rlim(values.hard),
+            },
+        })
+    }
+
+    fn in_units(&self, value: libc::rlim_t) -> Option<libc::rlim_t> {
+        match value {
+            libc::RLIM_INFINITY => None,
+            value => Some(value / self.unit),
+        }
+    }
+
+    fn in_rlim(&self, value: Option<libc::rlim_t>) -> libc::rlim_t {
+        match value {
+            None => libc::RLIM_INFINITY,
+            Some(value) => value.saturating_mul(self.unit).min(libc::RLIM_INFINITY - 1),
+        }
+    }
+}
+
+/// Whether the shell may raise hard limits, having CAP_SYS_RESOURCE. Root
+/// in a container may not.
+fn may_raise() -> bool {
+    const CAP_SYS_RESOURCE: u32 = 24;
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("CapEff:"))
+                .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        })
+        .is_some_and(|caps| caps & 1 << CAP_SYS_RESOURCE != 0)
+}
+
+/// Replace the limit of the same resource, if one is set
+pub(crate) fn set(limits: &mut Vec<Limit>, limit: Limit) {
+    limits.retain(|set| set.resource != limit.resource);
+    limits.push(limit);
+}
+
+/// Set the limits in a command about to run. Only calls setrlimit(2), so
+/// it is safe between fork and exec.
+pub(crate) fn apply(limits: &[Limit]) -> io::Result<()> {
+    for limit in limits {
+        if unsafe { libc::setrlimit(limit.resource, &limit.limit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -1,3 +1,14 @@
-fn main() {
-    pieshell::run()
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    match pieshell::run() {
+        Ok(status) => {
+            println!("Exiting program");
+            ExitCode::from(status)
+        }
+        Err(error) => {
+            eprintln!("pieshell: {}", error);
+            ExitCode::from(error.exit_code() as u8)
+        }
+    }
 }
@@ -0,0 +1,95 @@
+//! The banner shown when a session starts, like the message of the day of a
+//! login. Its text, from `/etc/motd`, a configured file or the config itself,
+//! can name values of the board in braces, e.g. `{hostname}` or `{ip}`, so a
+//! console tells which unit it is attached to.
+
+use std::ffi::CStr;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::{pinout, sysinfo};
+
+/// The names known in banners. Other text in braces is left as it is.
+const VARIABLES: [&str; 5] = ["hostname", "ip", "model", "temperature", "uptime"];
+
+/// The banner with the values of the variables it names filled in. Values
+/// that can't be read are `unknown`.
+pub(crate) fn render(template: &str) -> String {
+    let mut banner = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        banner.push_str(&rest[..start]);
+        let name = rest[start + 1..]
+            .find('}')
+            .map(|end| &rest[start + 1..start + 1 + end])
+            .filter(|name| VARIABLES.contains(name));
+        match name {
+            Some(name) => {
+                banner.push_str(&value(name).unwrap_or_else(|| String::from("unknown")));
+                rest = &rest[start + name.len() + 2..];
+            }
+            None => {
+                banner.push('{');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    banner.push_str(rest);
+    banner
+}
+
+fn value(name: &str) -> Option<String> {
+    match name {
+        "hostname" => fs::read_to_string("/proc/sys/kernel/hostname")
+            .ok()
+            .map(|name| name.trim().to_owned()),
+        "ip" => addresses(),
+        "model" => pinout::model(),
+        "temperature" => sysinfo::temperature(),
+        "uptime" => sysinfo::uptime(),
+        _ => None,
+    }
+}
+
+/// The addresses of the interfaces that are up, other than loopback and
+/// link-local ones, like `eth0 192.168.1.20, wlan0 10.0.0.7`
+fn addresses() -> Option<String> {
+    let mut interfaces: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut interfaces) } != 0 {
+        return None;
+    }
+
+    let mut addresses = Vec::new();
+    let mut interface = interfaces;
+    while let Some(entry) = unsafe { interface.as_ref() } {
+        interface = entry.ifa_next;
+        let up = entry.ifa_flags & libc::IFF_UP as u32 != 0;
+        let loopback = entry.ifa_flags & libc::IFF_LOOPBACK as u32 != 0;
+        if !up || loopback || entry.ifa_addr.is_null() {
+            continue;
+        }
+        let address = match unsafe { (*entry.ifa_addr).sa_family } as i32 {
+            libc::AF_INET => {
+                let address = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)).to_string()
+            }
+            libc::AF_INET6 => {
+                let address = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                let address = Ipv6Addr::from(address.sin6_addr.s6_addr);
+                match address.is_unicast_link_local() {
+                    true => continue,
+                    false => address.to_string(),
+                }
+            }
+            _ => continue,
+        };
+        let name = unsafe { CStr::from_ptr(entry.ifa_name) }.to_string_lossy();
+        addresses.push(format!("{} {}", name, address));
+    }
+    unsafe { libc::freeifaddrs(interfaces) };
+
+    match addresses.is_empty() {
+        true => Some(String::from("none")),
+        false => Some(addresses.join(", ")),
+    }
+}
@@ -0,0 +1,72 @@
+//! Flags changing how commands run, switched with `set -e`, `set -u` and
+//! `set -x` or their long names with `set -o`. Flags of pieshell's own only
+//! have a long name.
+
+/// A set of the flags
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ShellOptions(u8);
+
+impl ShellOptions {
+    /// A failing command ends the script, or the session
+    pub(crate) const ERREXIT: ShellOptions = ShellOptions(1);
+    /// Expanding a variable that isn't set fails the command
+    pub(crate) const NOUNSET: ShellOptions = ShellOptions(1 << 1);
+    /// Commands are shown as they run, after expansion
+    pub(crate) const XTRACE: ShellOptions = ShellOptions(1 << 2);
+    /// A line with the exit status and duration follows each command line
+    pub(crate) const SUMMARY: ShellOptions = ShellOptions(1 << 3);
+    /// Typed lines can refer to the history with `!`, see `bang`
+    pub(crate) const HISTEXPAND: ShellOptions = ShellOptions(1 << 4);
+
+    pub(crate) fn contains(self, flags: ShellOptions) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    pub(crate) fn set(&mut self, flags: ShellOptions, on: bool) {
+        match on {
+            true => self.0 |= flags.0,
+            false => self.0 &= !flags.0,
+        }
+    }
+
+    /// The letters of the flags that are set, the value of `$-`
+    pub(crate) fn letters(self) -> String {
+        FLAGS
+            .iter()
+            .filter(|(_, _, flags)| self.contains(*flags))
+            .filter_map(|(letter, _, _)| *letter)
+            .collect()
+    }
+
+    /// The long names of the flags that are set and have no letter
+    pub(crate) fn unlettered(self) -> Vec<&'static str> {
+        FLAGS
+            .iter()
+            .filter(|(letter, _, flags)| letter.is_none() && self.contains(*flags))
+            .map(|(_, name, _)| *name)
+            .collect()
+    }
+}
+
+/// Every flag with its letter, if it has one, and long name
+pub(crate) const FLAGS: [(Option<char>, &str, ShellOptions); 5] = [
+    (Some('H'), "histexpand", ShellOptions::HISTEXPAND),
+    (Some('e'), "errexit", ShellOptions::ERREXIT),
+    (Some('u'), "nounset", ShellOptions::NOUNSET),
+    (Some('x'), "xtrace", ShellOptions::XTRACE),
+    (None, "summary", ShellOptions::SUMMARY),
+];
+
+pub(crate) fn by_letter(letter: char) -> Option<ShellOptions> {
+    FLAGS
+        .iter()
+        .find(|(flag, _, _)| *flag == Some(letter))
+        .map(|(_, _, flags)| *flags)
+}
+
+pub(crate) fn by_name(name: &str) -> Option<ShellOptions> {
+    FLAGS
+        .iter()
+        .find(|(_, flag, _)| *flag == name)
+        .map(|(_, _, flags)| *flags)
+}
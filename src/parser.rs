@@ -0,0 +1,1516 @@
+//! Tokenizer and parser for the shell language. It can be used on its own,
+//! e.g. by syntax highlighters and linters, and the AST can be serialized
+//! with serde.
+//!
+//! ```
+//! use pieshell::parser::{self, Command};
+//!
+//! let ast = parser::parse("ls -l /boot | grep config > files.txt").unwrap();
+//! let pipeline = &ast.items[0].and_or.first;
+//! assert_eq!(pipeline.commands.len(), 2);
+//! let Command::Simple(ls) = &pipeline.commands[0] else {
+//!     panic!("expected a simple command");
+//! };
+//! assert_eq!(ls.words[0].as_literal().as_deref(), Some("ls"));
+//! ```
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use serde::{Deserialize, Serialize};
+
+/// Transformations of parameters, as in `${SIZE@human}`
+const TRANSFORMATIONS: [&str; 2] = ["human", "bytes"];
+
+/// Words ending a part of a compound command, only recognized in place of a
+/// command
+const RESERVED: [&str; 7] = ["then", "elif", "else", "fi", "do", "done", "}"];
+
+/// A parsed line or script: and-or lists separated by `;`, `&` or newlines
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ast {
+    pub items: Vec<ListItem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListItem {
+    pub and_or: AndOr,
+    /// Ended with `&`, to run without waiting for it
+    pub background: bool,
+}
+
+/// Pipelines joined by `&&` and `||`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Connector {
+    /// `&&`: run the next pipeline if the previous one succeeded
+    And,
+    /// `||`: run the next pipeline if the previous one failed
+    Or,
+}
+
+/// Commands joined by `|`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+    /// Preceded by `time`, to report how long it took
+    #[serde(default)]
+    pub timed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    Simple(SimpleCommand),
+    /// `name() { ...; }`
+    Function(Function),
+    /// `if ...; then ...; elif ...; then ...; else ...; fi`
+    If(If),
+    /// `for NAME in WORD...; do ...; done`
+    For(For),
+    /// `while ...; do ...; done` or `until ...; do ...; done`
+    While(While),
+    /// `{ ...; }`, run in the shell itself
+    Group(Group),
+    /// `( ... )`, run apart from the shell, so what the commands change
+    /// doesn't outlast them
+    Subshell(Group),
+}
+
+/// Variable assignments, words and redirections, e.g.
+/// `LANG=C ls -l > files.txt`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimpleCommand {
+    pub assignments: Vec<Assignment>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+/// A function definition, run as a command when it is called
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Function {
+    pub name: String,
+    pub body: Ast,
+}
+
+/// Commands run as one, with redirections applying to all of them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Group {
+    pub body: Ast,
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct If {
+    /// Conditions with the commands run if they succeed, tried in turn
+    pub branches: Vec<(Ast, Ast)>,
+    /// Commands run if no condition succeeded
+    pub otherwise: Option<Ast>,
+    /// Redirections applying to all the commands, as for a `Group`
+    #[serde(default)]
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct For {
+    pub name: String,
+    /// The words to loop over, or `None` for the arguments of the function
+    /// running, as in `for NAME; do`
+    pub words: Option<Vec<Word>>,
+    pub body: Ast,
+    #[serde(default)]
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct While {
+    pub condition: Ast,
+    pub body: Ast,
+    /// Loop while the condition fails instead
+    pub until: bool,
+    #[serde(default)]
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Assignment {
+    pub name: String,
+    pub value: Word,
+}
+
+/// A word before expansion, keeping track of what was quoted
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Word {
+    pub parts: Vec<WordPart>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WordPart {
+    Literal(String),
+    SingleQuoted(String),
+    DoubleQuoted(Vec<WordPart>),
+    /// `$NAME`, `${NAME}` or a special parameter like `$?`. Includes the
+    /// transformation of `${NAME@human}`.
+    Parameter(String),
+    /// `~` or `~user` at the start of a word
+    Tilde(String),
+    /// `<(LIST)` or `>(LIST)`, the path of a pipe from the output of the
+    /// commands or, if `writable`, to their input
+    ProcessSubstitution {
+        commands: Ast,
+        writable: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Redirect {
+    /// The file descriptor redirected, if given explicitly as in `2>`
+    pub fd: Option<u32>,
+    pub kind: RedirectKind,
+    pub target: Word,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RedirectKind {
+    /// `<`
+    Input,
+    /// `>`
+    Output,
+    /// `>>`
+    Append,
+    /// `<&`
+    DuplicateInput,
+    /// `>&`
+    DuplicateOutput,
+    /// `<<` or `<<-`, with the lines up to the delimiter as the target
+    HereDocument,
+    /// `<<<`
+    HereString,
+}
+
+impl RedirectKind {
+    /// The file descriptor redirected when none is given
+    pub fn default_fd(self) -> u32 {
+        match self {
+            RedirectKind::Input
+            | RedirectKind::DuplicateInput
+            | RedirectKind::HereDocument
+            | RedirectKind::HereString => 0,
+            RedirectKind::Output | RedirectKind::Append | RedirectKind::DuplicateOutput => 1,
+        }
+    }
+}
+
+impl Word {
+    /// A word consisting of unquoted text
+    pub fn literal(text: &str) -> Word {
+        Word {
+            parts: vec![WordPart::Literal(text.to_owned())],
+        }
+    }
+
+    /// The text of the word if it contains nothing to expand, with quotes
+    /// removed
+    pub fn as_literal(&self) -> Option<String> {
+        let mut text = String::new();
+        for part in &self.parts {
+            match part {
+                WordPart::Literal(literal) | WordPart::SingleQuoted(literal) => {
+                    text.push_str(literal)
+                }
+                WordPart::DoubleQuoted(parts) => text.push_str(
+                    &Word {
+                        parts: parts.clone(),
+                    }
+                    .as_literal()?,
+                ),
+                WordPart::Parameter(_)
+                | WordPart::Tilde(_)
+                | WordPart::ProcessSubstitution { .. } => return None,
+            }
+        }
+        Some(text)
+    }
+
+    /// Whether any part of the word was quoted
+    pub fn is_quoted(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, WordPart::SingleQuoted(_) | WordPart::DoubleQuoted(_)))
+    }
+}
+
+/* The AST is displayed as equivalent shell source, e.g. to show commands
+of background jobs */
+
+impl fmt::Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", item)?;
+            if !item.background && i + 1 < self.items.len() {
+                write!(f, ";")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ListItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.and_or)?;
+        if self.background {
+            write!(f, " &")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for AndOr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.first)?;
+        for (connector, pipeline) in &self.rest {
+            let connector = match connector {
+                Connector::And => "&&",
+                Connector::Or => "||",
+            };
+            write!(f, " {} {}", connector, pipeline)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.timed {
+            write!(f, "time ")?;
+        }
+        for (i, command) in self.commands.iter().enumerate() {
+            if i > 0 {
+                write!(f, " | ")?;
+            }
+            write!(f, "{}", command)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Simple(command) => write!(f, "{}", command),
+            Command::Function(function) => {
+                write!(f, "{}() {{ ", function.name)?;
+                write_body(f, &function.body)?;
+                write!(f, " }}")
+            }
+            Command::If(command) => {
+                for (i, (condition, body)) in command.branches.iter().enumerate() {
+                    let keyword = match i {
+                        0 => "if",
+                        _ => " elif",
+                    };
+                    write!(f, "{} ", keyword)?;
+                    write_body(f, condition)?;
+                    write!(f, " then ")?;
+                    write_body(f, body)?;
+                }
+                if let Some(otherwise) = &command.otherwise {
+                    write!(f, " else ")?;
+                    write_body(f, otherwise)?;
+                }
+                write!(f, " fi")?;
+                write_redirects(f, &command.redirects)
+            }
+            Command::For(command) => {
+                write!(f, "for {}", command.name)?;
+                if let Some(words) = &command.words {
+                    write!(f, " in")?;
+                    for word in words {
+                        write!(f, " {}", word)?;
+                    }
+                }
+                write!(f, "; do ")?;
+                write_body(f, &command.body)?;
+                write!(f, " done")?;
+                write_redirects(f, &command.redirects)
+            }
+            Command::While(command) => {
+                let keyword = match command.until {
+                    true => "until",
+                    false => "while",
+                };
+                write!(f, "{} ", keyword)?;
+                write_body(f, &command.condition)?;
+                write!(f, " do ")?;
+                write_body(f, &command.body)?;
+                write!(f, " done")?;
+                write_redirects(f, &command.redirects)
+            }
+            Command::Group(group) => {
+                write!(f, "{{ ")?;
+                write_body(f, &group.body)?;
+                write!(f, " }}")?;
+                write_redirects(f, &group.redirects)
+            }
+            Command::Subshell(group) => {
+                write!(f, "({})", group.body)?;
+                write_redirects(f, &group.redirects)
+            }
+        }
+    }
+}
+
+fn write_redirects(f: &mut fmt::Formatter<'_>, redirects: &[Redirect]) -> fmt::Result {
+    for redirect in redirects {
+        write!(f, " {}", redirect)?;
+    }
+    Ok(())
+}
+
+/// Write the commands of a compound command, ended by `;` unless they run in
+/// the background
+fn write_body(f: &mut fmt::Formatter<'_>, body: &Ast) -> fmt::Result {
+    write!(f, "{}", body)?;
+    match body.items.last() {
+        Some(item) if item.background => Ok(()),
+        _ => write!(f, ";"),
+    }
+}
+
+impl fmt::Display for SimpleCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let assignments = self
+            .assignments
+            .iter()
+            .map(|assignment| format!("{}={}", assignment.name, assignment.value));
+        let words = self.words.iter().map(Word::to_string);
+        let redirects = self.redirects.iter().map(Redirect::to_string);
+        let parts: Vec<String> = assignments.chain(words).chain(redirects).collect();
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(fd) = self.fd {
+            write!(f, "{}", fd)?;
+        }
+        let operator = match self.kind {
+            RedirectKind::Input => "<",
+            RedirectKind::Output => ">",
+            RedirectKind::Append => ">>",
+            RedirectKind::DuplicateInput => "<&",
+            RedirectKind::DuplicateOutput => ">&",
+            /* Here-documents take lines of their own, so they are shown as
+            the here-string giving the same input */
+            RedirectKind::HereDocument => {
+                let mut parts = match &self.target.parts[..] {
+                    [WordPart::DoubleQuoted(parts)] => parts.clone(),
+                    parts => parts.to_vec(),
+                };
+                /* Double quotes, as the lines may hold single ones */
+                for part in &mut parts {
+                    if let WordPart::SingleQuoted(text) = part {
+                        *part = WordPart::Literal(std::mem::take(text));
+                    }
+                }
+                if let Some(WordPart::Literal(text)) = parts.last_mut() {
+                    if text.ends_with('\n') {
+                        text.pop();
+                    }
+                }
+                let body = Word {
+                    parts: vec![WordPart::DoubleQuoted(parts)],
+                };
+                return write!(f, "<<<{}", body);
+            }
+            RedirectKind::HereString => "<<<",
+        };
+        write!(f, "{}{}", operator, self.target)
+    }
+}
+
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for part in &self.parts {
+            match part {
+                WordPart::Literal(text) => {
+                    for c in text.chars() {
+                        if c.is_whitespace() || "|&;<>()$`'\"\\#~*?".contains(c) {
+                            write!(f, "\\")?;
+                        }
+                        write!(f, "{}", c)?;
+                    }
+                }
+                WordPart::SingleQuoted(text) => write!(f, "'{}'", text)?,
+                WordPart::DoubleQuoted(parts) => {
+                    write!(f, "\"")?;
+                    for part in parts {
+                        match part {
+                            WordPart::Literal(text) => {
+                                for c in text.chars() {
+                                    if "$`\"\\".contains(c) {
+                                        write!(f, "\\")?;
+                                    }
+                                    write!(f, "{}", c)?;
+                                }
+                            }
+                            WordPart::Parameter(name) => write!(f, "${{{}}}", name)?,
+                            _ => {}
+                        }
+                    }
+                    write!(f, "\"")?;
+                }
+                WordPart::Parameter(name) => write!(f, "${{{}}}", name)?,
+                WordPart::Tilde(user) => write!(f, "~{}", user)?,
+                WordPart::ProcessSubstitution { commands, writable } => {
+                    let direction = if *writable { '>' } else { '<' };
+                    write!(f, "{}({})", direction, commands)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Byte offsets of a token in the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenKind {
+    Word(Word),
+    Operator(Operator),
+    /// The file descriptor number in front of a redirection, as in `2>`
+    IoNumber(u32),
+    Comment,
+    Newline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operator {
+    And,
+    Or,
+    Pipe,
+    Semicolon,
+    Background,
+    Less,
+    Great,
+    DoubleGreat,
+    LessAnd,
+    GreatAnd,
+    /// `<<`
+    DoubleLess,
+    /// `<<-`, which strips tabs at the start of the lines
+    DoubleLessDash,
+    /// `<<<`
+    TripleLess,
+    LeftParen,
+    RightParen,
+}
+
+impl Operator {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operator::And => "&&",
+            Operator::Or => "||",
+            Operator::Pipe => "|",
+            Operator::Semicolon => ";",
+            Operator::Background => "&",
+            Operator::Less => "<",
+            Operator::Great => ">",
+            Operator::DoubleGreat => ">>",
+            Operator::LessAnd => "<&",
+            Operator::GreatAnd => ">&",
+            Operator::DoubleLess => "<<",
+            Operator::DoubleLessDash => "<<-",
+            Operator::TripleLess => "<<<",
+            Operator::LeftParen => "(",
+            Operator::RightParen => ")",
+        }
+    }
+
+    fn redirect_kind(self) -> Option<RedirectKind> {
+        match self {
+            Operator::Less => Some(RedirectKind::Input),
+            Operator::Great => Some(RedirectKind::Output),
+            Operator::DoubleGreat => Some(RedirectKind::Append),
+            Operator::LessAnd => Some(RedirectKind::DuplicateInput),
+            Operator::GreatAnd => Some(RedirectKind::DuplicateOutput),
+            Operator::DoubleLess | Operator::DoubleLessDash => Some(RedirectKind::HereDocument),
+            Operator::TripleLess => Some(RedirectKind::HereString),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParseError {
+    pub message: String,
+    /// Byte offset in the input where the error was found
+    pub position: usize,
+    /// The input ended early, e.g. inside quotes or after `|`, so more input
+    /// could complete it
+    pub incomplete: bool,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Split input into tokens, keeping comments and newlines
+pub fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokenizer = Tokenizer {
+        input,
+        chars: input.char_indices().peekable(),
+    };
+    tokenizer.tokens(false)
+}
+
+/// A here-document whose delimiter was read but not its lines yet
+struct PendingDocument {
+    /// Index of the token of the delimiter, which the lines replace
+    token: usize,
+    delimiter: String,
+    /// The delimiter was quoted, so the lines are taken literally
+    quoted: bool,
+    /// `<<-` was used
+    strip_tabs: bool,
+}
+
+/// Parse a line or a whole script
+pub fn parse(input: &str) -> Result<Ast, ParseError> {
+    let tokens = tokenize(input)?
+        .into_iter()
+        .filter(|token| token.kind != TokenKind::Comment)
+        .collect();
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        end: input.len(),
+        subshells: 0,
+    };
+
+    parser.list(&[])
+}
+
+fn is_operator_start(c: char) -> bool {
+    matches!(c, '&' | '|' | ';' | '<' | '>' | '(' | ')')
+}
+
+/// Characters allowed in variable names
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Whether `name` can be assigned to
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(is_name_char)
+}
+
+struct Tokenizer<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl Tokenizer<'_> {
+    fn position(&mut self) -> usize {
+        match self.chars.peek() {
+            Some(&(position, _)) => position,
+            None => self.input.len(),
+        }
+    }
+
+    fn error(&mut self, message: &str, incomplete: bool) -> ParseError {
+        ParseError {
+            message: message.to_owned(),
+            position: self.position(),
+            incomplete,
+        }
+    }
+
+    /// Read tokens up to the end of input or, if `nested`, up to the `)`
+    /// closing a process substitution, which is skipped
+    fn tokens(&mut self, nested: bool) -> Result<Vec<Token>, ParseError> {
+        let mut tokens = Vec::new();
+        /* Here-documents whose lines start after the next newline */
+        let mut pending: Vec<PendingDocument> = Vec::new();
+        /* Parentheses opened inside a process substitution */
+        let mut depth = 0;
+        let mut closed = false;
+
+        while let Some(&(start, c)) = self.chars.peek() {
+            let kind = match c {
+                ' ' | '\t' => {
+                    self.chars.next();
+                    continue;
+                }
+                '\n' => {
+                    self.chars.next();
+                    tokens.push(Token {
+                        kind: TokenKind::Newline,
+                        span: Span {
+                            start,
+                            end: start + 1,
+                        },
+                    });
+                    for document in pending.drain(..) {
+                        let body = self.here_document(&document)?;
+                        tokens[document.token].kind = TokenKind::Word(body);
+                    }
+                    continue;
+                }
+                '#' => {
+                    while self.chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                    TokenKind::Comment
+                }
+                _ if is_operator_start(c) && !self.at_process_substitution() => {
+                    let operator = self.operator();
+                    match operator {
+                        Operator::LeftParen => depth += 1,
+                        Operator::RightParen if nested && depth == 0 => {
+                            closed = true;
+                            break;
+                        }
+                        Operator::RightParen => depth -= 1,
+                        _ => {}
+                    }
+                    TokenKind::Operator(operator)
+                }
+                _ => {
+                    let word = self.word()?;
+                    /* Digits directly followed by a redirection give the file
+                    descriptor to redirect */
+                    let next = self.chars.peek().map(|&(_, c)| c);
+                    match word.parts.as_slice() {
+                        [WordPart::Literal(digits)]
+                            if matches!(next, Some('<' | '>'))
+                                && digits.chars().all(|c| c.is_ascii_digit()) =>
+                        {
+                            match digits.parse() {
+                                Ok(fd) => TokenKind::IoNumber(fd),
+                                Err(_) => TokenKind::Word(word),
+                            }
+                        }
+                        _ => TokenKind::Word(word),
+                    }
+                }
+            };
+
+            let end = self.position();
+            let after_operator = match tokens.last().map(|token| &token.kind) {
+                Some(TokenKind::Operator(Operator::DoubleLess)) => Some(false),
+                Some(TokenKind::Operator(Operator::DoubleLessDash)) => Some(true),
+                _ => None,
+            };
+            if let (Some(strip_tabs), TokenKind::Word(word)) = (after_operator, &kind) {
+                let raw = &self.input[start..end];
+                pending.push(PendingDocument {
+                    token: tokens.len(),
+                    delimiter: word.as_literal().unwrap_or_else(|| raw.to_owned()),
+                    quoted: raw.contains(['\'', '"', '\\']),
+                    strip_tabs,
+                });
+            }
+            tokens.push(Token {
+                kind,
+                span: Span { start, end },
+            });
+        }
+
+        if let Some(document) = pending.first() {
+            return Err(ParseError {
+                message: format!(
+                    "here-document delimited by '{}' unfinished",
+                    document.delimiter
+                ),
+                position: self.input.len(),
+                incomplete: true,
+            });
+        }
+        if nested && !closed {
+            return Err(self.error("unterminated process substitution", true));
+        }
+        Ok(tokens)
+    }
+
+    /// Whether `<(` or `>(` of a process substitution comes next
+    fn at_process_substitution(&mut self) -> bool {
+        let rest = &self.input[self.position()..];
+        rest.starts_with("<(") || rest.starts_with(">(")
+    }
+
+    /// Parse the commands of a process substitution, after its `<(` or `>(`
+    fn process_substitution(&mut self, writable: bool) -> Result<WordPart, ParseError> {
+        let tokens = self
+            .tokens(true)?
+            .into_iter()
+            .filter(|token| token.kind != TokenKind::Comment)
+            .collect();
+        /* Up to the `)`, the commands are complete even if they don't parse */
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            end: self.position() - 1,
+            subshells: 0,
+        };
+        let commands = parser.list(&[]).map_err(|error| ParseError {
+            incomplete: false,
+            ..error
+        })?;
+        Ok(WordPart::ProcessSubstitution { commands, writable })
+    }
+
+    fn operator(&mut self) -> Operator {
+        let (_, c) = self.chars.next().expect("operator should have been peeked");
+        let next = self.chars.peek().map(|&(_, c)| c);
+        let (operator, two_chars) = match (c, next) {
+            ('&', Some('&')) => (Operator::And, true),
+            ('&', _) => (Operator::Background, false),
+            ('|', Some('|')) => (Operator::Or, true),
+            ('|', _) => (Operator::Pipe, false),
+            ('<', Some('&')) => (Operator::LessAnd, true),
+            ('<', Some('<')) => {
+                self.chars.next();
+                return match self.chars.peek().map(|&(_, c)| c) {
+                    Some('<') => {
+                        self.chars.next();
+                        Operator::TripleLess
+                    }
+                    Some('-') => {
+                        self.chars.next();
+                        Operator::DoubleLessDash
+                    }
+                    _ => Operator::DoubleLess,
+                };
+            }
+            ('<', _) => (Operator::Less, false),
+            ('>', Some('>')) => (Operator::DoubleGreat, true),
+            ('>', Some('&')) => (Operator::GreatAnd, true),
+            ('>', _) => (Operator::Great, false),
+            ('(', _) => (Operator::LeftParen, false),
+            (')', _) => (Operator::RightParen, false),
+            _ => (Operator::Semicolon, false),
+        };
+        if two_chars {
+            self.chars.next();
+        }
+
+        operator
+    }
+
+    fn word(&mut self) -> Result<Word, ParseError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+
+        if self.at_process_substitution() {
+            let writable = self.chars.next().is_some_and(|(_, c)| c == '>');
+            self.chars.next();
+            parts.push(self.process_substitution(writable)?);
+        } else if self.chars.next_if(|&(_, c)| c == '~').is_some() {
+            /* "~" or "~user" at the start, up to the first '/' */
+            let mut user = String::new();
+            while let Some((_, c)) = self
+                .chars
+                .next_if(|&(_, c)| is_name_char(c) || c == '.' || c == '-')
+            {
+                user.push(c);
+            }
+            match self.chars.peek() {
+                Some(&(_, c)) if c != '/' && !c.is_whitespace() && !is_operator_start(c) => {
+                    literal.push('~');
+                    literal.push_str(&user);
+                }
+                _ => parts.push(WordPart::Tilde(user)),
+            }
+        }
+
+        while let Some(&(_, c)) = self.chars.peek() {
+            if c.is_whitespace() || is_operator_start(c) {
+                break;
+            }
+            self.chars.next();
+
+            match c {
+                '\\' => match self.chars.next() {
+                    /* Line continuation */
+                    Some((_, '\n')) => {}
+                    /* Escaped, these don't take part in brace expansion */
+                    Some((_, c @ ('{' | '}' | ','))) => {
+                        flush_literal(&mut parts, &mut literal);
+                        parts.push(WordPart::SingleQuoted(c.to_string()));
+                    }
+                    Some((_, c)) => literal.push(c),
+                    None => return Err(self.error("unexpected end of input after '\\'", true)),
+                },
+                '\'' => {
+                    let mut quoted = String::new();
+                    loop {
+                        match self.chars.next() {
+                            Some((_, '\'')) => break,
+                            Some((_, c)) => quoted.push(c),
+                            None => return Err(self.error("unterminated single quote", true)),
+                        }
+                    }
+                    flush_literal(&mut parts, &mut literal);
+                    parts.push(WordPart::SingleQuoted(quoted));
+                }
+                '"' => {
+                    let quoted = self.double_quoted()?;
+                    flush_literal(&mut parts, &mut literal);
+                    parts.push(WordPart::DoubleQuoted(quoted));
+                }
+                '$' => match self.parameter()? {
+                    Some(name) => {
+                        flush_literal(&mut parts, &mut literal);
+                        parts.push(WordPart::Parameter(name));
+                    }
+                    None => literal.push('$'),
+                },
+                c => literal.push(c),
+            }
+        }
+
+        flush_literal(&mut parts, &mut literal);
+        Ok(Word { parts })
+    }
+
+    /// Parse the inside of double quotes, after the opening quote
+    fn double_quoted(&mut self) -> Result<Vec<WordPart>, ParseError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => break,
+                Some((_, '\\')) => match self.chars.next() {
+                    Some((_, c @ ('$' | '`' | '"' | '\\'))) => literal.push(c),
+                    Some((_, '\n')) => {}
+                    Some((_, c)) => {
+                        literal.push('\\');
+                        literal.push(c);
+                    }
+                    None => return Err(self.error("unterminated double quote", true)),
+                },
+                Some((_, '$')) => match self.parameter()? {
+                    Some(name) => {
+                        flush_literal(&mut parts, &mut literal);
+                        parts.push(WordPart::Parameter(name));
+                    }
+                    None => literal.push('$'),
+                },
+                Some((_, c)) => literal.push(c),
+                None => return Err(self.error("unterminated double quote", true)),
+            }
+        }
+
+        flush_literal(&mut parts, &mut literal);
+        Ok(parts)
+    }
+
+    /// Read the lines of a here-document, after the newline following its
+    /// delimiter, up to the line holding only the delimiter. Unless the
+    /// delimiter was quoted, parameters in them are expanded and `\` quotes
+    /// `$`, `` ` `` and itself, as in double quotes.
+    fn here_document(&mut self, document: &PendingDocument) -> Result<Word, ParseError> {
+        let mut body = String::new();
+        loop {
+            let mut line = String::new();
+            let mut ended = false;
+            for (_, c) in self.chars.by_ref() {
+                if c == '\n' {
+                    ended = true;
+                    break;
+                }
+                line.push(c);
+            }
+            let line = match document.strip_tabs {
+                true => line.trim_start_matches('\t'),
+                false => &line,
+            };
+            if line == document.delimiter {
+                break;
+            }
+            if !ended {
+                return Err(ParseError {
+                    message: format!(
+                        "here-document delimited by '{}' unfinished",
+                        document.delimiter
+                    ),
+                    position: self.input.len(),
+                    incomplete: true,
+                });
+            }
+            body.push_str(line);
+            body.push('\n');
+        }
+
+        if document.quoted {
+            return Ok(Word {
+                parts: vec![WordPart::SingleQuoted(body)],
+            });
+        }
+        let mut lines = Tokenizer {
+            input: &body,
+            chars: body.char_indices().peekable(),
+        };
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        while let Some((_, c)) = lines.chars.next() {
+            match c {
+                '\\' => match lines.chars.next() {
+                    Some((_, c @ ('$' | '`' | '\\'))) => literal.push(c),
+                    Some((_, '\n')) => {}
+                    Some((_, c)) => {
+                        literal.push('\\');
+                        literal.push(c);
+                    }
+                    None => literal.push('\\'),
+                },
+                '$' => match lines.parameter()? {
+                    Some(name) => {
+                        flush_literal(&mut parts, &mut literal);
+                        parts.push(WordPart::Parameter(name));
+                    }
+                    None => literal.push('$'),
+                },
+                c => literal.push(c),
+            }
+        }
+        flush_literal(&mut parts, &mut literal);
+        Ok(Word {
+            parts: vec![WordPart::DoubleQuoted(parts)],
+        })
+    }
+
+    /// Parse a parameter name after '$'. Returns `None` if the '$' doesn't
+    /// start a parameter and is meant literally.
+    fn parameter(&mut self) -> Result<Option<String>, ParseError> {
+        match self.chars.peek().map(|&(_, c)| c) {
+            Some('{') => {
+                self.chars.next();
+                let mut name = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some((_, '}')) => break,
+                        Some((_, c)) => name.push(c),
+                        None => return Err(self.error("unterminated '${'", true)),
+                    }
+                }
+                if name.is_empty() {
+                    return Err(self.error("bad substitution '${}'", false));
+                }
+                if let Some((_, transformation)) =
+                    name.split_once('@').filter(|(name, _)| !name.is_empty())
+                {
+                    if !TRANSFORMATIONS.contains(&transformation) {
+                        let message = format!("bad substitution '${{{}}}'", name);
+                        return Err(self.error(&message, false));
+                    }
+                }
+                Ok(Some(name))
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::new();
+                while let Some((_, c)) = self.chars.next_if(|&(_, c)| is_name_char(c)) {
+                    name.push(c);
+                }
+                Ok(Some(name))
+            }
+            Some(c @ ('0'..='9' | '?' | '$' | '!' | '#' | '@' | '*' | '-')) => {
+                self.chars.next();
+                Ok(Some(c.to_string()))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+fn flush_literal(parts: &mut Vec<WordPart>, literal: &mut String) {
+    if !literal.is_empty() {
+        parts.push(WordPart::Literal(std::mem::take(literal)));
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    /// Length of the input, the position of errors at its end
+    end: usize,
+    /// Subshells being parsed, whose commands end at a `)`
+    subshells: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.position).map(|token| &token.kind)
+    }
+
+    fn next(&mut self) -> Option<TokenKind> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .map(|token| token.kind.clone());
+        self.position += 1;
+        token
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&TokenKind::Newline) {
+            self.position += 1;
+        }
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        match self.tokens.get(self.position) {
+            Some(token) => ParseError {
+                message,
+                position: token.span.start,
+                incomplete: false,
+            },
+            None => ParseError {
+                message,
+                position: self.end,
+                incomplete: true,
+            },
+        }
+    }
+
+    fn unexpected(&self) -> ParseError {
+        let token = match self.peek() {
+            Some(TokenKind::Operator(operator)) => operator.as_str().to_owned(),
+            Some(TokenKind::Newline) | None => String::from("newline"),
+            Some(TokenKind::IoNumber(fd)) => fd.to_string(),
+            Some(TokenKind::Word(word)) => word.as_literal().unwrap_or_default(),
+            Some(TokenKind::Comment) => String::from("#"),
+        };
+        self.error(format!("unexpected token '{}'", token))
+    }
+
+    /// Parse and-or lists up to the end of input, or up to one of the
+    /// reserved words `ends` in place of a command, which is left for the
+    /// caller
+    fn list(&mut self, ends: &[&str]) -> Result<Ast, ParseError> {
+        let mut items = Vec::new();
+
+        loop {
+            self.skip_newlines();
+            if self.peek().is_none() && ends.is_empty() {
+                break;
+            }
+            if self.peek().is_none() {
+                return Err(self.error(format!("'{}' expected", ends.join("' or '"))));
+            }
+            if ends.iter().any(|end| self.at_word(end)) || self.at_subshell_end() {
+                break;
+            }
+
+            let and_or = self.and_or()?;
+            let background = match self.peek() {
+                Some(TokenKind::Operator(Operator::Semicolon)) | Some(TokenKind::Newline) => {
+                    self.position += 1;
+                    false
+                }
+                Some(TokenKind::Operator(Operator::Background)) => {
+                    self.position += 1;
+                    true
+                }
+                None => false,
+                Some(_) if self.at_subshell_end() => false,
+                Some(_) => return Err(self.unexpected()),
+            };
+            items.push(ListItem { and_or, background });
+        }
+
+        Ok(Ast { items })
+    }
+
+    fn and_or(&mut self) -> Result<AndOr, ParseError> {
+        let first = self.pipeline()?;
+        let mut rest = Vec::new();
+
+        loop {
+            let connector = match self.peek() {
+                Some(TokenKind::Operator(Operator::And)) => Connector::And,
+                Some(TokenKind::Operator(Operator::Or)) => Connector::Or,
+                _ => break,
+            };
+            self.position += 1;
+            /* The next pipeline may be on the next line */
+            self.skip_newlines();
+            rest.push((connector, self.pipeline()?));
+        }
+
+        Ok(AndOr { first, rest })
+    }
+
+    fn pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        /* `time` alone is run as a command */
+        let timed = self.at_word("time")
+            && matches!(
+                self.tokens.get(self.position + 1).map(|token| &token.kind),
+                Some(TokenKind::Word(_))
+            );
+        if timed {
+            self.position += 1;
+        }
+        let mut commands = vec![self.command()?];
+
+        while self.peek() == Some(&TokenKind::Operator(Operator::Pipe)) {
+            self.position += 1;
+            self.skip_newlines();
+            commands.push(self.command()?);
+        }
+
+        Ok(Pipeline { commands, timed })
+    }
+
+    /// Whether the `)` ending a subshell comes next
+    fn at_subshell_end(&self) -> bool {
+        self.subshells > 0 && self.peek() == Some(&TokenKind::Operator(Operator::RightParen))
+    }
+
+    /// Whether the next token is the unquoted word `text`
+    fn at_word(&self, text: &str) -> bool {
+        match self.peek() {
+            Some(TokenKind::Word(word)) => {
+                !word.is_quoted() && word.as_literal().as_deref() == Some(text)
+            }
+            _ => false,
+        }
+    }
+
+    /// Parse the commands of a part of a compound command, of which there
+    /// must be at least one
+    fn body(&mut self, ends: &[&str]) -> Result<Ast, ParseError> {
+        let body = self.list(ends)?;
+        match body.items.is_empty() {
+            true => Err(self.unexpected()),
+            false => Ok(body),
+        }
+    }
+
+    /// Skip the reserved word `word`, which must come next
+    fn expect(&mut self, word: &str) -> Result<(), ParseError> {
+        match self.at_word(word) {
+            true => {
+                self.position += 1;
+                Ok(())
+            }
+            false if self.peek().is_none() => Err(self.error(format!("'{}' expected", word))),
+            false => Err(self.unexpected()),
+        }
+    }
+
+    fn command(&mut self) -> Result<Command, ParseError> {
+        if let Some(name) = self.function_name() {
+            return self.function(name);
+        }
+        if self.at_word("if") {
+            return self.if_clause();
+        }
+        if self.at_word("for") {
+            return self.for_clause();
+        }
+        if self.at_word("while") || self.at_word("until") {
+            return self.while_clause();
+        }
+        if self.at_word("{") {
+            return self.group();
+        }
+        if self.peek() == Some(&TokenKind::Operator(Operator::LeftParen)) {
+            return self.subshell();
+        }
+        if RESERVED.iter().any(|word| self.at_word(word)) {
+            return Err(self.unexpected());
+        }
+        let mut command = SimpleCommand::default();
+
+        loop {
+            match self.peek() {
+                Some(TokenKind::Word(_)) => {
+                    let word = match self.next() {
+                        Some(TokenKind::Word(word)) => word,
+                        _ => unreachable!("peeked a word"),
+                    };
+                    /* Assignments are only recognized before the command
+                    name */
+                    match assignment(&word) {
+                        Some(assignment) if command.words.is_empty() => {
+                            command.assignments.push(assignment)
+                        }
+                        _ => command.words.push(word),
+                    }
+                }
+                Some(TokenKind::IoNumber(fd)) => {
+                    let fd = *fd;
+                    self.position += 1;
+                    command.redirects.push(self.redirect(Some(fd))?);
+                }
+                Some(TokenKind::Operator(operator)) if operator.redirect_kind().is_some() => {
+                    command.redirects.push(self.redirect(None)?);
+                }
+                _ => break,
+            }
+        }
+
+        if command.assignments.is_empty()
+            && command.words.is_empty()
+            && command.redirects.is_empty()
+        {
+            return Err(self.unexpected());
+        }
+        Ok(Command::Simple(command))
+    }
+
+    /// The name of a function being defined, as in `name() {`
+    fn function_name(&self) -> Option<String> {
+        let name = match self.peek() {
+            Some(TokenKind::Word(word)) if !word.is_quoted() => word.as_literal()?,
+            _ => return None,
+        };
+        let parentheses = self.tokens.get(self.position + 1..self.position + 3)?;
+        match parentheses
+            .iter()
+            .map(|token| &token.kind)
+            .collect::<Vec<_>>()[..]
+        {
+            [TokenKind::Operator(Operator::LeftParen), TokenKind::Operator(Operator::RightParen)]
+                if is_valid_name(&name) =>
+            {
+                Some(name)
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse a function definition after its name, up to the end of its body
+    fn function(&mut self, name: String) -> Result<Command, ParseError> {
+        self.position += 3;
+        /* The body may start on the next line */
+        self.skip_newlines();
+        if !self.at_word("{") {
+            return Err(match self.peek() {
+                Some(_) => self.error(String::from("'{' expected")),
+                None => self.unexpected(),
+            });
+        }
+        self.position += 1;
+
+        let body = self.body(&["}"])?;
+        self.position += 1;
+        Ok(Command::Function(Function { name, body }))
+    }
+
+    fn group(&mut self) -> Result<Command, ParseError> {
+        self.position += 1;
+        let body = self.body(&["}"])?;
+        self.position += 1;
+        Ok(Command::Group(Group {
+            body,
+            redirects: self.redirects()?,
+        }))
+    }
+
+    fn subshell(&mut self) -> Result<Command, ParseError> {
+        self.position += 1;
+        self.subshells += 1;
+        let body = self.list(&[]);
+        self.subshells -= 1;
+        let body = body?;
+        match self.peek() {
+            Some(TokenKind::Operator(Operator::RightParen)) if !body.items.is_empty() => {
+                self.position += 1
+            }
+            None => return Err(self.error(String::from("')' expected"))),
+            Some(_) => return Err(self.unexpected()),
+        }
+        Ok(Command::Subshell(Group {
+            body,
+            redirects: self.redirects()?,
+        }))
+    }
+
+    fn if_clause(&mut self) -> Result<Command, ParseError> {
+        self.position += 1;
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+
+        loop {
+            let condition = self.body(&["then"])?;
+            self.position += 1;
+            let body = self.body(&["elif", "else", "fi"])?;
+            branches.push((condition, body));
+
+            if self.at_word("elif") {
+                self.position += 1;
+                continue;
+            }
+            if self.at_word("else") {
+                self.position += 1;
+                otherwise = Some(self.body(&["fi"])?);
+            }
+            self.position += 1;
+            break;
+        }
+
+        Ok(Command::If(If {
+            branches,
+            otherwise,
+            redirects: self.redirects()?,
+        }))
+    }
+
+    fn for_clause(&mut self) -> Result<Command, ParseError> {
+        self.position += 1;
+        let name = match self.peek() {
+            Some(TokenKind::Word(word)) if !word.is_quoted() => word.as_literal(),
+            _ => None,
+        };
+        let name = match name.filter(|name| is_valid_name(name)) {
+            Some(name) => name,
+            None => return Err(self.unexpected()),
+        };
+        self.position += 1;
+
+        /* `for NAME; do` and `for NAME do` loop over the arguments */
+        self.skip_newlines();
+        let mut words = None;
+        if self.at_word("in") {
+            self.position += 1;
+            let mut list = Vec::new();
+            while let Some(TokenKind::Word(word)) = self.peek() {
+                list.push(word.clone());
+                self.position += 1;
+            }
+            words = Some(list);
+            match self.peek() {
+                Some(TokenKind::Operator(Operator::Semicolon)) | Some(TokenKind::Newline) => {
+                    self.position += 1
+                }
+                _ => return Err(self.error(String::from("'do' expected"))),
+            }
+        } else if self.peek() == Some(&TokenKind::Operator(Operator::Semicolon)) {
+            self.position += 1;
+        }
+
+        self.skip_newlines();
+        self.expect("do")?;
+        let body = self.body(&["done"])?;
+        self.position += 1;
+        Ok(Command::For(For {
+            name,
+            words,
+            body,
+            redirects: self.redirects()?,
+        }))
+    }
+
+    fn while_clause(&mut self) -> Result<Command, ParseError> {
+        let until = self.at_word("until");
+        self.position += 1;
+        let condition = self.body(&["do"])?;
+        self.position += 1;
+        let body = self.body(&["done"])?;
+        self.position += 1;
+        Ok(Command::While(While {
+            condition,
+            body,
+            until,
+            redirects: self.redirects()?,
+        }))
+    }
+
+    /// Parse the redirections after a compound command
+    fn redirects(&mut self) -> Result<Vec<Redirect>, ParseError> {
+        let mut redirects = Vec::new();
+        loop {
+            match self.peek() {
+                Some(TokenKind::IoNumber(fd)) => {
+                    let fd = *fd;
+                    self.position += 1;
+                    redirects.push(self.redirect(Some(fd))?);
+                }
+                Some(TokenKind::Operator(operator)) if operator.redirect_kind().is_some() => {
+                    redirects.push(self.redirect(None)?);
+                }
+                _ => return Ok(redirects),
+            }
+        }
+    }
+
+    fn redirect(&mut self, fd: Option<u32>) -> Result<Redirect, ParseError> {
+        let kind = match self.next() {
+            Some(TokenKind::Operator(operator)) => match operator.redirect_kind() {
+                Some(kind) => kind,
+                None => {
+                    self.position -= 1;
+                    return Err(self.unexpected());
+                }
+            },
+            _ => {
+                self.position -= 1;
+                return Err(self.unexpected());
+            }
+        };
+
+        match self.peek() {
+            Some(TokenKind::Word(word)) => {
+                let target = word.clone();
+                self.position += 1;
+                Ok(Redirect { fd, kind, target })
+            }
+            /* A missing file name is an error even at the end of input */
+            _ => Err(ParseError {
+                incomplete: false,
+                ..self.unexpected()
+            }),
+        }
+    }
+}
+
+/// Split `NAME=value` into an assignment
+fn assignment(word: &Word) -> Option<Assignment> {
+    let (first, rest) = word.parts.split_first()?;
+    let literal = match first {
+        WordPart::Literal(literal) => literal,
+        _ => return None,
+    };
+    let (name, value) = literal.split_once('=')?;
+    if !is_valid_name(name) {
+        return None;
+    }
+
+    let mut parts = Vec::new();
+    if !value.is_empty() {
+        parts.push(WordPart::Literal(value.to_owned()));
+    }
+    parts.extend(rest.iter().cloned());
+    Some(Assignment {
+        name: name.to_owned(),
+        value: Word { parts },
+    })
+}
@@ -0,0 +1,121 @@
+//! The diagram of the pin header for the `pinout` builtin, like `pinout` of
+//! gpiozero but without Python. GPIO pins can be annotated with their mode
+//! and level.
+
+use std::fs;
+
+/// What a contact of the header is connected to
+#[derive(Clone, Copy)]
+enum Contact {
+    Power(&'static str),
+    Ground,
+    Gpio(u8),
+}
+
+use Contact::{Gpio, Ground, Power};
+
+/// The contacts of the 40-pin header by their physical number, less one.
+/// Boards before the B+ only have the first 26.
+const HEADER: [Contact; 40] = [
+    Power("3V3"),
+    Power("5V"),
+    Gpio(2),
+    Power("5V"),
+    Gpio(3),
+    Ground,
+    Gpio(4),
+    Gpio(14),
+    Ground,
+    Gpio(15),
+    Gpio(17),
+    Gpio(18),
+    Gpio(27),
+    Ground,
+    Gpio(22),
+    Gpio(23),
+    Power("3V3"),
+    Gpio(24),
+    Gpio(10),
+    Ground,
+    Gpio(9),
+    Gpio(25),
+    Gpio(11),
+    Gpio(8),
+    Ground,
+    Gpio(7),
+    Gpio(0),
+    Gpio(1),
+    Gpio(5),
+    Ground,
+    Gpio(6),
+    Gpio(12),
+    Gpio(13),
+    Ground,
+    Gpio(19),
+    Gpio(16),
+    Gpio(26),
+    Gpio(20),
+    Ground,
+    Gpio(21),
+];
+
+/// The model of the board, from the device tree or else `/proc/cpuinfo`
+pub(crate) fn model() -> Option<String> {
+    if let Ok(model) = fs::read_to_string("/proc/device-tree/model") {
+        /* The string in the device tree ends with a NUL */
+        return Some(model.trim_end_matches('\0').to_owned());
+    }
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").ok()?;
+    cpuinfo
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "Model")
+        .map(|(_, model)| model.trim().to_owned())
+}
+
+/// Whether a board has the 26-pin header of the first models
+fn is_short(model: &str) -> bool {
+    model.starts_with("Raspberry Pi Model A Rev") || model.starts_with("Raspberry Pi Model B Rev")
+}
+
+/// The header of the board as two columns of contacts. `states` holds the
+/// mode and level of each GPIO pin, if they could be read.
+pub(crate) fn diagram(model: Option<&str>, states: Option<&[(&str, u8)]>) -> String {
+    let contacts = match model.is_some_and(is_short) {
+        true => &HEADER[..26],
+        false => &HEADER[..],
+    };
+    let describe = |contact: Contact| {
+        let state = match (contact, states) {
+            (Gpio(number), Some(states)) => match states.get(number as usize) {
+                Some((mode, level)) => format!("{} {}", mode, level),
+                None => String::new(),
+            },
+            _ => String::new(),
+        };
+        let name = match contact {
+            Power(name) => name.to_owned(),
+            Ground => String::from("GND"),
+            Gpio(number) => format!("GPIO{}", number),
+        };
+        (name, state)
+    };
+
+    let mut diagram = format!("{}\n\n", model.unwrap_or("Unknown board"));
+    for (row, pair) in contacts.chunks(2).enumerate() {
+        let (left, left_state) = describe(pair[0]);
+        let (right, right_state) = describe(pair[1]);
+        let line = format!(
+            "{:>6} {:>6} ({:>2}) ({:>2}) {:<6} {}",
+            left_state,
+            left,
+            row * 2 + 1,
+            row * 2 + 2,
+            right,
+            right_state
+        );
+        diagram.push_str(line.trim_end());
+        diagram.push('\n');
+    }
+    diagram
+}
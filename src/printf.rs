@@ -0,0 +1,529 @@
+//! Formatting for the `printf` builtin and the escapes of `echo -e`, the same
+//! on every Pi whether coreutils or busybox is installed
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Widest field and longest precision of a conversion. bash takes up to
+/// what fits an `int`, which is still enough to run a Pi out of memory.
+const FIELD_LIMIT: u64 = 1 << 20;
+
+/// What `printf` writes, along with the arguments that weren't numbers
+#[derive(Debug, Default)]
+pub(crate) struct Formatted {
+    pub(crate) output: Vec<u8>,
+    pub(crate) errors: Vec<String>,
+}
+
+/// How octal escapes are written
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Octal {
+    /// `\NNN`, as in the format of `printf`
+    Plain,
+    /// `\0NNN`, as in `echo -e` and arguments for `%b`
+    Zero,
+}
+
+/// A conversion like `%-8.3s`
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+/// Replace the escapes in an argument of `echo -e`. Returns true if `\c`
+/// asked for nothing more to be written.
+pub(crate) fn unescape(text: &str, output: &mut Vec<u8>) -> bool {
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if escape(&mut chars, output, Octal::Zero) {
+                    return true;
+                }
+            }
+            _ => push_char(output, c),
+        }
+    }
+    false
+}
+
+/// Format the arguments as `printf` does. The format is used again as long
+/// as there are arguments left. Fails for conversions it doesn't know.
+pub(crate) fn printf(format: &str, args: &[&str]) -> Result<Formatted, String> {
+    let mut formatted = Formatted::default();
+    let mut next = 0;
+    loop {
+        let start = next;
+        if format_once(format, args, &mut next, &mut formatted)? || next == start {
+            break;
+        }
+        if next >= args.len() {
+            break;
+        }
+    }
+    Ok(formatted)
+}
+
+/// Go through the format once, taking arguments from `next` on. Returns
+/// true if `\c` stopped the output.
+fn format_once(
+    format: &str,
+    args: &[&str],
+    next: &mut usize,
+    formatted: &mut Formatted,
+) -> Result<bool, String> {
+    let mut take = || {
+        let arg = args.get(*next).copied();
+        *next += 1;
+        arg
+    };
+
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if escape(&mut chars, &mut formatted.output, Octal::Plain) {
+                    return Ok(true);
+                }
+            }
+            '%' if chars.peek() == Some(&'%') => {
+                chars.next();
+                formatted.output.push(b'%');
+            }
+            '%' => {
+                let mut spec = Spec::default();
+                while let Some(&flag) = chars.peek() {
+                    match flag {
+                        '-' => spec.left = true,
+                        '+' => spec.plus = true,
+                        ' ' => spec.space = true,
+                        '#' => spec.alternate = true,
+                        '0' => spec.zero = true,
+                        _ => break,
+                    }
+                    chars.next();
+                }
+                if chars.next_if_eq(&'*').is_some() {
+                    let width = integer(take().unwrap_or_default(), &mut formatted.errors);
+                    spec.left |= width < 0;
+                    spec.width = field(width.unsigned_abs())?;
+                } else {
+                    spec.width = field(digits(&mut chars))?;
+                }
+                if chars.next_if_eq(&'.').is_some() {
+                    spec.precision = match chars.next_if_eq(&'*') {
+                        Some(_) => {
+                            let precision =
+                                integer(take().unwrap_or_default(), &mut formatted.errors);
+                            /* A negative precision is taken as none */
+                            match u64::try_from(precision) {
+                                Ok(precision) => Some(field(precision)?),
+                                Err(_) => None,
+                            }
+                        }
+                        None => Some(field(digits(&mut chars))?),
+                    };
+                }
+
+                let conversion = match chars.next() {
+                    Some(conversion) => conversion,
+                    None => return Err(String::from("missing conversion at end of format")),
+                };
+                let arg = take();
+                let text = match conversion {
+                    's' => {
+                        let text = arg.unwrap_or_default();
+                        match spec.precision {
+                            Some(precision) => text.chars().take(precision).collect(),
+                            None => text.to_owned(),
+                        }
+                    }
+                    'b' => {
+                        let mut unescaped = Vec::new();
+                        let stop = unescape(arg.unwrap_or_default(), &mut unescaped);
+                        let text = String::from_utf8_lossy(&unescaped).into_owned();
+                        pad(&mut formatted.output, &spec, &text, "");
+                        if stop {
+                            return Ok(true);
+                        }
+                        continue;
+                    }
+                    'c' => arg
+                        .and_then(|arg| arg.chars().next())
+                        .map(String::from)
+                        .unwrap_or_default(),
+                    'd' | 'i' => {
+                        let value = integer(arg.unwrap_or("0"), &mut formatted.errors);
+                        let digits = with_precision(value.unsigned_abs().to_string(), &spec);
+                        let sign = sign(value < 0, &spec);
+                        pad_number(&mut formatted.output, &spec, sign, &digits);
+                        continue;
+                    }
+                    'u' | 'o' | 'x' | 'X' => {
+                        let value = integer(arg.unwrap_or("0"), &mut formatted.errors) as u64;
+                        let (digits, prefix) = match conversion {
+                            'u' => (value.to_string(), ""),
+                            'o' => (format!("{:o}", value), "0"),
+                            'x' => (format!("{:x}", value), "0x"),
+                            _ => (format!("{:X}", value), "0X"),
+                        };
+                        let digits = with_precision(digits, &spec);
+                        let prefix = match spec.alternate && value != 0 && !digits.starts_with('0')
+                        {
+                            true => prefix,
+                            false => "",
+                        };
+                        pad_number(&mut formatted.output, &spec, prefix, &digits);
+                        continue;
+                    }
+                    'f' | 'F' | 'e' | 'E' | 'g' | 'G' => {
+                        let value = float(arg.unwrap_or("0"), &mut formatted.errors);
+                        let digits = format_float(value.abs(), conversion, &spec);
+                        let sign = sign(value.is_sign_negative() && !value.is_nan(), &spec);
+                        match value.is_finite() {
+                            true => pad_number(&mut formatted.output, &spec, sign, &digits),
+                            false => pad(&mut formatted.output, &spec, &digits, sign),
+                        }
+                        continue;
+                    }
+                    _ => return Err(format!("%{}: invalid conversion", conversion)),
+                };
+                pad(&mut formatted.output, &spec, &text, "");
+            }
+            _ => push_char(&mut formatted.output, c),
+        }
+    }
+    Ok(false)
+}
+
+/// Handle the escape after a backslash. Returns true for `\c`.
+fn escape(chars: &mut Peekable<Chars>, output: &mut Vec<u8>, octal: Octal) -> bool {
+    let c = match chars.next() {
+        Some(c) => c,
+        None => {
+            output.push(b'\\');
+            return false;
+        }
+    };
+    let byte = match c {
+        '\\' => b'\\',
+        'a' => 0x07,
+        'b' => 0x08,
+        'c' => return true,
+        'e' => 0x1b,
+        'f' => 0x0c,
+        'n' => b'\n',
+        'r' => b'\r',
+        't' => b'\t',
+        'v' => 0x0b,
+        '0' if octal == Octal::Zero => number(chars, 8, 3, 0),
+        '0'..='7' if octal == Octal::Plain => number(chars, 8, 2, c as u32 - '0' as u32),
+        'x' if chars.peek().is_some_and(char::is_ascii_hexdigit) => number(chars, 16, 2, 0),
+        _ => {
+            output.push(b'\\');
+            push_char(output, c);
+            return false;
+        }
+    };
+    output.push(byte);
+    false
+}
+
+/// Read up to `max` more digits of a number in an escape
+fn number(chars: &mut Peekable<Chars>, radix: u32, max: usize, mut value: u32) -> u8 {
+    for _ in 0..max {
+        match chars.peek().and_then(|c| c.to_digit(radix)) {
+            Some(digit) => {
+                value = value * radix + digit;
+                chars.next();
+            }
+            None => break,
+        }
+    }
+    value as u8
+}
+
+/// Read a width or precision
+fn digits(chars: &mut Peekable<Chars>) -> u64 {
+    let mut value = 0u64;
+    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+        value = value.saturating_mul(10).saturating_add(digit as u64);
+        chars.next();
+    }
+    value
+}
+
+/// Check a width or precision against `FIELD_LIMIT`
+fn field(value: u64) -> Result<usize, String> {
+    match value <= FIELD_LIMIT {
+        true => Ok(value as usize),
+        false => Err(format!("{}: Numerical result out of range", value)),
+    }
+}
+
+fn push_char(output: &mut Vec<u8>, c: char) {
+    let mut buf = [0u8; 4];
+    output.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+}
+
+/// Read an integer argument: decimal, hexadecimal after `0x`, octal after
+/// `0`, or the code of the character after a quote as in `"'A"`
+fn integer(arg: &str, errors: &mut Vec<String>) -> i64 {
+    let text = arg.trim_start();
+    if let Some(quoted) = text.strip_prefix(['\'', '"']) {
+        return quoted.chars().next().map_or(0, |c| c as i64);
+    }
+
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let parsed = match unsigned
+        .strip_prefix("0x")
+        .or_else(|| unsigned.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None if unsigned.len() > 1 && unsigned.starts_with('0') => {
+            u64::from_str_radix(&unsigned[1..], 8)
+        }
+        None => unsigned.parse(),
+    };
+    match parsed {
+        Ok(value) if negative => (value as i64).wrapping_neg(),
+        Ok(value) => value as i64,
+        Err(_) => {
+            errors.push(format!("'{}': expected a numeric value", arg));
+            0
+        }
+    }
+}
+
+fn float(arg: &str, errors: &mut Vec<String>) -> f64 {
+    let text = arg.trim_start();
+    if let Some(quoted) = text.strip_prefix(['\'', '"']) {
+        return quoted.chars().next().map_or(0.0, |c| c as u32 as f64);
+    }
+    match text.parse() {
+        Ok(value) => value,
+        Err(_) => {
+            errors.push(format!("'{}': expected a numeric value", arg));
+            0.0
+        }
+    }
+}
+
+/// Add leading zeros up to the precision of an integer conversion
+fn with_precision(digits: String, spec: &Spec) -> String {
+    match spec.precision {
+        /* Zero with a precision of zero is written as nothing */
+        Some(0) if digits == "0" => String::new(),
+        Some(precision) if precision > digits.len() => {
+            format!("{}{}", "0".repeat(precision - digits.len()), digits)
+        }
+        _ => digits,
+    }
+}
+
+fn sign(negative: bool, spec: &Spec) -> &'static str {
+    match (negative, spec.plus, spec.space) {
+        (true, _, _) => "-",
+        (false, true, _) => "+",
+        (false, false, true) => " ",
+        (false, false, false) => "",
+    }
+}
+
+/// Write a number of a magnitude of at least zero, like `printf` in C
+fn format_float(value: f64, conversion: char, spec: &Spec) -> String {
+    let upper = conversion.is_ascii_uppercase();
+    if !value.is_finite() {
+        let text = match value.is_nan() {
+            true => "nan",
+            false => "inf",
+        };
+        return match upper {
+            true => text.to_uppercase(),
+            false => text.to_owned(),
+        };
+    }
+
+    let precision = spec.precision.unwrap_or(6);
+    let text = match conversion.to_ascii_lowercase() {
+        'f' => format!("{:.*}", precision, value),
+        'e' => exponent(value, precision),
+        _ => {
+            /* %g picks the shorter of %f and %e, without trailing zeros */
+            let precision = precision.max(1);
+            let rounded = exponent(value, precision - 1);
+            let power: i32 = rounded
+                .rsplit_once('e')
+                .and_then(|(_, power)| power.parse().ok())
+                .unwrap_or(0);
+            let text = match power < -4 || power >= precision as i32 {
+                true => rounded,
+                false => format!("{:.*}", (precision as i32 - 1 - power) as usize, value),
+            };
+            match spec.alternate {
+                true => text,
+                false => strip_zeros(&text),
+            }
+        }
+    };
+    let text = match spec.alternate && precision == 0 && !text.contains('.') {
+        true => match text.split_once('e') {
+            Some((mantissa, power)) => format!("{}.e{}", mantissa, power),
+            None => format!("{}.", text),
+        },
+        false => text,
+    };
+    match upper {
+        true => text.to_uppercase(),
+        false => text,
+    }
+}
+
+/// Write a number as `%e` does, like `1.500000e+03`
+fn exponent(value: f64, precision: usize) -> String {
+    let text = format!("{:.*e}", precision, value);
+    let (mantissa, power) = text.split_once('e').unwrap_or((&text, "0"));
+    let (sign, digits) = match power.strip_prefix('-') {
+        Some(digits) => ('-', digits),
+        None => ('+', power),
+    };
+    format!("{}e{}{:0>2}", mantissa, sign, digits)
+}
+
+/// Drop trailing zeros after the decimal point of `%g`
+fn strip_zeros(text: &str) -> String {
+    let (mantissa, power) = match text.split_once('e') {
+        Some((mantissa, power)) => (mantissa, format!("e{}", power)),
+        None => (text, String::new()),
+    };
+    let mantissa = match mantissa.contains('.') {
+        true => mantissa.trim_end_matches('0').trim_end_matches('.'),
+        false => mantissa,
+    };
+    format!("{}{}", mantissa, power)
+}
+
+/// Write text padded to the width with spaces
+fn pad(output: &mut Vec<u8>, spec: &Spec, text: &str, prefix: &str) {
+    let length = prefix.chars().count() + text.chars().count();
+    let padding = " ".repeat(spec.width.saturating_sub(length));
+    let text = match spec.left {
+        true => format!("{}{}{}", prefix, text, padding),
+        false => format!("{}{}{}", padding, prefix, text),
+    };
+    output.extend_from_slice(text.as_bytes());
+}
+
+/// Write a number padded to the width, with zeros after the sign if asked to
+fn pad_number(output: &mut Vec<u8>, spec: &Spec, prefix: &str, digits: &str) {
+    let length = prefix.len() + digits.len();
+    match spec.zero && !spec.left && length < spec.width {
+        true => {
+            let zeros = "0".repeat(spec.width - length);
+            output.extend_from_slice(format!("{}{}{}", prefix, zeros, digits).as_bytes());
+        }
+        false => pad(output, spec, digits, prefix),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn formatted(format: &str, args: &[&str]) -> String {
+        let formatted = printf(format, args).expect("format should be valid");
+        assert!(formatted.errors.is_empty(), "{:?}", formatted.errors);
+        String::from_utf8(formatted.output).unwrap()
+    }
+
+    #[test]
+    fn formats_strings() {
+        assert_eq!(
+            formatted("%s|%5s|%-5s|\n", &["a", "b", "c"]),
+            "a|    b|c    |\n"
+        );
+        assert_eq!(formatted("%.2s", &["abc"]), "ab");
+        assert_eq!(formatted("%c%c", &["xyz", ""]), "x");
+        assert_eq!(formatted("%b", &["a\\tb\\0101"]), "a\tbA");
+        assert_eq!(formatted("100%%", &[]), "100%");
+    }
+
+    #[test]
+    fn formats_integers() {
+        assert_eq!(formatted("%d %i", &["42", "-7"]), "42 -7");
+        assert_eq!(
+            formatted("%05d|%-5d|%+d|% d", &["-42", "3", "3", "3"]),
+            "-0042|3    |+3| 3"
+        );
+        assert_eq!(formatted("%.3d|%.0d|", &["7", "0"]), "007||");
+        assert_eq!(
+            formatted("%x %X %o %#x %#o", &["255", "255", "8", "255", "8"]),
+            "ff FF 10 0xff 010"
+        );
+        assert_eq!(formatted("%d %d %d", &["0x10", "010", "'A"]), "16 8 65");
+        assert_eq!(formatted("%*d|%-*d|", &["4", "1", "-3", "2"]), "   1|2  |");
+    }
+
+    #[test]
+    fn formats_floats() {
+        assert_eq!(formatted("%.2f", &["3.14159"]), "3.14");
+        assert_eq!(formatted("%08.3f", &["-3.5"]), "-003.500");
+        assert_eq!(formatted("%e", &["12345.678"]), "1.234568e+04");
+        assert_eq!(formatted("%g %g", &["0.0001", "100000"]), "0.0001 100000");
+        assert_eq!(formatted("%f", &["inf"]), "inf");
+    }
+
+    #[test]
+    fn reuses_the_format_for_remaining_arguments() {
+        assert_eq!(formatted("<%s>", &["a", "b", "c"]), "<a><b><c>");
+        assert_eq!(formatted("%s=%s\n", &["a", "1", "b"]), "a=1\nb=\n");
+        assert_eq!(formatted("a\\cb%s", &["x", "y"]), "a");
+    }
+
+    #[test]
+    fn reports_arguments_that_are_not_numbers() {
+        let formatted = printf("%d", &["12abc"]).unwrap();
+        assert_eq!(formatted.output, b"0");
+        assert_eq!(formatted.errors, ["'12abc': expected a numeric value"]);
+    }
+
+    #[test]
+    fn refuses_invalid_conversions() {
+        assert_eq!(printf("%q", &[]).unwrap_err(), "%q: invalid conversion");
+        assert_eq!(
+            printf("%5", &[]).unwrap_err(),
+            "missing conversion at end of format"
+        );
+    }
+
+    #[test]
+    fn refuses_fields_too_wide_to_write() {
+        assert_eq!(
+            printf("%999999999999d\n", &["1"]).unwrap_err(),
+            "999999999999: Numerical result out of range"
+        );
+        assert!(printf("%.99999999999s", &["a"]).is_err());
+        assert!(printf("%0*d", &["99999999999", "1"]).is_err());
+        assert!(printf("%.*f", &["-99999999999", "1"]).is_ok());
+        assert_eq!(formatted("%1048576d", &["1"]).len(), 1 << 20);
+    }
+
+    #[test]
+    fn unescapes_echo_arguments() {
+        let mut output = Vec::new();
+        assert!(!unescape("a\\nb\\0101\\x41\\q", &mut output));
+        assert_eq!(output, b"a\nbAA\\q");
+        let mut output = Vec::new();
+        assert!(unescape("stop\\chere", &mut output));
+        assert_eq!(output, b"stop");
+    }
+}
@@ -0,0 +1,102 @@
+//! The prompt shown before each line of input. It is computed by a
+//! `PromptProvider`, by default `TemplatePrompt` which expands the template
+//! from the config like PS1 in other shells.
+
+use std::path::Path;
+
+use crate::theme::{Colors, Role};
+
+/// The prompt used when none is configured
+pub const DEFAULT_PROMPT: &str = "\\u@\\h:\\w\\$ ";
+/// The prompt for more lines of an unfinished command, like PS2 in other
+/// shells, used when none is configured
+pub const DEFAULT_CONTINUATION_PROMPT: &str = "> ";
+
+/// The state of the session a prompt is computed from
+pub struct PromptContext<'a> {
+    pub user: &'a str,
+    pub host_name: &'a str,
+    pub home: &'a str,
+    pub current_dir: &'a Path,
+    /// The prompt template from the settings of the session
+    pub template: &'a str,
+    /// Exit status of the last command
+    pub last_status: i32,
+}
+
+/// Computes the prompt. Embedders can implement this to show anything, e.g.
+/// a device ID or the state of a sensor. Providers are shared between the
+/// sessions of a shell.
+pub trait PromptProvider: Send + Sync {
+    fn prompt(&self, context: &PromptContext, colors: &Colors) -> String;
+}
+
+/// The default provider, expanding the template from the settings with
+/// `render`
+pub struct TemplatePrompt;
+
+impl PromptProvider for TemplatePrompt {
+    fn prompt(&self, context: &PromptContext, colors: &Colors) -> String {
+        render(context.template, context, colors)
+    }
+}
+
+impl<F> PromptProvider for F
+where
+    F: Fn(&PromptContext, &Colors) -> String + Send + Sync,
+{
+    fn prompt(&self, context: &PromptContext, colors: &Colors) -> String {
+        self(context, colors)
+    }
+}
+
+/// Expand a prompt template. Supported escapes are `\u` (user), `\h` (host
+/// name), `\w` (working directory with the home directory as `~`), `\W` (last
+/// component of the working directory), `\$` (`#` for root, `$` otherwise),
+/// `\n` and `\\`.
+pub fn render(template: &str, context: &PromptContext, colors: &Colors) -> String {
+    let current_dir = context.current_dir.to_string_lossy();
+    let mut prompt = String::new();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            prompt.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('u') => prompt.push_str(&colors.paint(Role::PromptUser, context.user)),
+            Some('h') => prompt.push_str(&colors.paint(Role::PromptUser, context.host_name)),
+            Some('w') => {
+                let dir = if !context.home.is_empty() && current_dir.starts_with(context.home) {
+                    current_dir.replacen(context.home, "~", 1)
+                } else {
+                    current_dir.to_string()
+                };
+                prompt.push_str(&colors.paint(Role::PromptPath, &dir));
+            }
+            Some('W') => {
+                let dir = match context.current_dir.file_name() {
+                    Some(name) => name.to_string_lossy().into_owned(),
+                    None => current_dir.to_string(),
+                };
+                prompt.push_str(&colors.paint(Role::PromptPath, &dir));
+            }
+            Some('$') => {
+                let symbol = if context.user == "root" { "#" } else { "$" };
+                prompt.push_str(&colors.paint(Role::PromptSymbol, symbol));
+            }
+            Some('n') => prompt.push('\n'),
+            Some('\\') => prompt.push('\\'),
+            /* Unknown escapes are printed as-is */
+            Some(other) => {
+                prompt.push('\\');
+                prompt.push(other);
+            }
+            None => prompt.push('\\'),
+        }
+    }
+
+    prompt
+}
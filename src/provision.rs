@@ -0,0 +1,70 @@
+//! First-boot provisioning. A commands file dropped on the boot partition of
+//! the SD card is run once when the shell starts, before the prompt is shown.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::script;
+use crate::session::Session;
+use crate::SHELL_NAME;
+
+/// Where the provisioning script is looked for
+pub const PROVISION_PATH: &str = "/boot/pieshell-provision.txt";
+
+/// Run the provisioning script if there is one, then rename it so it doesn't
+/// run again on the next boot. Returns the exit status of the script.
+pub fn run(session: &mut Session) -> Option<i32> {
+    let path = Path::new(PROVISION_PATH);
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return None,
+        Err(error) => {
+            session.print_error(&format!("{}: {}: {}", SHELL_NAME, path.display(), error));
+            return None;
+        }
+    };
+
+    say(
+        session,
+        &format!("Running provisioning script {}", path.display()),
+    );
+    let status = script::run_source(session, &contents, true);
+
+    /* Rename the script even if it failed, as running it again on every boot
+    could keep the Pi from ever becoming usable */
+    let renamed = renamed_path(path, status);
+    match fs::rename(path, &renamed) {
+        Ok(()) => say(
+            session,
+            &format!(
+                "Provisioning finished with status {}, script moved to {}",
+                status,
+                renamed.display()
+            ),
+        ),
+        Err(error) => session.print_error(&format!(
+            "{}: failed to rename {}: {}. It will run again on the next boot.",
+            SHELL_NAME,
+            path.display(),
+            error
+        )),
+    }
+
+    Some(status)
+}
+
+/// `pieshell-provision.txt.done` after success, `.failed` otherwise
+fn renamed_path(path: &Path, status: i32) -> PathBuf {
+    let suffix = if status == 0 { "done" } else { "failed" };
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn say(session: &mut Session, text: &str) {
+    if let Err(error) = session.write_output(format!("{}\n", text).as_bytes()) {
+        eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+    }
+}
@@ -0,0 +1,162 @@
+//! Pseudo-terminals for commands run over the UART or TCP. The commands see a
+//! terminal, so full-screen programs like `top` and programs editing their
+//! input work, while the shell relays between the pseudo-terminal and the
+//! transport.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+
+/// Size of the terminal at the other end of the transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WindowSize {
+    pub(crate) rows: u16,
+    pub(crate) columns: u16,
+}
+
+impl Default for WindowSize {
+    /// The size of a VT100, assumed while the real size is unknown
+    fn default() -> WindowSize {
+        WindowSize {
+            rows: 24,
+            columns: 80,
+        }
+    }
+}
+
+/// Save the cursor, move it as far as it goes, ask where it is and restore
+/// it. The answer tells the size of the terminal.
+pub(crate) const SIZE_QUERY: &[u8] = b"\x1b7\x1b[999;999H\x1b[6n\x1b8";
+
+/// Find the size in the answer to `SIZE_QUERY`, a cursor position report
+/// like `ESC [ 24 ; 80 R`, once all of it arrived
+pub(crate) fn parse_size_report(data: &[u8]) -> Option<WindowSize> {
+    let start = data.windows(2).rposition(|window| window == b"\x1b[")?;
+    let report = std::str::from_utf8(&data[start + 2..]).ok()?;
+    let (rows, columns) = report.strip_suffix('R')?.split_once(';')?;
+    match (rows.parse(), columns.parse()) {
+        (Ok(rows), Ok(columns)) if rows > 0 && columns > 0 => Some(WindowSize { rows, columns }),
+        _ => None,
+    }
+}
+
+/// The side of a pseudo-terminal the shell holds
+pub(crate) struct Pty {
+    master: File,
+}
+
+impl Pty {
+    /// Allocate a pseudo-terminal of the given size. Returns it along with
+    /// the side for the commands.
+    pub(crate) fn open(size: WindowSize) -> io::Result<(Pty, OwnedFd)> {
+        let mut master = -1;
+        let mut slave = -1;
+        let winsize = libc::winsize {
+            ws_row: size.rows,
+            ws_col: size.columns,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                ptr::null_mut(),
+                ptr::null(),
+                &winsize,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (master, slave) = unsafe { (File::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+
+        /* Commands only get the terminal as their standard file descriptors,
+        not the copies held by the shell */
+        set_close_on_exec(master.as_raw_fd())?;
+        set_close_on_exec(slave.as_raw_fd())?;
+
+        /* Newlines are translated for the transport by the session, like
+        those in other output */
+        let mut termios = attributes(slave.as_raw_fd())?;
+        termios.c_oflag &= !libc::ONLCR;
+        if unsafe { libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok((Pty { master }, slave))
+    }
+
+    /// Another handle to read the output of the commands from. Reads fail
+    /// once every command closed the terminal.
+    pub(crate) fn reader(&self) -> io::Result<File> {
+        self.master.try_clone()
+    }
+
+    /// Pass input on to the commands, as if typed on the terminal
+    pub(crate) fn write_input(&mut self, data: &[u8]) -> io::Result<()> {
+        self.master.write_all(data)
+    }
+
+    /// Change the size of the terminal. The command holding it is signalled
+    /// with SIGWINCH, so full-screen programs redraw.
+    pub(crate) fn resize(&self, size: WindowSize) -> io::Result<()> {
+        let winsize = libc::winsize {
+            ws_row: size.rows,
+            ws_col: size.columns,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        match unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the terminal interrupts the command holding it when `byte`
+    /// is typed. Full-screen programs usually turn this off.
+    pub(crate) fn interrupts(&self, byte: u8) -> bool {
+        match attributes(self.master.as_raw_fd()) {
+            Ok(termios) => termios.c_lflag & libc::ISIG != 0 && termios.c_cc[libc::VINTR] == byte,
+            Err(_) => false,
+        }
+    }
+
+    /// Process ID of the command holding the terminal, if any
+    pub(crate) fn holder(&self) -> Option<u32> {
+        match unsafe { libc::tcgetsid(self.master.as_raw_fd()) } {
+            -1 => None,
+            pid => Some(pid as u32),
+        }
+    }
+}
+
+/// Start a new session for a command running on a pseudo-terminal, with the
+/// terminal open as `fd`. Only the first command of a pipeline to do so gets
+/// it as its controlling terminal. Runs in the child process right before
+/// the program is executed.
+pub(crate) fn attach(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    /* Fails if another command took the terminal, which is fine */
+    unsafe { libc::ioctl(fd, libc::TIOCSCTTY, 0) };
+    Ok(())
+}
+
+pub(crate) fn attributes(fd: RawFd) -> io::Result<libc::termios> {
+    let mut termios: libc::termios = unsafe { mem::zeroed() };
+    match unsafe { libc::tcgetattr(fd, &mut termios) } {
+        0 => Ok(termios),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn set_close_on_exec(fd: RawFd) -> io::Result<()> {
+    match unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
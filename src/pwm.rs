@@ -0,0 +1,65 @@
+//! The hardware PWM channels for the `pwm` builtin, to drive servos and dim
+//! LEDs while bringing a board up. What is set stays set after the builtin
+//! returns.
+
+use std::io;
+
+use rppal::pwm::{Channel, Polarity, Pwm};
+
+use crate::hardware;
+
+/// The channels, numbered as in the device tree overlay
+pub(crate) const CHANNELS: [Channel; 2] = [Channel::Pwm0, Channel::Pwm1];
+
+/// A channel, left as it is when dropped
+fn channel(number: usize) -> io::Result<Pwm> {
+    let mut pwm = Pwm::new(CHANNELS[number]).map_err(hardware::pwm_error)?;
+    pwm.set_reset_on_drop(false);
+    Ok(pwm)
+}
+
+/// Output a signal on a channel, with the duty cycle from 0 to 1
+pub(crate) fn set(number: usize, frequency: f64, duty_cycle: f64) -> io::Result<()> {
+    let mut pwm = Pwm::with_frequency(
+        CHANNELS[number],
+        frequency,
+        duty_cycle,
+        Polarity::Normal,
+        true,
+    )
+    .map_err(hardware::pwm_error)?;
+    pwm.set_reset_on_drop(false);
+    Ok(())
+}
+
+pub(crate) fn off(number: usize) -> io::Result<()> {
+    channel(number)?.disable().map_err(hardware::pwm_error)
+}
+
+/// A table of whether each channel is on, with its frequency and duty cycle
+pub(crate) fn status() -> io::Result<String> {
+    let mut table = String::from("CHANNEL  STATE  FREQUENCY  DUTY\n");
+    for number in 0..CHANNELS.len() {
+        let pwm = channel(number)?;
+        let state = match pwm.is_enabled().map_err(hardware::pwm_error)? {
+            true => "on",
+            false => "off",
+        };
+        let frequency = pwm.frequency().map_err(hardware::pwm_error)?;
+        let duty_cycle = pwm.duty_cycle().map_err(hardware::pwm_error)?;
+        table.push_str(&format!(
+            "{:>7}  {:<5}  {:>9}  {}%\n",
+            number,
+            state,
+            format!("{} Hz", round(frequency)),
+            round(duty_cycle * 100.0)
+        ));
+    }
+    Ok(table)
+}
+
+/// A number with at most two decimals, as the sysfs interface counts
+/// nanoseconds and rarely gives back exactly what was set
+fn round(number: f64) -> f64 {
+    (number * 100.0).round() / 100.0
+}
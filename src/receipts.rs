@@ -0,0 +1,80 @@
+//! Receipts of commands run with `once KEY COMMAND`. A client retrying a
+//! non-idempotent step after a glitch of the serial line, e.g. because the
+//! status got garbled, sends the same key again and gets the result of the
+//! first run instead of running the command twice. Receipts are kept for
+//! the whole shell, so the session started after the line dropped still has
+//! them.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::exec;
+
+/// How many keys are remembered. The oldest receipt is dropped for a new one.
+const KEPT: usize = 64;
+/// Bytes of output kept per receipt. Longer output keeps its end.
+const OUTPUT_KEPT: usize = 16 * 1024;
+
+/// Receipts of all sessions, oldest first
+static KEPT_RECEIPTS: Mutex<VecDeque<Receipt>> = Mutex::new(VecDeque::new());
+
+/// What a command run with a key did
+#[derive(Clone)]
+pub(crate) struct Receipt {
+    pub(crate) key: String,
+    pub(crate) status: i32,
+    /// Output and errors written to the transport, in the order they were
+    pub(crate) output: Vec<u8>,
+}
+
+/// Output a session records for receipts
+#[derive(Default)]
+pub(crate) struct Receipts {
+    /// Output of the command being run with a key, if any
+    recording: Option<VecDeque<u8>>,
+}
+
+impl Receipts {
+    pub(crate) fn find(&self, key: &str) -> Option<Receipt> {
+        let receipts = KEPT_RECEIPTS
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        receipts.iter().find(|receipt| receipt.key == key).cloned()
+    }
+
+    /// Start recording output for a receipt. Returns the output recorded so
+    /// far for an outer command, to be given back to `finish`.
+    pub(crate) fn start(&mut self) -> Option<VecDeque<u8>> {
+        self.recording.replace(VecDeque::new())
+    }
+
+    /// Keep what was written since `start`
+    pub(crate) fn record(&mut self, data: &[u8]) {
+        if let Some(recording) = &mut self.recording {
+            exec::append_capped(recording, data, OUTPUT_KEPT);
+        }
+    }
+
+    /// Stop recording and keep the receipt of the command, unless `key` is
+    /// `None`
+    pub(crate) fn finish(&mut self, key: Option<&str>, status: i32, outer: Option<VecDeque<u8>>) {
+        let output: Vec<u8> = self.recording.take().unwrap_or_default().into();
+        self.recording = outer;
+        /* The output also belongs to the command running this one */
+        self.record(&output);
+
+        if let Some(key) = key {
+            let mut receipts = KEPT_RECEIPTS
+                .lock()
+                .unwrap_or_else(|error| error.into_inner());
+            if receipts.len() == KEPT {
+                receipts.pop_front();
+            }
+            receipts.push_back(Receipt {
+                key: key.to_owned(),
+                status,
+                output,
+            });
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Non-interactive execution of script files, e.g. provisioning scripts run
+//! from the serial console at boot.
+
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::session::{self, Session};
+use crate::{StatusCode, SHELL_NAME};
+
+/// Run every line of a script in the session, with `args` as `$1` and on.
+/// Returns the exit status of the shell.
+pub fn run(session: &mut Session, path: &Path, args: &[String]) -> i32 {
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            session.script_name = Some(path.display().to_string());
+            session.positional = args.to_vec();
+            run_source(session, &contents, false);
+            session.run_exit_trap();
+            session.last_status
+        }
+        Err(error) => {
+            session.print_error(&format!("{}: {}: {}", SHELL_NAME, path.display(), error));
+            StatusCode::NotFound.code()
+        }
+    }
+}
+
+/// Run the lines of a script, optionally printing each step before it runs.
+/// Returns the status of the last command.
+pub fn run_source(session: &mut Session, contents: &str, progress: bool) -> i32 {
+    /* Every line that isn't blank or a comment is a step, together with
+    the lines after it for commands like `if` that go on over several */
+    let mut commands = Vec::new();
+    let mut command = String::new();
+    for line in contents.lines() {
+        if command.is_empty() {
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                command.push_str(line);
+            }
+        } else {
+            command.push('\n');
+            command.push_str(line);
+        }
+        if !command.is_empty() && !session::is_unfinished(&command) {
+            commands.push(std::mem::take(&mut command));
+        }
+    }
+    /* An unfinished command at the end fails as it runs */
+    if !command.is_empty() {
+        commands.push(command);
+    }
+    let steps: Vec<&str> = commands.iter().map(String::as_str).collect();
+
+    /* The budget covers the whole script, counted from its start, no matter
+    where in the script it was set */
+    let started = Instant::now();
+    let status = run_steps(session, &steps, started, progress);
+    session.deadline = None;
+    status
+}
+
+fn run_steps(session: &mut Session, steps: &[&str], started: Instant, progress: bool) -> i32 {
+    for (i, step) in steps.iter().enumerate() {
+        /* A step may have just set the budget */
+        session.deadline = session.script_timeout.map(|timeout| started + timeout);
+        if session
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            report_timeout(session, steps, i, None);
+            return StatusCode::Timeout.code();
+        }
+
+        if progress {
+            let line = format!("[{}/{}] {}\n", i + 1, steps.len(), step);
+            if let Err(error) = session.write_output(line.as_bytes()) {
+                eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+            }
+        }
+
+        if let Err(error) = session.execute_line(step) {
+            eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+        }
+        if std::mem::take(&mut session.timed_out) {
+            report_timeout(session, steps, i, Some(step));
+            return StatusCode::Timeout.code();
+        }
+        if session.exit_requested {
+            break;
+        }
+    }
+
+    /* Like other shells, a script exits with the status of its last command */
+    session.last_status
+}
+
+/// Print which steps of the script completed and which did not run
+fn report_timeout(
+    session: &mut Session,
+    steps: &[&str],
+    current: usize,
+    interrupted: Option<&str>,
+) {
+    let timeout = session.script_timeout.unwrap_or_default();
+    session.print_error(&format!(
+        "{}: script timeout of {}s exceeded, aborting",
+        SHELL_NAME,
+        timeout.as_secs()
+    ));
+
+    let mut summary = format!("Completed {} of {} steps:\n", current, steps.len());
+    for step in &steps[..current] {
+        summary.push_str(&format!("  done     {}\n", step));
+    }
+    if let Some(step) = interrupted {
+        summary.push_str(&format!("  killed   {}\n", step));
+    }
+    let pending_from = current + usize::from(interrupted.is_some());
+    for step in &steps[pending_from..] {
+        summary.push_str(&format!("  pending  {}\n", step));
+    }
+
+    if let Err(error) = session.write_output(summary.as_bytes()) {
+        eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+    }
+}
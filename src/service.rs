@@ -0,0 +1,98 @@
+//! Running as a systemd service, e.g. in place of `serial-getty@ttyAMA0`.
+//! Readiness is told to systemd with the sd_notify protocol, SIGTERM ends
+//! the sessions cleanly, and the UART gets a new session whenever one ends.
+
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::diagnostics;
+use crate::terminal;
+
+/// How long sessions get to close after SIGTERM before the process exits
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+/// Sessions that haven't ended yet
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether SIGTERM arrived and sessions must end
+pub(crate) fn terminating() -> bool {
+    TERMINATING.load(Ordering::SeqCst)
+}
+
+/// Tell systemd about the state of the service, e.g. `READY=1`. Nothing is
+/// sent when the shell wasn't started by systemd with `Type=notify`.
+pub(crate) fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(error) => {
+            diagnostics::log(&format!("sd_notify: {}", error));
+            return;
+        }
+    };
+    /* Names starting with '@' are in the abstract namespace */
+    let sent = match path.as_encoded_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)
+            .and_then(|address| socket.send_to_addr(state.as_bytes(), &address)),
+        None => socket.send_to(state.as_bytes(), &path),
+    };
+    if let Err(error) = sent {
+        diagnostics::log(&format!("sd_notify: {}", error));
+    }
+}
+
+/// Handle SIGTERM in a thread of its own. Must be called before any other
+/// thread is started, as they inherit the blocked signal. Commands get it
+/// unblocked again.
+pub(crate) fn handle_termination() {
+    let mut signals: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+    }
+    thread::spawn(move || {
+        let mut signal = 0;
+        while unsafe { libc::sigwait(&signals, &mut signal) } != 0 {}
+        shut_down();
+    });
+}
+
+/// Let the sessions close, draining their output, then exit
+fn shut_down() -> ! {
+    notify("STOPPING=1");
+    diagnostics::log("got SIGTERM, closing sessions");
+    TERMINATING.store(true, Ordering::SeqCst);
+    let deadline = Instant::now() + SHUTDOWN_GRACE;
+    while ACTIVE.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        thread::sleep(SHUTDOWN_POLL);
+    }
+    terminal::restore();
+    process::exit(0)
+}
+
+/// Counts a session as active while it is alive
+pub(crate) struct Active(());
+
+impl Active {
+    pub(crate) fn new() -> Active {
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        Active(())
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,1284 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, PipeWriter, Read, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::auth;
+use crate::builtins::CustomBuiltin;
+use crate::clipboard;
+use crate::completion::{Completer, SessionCompleter};
+use crate::config::{FlowControl, Settings};
+use crate::diagnostics;
+use crate::discipline::{InputDiscipline, Translated};
+use crate::elevate;
+use crate::encoding::{LossyFilter, OutputEncoding};
+use crate::exec::{DeadMan, Input, Jump, PathCache, Running};
+use crate::history::{self, HistoryStore};
+use crate::hooks::Hooks;
+use crate::images::ImageFilter;
+use crate::inputrc::Inputrc;
+use crate::jobs::Jobs;
+use crate::limits::Limit;
+use crate::motd;
+use crate::options::ShellOptions;
+use crate::parser::{self, Ast};
+use crate::prompt::{self, PromptContext, PromptProvider, TemplatePrompt};
+use crate::pty::{self, WindowSize};
+use crate::receipts::Receipts;
+use crate::service;
+use crate::terminal;
+use crate::theme::{Colors, Role};
+use crate::transcript::{self, Recorded, SharedTranscript, Transcript};
+use crate::transport::{Reader, TransportKind, Writer};
+use crate::traps::Traps;
+use crate::{ShellError, StatusCode, SHELL_NAME};
+use editor::Editor;
+
+#[cfg(feature = "async")]
+mod async_loop;
+mod editor;
+
+/// Time the first prompt should take to appear on a Pi Zero. Past it, the
+/// session tells what it is waiting for.
+const STARTUP_BUDGET: Duration = Duration::from_millis(50);
+
+/// Time the terminal at the other end gets to tell its size
+const SIZE_QUERY_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Input arriving within this time of a connection is taken as left over
+/// from before, e.g. half-typed commands buffered by the terminal
+const RECONNECT_GRACE: Duration = Duration::from_millis(250);
+/// Longest time spent dropping input that keeps coming
+const RECONNECT_DRAIN_LIMIT: Duration = Duration::from_secs(2);
+
+/// Tries to log in before the session ends
+const LOGIN_ATTEMPTS: usize = 3;
+
+/// One shell session running over a single transport. Sessions don't share
+/// state, so several can run at the same time in different threads.
+pub struct Session {
+    reader: Recorded<Reader>,
+    writer: Translated<Recorded<Writer>>,
+    /// Translates the ends of lines typed for the shell itself
+    pub(crate) input_discipline: InputDiscipline,
+    /// The session runs in a terminal on stdio, which is in raw mode while
+    /// lines are edited
+    raw_terminal: bool,
+    /// The transcript being written of the session, if any
+    transcript: SharedTranscript,
+    settings: Settings,
+    colors: Colors,
+    prompt_provider: Arc<dyn PromptProvider>,
+    /// Strips images from command output, if the transport can't show them
+    image_filter: Option<ImageFilter>,
+    /// Replaces output that isn't valid UTF-8, if the settings ask for it
+    lossy_filter: Option<LossyFilter>,
+    /// Working directory of the session, used for commands it starts
+    pub(crate) cwd: PathBuf,
+    pub(crate) previous_dir: Option<PathBuf>,
+    /// Directories saved with `pushd`, the latest first
+    pub(crate) dir_stack: Vec<PathBuf>,
+    pub(crate) history: Vec<String>,
+    /// Where the history is kept between sessions, once loaded
+    pub(crate) history_store: Option<Box<dyn HistoryStore>>,
+    /// Variables passed to commands on top of the process environment
+    pub(crate) env: HashMap<String, String>,
+    /// Mask for files created by the session, if it isn't the one of the
+    /// shell process
+    pub(crate) umask: Option<u32>,
+    /// Resource limits set with `ulimit` for commands of the session
+    pub(crate) limits: Vec<Limit>,
+    /// Variables only known to the shell itself
+    pub(crate) vars: HashMap<String, String>,
+    pub(crate) path_cache: PathCache,
+    /// Exit status of the last command
+    pub(crate) last_status: i32,
+    /// Flags set with `set -e`, `-u` and `-x`
+    pub(crate) options: ShellOptions,
+    /// How many conditions are running, e.g. of `if` or before `&&`. Their
+    /// commands failing doesn't end the session with `set -e`.
+    pub(crate) conditions: usize,
+    /// Time budget for a whole script, set with `set -o script-timeout`
+    pub(crate) script_timeout: Option<Duration>,
+    /// Standard output of the last command, for copying to the clipboard
+    pub(crate) last_output: VecDeque<u8>,
+    /// Builtins added by the program embedding the shell
+    pub(crate) custom_builtins: Arc<HashMap<String, CustomBuiltin>>,
+    /// Completers of arguments added with `complete`, by command
+    pub(crate) completers: HashMap<String, SessionCompleter>,
+    /// Completers added by the program embedding the shell
+    pub(crate) custom_completers: Arc<HashMap<String, Completer>>,
+    pub(crate) hooks: Arc<Hooks>,
+    /// Output collected instead of written, for builtins whose output is
+    /// redirected
+    pub(crate) capture: Option<Vec<u8>>,
+    /// Where `exec` without a command redirected standard input, output
+    /// and errors, for the commands that follow and errors of the shell
+    pub(crate) exec_fds: [Option<OwnedFd>; 3],
+    /// Commands still running at this time are killed, see `timed_out`
+    pub(crate) deadline: Option<Instant>,
+    /// A command was killed at the deadline. Nothing more runs until this is
+    /// cleared.
+    pub(crate) timed_out: bool,
+    /// Commands start in process groups of their own, so killing them at
+    /// the deadline kills what they started too. Set while `timeout` runs.
+    pub(crate) grouped: bool,
+    /// `exit` was run. Nothing more runs and the session ends.
+    pub(crate) exit_requested: bool,
+    /// How deeply commands are nested, see `run_nested`
+    pub(crate) depth: usize,
+    /// Who logged in, if sessions on the transport log in
+    pub(crate) login_user: Option<String>,
+    /// What is typed is a secret, like a password: it isn't echoed and is
+    /// masked in the transcript
+    secret_input: bool,
+    /// Functions defined in the session, by name
+    pub(crate) functions: HashMap<String, Arc<Ast>>,
+    /// Arguments of the function or script running, `$1` and on
+    pub(crate) positional: Vec<String>,
+    /// Path of the script running, `$0`
+    pub(crate) script_name: Option<String>,
+    /// Process ID of the last job started in the background, `$!`
+    pub(crate) last_job_pid: Option<u32>,
+    /// How many function calls are running
+    pub(crate) function_depth: usize,
+    /// Output recorded for the receipt of a command run with `once`
+    pub(crate) receipts: Receipts,
+    /// `return` was run. Nothing more runs until the function or sourced file
+    /// ends.
+    pub(crate) returning: bool,
+    /// How many loops are running, which `break` and `continue` can leave
+    pub(crate) loop_depth: usize,
+    /// How many subshells are running, which `exec` replaces instead of the
+    /// shell
+    pub(crate) subshells: usize,
+    /// `break` or `continue` was run. Nothing more runs until the loops it
+    /// leaves end.
+    pub(crate) jump: Option<Jump>,
+    /// Ctrl-C killed a command. Nothing more of the line runs.
+    pub(crate) interrupted: bool,
+    /// Commands run when signals arrive, set with `trap`
+    pub(crate) traps: Traps,
+    /// Files being sourced, innermost last
+    pub(crate) sourcing: Vec<PathBuf>,
+    pub(crate) jobs: Jobs,
+    /// An error of the last line told that permissions were missing, see
+    /// `offer_elevation`
+    pub(crate) permission_denied: bool,
+    /// Size of the terminal at the other end of the transport, given to the
+    /// pseudo-terminals of commands
+    pub(crate) window_size: WindowSize,
+    /// When the session started, until the first prompt is shown
+    starting: Option<Instant>,
+    /// Key bindings and settings of the line editor
+    inputrc: Inputrc,
+    editor: Editor,
+    /// Typed input for builtins while the async loop holds the reader
+    #[cfg(feature = "async")]
+    lent_events: Option<async_loop::LentEvents>,
+}
+
+impl Session {
+    pub fn new(reader: Reader, writer: Writer, settings: Settings) -> Session {
+        let colors = Colors::new(settings.theme, settings.color.resolve(writer.is_terminal()));
+        let image_filter = image_filter(&settings, &writer);
+        let lossy_filter = lossy_filter(&settings);
+        let env = settings.env.clone();
+        let umask = settings.umask;
+        let mut options = ShellOptions::default();
+        options.set(ShellOptions::SUMMARY, settings.summary);
+        options.set(ShellOptions::HISTEXPAND, true);
+        let transcript = SharedTranscript::default();
+        /* Scripts fed to a batch session leave the terminal as it is */
+        let raw_terminal = reader.is_terminal() && writer.is_terminal() && !settings.batch;
+        let cwd = match settings
+            .restricted
+            .as_ref()
+            .and_then(|restricted| restricted.directory.clone())
+        {
+            Some(directory) => directory,
+            None => env::current_dir().unwrap_or_else(|_| PathBuf::from("/")),
+        };
+
+        Session {
+            reader: Recorded::new(reader, Arc::clone(&transcript)),
+            writer: Translated::new(
+                Recorded::new(writer, Arc::clone(&transcript)),
+                settings.newline,
+            ),
+            input_discipline: InputDiscipline::new(settings.input_newline),
+            raw_terminal,
+            transcript,
+            settings,
+            colors,
+            prompt_provider: Arc::new(TemplatePrompt),
+            image_filter,
+            lossy_filter,
+            cwd,
+            previous_dir: None,
+            dir_stack: Vec::new(),
+            history: Vec::new(),
+            history_store: None,
+            env,
+            umask,
+            limits: Vec::new(),
+            vars: HashMap::new(),
+            path_cache: PathCache::default(),
+            last_status: 0,
+            options,
+            conditions: 0,
+            script_timeout: None,
+            last_output: VecDeque::new(),
+            custom_builtins: Arc::new(HashMap::new()),
+            completers: HashMap::new(),
+            custom_completers: Arc::new(HashMap::new()),
+            hooks: Arc::new(Hooks::default()),
+            capture: None,
+            exec_fds: Default::default(),
+            deadline: None,
+            timed_out: false,
+            grouped: false,
+            exit_requested: false,
+            depth: 0,
+            login_user: None,
+            secret_input: false,
+            functions: HashMap::new(),
+            positional: Vec::new(),
+            script_name: None,
+            last_job_pid: None,
+            function_depth: 0,
+            receipts: Receipts::default(),
+            returning: false,
+            loop_depth: 0,
+            subshells: 0,
+            jump: None,
+            interrupted: false,
+            traps: Traps::default(),
+            sourcing: Vec::new(),
+            jobs: Jobs::default(),
+            permission_denied: false,
+            window_size: WindowSize::default(),
+            starting: None,
+            inputrc: Inputrc::default(),
+            editor: Editor::default(),
+            #[cfg(feature = "async")]
+            lent_events: None,
+        }
+    }
+
+    /// Replace the settings of the session, e.g. after the config changed
+    pub fn set_settings(&mut self, settings: Settings) {
+        self.colors = Colors::new(
+            settings.theme,
+            settings.color.resolve(self.writer.is_terminal()),
+        );
+        self.image_filter = image_filter(&settings, &self.writer);
+        self.lossy_filter = lossy_filter(&settings);
+        self.options.set(ShellOptions::SUMMARY, settings.summary);
+        self.writer.set_newline(settings.newline);
+        self.input_discipline = InputDiscipline::new(settings.input_newline);
+        self.settings = settings;
+    }
+
+    /// Compute the prompt with another provider than the configured template
+    pub fn set_prompt_provider(&mut self, provider: Arc<dyn PromptProvider>) {
+        self.prompt_provider = provider;
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn current_dir(&self) -> &Path {
+        &self.cwd
+    }
+
+    /// Set a variable passed to commands started from the session
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        self.env.insert(name.to_owned(), value.to_owned());
+    }
+
+    pub fn kind(&self) -> TransportKind {
+        self.writer.kind()
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        self.writer.is_terminal()
+    }
+
+    /// Look up a variable in the session, falling back to the process
+    /// environment. PWD and OLDPWD are the working directories of the
+    /// session.
+    pub fn var(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.working_dirs().remove(name) {
+            return Some(value);
+        }
+        match self.env.get(name).or_else(|| self.vars.get(name)) {
+            Some(value) => Some(value.clone()),
+            None => env::var(name).ok(),
+        }
+    }
+
+    /// Variables passed to commands on top of the process environment:
+    /// those exported in the session, and PWD and OLDPWD
+    pub(crate) fn exported(&self) -> HashMap<String, String> {
+        let mut exported = self.env.clone();
+        exported.extend(self.working_dirs());
+        exported
+    }
+
+    /// The environment commands of the session get, sorted by name
+    pub(crate) fn environment(&self) -> Vec<(String, String)> {
+        let mut environment: HashMap<String, String> = env::vars().collect();
+        environment.extend(self.exported());
+        let mut environment: Vec<(String, String)> = environment.into_iter().collect();
+        environment.sort();
+        environment
+    }
+
+    /// PWD and OLDPWD, which sessions keep apart from the process
+    fn working_dirs(&self) -> HashMap<String, String> {
+        let mut dirs = HashMap::from([(String::from("PWD"), self.cwd.display().to_string())]);
+        if let Some(previous) = &self.previous_dir {
+            dirs.insert(String::from("OLDPWD"), previous.display().to_string());
+        }
+        dirs
+    }
+
+    /// Set a variable from an assignment. Variables that are passed to
+    /// commands stay passed, others are only known to the shell.
+    pub(crate) fn assign(&mut self, name: &str, value: &str) {
+        if self.env.contains_key(name) || env::var_os(name).is_some() {
+            self.env.insert(name.to_owned(), value.to_owned());
+        } else {
+            self.vars.insert(name.to_owned(), value.to_owned());
+        }
+    }
+
+    /// Run the read/parse/execute loop until the reader reaches end of file
+    /// or Ctrl-D is pressed. Errors of the transport end the session.
+    pub fn run(&mut self) -> io::Result<()> {
+        if let Err(error) = self.reader.set_idle_timeout(self.settings.idle_timeout) {
+            self.print_error(&format!(
+                "{}: failed to set idle timeout: {}",
+                SHELL_NAME, error
+            ));
+        }
+
+        if self.settings.batch {
+            return self.run_batch();
+        }
+
+        self.drop_stale_input()?;
+        self.query_window_size()?;
+        self.start()?;
+        if self.exit_requested {
+            return self.finish();
+        }
+        loop {
+            self.before_prompt();
+            let mut prompt = self.prompt();
+            let mut input = String::new();
+            loop {
+                /* Print prompt, after what background jobs did since the
+                last one */
+                self.show_prompt(&prompt)?;
+
+                /* Get input, over more lines while the command is unfinished */
+                let line = match self.read_line() {
+                    Ok(Some(line)) => line,
+                    Ok(None) => return self.finish(),
+                    Err(error)
+                        if matches!(
+                            error.kind(),
+                            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                        ) =>
+                    {
+                        self.print_error("\nIdle timeout reached, closing session");
+                        return self.finish();
+                    }
+                    Err(_) if service::terminating() => {
+                        self.print_error("\nThe shell is stopping, closing session");
+                        return self.finish();
+                    }
+                    Err(error) => return Err(error),
+                };
+                if !self.add_line(&mut input, &line) {
+                    break;
+                }
+                prompt = self.continuation_prompt();
+            }
+            if is_end_of_input(&input) {
+                return self.finish();
+            }
+            let input = match self.expand_history(&input)? {
+                Some(input) => input,
+                None => continue,
+            };
+
+            self.execute_line(&input)?;
+            if self.exit_requested {
+                return self.finish();
+            }
+            self.offer_elevation(&input)?;
+        }
+    }
+
+    /// Run each line of input, or several for an unfinished command, and
+    /// follow it with `<<<EXIT STATUS TIMEms>>>`. There is no prompt, and no
+    /// question is asked, so a test rig can send a line and wait for the
+    /// status.
+    fn run_batch(&mut self) -> io::Result<()> {
+        self.load_history();
+        if !self.login()? {
+            return self.finish();
+        }
+        self.run_rc_file();
+
+        loop {
+            let mut input = String::new();
+            loop {
+                let line = match self.read_line() {
+                    Ok(Some(line)) => line,
+                    Ok(None) => return self.finish(),
+                    Err(error)
+                        if matches!(
+                            error.kind(),
+                            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                        ) =>
+                    {
+                        return self.finish();
+                    }
+                    Err(error) => return Err(error),
+                };
+                if !self.add_line(&mut input, &line) {
+                    break;
+                }
+            }
+            if is_end_of_input(&input) {
+                return self.finish();
+            }
+
+            let started = Instant::now();
+            self.execute_line(&input)?;
+            /* What background jobs did comes before the status, so it isn't
+            taken for output of the next line */
+            let messages = self.jobs.take_messages();
+            self.write_output(&messages)?;
+            let trailer = format!(
+                "<<<EXIT {} {}ms>>>\n",
+                self.last_status,
+                started.elapsed().as_millis()
+            );
+            self.write_output(trailer.as_bytes())?;
+            self.writer.flush()?;
+            if self.exit_requested {
+                return self.finish();
+            }
+        }
+    }
+
+    /// Add a line read to the input of a command. Returns whether the
+    /// command is unfinished and needs more lines. Ctrl-C drops the whole
+    /// command, Ctrl-D leaves it unfinished.
+    pub(crate) fn add_line(&mut self, input: &mut String, line: &str) -> bool {
+        if self.line_cancelled() {
+            input.clear();
+            return false;
+        }
+        if is_end_of_input(line) {
+            if input.is_empty() {
+                input.push_str(line);
+            }
+            return false;
+        }
+
+        if !input.is_empty() {
+            input.push('\n');
+        }
+        input.push_str(line);
+        is_unfinished(input)
+    }
+
+    /// End the session, which happens on `exit`, Ctrl-D and end of input.
+    /// Waits for everything written to be sent, as the UART may still be
+    /// transmitting.
+    pub(crate) fn finish(&mut self) -> io::Result<()> {
+        if self.raw_terminal {
+            terminal::restore();
+        }
+        self.run_exit_trap();
+        self.writer.drain()
+    }
+
+    /// Get the transport ready for a program replacing the shell with
+    /// `exec`, and return the device to connect it to, or None for the
+    /// stdio of the shell. Fails for transports that can't be handed over.
+    pub(crate) fn hand_over(&mut self) -> io::Result<Option<File>> {
+        let console = self.writer.console()?;
+        if self.raw_terminal {
+            terminal::restore();
+        }
+        self.writer.drain()?;
+        Ok(console)
+    }
+
+    /// Get the session ready for the first prompt. Everything else, like
+    /// looking up programs, waits until it is needed.
+    pub(crate) fn start(&mut self) -> io::Result<()> {
+        self.starting = Some(Instant::now());
+        if let Some(path) = self.settings.transcript.clone() {
+            if let Err(error) = self.start_transcript(&path) {
+                self.print_error(&format!(
+                    "{}: transcript: {}: {}",
+                    SHELL_NAME,
+                    path.display(),
+                    error
+                ));
+            }
+        }
+        self.load_history();
+        self.load_inputrc();
+        let template = match &self.settings.banner_file {
+            Some(path) => fs::read_to_string(path).ok(),
+            None => None,
+        }
+        .unwrap_or_else(|| self.settings.banner.clone());
+        if !template.is_empty() {
+            /* Files end with a newline of their own */
+            let mut banner = motd::render(&template);
+            if !banner.ends_with('\n') {
+                banner.push('\n');
+            }
+            self.write_output(banner.as_bytes())?;
+        }
+        if !self.login()? {
+            self.exit_requested = true;
+            return Ok(());
+        }
+        self.run_rc_file();
+        self.starting = None;
+        Ok(())
+    }
+
+    /// Ask who logs in and check it with the backend of the transport, if
+    /// sessions log in. Returns false if the session must end.
+    fn login(&mut self) -> io::Result<bool> {
+        let credentials = self.settings.credentials.clone();
+        let authenticator = match auth::authenticator(self.settings.auth, credentials.as_deref()) {
+            Some(authenticator) => authenticator,
+            None => return Ok(true),
+        };
+
+        let kind = self.kind();
+        for _ in 0..LOGIN_ATTEMPTS {
+            /* Failures on this transport slow down all logins on it */
+            let delay = auth::login_delay(kind);
+            if !delay.is_zero() {
+                self.print_error(&format!(
+                    "{}: login: too many failed logins, waiting {}s",
+                    SHELL_NAME,
+                    delay.as_secs_f64().ceil()
+                ));
+                thread::sleep(delay);
+            }
+            let user = match self.ask("login: ")? {
+                Some(user) if !is_end_of_input(&user) => user,
+                _ => return Ok(false),
+            };
+            let answer = match self.ask_secret(authenticator.prompt())? {
+                Some(answer) if !is_end_of_input(&answer) => answer,
+                _ => return Ok(false),
+            };
+            match authenticator.verify(user.trim(), &answer) {
+                Ok(true) => {
+                    auth::login_succeeded(kind);
+                    self.login_user = Some(user.trim().to_owned());
+                    return Ok(true);
+                }
+                Ok(false) => {
+                    auth::login_failed(kind);
+                    diagnostics::log(&format!(
+                        "failed login as {} on {}",
+                        user.trim(),
+                        format!("{:?}", kind).to_lowercase()
+                    ));
+                    self.print_error("Login incorrect");
+                }
+                /* A backend that can't check lets nobody in */
+                Err(error) => {
+                    self.print_error(&format!("{}: login: {}", SHELL_NAME, error));
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Run the commands of the rc file, if there is one
+    fn run_rc_file(&mut self) {
+        let path = match &self.settings.rc_file {
+            Some(path) => path.clone(),
+            None => return,
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return,
+            Err(error) => {
+                self.print_error(&format!("{}: {}: {}", SHELL_NAME, path.display(), error));
+                return;
+            }
+        };
+        self.sourcing.push(path.canonicalize().unwrap_or(path));
+        self.run_nested("rc", &contents);
+        self.sourcing.pop();
+        self.returning = false;
+    }
+
+    /// Tell why the prompt hasn't appeared yet, once the rc file has kept
+    /// the session starting for longer than `STARTUP_BUDGET`
+    pub(crate) fn note_slow_start(&mut self) {
+        let slow = match self.starting {
+            Some(started) => started.elapsed() > STARTUP_BUDGET,
+            None => false,
+        };
+        if slow {
+            self.starting = None;
+            if let Some(path) = &self.settings.rc_file {
+                let note = format!("{}: still running {}...", SHELL_NAME, path.display());
+                self.print_error(&note);
+            }
+        }
+    }
+
+    pub(crate) fn prompt(&self) -> String {
+        self.with_prompt_context(&self.settings.prompt, |context| {
+            self.prompt_provider.prompt(context, &self.colors)
+        })
+    }
+
+    /// The prompt for more lines of an unfinished command, like an `if`
+    /// without `fi`. Its template is always expanded, providers only compute
+    /// the first prompt.
+    pub(crate) fn continuation_prompt(&self) -> String {
+        self.with_prompt_context(&self.settings.continuation_prompt, |context| {
+            prompt::render(context.template, context, &self.colors)
+        })
+    }
+
+    fn with_prompt_context<T>(&self, template: &str, f: impl FnOnce(&PromptContext) -> T) -> T {
+        let host_name = match fs::read_to_string("/proc/sys/kernel/hostname") {
+            Ok(name) => name.trim().to_owned(),
+            Err(_) => String::new(),
+        };
+        let context = PromptContext {
+            user: &env::var("USER").unwrap_or_default(),
+            host_name: &host_name,
+            home: &self.var("HOME").unwrap_or_default(),
+            current_dir: &self.cwd,
+            template,
+            last_status: self.last_status,
+        };
+        f(&context)
+    }
+
+    /// Read a line of input. Returns `None` when the reader reaches end of file.
+    pub fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut input = String::new();
+
+        /* Read until a newline or a control character */
+        loop {
+            let c = match self.read_char() {
+                Ok(Some(c)) => c,
+                Ok(None) => return Ok(None),
+                Err(error) => return Err(error),
+            };
+
+            if let Some(line) = self.edit_line(&mut input, c)? {
+                return Ok(Some(line));
+            }
+        }
+    }
+
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        #[cfg(feature = "async")]
+        if let Some(result) = self.read_lent_char() {
+            return result;
+        }
+        self.reader.read_utf8_char()
+    }
+
+    /// Print a question and read the answer
+    pub(crate) fn ask(&mut self, question: &str) -> io::Result<Option<String>> {
+        self.writer.write_all(question.as_bytes())?;
+        self.writer.flush()?;
+        self.begin_line(question);
+        self.read_line()
+    }
+
+    /// Ask for something that must not be shown, like a password
+    pub(crate) fn ask_secret(&mut self, question: &str) -> io::Result<Option<String>> {
+        self.set_secret_input(true);
+        let answer = self.ask(question);
+        self.set_secret_input(false);
+        self.write_output(b"\n")?;
+        answer
+    }
+
+    /// Whether the shell echoes what is typed: the terminal doesn't do it
+    /// itself and the input isn't a secret
+    pub(crate) fn echoes(&self) -> bool {
+        self.settings.echo && !self.secret_input
+    }
+
+    /// Echo typed input or leave it to the terminal, for the rest of the
+    /// session, e.g. after switching local echo of the terminal
+    pub(crate) fn set_echo(&mut self, echo: bool) {
+        self.settings.echo = echo;
+    }
+
+    /// Stop echoing what is typed and mask it in the transcript while it is
+    /// a secret, like a password
+    pub(crate) fn set_secret_input(&mut self, secret: bool) {
+        self.secret_input = secret;
+        if let Some(transcript) = transcript::lock(&self.transcript).as_mut() {
+            transcript.set_hidden(secret);
+        }
+    }
+
+    /// Record everything read and written from now on in a transcript
+    /// appended to `path`, instead of the one written so far
+    pub(crate) fn start_transcript(&mut self, path: &Path) -> io::Result<()> {
+        let what = format!("a session on {:?}", self.kind());
+        let started = Transcript::open(path, &what)?;
+        *transcript::lock(&self.transcript) = Some(started);
+        Ok(())
+    }
+
+    /// Stop writing the transcript. Returns the file it went to, if one was
+    /// written.
+    pub(crate) fn stop_transcript(&mut self) -> Option<PathBuf> {
+        let stopped = transcript::lock(&self.transcript).take();
+        stopped.map(|transcript| transcript.path().to_owned())
+    }
+
+    /// The file the transcript goes to, if one is written
+    pub(crate) fn transcript_path(&self) -> Option<PathBuf> {
+        let transcript = transcript::lock(&self.transcript);
+        transcript
+            .as_ref()
+            .map(|transcript| transcript.path().to_owned())
+    }
+
+    /// Remember an input line, dropping the oldest entries once the history
+    /// is full
+    pub(crate) fn add_history(&mut self, input: &str) {
+        let line = input.trim();
+        if line.is_empty() || line.starts_with('\u{4}') || self.settings.history_size == 0 {
+            return;
+        }
+        /* History entries are single lines, so commands written over several
+        are kept written on one */
+        let joined;
+        let line = match line.contains('\n') {
+            true => {
+                joined = match parser::parse(line) {
+                    Ok(ast) => ast.to_string(),
+                    Err(_) => line.replace('\n', " "),
+                };
+                &joined
+            }
+            false => line,
+        };
+
+        self.history.push(line.to_owned());
+        self.trim_history();
+
+        if let Some(store) = &mut self.history_store {
+            if let Err(error) = store.append(line) {
+                self.print_error(&format!("{}: {}", SHELL_NAME, error));
+            }
+        }
+    }
+
+    /// Drop the oldest history entries beyond the size and memory caps
+    fn trim_history(&mut self) {
+        let mut excess = self
+            .history
+            .len()
+            .saturating_sub(self.settings.history_size);
+        let mut memory = history_memory(&self.history[excess..]);
+        while memory > self.settings.history_memory && excess < self.history.len() {
+            memory -= self.history[excess].len();
+            excess += 1;
+        }
+        self.history.drain(..excess);
+    }
+
+    /// Load the latest history entries kept by the history backend, if
+    /// there is one
+    fn load_history(&mut self) {
+        let store = history::store(
+            self.settings.history_backend,
+            self.settings.history_file.as_deref(),
+        );
+        self.history_store = match store {
+            Ok(store) => store,
+            Err(error) => {
+                self.print_error(&format!("{}: {}", SHELL_NAME, error));
+                return;
+            }
+        };
+
+        if let Some(store) = &mut self.history_store {
+            match store.load(self.settings.history_size) {
+                Ok(lines) => {
+                    self.history = lines;
+                    self.trim_history();
+                }
+                Err(error) => self.print_error(&format!("{}: {}", SHELL_NAME, error)),
+            }
+        }
+    }
+
+    /// Write output to the transport, translating line endings and pausing
+    /// every `pager` lines if paging is enabled. Images are stripped if the
+    /// transport can't show them, and invalid UTF-8 is replaced if the
+    /// settings ask for it.
+    pub fn write_output(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(capture) = &mut self.capture {
+            capture.extend_from_slice(data);
+            return Ok(());
+        }
+        self.receipts.record(data);
+
+        let data = self.filter_output(data);
+        let lines: Vec<&[u8]> = data.split(|byte| *byte == b'\n').collect();
+        let mut lines_shown = 0;
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 {
+                self.writer.write_all(b"\n")?;
+                lines_shown += 1;
+
+                /* Only pause if there is something left to show */
+                let remaining = &lines[i..];
+                if self.settings.pager == Some(lines_shown)
+                    && (remaining.len() > 1 || !remaining[0].is_empty())
+                {
+                    lines_shown = 0;
+                    if !self.more()? {
+                        return Ok(());
+                    }
+                }
+            }
+            self.writer.write_all(line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Show a prompt after the output of background jobs since the last one.
+    /// It all goes out in a single write, so on slow links the prompt isn't
+    /// torn apart by other output or echoed input.
+    pub(crate) fn show_prompt(&mut self, prompt: &str) -> io::Result<()> {
+        let messages = self.jobs.take_messages();
+        let mut buffer = self.render_output(&messages);
+        self.begin_line(prompt);
+        buffer.extend_from_slice(self.start_bracketed_paste().as_bytes());
+        buffer.extend_from_slice(prompt.as_bytes());
+        self.writer.write_all(&buffer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Output as `write_output` would send it, without paging, for writing it
+    /// together with something else
+    pub(crate) fn render_output(&mut self, data: &[u8]) -> Vec<u8> {
+        self.filter_output(data).into_owned()
+    }
+
+    /// Strip images and replace invalid UTF-8, as far as the transport and
+    /// the settings ask for it
+    fn filter_output<'a>(&mut self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        let data = match &mut self.image_filter {
+            Some(filter) => Cow::Owned(filter.filter(data)),
+            None => Cow::Borrowed(data),
+        };
+        match &mut self.lossy_filter {
+            Some(filter) => Cow::Owned(filter.filter(&data)),
+            None => data,
+        }
+    }
+
+    /// Pass on what the output filters held back once a command ended, so
+    /// an escape sequence or character it left unfinished doesn't swallow or
+    /// garble the output of later commands
+    pub(crate) fn finish_output(&mut self) -> io::Result<()> {
+        let rest = match &mut self.image_filter {
+            Some(filter) => filter.finish(),
+            None => Vec::new(),
+        };
+        let rest = match &mut self.lossy_filter {
+            Some(filter) => {
+                let mut rest = filter.filter(&rest);
+                rest.extend(filter.finish());
+                rest
+            }
+            None => rest,
+        };
+        self.writer.write_all(&rest)
+    }
+
+    /// Input of commands run in the foreground. On a terminal on stdio they
+    /// use it directly, over the UART and TCP they get a pseudo-terminal
+    /// relayed to the transport.
+    pub(crate) fn foreground_input(&self) -> Input {
+        match &**self.writer {
+            Writer::Stdout(_) if self.reader.is_terminal() && self.writer.is_terminal() => {
+                Input::Terminal
+            }
+            Writer::Uart(_) | Writer::Tcp(_) | Writer::Telnet(_) => Input::Pty(self.window_size),
+            _ => Input::Null,
+        }
+    }
+
+    /// Drop input the other end of the UART or a TCP connection had
+    /// buffered before it connected, so it doesn't replay into the first
+    /// prompt. Input is read until none arrived for `RECONNECT_GRACE`, and the
+    /// line editor starts over, not in the middle of an escape sequence.
+    /// Returns whether anything was dropped.
+    pub(crate) fn drop_stale_input(&mut self) -> io::Result<bool> {
+        if !matches!(
+            **self.writer,
+            Writer::Uart(_) | Writer::Tcp(_) | Writer::Telnet(_)
+        ) {
+            return Ok(false);
+        }
+
+        self.set_input_polling(true)?;
+        let started = Instant::now();
+        let mut quiet_since = started;
+        let mut dropped = false;
+        while quiet_since.elapsed() < RECONNECT_GRACE && started.elapsed() < RECONNECT_DRAIN_LIMIT {
+            let mut buf = [0u8; 64];
+            match self.reader.read(&mut buf) {
+                Ok(0) => thread::sleep(Duration::from_millis(10)),
+                Ok(_) => {
+                    dropped = true;
+                    quiet_since = Instant::now();
+                }
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        self.set_input_polling(false)?;
+
+        self.editor = Editor::default();
+        Ok(dropped)
+    }
+
+    /// Lend the UART to a file transfer, which reads and writes bytes as
+    /// they are, without line editing or newline translation. Reads return
+    /// what has arrived right away while it is lent, and the line editor
+    /// starts over afterwards.
+    pub(crate) fn transfer<T>(
+        &mut self,
+        f: impl FnOnce(&mut Reader, &mut Writer) -> io::Result<T>,
+    ) -> io::Result<T> {
+        /* The async loop holds the reader, decoding input as text */
+        if !matches!(*self.reader, Reader::Uart(_)) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "file transfers need a session on the UART without the async loop",
+            ));
+        }
+
+        /* XON and XOFF are bytes like any other in files */
+        let software_flow_control = self.settings.flow_control == FlowControl::XonXoff;
+        if software_flow_control {
+            self.writer.set_software_flow_control(false)?;
+        }
+        self.set_input_polling(true)?;
+        /* Files sent are left out of the transcript */
+        let result = f(self.reader.unrecorded(), self.writer.unrecorded());
+        self.set_input_polling(false)?;
+        if software_flow_control {
+            self.writer.set_software_flow_control(true)?;
+        }
+        self.editor = Editor::default();
+        result
+    }
+
+    /// Ask the terminal at the other end of the UART or a plain TCP
+    /// connection for its size. Terminals that don't answer in time keep the
+    /// default size.
+    pub(crate) fn query_window_size(&mut self) -> io::Result<()> {
+        if !matches!(**self.writer, Writer::Uart(_) | Writer::Tcp(_)) {
+            return Ok(());
+        }
+
+        self.writer.write_all(pty::SIZE_QUERY)?;
+        self.writer.flush()?;
+        self.set_input_polling(true)?;
+        let deadline = Instant::now() + SIZE_QUERY_TIMEOUT;
+        let mut answer = Vec::new();
+        let size = loop {
+            if let Some(size) = pty::parse_size_report(&answer) {
+                break Some(size);
+            }
+            if Instant::now() >= deadline {
+                break None;
+            }
+
+            let mut buf = [0u8; 16];
+            match self.reader.read(&mut buf) {
+                Ok(0) => thread::sleep(Duration::from_millis(10)),
+                Ok(length) => answer.extend_from_slice(&buf[..length]),
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(error) => return Err(error),
+            }
+        };
+        self.set_input_polling(false)?;
+
+        if let Some(size) = size {
+            self.window_size = size;
+        }
+        Ok(())
+    }
+
+    /// Take the size the terminal told over the transport, if it did.
+    /// Returns true if it changed.
+    pub(crate) fn follow_window_size(&mut self) -> bool {
+        match self.reader.window_size() {
+            Some(size) if size != self.window_size => {
+                self.window_size = size;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) fn set_input_polling(&mut self, polling: bool) -> io::Result<()> {
+        self.reader.set_polling(polling)?;
+        if !polling {
+            self.reader.set_idle_timeout(self.settings.idle_timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Pass input that arrived on to the commands running in the foreground.
+    /// On a pseudo-terminal it goes through as typed. Otherwise Ctrl-D closes
+    /// their input and true is returned for Ctrl-C, which kills them. True is
+    /// also returned for the keys killing them anyway, see `DeadMan`.
+    pub(crate) fn bridge_input(
+        &mut self,
+        running: &mut Running,
+        stdin: &mut Option<PipeWriter>,
+        dead_man: &mut DeadMan,
+    ) -> io::Result<bool> {
+        let mut buf = [0u8; 64];
+        let length = match self.reader.read(&mut buf) {
+            Ok(length) => length,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                0
+            }
+            Err(error) => return Err(error),
+        };
+        if self.follow_window_size() {
+            running.resize(self.window_size);
+        }
+
+        let mut typed = Vec::with_capacity(length);
+        for &byte in &buf[..length] {
+            if dead_man.feed(byte, &mut typed) {
+                self.print_error(&format!("\n{}: killed the foreground command", SHELL_NAME));
+                return Ok(true);
+            }
+        }
+        if running.has_terminal() {
+            /* The commands may have closed the terminal already */
+            let _ = running.write_terminal(&typed);
+            return Ok(false);
+        }
+
+        for byte in typed {
+            match byte {
+                0x3 => {
+                    self.write_output(b"^C\n")?;
+                    return Ok(true);
+                }
+                0x4 => *stdin = None,
+                _ => {
+                    /* Programs expect lines to end with a newline */
+                    let byte = match self.input_discipline.translate(char::from(byte)) {
+                        Some(c) => c as u8,
+                        None => continue,
+                    };
+                    if self.echoes() {
+                        self.write_output(&[byte])?;
+                    }
+                    if let Some(pipe) = stdin {
+                        if pipe.write_all(&[byte]).is_err() {
+                            *stdin = None;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Read what was typed while a builtin waits with nothing in the
+    /// foreground to pass it to. Returns true for Ctrl-C, other input is
+    /// dropped. Input on stdio is left alone, as it may be a script, and
+    /// Ctrl-C in a terminal there is a signal anyway.
+    pub(crate) fn poll_interrupt(&mut self) -> io::Result<bool> {
+        if !matches!(self.foreground_input(), Input::Pty(_)) {
+            return Ok(false);
+        }
+        self.set_input_polling(true)?;
+        let mut buf = [0u8; 64];
+        let read = self.reader.read(&mut buf);
+        self.set_input_polling(false)?;
+        let length = match read {
+            Ok(length) => length,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                0
+            }
+            Err(error) => return Err(error),
+        };
+        if buf[..length].contains(&0x3) {
+            self.write_output(b"^C\n")?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Show a "--More--" marker and wait for a key. Returns false if the user
+    /// asked to skip the rest of the output.
+    fn more(&mut self) -> io::Result<bool> {
+        const MARKER: &str = "--More--";
+
+        self.writer.write_all(MARKER.as_bytes())?;
+        let key = self.reader.read_utf8_char()?;
+        self.writer
+            .write_all(format!("\r{}\r", " ".repeat(MARKER.len())).as_bytes())?;
+
+        Ok(!matches!(key, None | Some('q') | Some('\u{3}')))
+    }
+
+    /// Copy data to the clipboard of the terminal, if it is allowed to
+    pub fn copy_to_clipboard(&mut self, data: &[u8]) -> Result<(), String> {
+        if !self.settings.clipboard || !self.is_terminal() {
+            return Err(String::from("the terminal does not support the clipboard"));
+        }
+        if data.len() > clipboard::MAX_LENGTH {
+            return Err(format!(
+                "{} bytes is more than the {} bytes that can be copied",
+                data.len(),
+                clipboard::MAX_LENGTH
+            ));
+        }
+
+        let sequence = clipboard::osc52(data);
+        self.writer
+            .write_all(&sequence)
+            .and_then(|()| self.writer.flush())
+            .map_err(|error| error.to_string())
+    }
+
+    /// Print an error that made a command fail and set the exit status
+    pub(crate) fn report(&mut self, error: &ShellError) {
+        self.print_error(&format!("{}: {}", SHELL_NAME, error));
+        self.last_status = error.exit_code();
+    }
+
+    pub fn print_error(&mut self, message: &str) {
+        self.permission_denied |= elevate::mentions_denied(message.as_bytes());
+        /* Errors are shown even when the output is collected */
+        /* `exec 2>FILE` sends them to the file instead */
+        if let Some(fd) = &self.exec_fds[2] {
+            let written = fd
+                .try_clone()
+                .and_then(|fd| File::from(fd).write_all(format!("{}\n", message).as_bytes()));
+            if let Err(error) = written {
+                eprintln!("{}: failed to write error: {}", SHELL_NAME, error);
+            }
+            return;
+        }
+        let capture = self.capture.take();
+        let message = format!("{}\n", self.colors.paint(Role::Error, message));
+        if let Err(error) = self.write_output(message.as_bytes()) {
+            eprintln!("{}: failed to write error: {}", SHELL_NAME, error);
+        }
+        self.capture = capture;
+    }
+}
+
+fn image_filter(settings: &Settings, writer: &Writer) -> Option<ImageFilter> {
+    if settings.images.resolve(writer.supports_images()) {
+        None
+    } else {
+        Some(ImageFilter::new())
+    }
+}
+
+fn lossy_filter(settings: &Settings) -> Option<LossyFilter> {
+    match settings.output_encoding {
+        OutputEncoding::Raw => None,
+        OutputEncoding::Lossy => Some(LossyFilter::new()),
+    }
+}
+
+/// Bytes used by history entries
+pub(crate) fn history_memory(history: &[String]) -> usize {
+    history.iter().map(String::len).sum()
+}
+
+/// Whether a line read from the user asks to end the session, which is what
+/// the line editor returns for Ctrl-D
+pub(crate) fn is_end_of_input(input: &str) -> bool {
+    input.starts_with('\u{4}')
+}
+
+/// Whether input ends inside a command, e.g. in quotes or after `then`, so
+/// more lines could finish it
+pub(crate) fn is_unfinished(input: &str) -> bool {
+    matches!(parser::parse(input), Err(error) if error.incomplete)
+}
+
+/// The exit status of a process as the shell reports it, with 128 plus the
+/// signal number for processes killed by a signal
+pub(crate) fn exit_code(status: ExitStatus) -> i32 {
+    match status.code() {
+        Some(code) => code,
+        None => StatusCode::signaled(status.signal().unwrap_or_default()),
+    }
+}
@@ -0,0 +1,601 @@
+//! Session loop built on an async runtime, enabled with the `async` feature.
+//!
+//! Typed input, the output of the running command and notifications from
+//! background jobs all arrive as events, so they can be handled as they come
+//! instead of one blocking read at a time.
+
+use std::collections::VecDeque;
+use std::io::{self, PipeWriter, Write};
+use std::mem;
+use std::sync::Arc;
+use std::thread;
+
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::Session;
+use crate::elevate::{self, Authentication};
+use crate::exec::{self, Chunk, DeadMan, Input, OutputCap, Running, Timer, POLL_INTERVAL};
+use crate::parser::{self, AndOr, Ast, Pipeline};
+use crate::pty::WindowSize;
+use crate::service;
+use crate::transcript::Recorded;
+use crate::transport::Reader;
+use crate::{ShellError, StatusCode, SHELL_NAME};
+
+pub(crate) enum Event {
+    Input(char),
+    /// The transport reached end of file or failed
+    InputClosed(Option<io::Error>),
+    /// Background jobs have output or completed
+    Jobs,
+    /// The terminal at the other end told its new size
+    Resize(WindowSize),
+}
+
+/// The events of the async loop, lent to the session while builtins run
+pub(crate) struct LentEvents {
+    events: UnboundedReceiver<Event>,
+    /// Events other than typed input, handled once the loop has them back
+    skipped: Vec<Event>,
+}
+
+struct AsyncLoop<'a> {
+    session: &'a mut Session,
+    events: UnboundedReceiver<Event>,
+    /// Input received while a command ran that is meant for the shell
+    pending: VecDeque<Event>,
+    sender: UnboundedSender<Event>,
+    /// The prompt and input shown while a line is being edited, used to
+    /// redraw the line after printing a notification
+    prompt: Option<String>,
+    input: String,
+}
+
+impl Session {
+    /// Run the session loop on an async runtime until the reader reaches end
+    /// of file. Falls back to `run` if the runtime can't be started, and in
+    /// batch mode, where lines run one after the other anyway.
+    pub fn run_async(&mut self) -> io::Result<()> {
+        if self.settings.batch {
+            return self.run();
+        }
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(error) => {
+                /* Fall back to the blocking loop */
+                self.print_error(&format!(
+                    "{}: failed to start runtime: {}",
+                    SHELL_NAME, error
+                ));
+                return self.run();
+            }
+        };
+
+        if let Err(error) = self.reader.set_idle_timeout(self.settings.idle_timeout) {
+            self.print_error(&format!(
+                "{}: failed to set idle timeout: {}",
+                SHELL_NAME, error
+            ));
+        }
+
+        self.drop_stale_input()?;
+        self.query_window_size()?;
+
+        /* Transports only offer blocking reads, so a thread turns the input
+        into events */
+        let (sender, events) = mpsc::unbounded_channel();
+        let closed = Recorded::new(Reader::Closed, Arc::clone(&self.transcript));
+        let mut reader = mem::replace(&mut self.reader, closed);
+        /* Sizes arrive in between input, which the thread may wait for */
+        let resize_sender = sender.clone();
+        reader.set_resize_callback(Box::new(move |size| {
+            let _ = resize_sender.send(Event::Resize(size));
+        }));
+        let input_sender = sender.clone();
+        thread::spawn(move || loop {
+            let event = match reader.read_utf8_char() {
+                Ok(Some(c)) => Event::Input(c),
+                Ok(None) => Event::InputClosed(None),
+                Err(error) => Event::InputClosed(Some(error)),
+            };
+            let closed = matches!(event, Event::InputClosed(_));
+            if input_sender.send(event).is_err() || closed {
+                return;
+            }
+        });
+
+        let mut async_loop = AsyncLoop {
+            session: self,
+            events,
+            pending: VecDeque::new(),
+            sender,
+            prompt: None,
+            input: String::new(),
+        };
+        runtime.block_on(async_loop.run())
+    }
+
+    /// Read a typed character from the events lent to the session, if they
+    /// are. Waits for one like a blocking read of the transport would.
+    pub(crate) fn read_lent_char(&mut self) -> Option<io::Result<Option<char>>> {
+        let lent = self.lent_events.as_mut()?;
+        loop {
+            match lent.events.try_recv() {
+                Ok(Event::Input(c)) => return Some(Ok(Some(c))),
+                Ok(Event::InputClosed(error)) => {
+                    let result = match &error {
+                        Some(error) => Err(io::Error::new(error.kind(), error.to_string())),
+                        None => Ok(None),
+                    };
+                    lent.skipped.push(Event::InputClosed(error));
+                    return Some(result);
+                }
+                Ok(event) => lent.skipped.push(event),
+                Err(TryRecvError::Empty) => thread::sleep(POLL_INTERVAL),
+                Err(TryRecvError::Disconnected) => return Some(Ok(None)),
+            }
+        }
+    }
+}
+
+impl AsyncLoop<'_> {
+    async fn run(&mut self) -> io::Result<()> {
+        let sender = self.sender.clone();
+        self.session.jobs.set_waker(Arc::new(move || {
+            let _ = sender.send(Event::Jobs);
+        }));
+
+        /* Logging in reads typed input */
+        self.lend_events();
+        let started = self.session.start();
+        self.take_back_events();
+        started?;
+        if self.session.exit_requested {
+            return self.session.finish();
+        }
+        loop {
+            let input = match self.read_line().await? {
+                Some(input) => input,
+                None => return self.session.finish(),
+            };
+            if super::is_end_of_input(&input) {
+                return self.session.finish();
+            }
+            let input = match self.session.expand_history(&input)? {
+                Some(input) => input,
+                None => continue,
+            };
+
+            self.session.add_history(&input);
+            self.session.permission_denied = false;
+            self.session.interrupted = false;
+            let command = input.trim();
+            if command.is_empty() {
+                continue;
+            }
+
+            let started = self.session.before_command(command);
+            let input_open = match parser::parse(&input) {
+                Ok(ast) => self.execute(&ast).await?,
+                Err(error) => {
+                    self.session.report(&ShellError::Parse(error.message));
+                    true
+                }
+            };
+            self.session.run_line_traps();
+            self.session.after_command(command, started);
+            if !input_open || self.session.exit_requested || !self.offer_elevation(&input).await? {
+                return self.session.finish();
+            }
+        }
+    }
+
+    /// After a line failed for lack of permissions, offer to run it again as
+    /// root. Returns false if the session must end because the input was
+    /// closed meanwhile.
+    async fn offer_elevation(&mut self, line: &str) -> io::Result<bool> {
+        if !self.session.should_offer_elevation(line) {
+            return Ok(true);
+        }
+
+        match self.ask(elevate::QUESTION, false).await? {
+            Some(answer) if elevate::is_yes(Some(&answer)) => {}
+            Some(_) => return Ok(true),
+            None => return Ok(false),
+        }
+
+        let authentication = self.session.authentication();
+        let pipeline = elevate::elevated(line, authentication);
+        if authentication != Authentication::Shell {
+            return self.run_foreground(&pipeline).await;
+        }
+
+        let password = match self.ask(elevate::PASSWORD_PROMPT, true).await? {
+            Some(password) => password,
+            None => return Ok(false),
+        };
+        let stdin = self.session.lend_password(&password)?;
+        let result = self.run_foreground(&pipeline).await;
+        self.session.forget_password(stdin);
+        result
+    }
+
+    /// Run parsed input. Returns false if the session must end because the
+    /// input was closed meanwhile.
+    async fn execute(&mut self, ast: &Ast) -> io::Result<bool> {
+        for item in &ast.items {
+            self.session.run_traps();
+            if self.session.stopped() {
+                break;
+            }
+            if item.background {
+                self.session.start_job(&item.and_or)?;
+            } else if !self.run_and_or(&item.and_or).await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Print the prompt and edit lines until the command is finished.
+    /// Returns `None` when the input is closed.
+    async fn read_line(&mut self) -> io::Result<Option<String>> {
+        self.session.before_prompt();
+        let mut prompt = self.session.prompt();
+        let mut input = String::new();
+        loop {
+            let line = match self.edit(prompt).await? {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+            if !self.session.add_line(&mut input, &line) {
+                return Ok(Some(input));
+            }
+            prompt = self.session.continuation_prompt();
+        }
+    }
+
+    /// Ask a question and read the answer, without echoing it if it is a
+    /// secret like a password
+    async fn ask(&mut self, question: &str, secret: bool) -> io::Result<Option<String>> {
+        self.session.set_secret_input(secret);
+        let answer = self.edit(question.to_owned()).await;
+        self.session.set_secret_input(false);
+        if secret {
+            self.output(b"\n");
+        }
+        answer
+    }
+
+    /// Show a prompt and edit a line until it is finished
+    async fn edit(&mut self, prompt: String) -> io::Result<Option<String>> {
+        if let Err(error) = self.session.show_prompt(&prompt) {
+            eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+        }
+        self.prompt = Some(prompt);
+        self.input.clear();
+
+        let line = loop {
+            let event = match self.pending.pop_front() {
+                Some(event) => Some(event),
+                None => self.events.recv().await,
+            };
+            match event {
+                Some(Event::Input(c)) => {
+                    if let Some(line) = self.session.edit_line(&mut self.input, c)? {
+                        break Some(line);
+                    }
+                }
+                Some(Event::InputClosed(error)) => {
+                    self.report_closed(error)?;
+                    break None;
+                }
+                None => break None,
+                Some(Event::Jobs) => self.notify(),
+                Some(Event::Resize(size)) => self.session.window_size = size,
+            }
+        };
+
+        self.prompt = None;
+        Ok(line)
+    }
+
+    /// Run the pipelines of an and-or list. Returns false if the session
+    /// must end because the input was closed meanwhile.
+    async fn run_and_or(&mut self, and_or: &AndOr) -> io::Result<bool> {
+        /* As in `Session::run_and_or`, only the last pipeline failing ends
+        the session with `set -e` */
+        let mut ran_last = and_or.rest.is_empty();
+        if !self.run_tested(&and_or.first, !ran_last).await? {
+            return Ok(false);
+        }
+        for (i, (connector, pipeline)) in and_or.rest.iter().enumerate() {
+            if self.session.stopped() {
+                break;
+            }
+            if exec::should_run(*connector, self.session.last_status) {
+                ran_last = i + 1 == and_or.rest.len();
+                if !self.run_tested(pipeline, !ran_last).await? {
+                    return Ok(false);
+                }
+            }
+        }
+        if ran_last {
+            self.session.check_errexit();
+        }
+        Ok(true)
+    }
+
+    /// Run a pipeline in the foreground, whose status is tested if `tested`
+    async fn run_tested(&mut self, pipeline: &Pipeline, tested: bool) -> io::Result<bool> {
+        let timer = pipeline.timed.then(Timer::start);
+        self.session.conditions += usize::from(tested);
+        let result = self.run_foreground(pipeline).await;
+        self.session.conditions -= usize::from(tested);
+        if let (Ok(true), Some(timer)) = (&result, timer) {
+            self.session.write_output(timer.report().as_bytes())?;
+        }
+        result
+    }
+
+    /// Run a pipeline while relaying its output as it arrives and passing
+    /// typed input to it. Returns false if the session must end because the
+    /// input was closed meanwhile.
+    async fn run_foreground(&mut self, pipeline: &Pipeline) -> io::Result<bool> {
+        /* The input is read by another thread, so it can't be handed to the
+        commands as their terminal */
+        let input = match self.session.foreground_input() {
+            Input::Pty(size) => Input::Pty(size),
+            _ => Input::Pipe,
+        };
+        /* Builtins like `read` run right away and get typed input from the
+        events meanwhile */
+        self.lend_events();
+        let running = match self.session.start_pipeline(pipeline, input) {
+            Err(ShellError::Pty(_)) => self.session.start_pipeline(pipeline, Input::Pipe),
+            result => result,
+        };
+        self.take_back_events();
+        let mut running = match running {
+            Ok(running) => running,
+            Err(error) => {
+                self.session.report(&error);
+                return Ok(true);
+            }
+        };
+
+        let mut stdin = running.input.take();
+        let (sender, mut chunks) = mpsc::unbounded_channel();
+        running.relay_output(move |chunk| sender.send(chunk).is_ok());
+        let mut output_open = true;
+        let mut input_open = true;
+        let mut output = VecDeque::new();
+        let mut cap = OutputCap::new(self.session.settings.output_limit);
+        let mut dead_man = DeadMan::new();
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+
+        loop {
+            tokio::select! {
+                chunk = chunks.recv(), if output_open => match chunk {
+                    Some(chunk) => self.relay(chunk, &mut output, &mut cap),
+                    None => output_open = false,
+                },
+                event = self.events.recv(), if input_open => match event {
+                    /* Input that is not typed, e.g. a script piped to the
+                    shell, is kept for the shell instead of going to commands */
+                    Some(event @ (Event::Input(_) | Event::InputClosed(_)))
+                        if !self.session.is_terminal() =>
+                    {
+                        input_open = !matches!(event, Event::InputClosed(_));
+                        self.pending.push_back(event);
+                    }
+                    Some(Event::Input(c)) => {
+                        if self.forward_input(&mut running, &mut stdin, &mut dead_man, c) {
+                            running.kill();
+                            self.session.interrupted = true;
+                        }
+                    }
+                    Some(Event::InputClosed(error)) => {
+                        self.report_closed(error)?;
+                        input_open = false;
+                        running.kill();
+                    }
+                    None => {
+                        input_open = false;
+                        running.kill();
+                    }
+                    Some(Event::Jobs) => self.notify(),
+                    Some(Event::Resize(size)) => {
+                        self.session.window_size = size;
+                        running.resize(size);
+                    }
+                },
+                _ = poll.tick() => {
+                    let status = match running.try_wait() {
+                        Ok(Some(status)) => status,
+                        Ok(None) => continue,
+                        Err(error) => {
+                            running.kill();
+                            let error = ShellError::Exec {
+                                program: pipeline.to_string(),
+                                error,
+                            };
+                            error.exit_code()
+                        }
+                    };
+
+                    /* Output written just before exiting may still be on its
+                    way */
+                    while let Ok(Some(chunk)) =
+                        tokio::time::timeout(POLL_INTERVAL, chunks.recv()).await
+                    {
+                        self.relay(chunk, &mut output, &mut cap);
+                    }
+                    self.session.finish_output()?;
+                    self.session.last_status = status;
+                    self.session.interrupted |= StatusCode::signal(status) == Some(libc::SIGINT);
+                    if running.pid().is_some() {
+                        self.session.last_output = output;
+                    }
+                    return Ok(input_open || !self.pending.is_empty());
+                }
+            }
+        }
+    }
+
+    /// Let the session read typed input from the events while it runs
+    /// something blocking
+    fn lend_events(&mut self) {
+        let (_, closed) = mpsc::unbounded_channel();
+        self.session.lent_events = Some(LentEvents {
+            events: mem::replace(&mut self.events, closed),
+            skipped: Vec::new(),
+        });
+    }
+
+    /// Get the events back from the session, with those it skipped
+    fn take_back_events(&mut self) {
+        if let Some(lent) = self.session.lent_events.take() {
+            self.events = lent.events;
+            self.pending.extend(lent.skipped);
+        }
+    }
+
+    /// Pass a typed character on to the foreground pipeline. On a
+    /// pseudo-terminal it goes through as typed. Otherwise Ctrl-D closes its
+    /// input and true is returned for Ctrl-C, which kills the pipeline. True
+    /// is also returned for the keys killing it anyway, see `DeadMan`.
+    fn forward_input(
+        &mut self,
+        running: &mut Running,
+        stdin: &mut Option<PipeWriter>,
+        dead_man: &mut DeadMan,
+        c: char,
+    ) -> bool {
+        let mut encoded = [0u8; 4];
+        let mut typed = Vec::new();
+        for &byte in c.encode_utf8(&mut encoded).as_bytes() {
+            if dead_man.feed(byte, &mut typed) {
+                let message = format!("\n{}: killed the foreground command", SHELL_NAME);
+                self.session.print_error(&message);
+                return true;
+            }
+        }
+        if running.has_terminal() {
+            /* The commands may have closed the terminal already */
+            let _ = running.write_terminal(&typed);
+            return false;
+        }
+
+        for c in String::from_utf8_lossy(&typed).chars() {
+            match c {
+                '\u{3}' => {
+                    self.write(b"^C\n");
+                    return true;
+                }
+                '\u{4}' => *stdin = None,
+                _ => {
+                    /* Programs expect lines to end with a newline */
+                    let c = match self.session.input_discipline.translate(c) {
+                        Some(c) => c,
+                        None => continue,
+                    };
+                    if self.session.echoes() {
+                        let mut echo = [0u8; 4];
+                        self.write(c.encode_utf8(&mut echo).as_bytes());
+                    }
+                    if let Some(pipe) = stdin {
+                        let mut data = [0u8; 4];
+                        if pipe.write_all(c.encode_utf8(&mut data).as_bytes()).is_err() {
+                            *stdin = None;
+                        }
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Write output of the foreground pipeline, keeping what went to its
+    /// standard output
+    fn relay(&mut self, chunk: Chunk, output: &mut VecDeque<u8>, cap: &mut OutputCap) {
+        let data = match chunk {
+            Chunk::Stdout(data) => {
+                let limit = self.session.settings.scrollback_memory;
+                exec::append_capped(output, &data, limit);
+                data
+            }
+            Chunk::Stderr(data) => {
+                self.session.permission_denied |= elevate::mentions_denied(&data);
+                data
+            }
+        };
+        if let Err(error) = self.session.write_capped(&data, cap) {
+            eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+        }
+    }
+
+    /// Print output and notifications from background jobs, redrawing the
+    /// line being edited after them
+    fn notify(&mut self) {
+        let text = self.session.jobs.take_messages();
+        if text.is_empty() {
+            return;
+        }
+
+        match self.prompt.clone() {
+            Some(prompt) => {
+                /* Start on a fresh line, then bring back the prompt, all in
+                one write */
+                let mut buffer = b"\r\n".to_vec();
+                buffer.extend(self.session.render_output(&text));
+                buffer.extend_from_slice(prompt.as_bytes());
+                buffer.extend_from_slice(self.input.as_bytes());
+                self.write(&buffer);
+            }
+            None => self.output(&text),
+        }
+    }
+
+    /// Handle the end of input. Idle timeouts end the session normally, other
+    /// errors are returned.
+    fn report_closed(&mut self, error: Option<io::Error>) -> io::Result<()> {
+        match error {
+            Some(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                self.session
+                    .print_error("\nIdle timeout reached, closing session");
+                Ok(())
+            }
+            Some(_) if service::terminating() => {
+                self.session
+                    .print_error("\nThe shell is stopping, closing session");
+                Ok(())
+            }
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Write command output, going through the session's newline handling
+    fn output(&mut self, data: &[u8]) {
+        if let Err(error) = self.session.write_output(data) {
+            eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+        }
+    }
+
+    /// Write raw bytes such as the prompt or echoed input
+    fn write(&mut self, data: &[u8]) {
+        if let Err(error) = self.session.writer.write_all(data) {
+            eprintln!("{}: failed to write output: {}", SHELL_NAME, error);
+        }
+    }
+}
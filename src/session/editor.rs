@@ -0,0 +1,571 @@
+//! The line editor. Typed keys are looked up in the key bindings of the
+//! inputrc and edit the line, whose end is always where the cursor is.
+
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use super::Session;
+use crate::builtins;
+use crate::completion::Completion;
+use crate::diagnostics;
+use crate::inputrc::{BellStyle, EditCommand, EditingMode, Inputrc, Keymap};
+use crate::terminal;
+use crate::SHELL_NAME;
+
+/// Sent by terminals before and after pasted text once bracketed paste is on
+const PASTE_START: &str = "\u{1b}[200~";
+const PASTE_END: &str = "\u{1b}[201~";
+
+/// State of the line editor between typed keys
+#[derive(Default)]
+pub(crate) struct Editor {
+    /// Prompt of the line being edited, shown again after clearing the
+    /// screen or listing completions
+    prompt: String,
+    /// Typed keys that may be the start of a longer key sequence
+    keys: String,
+    /// Ctrl-X was pressed and the next key completes a key sequence
+    ctrl_x: bool,
+    /// In vi mode, keys are commands instead of text after Escape
+    vi_command: bool,
+    /// The history entry shown while moving through the history
+    history_index: Option<usize>,
+    /// The line that was being edited before moving through the history
+    saved_input: String,
+    /// The line was dropped with Ctrl-C
+    cancelled: bool,
+    /// The terminal was asked to mark pasted text while the line is edited
+    bracketed_paste: bool,
+    /// Text being pasted, until the terminal marks its end
+    paste: Option<String>,
+}
+
+impl Session {
+    /// Set up the line editor as the config asks, then read the inputrc, if
+    /// there is one
+    pub(crate) fn load_inputrc(&mut self) {
+        self.inputrc = Inputrc::configured(self.settings.editing_mode, &self.settings.key_bindings);
+        let path = match &self.settings.inputrc {
+            Some(path) => path.clone(),
+            None => return,
+        };
+
+        match self.inputrc.load(&path) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => {
+                self.print_error(&format!("{}: {}: {}", SHELL_NAME, path.display(), error))
+            }
+        }
+    }
+
+    /// Get ready to edit a new line after `prompt` was shown
+    pub(crate) fn begin_line(&mut self, prompt: &str) {
+        self.editor = Editor {
+            prompt: prompt.to_owned(),
+            ..Editor::default()
+        };
+        if self.raw_terminal {
+            if let Err(error) = terminal::enter_raw_mode() {
+                diagnostics::log(&format!("raw mode of the terminal: {}", error));
+            }
+        }
+    }
+
+    /// The sequence asking the terminal to mark pasted text while a command
+    /// line is edited, if it should. Terminals echoing by themselves are in
+    /// cooked mode and would show the marks.
+    pub(crate) fn start_bracketed_paste(&mut self) -> &'static str {
+        self.editor.bracketed_paste =
+            self.inputrc.enable_bracketed_paste && self.settings.echo && self.writer.is_terminal();
+        match self.editor.bracketed_paste {
+            true => "\x1b[?2004h",
+            false => "",
+        }
+    }
+
+    /// Feed one typed character to the line being edited. Returns the
+    /// finished line once a newline or a control character ends it.
+    pub(crate) fn edit_line(&mut self, input: &mut String, c: char) -> io::Result<Option<String>> {
+        let c = match self.input_discipline.translate(c) {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        /* Pasted text is inserted as it is, newlines too */
+        if let Some(paste) = &mut self.editor.paste {
+            paste.push(c);
+            if let Some(text) = paste.strip_suffix(PASTE_END) {
+                let text = text.to_owned();
+                self.editor.paste = None;
+                self.insert_paste(input, &text)?;
+            }
+            return Ok(None);
+        }
+
+        /* Ctrl-X Ctrl-C copies the output of the last command */
+        let ctrl_x = mem::take(&mut self.editor.ctrl_x);
+        if ctrl_x && c == '\u{3}' {
+            let mut output = mem::take(&mut self.last_output);
+            if let Err(error) = self.copy_to_clipboard(output.make_contiguous()) {
+                self.print_error(&format!("\n{}: {}", SHELL_NAME, error));
+                self.writer.write_all(input.as_bytes())?;
+            }
+            self.last_output = output;
+            return Ok(None);
+        }
+        if c == '\u{18}' && self.editor.keys.is_empty() {
+            self.editor.ctrl_x = true;
+            return Ok(None);
+        }
+
+        /* Keys ending the line can't be bound to anything else */
+        if matches!(c, '\r' | '\n' | '\u{3}' | '\u{4}') {
+            self.editor.keys.clear();
+            return self.end_line(input, c);
+        }
+
+        if ctrl_x {
+            self.editor.keys.push('\u{18}');
+        }
+        self.editor.keys.push(c);
+        let mut keys = mem::take(&mut self.editor.keys);
+        while !keys.is_empty() {
+            if keys == PASTE_START {
+                self.editor.paste = Some(String::new());
+                break;
+            }
+            let keymap = self.keymap();
+            if self.inputrc.lookup(keymap, &keys).longer || is_partial_escape(&keys) {
+                self.editor.keys = keys;
+                break;
+            }
+
+            /* Run the longest binding the keys start with, then go on with
+            the rest of them */
+            let bound = (1..=keys.len())
+                .rev()
+                .filter(|&length| keys.is_char_boundary(length))
+                .find_map(|length| {
+                    let command = self.inputrc.lookup(keymap, &keys[..length]).command;
+                    command.map(|command| (length, command))
+                });
+            let length = match bound {
+                Some((length, command)) => {
+                    self.run_edit_command(input, command)?;
+                    length
+                }
+                /* Keys like F1 are ignored as a whole */
+                None => match escape_length(&keys) {
+                    Some(length) => {
+                        self.ring_bell()?;
+                        length
+                    }
+                    None => {
+                        let c = keys.chars().next().unwrap_or_default();
+                        self.self_insert(input, c, keymap)?;
+                        c.len_utf8()
+                    }
+                },
+            };
+            keys.drain(..length);
+        }
+
+        Ok(None)
+    }
+
+    /// Handle a key ending the line
+    fn end_line(&mut self, input: &mut String, c: char) -> io::Result<Option<String>> {
+        /* Echo back character to give feedback of what was actually
+        written. Without this you can't see what you type in a serial
+        terminal */
+        if self.echoes() {
+            let echo = match c {
+                '\u{3}' => String::from("^C\r"),
+                '\u{4}' => String::from("exit\r\r"),
+                _ => String::from(c),
+            };
+
+            self.writer.write_all(echo.as_bytes())?;
+        }
+        /* Commands get pasted text as it is, and the terminal as it was */
+        if mem::take(&mut self.editor.bracketed_paste) {
+            self.writer.write_all(b"\x1b[?2004l")?;
+            self.writer.flush()?;
+        }
+        if self.raw_terminal {
+            terminal::restore();
+        }
+
+        let line = match c {
+            /* CTRL + C */
+            '\u{3}' => {
+                input.clear();
+                self.editor.cancelled = true;
+                String::new()
+            }
+            /* CTRL + D */
+            '\u{4}' => String::from(c),
+            _ => mem::take(input),
+        };
+        Ok(Some(line))
+    }
+
+    /// Whether the last line was dropped with Ctrl-C
+    pub(crate) fn line_cancelled(&self) -> bool {
+        self.editor.cancelled
+    }
+
+    fn keymap(&self) -> Keymap {
+        match (self.inputrc.editing_mode, self.editor.vi_command) {
+            (EditingMode::Emacs, _) => Keymap::Emacs,
+            (EditingMode::Vi, false) => Keymap::ViInsert,
+            (EditingMode::Vi, true) => Keymap::ViCommand,
+        }
+    }
+
+    /// Handle a key that isn't bound to anything
+    fn self_insert(&mut self, input: &mut String, c: char, keymap: Keymap) -> io::Result<()> {
+        if keymap == Keymap::ViCommand || c.is_control() {
+            return self.ring_bell();
+        }
+        input.push(c);
+        if self.echoes() {
+            let mut echo = [0u8; 4];
+            self.writer.write_all(c.encode_utf8(&mut echo).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn run_edit_command(&mut self, input: &mut String, command: EditCommand) -> io::Result<()> {
+        let history_length = self.history.len();
+        match command {
+            /* A letter with accents or an emoji made of several characters
+            is deleted as a whole */
+            EditCommand::BackwardDeleteChar => match input.grapheme_indices(true).next_back() {
+                Some((start, grapheme)) => {
+                    let columns = grapheme.width();
+                    input.truncate(start);
+                    match self.echoes() {
+                        true => self.writer.write_all(erase(columns).as_bytes()),
+                        false => Ok(()),
+                    }
+                }
+                None => self.ring_bell(),
+            },
+            EditCommand::UnixLineDiscard => self.replace_input(input, ""),
+            EditCommand::UnixWordRubout => {
+                let trimmed = input.trim_end_matches([' ', '\t']);
+                let start = trimmed.rfind([' ', '\t']).map_or(0, |i| i + 1);
+                let line = input[..start].to_owned();
+                self.replace_input(input, &line)
+            }
+            EditCommand::ClearScreen => {
+                let screen = format!("\x1b[H\x1b[2J{}{}", self.editor.prompt, input);
+                self.writer.write_all(screen.as_bytes())
+            }
+            EditCommand::PreviousHistory => match self.editor.history_index {
+                Some(0) => self.ring_bell(),
+                Some(index) => self.show_history(input, Some(index - 1)),
+                None if history_length == 0 => self.ring_bell(),
+                None => self.show_history(input, Some(history_length - 1)),
+            },
+            EditCommand::NextHistory => match self.editor.history_index {
+                Some(index) if index + 1 < history_length => {
+                    self.show_history(input, Some(index + 1))
+                }
+                Some(_) => self.show_history(input, None),
+                None => self.ring_bell(),
+            },
+            EditCommand::BeginningOfHistory if history_length == 0 => self.ring_bell(),
+            EditCommand::BeginningOfHistory => self.show_history(input, Some(0)),
+            EditCommand::EndOfHistory => self.show_history(input, None),
+            EditCommand::HistorySearchBackward => {
+                let prefix = self.history_prefix(input);
+                let end = self.editor.history_index.unwrap_or(history_length);
+                match (0..end)
+                    .rev()
+                    .find(|&index| self.history[index].starts_with(&prefix))
+                {
+                    Some(index) => self.show_history(input, Some(index)),
+                    None => self.ring_bell(),
+                }
+            }
+            EditCommand::HistorySearchForward => {
+                let start = match self.editor.history_index {
+                    Some(index) => index + 1,
+                    None => return self.ring_bell(),
+                };
+                let prefix = self.history_prefix(input);
+                let found =
+                    (start..history_length).find(|&index| self.history[index].starts_with(&prefix));
+                self.show_history(input, found)
+            }
+            EditCommand::Complete => self.complete(input),
+            EditCommand::Abort => self.ring_bell(),
+            EditCommand::EmacsEditingMode => {
+                self.inputrc.editing_mode = EditingMode::Emacs;
+                Ok(())
+            }
+            EditCommand::ViEditingMode => {
+                self.inputrc.editing_mode = EditingMode::Vi;
+                self.editor.vi_command = false;
+                Ok(())
+            }
+            EditCommand::ViMovementMode => {
+                self.editor.vi_command = true;
+                Ok(())
+            }
+            EditCommand::ViInsertionMode => {
+                self.editor.vi_command = false;
+                Ok(())
+            }
+            EditCommand::Insert(text) => {
+                for c in text.chars() {
+                    self.self_insert(input, c, Keymap::Emacs)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Insert pasted text into the line, leaving out control characters
+    /// other than newlines and tabs, so it only runs once Enter is pressed
+    fn insert_paste(&mut self, input: &mut String, text: &str) -> io::Result<()> {
+        let text: String = text
+            .chars()
+            .filter(|&c| matches!(c, '\n' | '\t') || !c.is_control())
+            .collect();
+        if self.echoes() {
+            self.writer.write_all(text.as_bytes())?;
+        }
+        input.push_str(&text);
+        Ok(())
+    }
+
+    /// Let the user know something couldn't be done, as the inputrc asks
+    fn ring_bell(&mut self) -> io::Result<()> {
+        let bell: &[u8] = match self.inputrc.bell_style {
+            BellStyle::None => b"",
+            BellStyle::Audible => b"\x07",
+            /* Turn on reverse video and right off again */
+            BellStyle::Visible => b"\x1b[?5h\x1b[?5l",
+        };
+        self.writer.write_all(bell)
+    }
+
+    /// Replace the line being edited, erasing it from the terminal unless
+    /// the new line just continues it
+    fn replace_input(&mut self, input: &mut String, line: &str) -> io::Result<()> {
+        if self.echoes() {
+            match line.strip_prefix(input.as_str()) {
+                Some(added) => self.writer.write_all(added.as_bytes())?,
+                None => {
+                    self.writer.write_all(erase(input.width()).as_bytes())?;
+                    self.writer.write_all(line.as_bytes())?;
+                }
+            }
+        }
+        *input = line.to_owned();
+        Ok(())
+    }
+
+    /// What lines searched for in the history start with
+    fn history_prefix(&self, input: &str) -> String {
+        match self.editor.history_index {
+            Some(_) => self.editor.saved_input.clone(),
+            None => input.to_owned(),
+        }
+    }
+
+    /// Show an entry of the history, or the line that was being edited
+    /// before for `None`
+    fn show_history(&mut self, input: &mut String, index: Option<usize>) -> io::Result<()> {
+        if self.editor.history_index.is_none() {
+            self.editor.saved_input = input.clone();
+        }
+        self.editor.history_index = index;
+        let line = match index {
+            Some(index) => self.history[index].clone(),
+            None => self.editor.saved_input.clone(),
+        };
+        self.replace_input(input, &line)
+    }
+
+    /// Complete the word being typed: commands for the first word, and
+    /// for the others what the completer of the command offers or else
+    /// paths. If there are several ways to complete it, they are listed.
+    fn complete(&mut self, input: &mut String) -> io::Result<()> {
+        let start = input.rfind([' ', '\t']).map_or(0, |i| i + 1);
+        let word = input[start..].to_owned();
+        /* The words of the command being typed, after any pipe or list */
+        let command_start = input[..start]
+            .rfind([';', '|', '&', '('])
+            .map_or(0, |i| i + 1);
+        let line = input[command_start..start].to_owned();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let ignore_case = self.inputrc.completion_ignore_case;
+        let mut candidates = match words.is_empty() {
+            true if !word.contains('/') => self.command_candidates(&word),
+            true => self.path_candidates(&word),
+            false => {
+                let completion = Completion {
+                    words: &words,
+                    word: &word,
+                };
+                match self.argument_candidates(&completion) {
+                    Some(candidates) => candidates
+                        .into_iter()
+                        .filter(|candidate| starts_with(candidate, &word, ignore_case))
+                        .collect(),
+                    None => self.path_candidates(&word),
+                }
+            }
+        };
+        candidates.sort();
+        candidates.dedup();
+
+        let completed = match candidates.as_slice() {
+            [] => return self.ring_bell(),
+            /* Directories are likely followed by more of the path */
+            [only] if only.ends_with('/') => only.clone(),
+            [only] => format!("{} ", only),
+            _ => common_prefix(&candidates, ignore_case),
+        };
+        if completed.chars().count() > word.chars().count() || completed.ends_with(' ') {
+            let line = format!("{}{}", &input[..start], completed);
+            return self.replace_input(input, &line);
+        }
+
+        /* List the candidates by name, then bring back the line */
+        let directory = word.rfind('/').map_or(0, |i| i + 1);
+        let names: Vec<&str> = candidates
+            .iter()
+            .map(|candidate| candidate.get(directory..).unwrap_or(candidate))
+            .collect();
+        self.write_output(format!("\n{}\n", names.join("  ")).as_bytes())?;
+        let line = format!("{}{}", self.editor.prompt, input);
+        self.writer.write_all(line.as_bytes())
+    }
+
+    /// Builtins and programs in PATH whose name starts with `word`
+    fn command_candidates(&self, word: &str) -> Vec<String> {
+        let ignore_case = self.inputrc.completion_ignore_case;
+        let mut names: Vec<String> = builtins::names()
+            .map(str::to_owned)
+            .chain(self.custom_builtins.keys().cloned())
+            .chain(self.functions.keys().cloned())
+            .filter(|name| starts_with(name, word, ignore_case))
+            .collect();
+
+        let path_variable = self.var("PATH").unwrap_or_default();
+        for dir in path_variable.split(':').filter(|dir| !dir.is_empty()) {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if starts_with(&name, word, ignore_case) && crate::is_executable(&entry.path()) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    /// Paths starting with `word`, with a slash after directories. Hidden
+    /// files are left out unless `word` names one.
+    fn path_candidates(&self, word: &str) -> Vec<String> {
+        let ignore_case = self.inputrc.completion_ignore_case;
+        let (directory, prefix) = match word.rfind('/') {
+            Some(i) => word.split_at(i + 1),
+            None => ("", word),
+        };
+        let path = match (directory.strip_prefix("~/"), self.var("HOME")) {
+            (Some(rest), Some(home)) => Path::new(&home).join(rest),
+            _ => self.cwd.join(directory),
+        };
+
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        entries
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !starts_with(&name, prefix, ignore_case)
+                    || (name.starts_with('.') && !prefix.starts_with('.'))
+                {
+                    return None;
+                }
+                let slash = match entry.path().is_dir() {
+                    true => "/",
+                    false => "",
+                };
+                Some(format!("{}{}{}", directory, name, slash))
+            })
+            .collect()
+    }
+}
+
+fn starts_with(name: &str, prefix: &str, ignore_case: bool) -> bool {
+    match ignore_case {
+        true => name.to_lowercase().starts_with(&prefix.to_lowercase()),
+        false => name.starts_with(prefix),
+    }
+}
+
+/// The longest start all candidates share
+fn common_prefix(candidates: &[String], ignore_case: bool) -> String {
+    let same = |a: char, b: char| match ignore_case {
+        true => a.to_lowercase().eq(b.to_lowercase()),
+        false => a == b,
+    };
+    let first = &candidates[0];
+    let length = candidates[1..]
+        .iter()
+        .map(|candidate| {
+            first
+                .chars()
+                .zip(candidate.chars())
+                .take_while(|(a, b)| same(*a, *b))
+                .map(|(a, _)| a.len_utf8())
+                .sum()
+        })
+        .min()
+        .unwrap_or(first.len());
+    first[..length].to_owned()
+}
+
+/// Move back over the last columns of the line, clearing them. Wide
+/// characters like CJK take two.
+fn erase(columns: usize) -> String {
+    let back = "\x08".repeat(columns);
+    format!("{}{}{}", back, " ".repeat(columns), back)
+}
+
+/// Whether the keys are the start of an escape sequence sent by a special
+/// key, like `ESC [ 1 5 ~` for F5
+fn is_partial_escape(keys: &str) -> bool {
+    match keys.strip_prefix("\u{1b}[") {
+        Some(rest) => rest.chars().all(|c| ('\u{20}'..='\u{3f}').contains(&c)),
+        None => keys == "\u{1b}O",
+    }
+}
+
+/// Length of the escape sequence the keys start with, if they do
+fn escape_length(keys: &str) -> Option<usize> {
+    if let Some(rest) = keys.strip_prefix("\u{1b}[") {
+        let end = rest.find(|c| ('\u{40}'..='\u{7e}').contains(&c))?;
+        return Some(2 + end + 1);
+    }
+    let c = keys.strip_prefix("\u{1b}O")?.chars().next()?;
+    Some(2 + c.len_utf8())
+}
@@ -0,0 +1,573 @@
+//! Building and running a shell with its sessions. This is what the
+//! `pieshell` binary uses, and what programs embedding the shell, e.g. as the
+//! debug console of a firmware, use to customize it.
+//!
+//! ```no_run
+//! use pieshell::{Shell, Transport};
+//!
+//! let shell = Shell::builder()
+//!     .transport(Transport::Uart)
+//!     .history_file("/var/lib/console/history")
+//!     .builtin("flash", |session, args| {
+//!         let _ = session.write_output(format!("flashing {}\n", args[1..].join(" ")).as_bytes());
+//!         0
+//!     })
+//!     .after_command(|_session, completed| {
+//!         eprintln!("{} exited with {} after {:?}", completed.command, completed.status, completed.duration);
+//!     })
+//!     .build();
+//! shell.run().unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::io::{self, IsTerminal};
+use std::net::TcpListener;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::builtins::CustomBuiltin;
+use crate::completion::{Completer, Completion};
+use crate::config::{self, Config, Settings};
+use crate::hooks::{Completed, Hooks};
+use crate::prompt::PromptProvider;
+use crate::session::Session;
+use crate::theme::{ColorPolicy, Theme};
+use crate::transport::{self, TransportKind};
+use crate::{diagnostics, provision, script, service, wizard, ExitStatus, ShellError};
+
+/// How long to wait before opening the UART again after it failed
+const CONSOLE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A link to serve sessions on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    Stdio,
+    Uart,
+    /// Accept TCP connections on the address, one session per connection
+    Tcp(String),
+}
+
+/// Settings given to the builder, taking precedence over the config file
+#[derive(Clone, Default)]
+struct Overrides {
+    color: Option<ColorPolicy>,
+    theme: Option<&'static Theme>,
+    telnet: bool,
+    batch: bool,
+    history_file: Option<PathBuf>,
+    transcript: Option<PathBuf>,
+}
+
+/// What every session of the shell is set up with
+#[derive(Clone, Default)]
+struct Customization {
+    prompt_provider: Option<Arc<dyn PromptProvider>>,
+    builtins: Arc<HashMap<String, CustomBuiltin>>,
+    completers: Arc<HashMap<String, Completer>>,
+    hooks: Arc<Hooks>,
+}
+
+/// A configured shell, ready to run
+pub struct Shell {
+    config: Config,
+    transports: Vec<Transport>,
+    overrides: Overrides,
+    customization: Customization,
+    script: Option<PathBuf>,
+    script_args: Vec<String>,
+    setup_wizard: bool,
+    provisioning: bool,
+    service: bool,
+}
+
+pub struct ShellBuilder {
+    shell: Shell,
+    builtins: HashMap<String, CustomBuiltin>,
+    completers: HashMap<String, Completer>,
+    hooks: Hooks,
+}
+
+impl Shell {
+    pub fn builder() -> ShellBuilder {
+        ShellBuilder {
+            shell: Shell {
+                config: Config::default(),
+                transports: Vec::new(),
+                overrides: Overrides::default(),
+                customization: Customization::default(),
+                script: None,
+                script_args: Vec::new(),
+                setup_wizard: false,
+                provisioning: false,
+                service: false,
+            },
+            builtins: HashMap::new(),
+            completers: HashMap::new(),
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// Serve sessions on every transport, each in its own thread. Returns
+    /// when all of them have ended, with the status of the first session or
+    /// the first error of any of them.
+    pub fn run(mut self) -> Result<ExitStatus, ShellError> {
+        if self.service {
+            service::handle_termination();
+        }
+
+        /* Without any transport given, serve the default one for the
+        platform */
+        let mut kinds: Vec<TransportKind> = self
+            .transports
+            .iter()
+            .filter_map(|transport| match transport {
+                Transport::Stdio => Some(TransportKind::Stdio),
+                Transport::Uart => Some(TransportKind::Uart),
+                Transport::Tcp(_) => None,
+            })
+            .collect();
+        let addresses: Vec<String> = self
+            .transports
+            .iter()
+            .filter_map(|transport| match transport {
+                Transport::Tcp(address) => Some(address.clone()),
+                _ => None,
+            })
+            .collect();
+        if kinds.is_empty() && addresses.is_empty() {
+            kinds.push(transport::default_kind());
+        }
+
+        let mut sessions = Vec::new();
+        let others = (kinds.len() + addresses.len()).saturating_sub(1);
+        for &kind in &kinds {
+            let (kind, (reader, writer)) = match kind {
+                TransportKind::Uart => {
+                    let settings = self.settings(kind)?;
+                    match transport::uart_reader_writer(settings.baud, settings.flow_control) {
+                        Ok(reader_writer) => (kind, reader_writer),
+                        /* A missing or inaccessible UART shouldn't leave the Pi
+                        without a shell, so keep serving the other transports,
+                        or stdio if there are none */
+                        Err(error) if others > 0 => {
+                            diagnostics::log(&format!(
+                                "UART not available, skipping it: {}",
+                                error
+                            ));
+                            continue;
+                        }
+                        Err(error) => {
+                            diagnostics::log(&format!(
+                                "UART not available, using stdio instead: {}",
+                                error
+                            ));
+                            (TransportKind::Stdio, transport::stdio_reader_writer())
+                        }
+                    }
+                }
+                _ => (kind, transport::stdio_reader_writer()),
+            };
+            let settings = self.settings(kind)?;
+            let mut session = Session::new(reader, writer, settings);
+            self.customization.apply(&mut session);
+            sessions.push(session);
+        }
+
+        /* Scripts run unattended, so skip the wizard and the other
+        transports */
+        if let Some(path) = &self.script {
+            return match sessions.first_mut() {
+                Some(session) => Ok(ExitStatus(script::run(session, path, &self.script_args))),
+                None => Err(ShellError::Usage(String::from(
+                    "a script can't be run on a TCP transport",
+                ))),
+            };
+        }
+
+        /* Connections are accepted once the sessions are running, but
+        clients can connect as soon as the addresses are bound */
+        let listeners = addresses
+            .iter()
+            .map(|address| bind(address))
+            .collect::<Result<Vec<_>, ShellError>>()?;
+        if self.service {
+            service::notify("READY=1");
+        }
+
+        /* Run the first-boot provisioning script with its progress shown on
+        the UART, if there is one */
+        if self.provisioning {
+            let console = sessions
+                .iter()
+                .position(|session| session.kind() == TransportKind::Uart)
+                .or_else(|| (!sessions.is_empty()).then_some(0));
+            if let Some(index) = console {
+                provision::run(&mut sessions[index]);
+            }
+        }
+
+        /* Offer the setup wizard the first time the shell is started */
+        if self.setup_wizard && !self.overrides.batch {
+            if let Some(session) = sessions.iter_mut().find(|session| session.is_terminal()) {
+                match wizard::run(session) {
+                    Ok(Some(config)) => {
+                        self.config = config;
+                        for session in sessions.iter_mut() {
+                            session.set_settings(self.settings(session.kind())?);
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(error) => diagnostics::log(&format!("setup wizard failed: {}", error)),
+                }
+            }
+        }
+
+        let mut threads = Vec::new();
+        for session in sessions {
+            match session.kind() {
+                TransportKind::Uart => {
+                    let settings = self.settings(TransportKind::Uart)?;
+                    let customization = self.customization.clone();
+                    let respawn = self.service;
+                    threads.push(thread::spawn(move || {
+                        serve_console(session, &settings, &customization, respawn)
+                    }));
+                }
+                _ => threads.push(thread::spawn(move || serve(session))),
+            }
+        }
+        for listener in listeners {
+            let settings = self.settings(TransportKind::Tcp)?;
+            let customization = self.customization.clone();
+            threads.push(thread::spawn(move || {
+                listen(listener, &settings, &customization);
+                Ok(ExitStatus::SUCCESS)
+            }));
+        }
+
+        let mut result = None;
+        for thread in threads {
+            let thread_result = match thread.join() {
+                Ok(thread_result) => thread_result,
+                Err(panic) => panic::resume_unwind(panic),
+            };
+            match (&result, thread_result) {
+                (None, thread_result) | (Some(Ok(_)), thread_result @ Err(_)) => {
+                    result = Some(thread_result)
+                }
+                (Some(_), _) => {}
+            }
+        }
+
+        result.unwrap_or(Ok(ExitStatus::SUCCESS))
+    }
+
+    /// Resolve the settings for a transport, letting the settings given to
+    /// the builder take precedence over the config file
+    fn settings(&self, kind: TransportKind) -> Result<Settings, ShellError> {
+        let mut settings = self.config.settings(kind).map_err(ShellError::Config)?;
+        let overrides = &self.overrides;
+        if let Some(color) = overrides.color {
+            settings.color = color;
+        }
+        if let Some(theme) = overrides.theme {
+            settings.theme = theme;
+        }
+        if overrides.telnet && kind == TransportKind::Tcp && !settings.telnet {
+            settings.telnet = true;
+            settings.echo = true;
+        }
+        /* A terminal on stdio is in raw mode while lines are edited, so the
+        shell echoes them */
+        if kind == TransportKind::Stdio
+            && !settings.batch
+            && io::stdin().is_terminal()
+            && io::stdout().is_terminal()
+        {
+            settings.echo = true;
+        }
+        if overrides.batch {
+            settings.batch = true;
+            settings.echo = false;
+        }
+        if let Some(path) = &overrides.history_file {
+            settings.history_file = Some(path.clone());
+        }
+        if let Some(path) = &overrides.transcript {
+            settings.transcript = Some(path.clone());
+        }
+
+        Ok(settings)
+    }
+}
+
+impl ShellBuilder {
+    /// Use the settings of a config file, see `Config::load`
+    pub fn config(mut self, config: Config) -> ShellBuilder {
+        self.shell.config = config;
+        self
+    }
+
+    /// Serve sessions on a transport. Can be given several times. Without
+    /// any, the UART is used on the Pi and stdio elsewhere.
+    pub fn transport(mut self, transport: Transport) -> ShellBuilder {
+        self.shell.transports.push(transport);
+        self
+    }
+
+    pub fn color(mut self, color: ColorPolicy) -> ShellBuilder {
+        self.shell.overrides.color = Some(color);
+        self
+    }
+
+    pub fn theme(mut self, theme: &'static Theme) -> ShellBuilder {
+        self.shell.overrides.theme = Some(theme);
+        self
+    }
+
+    /// Speak the telnet protocol on TCP connections
+    pub fn telnet(mut self, telnet: bool) -> ShellBuilder {
+        self.shell.overrides.telnet = telnet;
+        self
+    }
+
+    /// Run each line of input with a status line after it, without prompt or
+    /// echo, see `Session::run_batch`
+    pub fn batch(mut self, batch: bool) -> ShellBuilder {
+        self.shell.overrides.batch = batch;
+        self
+    }
+
+    /// Compute the prompt with `provider` instead of the configured template
+    pub fn prompt<P: PromptProvider + 'static>(mut self, provider: P) -> ShellBuilder {
+        self.shell.customization.prompt_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Keep the history of the sessions in a file, so it survives restarts
+    pub fn history_file<P: Into<PathBuf>>(mut self, path: P) -> ShellBuilder {
+        self.shell.overrides.history_file = Some(path.into());
+        self
+    }
+
+    /// Append a transcript of everything read and written in the sessions
+    /// to a file, with the time
+    pub fn transcript<P: Into<PathBuf>>(mut self, path: P) -> ShellBuilder {
+        self.shell.overrides.transcript = Some(path.into());
+        self
+    }
+
+    /// Add a builtin command. It gets the session it runs in and its
+    /// arguments, including its own name, and returns an exit status. Added
+    /// builtins take precedence over the shell's own.
+    pub fn builtin<F>(mut self, name: &str, builtin: F) -> ShellBuilder
+    where
+        F: Fn(&mut Session, &[&str]) -> i32 + Send + Sync + 'static,
+    {
+        self.builtins.insert(name.to_owned(), Arc::new(builtin));
+        self
+    }
+
+    /// Complete the arguments of a command with `completer` when Tab is
+    /// pressed, instead of as paths. It gets the session and the words typed,
+    /// and returns what the word being typed could be.
+    pub fn completer<F>(mut self, command: &str, completer: F) -> ShellBuilder
+    where
+        F: Fn(&Session, &Completion) -> Vec<String> + Send + Sync + 'static,
+    {
+        self.completers
+            .insert(command.to_owned(), Arc::new(completer));
+        self
+    }
+
+    /// Call `hook` before each command line runs, with the session and the
+    /// line. This includes the lines of scripts.
+    pub fn before_command<F>(mut self, hook: F) -> ShellBuilder
+    where
+        F: Fn(&Session, &str) + Send + Sync + 'static,
+    {
+        self.hooks.before_command.push(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` after each command line completed, with its exit status
+    /// and how long it took. Background jobs it started may still be running.
+    pub fn after_command<F>(mut self, hook: F) -> ShellBuilder
+    where
+        F: Fn(&Session, &Completed) + Send + Sync + 'static,
+    {
+        self.hooks.after_command.push(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` before the prompt is computed, e.g. to set variables for a
+    /// prompt provider to show
+    pub fn before_prompt<F>(mut self, hook: F) -> ShellBuilder
+    where
+        F: Fn(&mut Session) + Send + Sync + 'static,
+    {
+        self.hooks.before_prompt.push(Arc::new(hook));
+        self
+    }
+
+    /// Run a script instead of serving interactive sessions
+    pub fn script<P: Into<PathBuf>>(mut self, path: P) -> ShellBuilder {
+        self.shell.script = Some(path.into());
+        self
+    }
+
+    /// Arguments of the script, its `$1` and on
+    pub fn script_args<I>(mut self, args: I) -> ShellBuilder
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.shell.script_args = args.into_iter().collect();
+        self
+    }
+
+    /// Offer the setup wizard writing a config file before the first prompt
+    pub fn setup_wizard(mut self, enabled: bool) -> ShellBuilder {
+        self.shell.setup_wizard = enabled;
+        self
+    }
+
+    /// Run the provisioning script on the boot partition, if there is one
+    pub fn provisioning(mut self, enabled: bool) -> ShellBuilder {
+        self.shell.provisioning = enabled;
+        self
+    }
+
+    /// Run as a systemd service in place of a serial getty: tell systemd
+    /// when the transports are ready, close the sessions cleanly on SIGTERM
+    /// and start a new session on the UART whenever one ends
+    pub fn service(mut self, enabled: bool) -> ShellBuilder {
+        self.shell.service = enabled;
+        self
+    }
+
+    pub fn build(mut self) -> Shell {
+        self.shell.customization.builtins = Arc::new(self.builtins);
+        self.shell.customization.completers = Arc::new(self.completers);
+        self.shell.customization.hooks = Arc::new(self.hooks);
+        self.shell
+    }
+}
+
+impl Customization {
+    fn apply(&self, session: &mut Session) {
+        if let Some(provider) = &self.prompt_provider {
+            session.set_prompt_provider(Arc::clone(provider));
+        }
+        session.custom_builtins = Arc::clone(&self.builtins);
+        session.custom_completers = Arc::clone(&self.completers);
+        session.hooks = Arc::clone(&self.hooks);
+    }
+}
+
+/// Run a session until it ends, using the async session loop if the crate
+/// was built with it
+pub(crate) fn serve(mut session: Session) -> Result<ExitStatus, ShellError> {
+    let _active = service::Active::new();
+    #[cfg(feature = "async")]
+    session.run_async()?;
+    #[cfg(not(feature = "async"))]
+    session.run()?;
+
+    Ok(ExitStatus(session.last_status))
+}
+
+/// Serve sessions on the UART. When the UART fails, e.g. as a USB adapter
+/// was pulled out or the other end hung up, it is opened again once it can
+/// be and a new session starts with the banner. With `respawn`, sessions
+/// that ended normally are followed by a new one too, like with a getty,
+/// until the shell is stopped.
+fn serve_console(
+    mut session: Session,
+    settings: &Settings,
+    customization: &Customization,
+    respawn: bool,
+) -> Result<ExitStatus, ShellError> {
+    loop {
+        match serve(session) {
+            Ok(status) if !respawn || service::terminating() => return Ok(status),
+            Ok(_) => {}
+            Err(error) => {
+                diagnostics::log(&format!("UART failed, opening it again: {}", error));
+                thread::sleep(CONSOLE_RETRY_INTERVAL);
+            }
+        }
+        let mut logged = false;
+        session = loop {
+            if service::terminating() {
+                return Ok(ExitStatus::SUCCESS);
+            }
+            match transport::uart_reader_writer(settings.baud, settings.flow_control) {
+                Ok((reader, writer)) => break Session::new(reader, writer, settings.clone()),
+                /* Only the first failure is logged, the UART may be gone
+                for long */
+                Err(error) => {
+                    if !logged {
+                        diagnostics::log(&format!("failed to open the UART: {}", error));
+                        logged = true;
+                    }
+                    thread::sleep(CONSOLE_RETRY_INTERVAL);
+                }
+            }
+        };
+        customization.apply(&mut session);
+    }
+}
+
+fn bind(address: &str) -> Result<TcpListener, ShellError> {
+    match TcpListener::bind(address) {
+        Ok(listener) => {
+            diagnostics::log(&format!("listening on {}", address));
+            Ok(listener)
+        }
+        Err(error) => Err(ShellError::Transport(io::Error::new(
+            error.kind(),
+            format!("failed to listen on {}: {}", address, error),
+        ))),
+    }
+}
+
+/// Serve the shell over TCP, running one session per accepted connection
+fn listen(listener: TcpListener, settings: &Settings, customization: &Customization) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                diagnostics::log(&format!("failed to accept connection: {}", error));
+                continue;
+            }
+        };
+        let peer = match stream.peer_addr() {
+            Ok(peer) => peer.to_string(),
+            Err(_) => String::from("unknown peer"),
+        };
+
+        match transport::tcp_reader_writer(stream, settings.telnet) {
+            Ok((reader, writer)) => {
+                diagnostics::log(&format!("connection from {}", peer));
+                let mut session = Session::new(reader, writer, settings.clone());
+                customization.apply(&mut session);
+                thread::spawn(move || match serve(session) {
+                    Ok(_) => diagnostics::log(&format!("connection from {} closed", peer)),
+                    Err(error) => {
+                        diagnostics::log(&format!("connection from {} failed: {}", peer, error))
+                    }
+                });
+            }
+            Err(error) => diagnostics::log(&format!("failed to set up connection: {}", error)),
+        }
+    }
+}
+
+/// Whether the setup wizard should be offered, which it is when no config
+/// file was given and there is none in the default locations
+pub(crate) fn wants_setup_wizard(config_path: Option<&PathBuf>) -> bool {
+    config_path.is_none() && config::find().is_none()
+}
@@ -0,0 +1,104 @@
+//! Sizes in bytes written for humans, like `1.5G`, as `numfmt` from GNU
+//! coreutils writes and reads them
+
+/// Suffixes of the units, each one a thousand or 1024 times the previous one
+const SUFFIXES: [char; 6] = ['K', 'M', 'G', 'T', 'P', 'E'];
+
+/// What a unit like `K` stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Units {
+    /// Powers of 1024, as `ls -h` and `df -h` use
+    Iec,
+    /// Powers of 1000, as disk makers use
+    Si,
+    /// Powers of 1024 for units ending in `i` like `Ki`, else powers of 1000.
+    /// Only used for reading sizes.
+    Auto,
+}
+
+impl Units {
+    pub(crate) fn from_name(name: &str) -> Option<Units> {
+        match name {
+            "iec" => Some(Units::Iec),
+            "si" => Some(Units::Si),
+            "auto" => Some(Units::Auto),
+            _ => None,
+        }
+    }
+
+    fn base(self) -> u64 {
+        match self {
+            Units::Iec => 1024,
+            Units::Si | Units::Auto => 1000,
+        }
+    }
+}
+
+/// Write a size with the largest unit that keeps it below the next one.
+/// Values below ten get one decimal. Like `numfmt`, values are rounded up,
+/// so space is never shown as more than there is.
+pub(crate) fn format(bytes: u64, units: Units) -> String {
+    let base = units.base() as f64;
+    let mut value = bytes as f64;
+    if value < base {
+        return bytes.to_string();
+    }
+
+    let mut unit = 0;
+    value /= base;
+    while value >= base && unit + 1 < SUFFIXES.len() {
+        value /= base;
+        unit += 1;
+    }
+
+    /* Rounding up may reach the next unit */
+    let mut rounded = match value < 10.0 {
+        true => (value * 10.0).ceil() / 10.0,
+        false => value.ceil(),
+    };
+    if rounded >= base && unit + 1 < SUFFIXES.len() {
+        rounded = 1.0;
+        unit += 1;
+    }
+    match rounded < 10.0 {
+        true => format!("{:.1}{}", rounded, SUFFIXES[unit]),
+        false => format!("{:.0}{}", rounded, SUFFIXES[unit]),
+    }
+}
+
+/// Read a size like `512`, `1.5G`, `4Ki` or `200MB`. Returns `None` if it
+/// isn't one or is too big.
+pub(crate) fn parse(text: &str, units: Units) -> Option<u64> {
+    let text = text.trim();
+    let end = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, suffix) = text.split_at(end);
+    let value: f64 = number.parse().ok()?;
+
+    let suffix = suffix.strip_suffix('B').unwrap_or(suffix);
+    let multiplier = match suffix.chars().next() {
+        None => 1.0,
+        Some(unit) => {
+            let power = SUFFIXES
+                .iter()
+                .position(|&suffix| suffix == unit.to_ascii_uppercase())?;
+            let binary = match &suffix[1..] {
+                "" => false,
+                "i" => true,
+                _ => return None,
+            };
+            let base = match (units, binary) {
+                (Units::Iec, _) | (Units::Auto, true) => 1024.0,
+                (Units::Si, _) | (Units::Auto, false) => 1000.0,
+            };
+            f64::powi(base, power as i32 + 1)
+        }
+    };
+
+    let bytes = (value * multiplier).ceil();
+    match bytes < u64::MAX as f64 {
+        true => Some(bytes as u64),
+        false => None,
+    }
+}
@@ -0,0 +1,139 @@
+//! The state of a session as JSON, written with `session export` and read
+//! with `session import`. Capturing a misbehaving session on one device and
+//! importing it on another gives the same variables, functions, options and
+//! directories to debug with.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::options::{self, ShellOptions};
+use crate::parser;
+use crate::session::Session;
+
+/// Version of the format, raised when fields change meaning
+const VERSION: u32 = 1;
+
+/// Everything of a session that commands typed later depend on. Maps are
+/// sorted so exports of the same state are the same.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    cwd: PathBuf,
+    #[serde(default)]
+    previous_dir: Option<PathBuf>,
+    /// Directories saved with `pushd`, the latest first
+    #[serde(default)]
+    dir_stack: Vec<PathBuf>,
+    /// Variables passed to commands on top of the process environment
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    /// Variables only known to the shell
+    #[serde(default)]
+    vars: BTreeMap<String, String>,
+    /// Bodies of functions as shell source, readable in the file
+    #[serde(default)]
+    functions: BTreeMap<String, String>,
+    #[serde(default)]
+    options: Options,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Options {
+    /// Letters of the flags of `set`, as in `$-`
+    #[serde(default)]
+    flags: String,
+    /// Names of the flags of `set -o` without a letter
+    #[serde(default)]
+    named_flags: Vec<String>,
+    /// Seconds of `set -o script-timeout`
+    #[serde(default)]
+    script_timeout: Option<u64>,
+    #[serde(default)]
+    umask: Option<u32>,
+}
+
+/// The state of a session as pretty-printed JSON
+pub(crate) fn export(session: &Session) -> String {
+    let snapshot = Snapshot {
+        version: VERSION,
+        cwd: session.cwd.clone(),
+        previous_dir: session.previous_dir.clone(),
+        dir_stack: session.dir_stack.clone(),
+        env: session.env.clone().into_iter().collect(),
+        vars: session.vars.clone().into_iter().collect(),
+        functions: session
+            .functions
+            .iter()
+            .map(|(name, body)| (name.clone(), body.to_string()))
+            .collect(),
+        options: Options {
+            flags: session.options.letters(),
+            named_flags: session
+                .options
+                .unlettered()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            script_timeout: session.script_timeout.map(|timeout| timeout.as_secs()),
+            umask: session.umask,
+        },
+    };
+    let mut json = serde_json::to_string_pretty(&snapshot).expect("snapshot is serializable");
+    json.push('\n');
+    json
+}
+
+/// Set up a session as in an export. Variables and functions are added to
+/// the ones defined already. A working directory missing on this system is
+/// left as it is and returned as a warning.
+pub(crate) fn import(session: &mut Session, json: &str) -> Result<Option<String>, String> {
+    let snapshot: Snapshot = serde_json::from_str(json).map_err(|error| error.to_string())?;
+    if snapshot.version > VERSION {
+        return Err(format!("unknown version {}", snapshot.version));
+    }
+
+    /* Functions are parsed first, so a broken file changes nothing */
+    let mut functions = Vec::new();
+    for (name, body) in snapshot.functions {
+        let body = parser::parse(&body).map_err(|error| format!("function {}: {}", name, error))?;
+        functions.push((name, Arc::new(body)));
+    }
+
+    session.env.extend(snapshot.env);
+    session.vars.extend(snapshot.vars);
+    session.functions.extend(functions);
+    session.options = ShellOptions::default();
+    for flags in snapshot
+        .options
+        .flags
+        .chars()
+        .filter_map(options::by_letter)
+    {
+        session.options.set(flags, true);
+    }
+    for flags in snapshot
+        .options
+        .named_flags
+        .iter()
+        .filter_map(|name| options::by_name(name))
+    {
+        session.options.set(flags, true);
+    }
+    session.script_timeout = snapshot.options.script_timeout.map(Duration::from_secs);
+    session.umask = snapshot.options.umask;
+    session.dir_stack = snapshot.dir_stack;
+
+    let warning = match snapshot.cwd.is_dir() {
+        true => {
+            let previous = std::mem::replace(&mut session.cwd, snapshot.cwd);
+            session.previous_dir = snapshot.previous_dir.or(Some(previous));
+            None
+        }
+        false => Some(format!("{}: No such directory", snapshot.cwd.display())),
+    };
+    Ok(warning)
+}
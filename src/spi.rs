@@ -0,0 +1,94 @@
+//! SPI buses for the `spi` builtin, for poking sensors from the console
+//! without writing a program.
+
+use std::io;
+
+use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+
+use crate::hardware;
+
+/// Clock speed in Hz unless one is given, which most devices can keep up
+/// with
+pub(crate) const DEFAULT_SPEED: u32 = 1_000_000;
+
+const BUSES: [Bus; 7] = [
+    Bus::Spi0,
+    Bus::Spi1,
+    Bus::Spi2,
+    Bus::Spi3,
+    Bus::Spi4,
+    Bus::Spi5,
+    Bus::Spi6,
+];
+
+const SELECTS: [SlaveSelect; 16] = [
+    SlaveSelect::Ss0,
+    SlaveSelect::Ss1,
+    SlaveSelect::Ss2,
+    SlaveSelect::Ss3,
+    SlaveSelect::Ss4,
+    SlaveSelect::Ss5,
+    SlaveSelect::Ss6,
+    SlaveSelect::Ss7,
+    SlaveSelect::Ss8,
+    SlaveSelect::Ss9,
+    SlaveSelect::Ss10,
+    SlaveSelect::Ss11,
+    SlaveSelect::Ss12,
+    SlaveSelect::Ss13,
+    SlaveSelect::Ss14,
+    SlaveSelect::Ss15,
+];
+
+const MODES: [Mode; 4] = [Mode::Mode0, Mode::Mode1, Mode::Mode2, Mode::Mode3];
+
+/// How a transfer is done, with the numbers of the bus, chip select and
+/// mode as on the Pi
+pub(crate) struct Transfer {
+    pub(crate) bus: u8,
+    pub(crate) select: u8,
+    pub(crate) speed: u32,
+    pub(crate) mode: u8,
+}
+
+impl Transfer {
+    /// Send `bytes` while receiving as many, which are returned
+    pub(crate) fn run(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let invalid = |what: &str, number: u8| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: invalid {}", number, what),
+            )
+        };
+        let bus = *BUSES
+            .get(self.bus as usize)
+            .ok_or_else(|| invalid("bus", self.bus))?;
+        let select = *SELECTS
+            .get(self.select as usize)
+            .ok_or_else(|| invalid("chip select", self.select))?;
+        let mode = *MODES
+            .get(self.mode as usize)
+            .ok_or_else(|| invalid("mode", self.mode))?;
+
+        let error = |error| hardware::spi_error(self.bus, self.select, error);
+        let spi = Spi::new(bus, select, self.speed, mode).map_err(error)?;
+        let mut received = vec![0; bytes.len()];
+        spi.transfer(&mut received, bytes).map_err(error)?;
+        Ok(received)
+    }
+}
+
+/// Bytes written in hex, e.g. `9f 00 00` or `0x9f0000`, across words
+pub(crate) fn parse_hex(words: &[&str]) -> Option<Vec<u8>> {
+    let digits: String = words
+        .iter()
+        .map(|word| word.strip_prefix("0x").unwrap_or(word))
+        .collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,46 @@
+//! Exit statuses the shell uses for failures of its own, following the
+//! conventions of POSIX shells. Scripts and programs driving the shell can
+//! rely on these.
+
+/// Exit status of a command the shell failed to run or ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCode {
+    Success = 0,
+    /// A builtin failed, or a file could not be opened for a redirection
+    Failure = 1,
+    /// Invalid syntax or usage, of the shell or of a builtin
+    Usage = 2,
+    /// The command was killed because a time budget ran out, the same as
+    /// used by `timeout(1)`
+    Timeout = 124,
+    /// The program was found but could not be executed, e.g. because it is
+    /// not executable
+    NotExecutable = 126,
+    /// The program was not found
+    NotFound = 127,
+}
+
+impl StatusCode {
+    /// Added to the signal number for commands killed by a signal
+    pub const SIGNAL_BASE: i32 = 128;
+
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    /// The exit status of a command killed by `signal`
+    pub fn signaled(signal: i32) -> i32 {
+        StatusCode::SIGNAL_BASE + signal
+    }
+
+    /// The signal that killed a command, if the status says it was killed
+    pub fn signal(status: i32) -> Option<i32> {
+        (status > StatusCode::SIGNAL_BASE).then(|| status - StatusCode::SIGNAL_BASE)
+    }
+}
+
+impl From<StatusCode> for i32 {
+    fn from(status: StatusCode) -> i32 {
+        status.code()
+    }
+}
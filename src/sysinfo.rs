@@ -0,0 +1,162 @@
+//! The health of the board for the `sysinfo` builtin: temperature,
+//! throttling, core voltage, memory and load. What `vcgencmd` tells is asked
+//! from the firmware directly, as images without it are common.
+
+use std::fs;
+use std::os::fd::AsRawFd;
+
+use crate::pinout;
+use crate::size::{self, Units};
+
+/// The mailbox of the VideoCore firmware, as used by `vcgencmd`
+const MAILBOX: &str = "/dev/vcio";
+
+/// Tags of the firmware property interface
+const TAG_GET_VOLTAGE: u32 = 0x0003_0003;
+const TAG_GET_THROTTLED: u32 = 0x0003_0046;
+
+/// Id of the core voltage for TAG_GET_VOLTAGE
+const VOLTAGE_CORE: u32 = 1;
+
+/// Bits of the throttled state, for now and for since boot 16 bits higher
+const THROTTLE_FLAGS: [(u32, &str); 4] = [
+    (0, "under-voltage"),
+    (1, "frequency capped"),
+    (2, "throttled"),
+    (3, "soft temperature limit"),
+];
+
+/// A block of lines about the board, with `unknown` for what can't be read
+pub(crate) fn report() -> String {
+    let unknown = || String::from("unknown");
+    let lines = [
+        ("model", pinout::model()),
+        ("temperature", temperature()),
+        ("throttling", throttling()),
+        ("core voltage", voltage()),
+        ("cpu clock", clock()),
+        ("memory", memory()),
+        ("load", load()),
+        ("uptime", uptime()),
+    ];
+    lines
+        .into_iter()
+        .map(|(name, value)| format!("{:<13}{}\n", name, value.unwrap_or_else(unknown)))
+        .collect()
+}
+
+pub(crate) fn temperature() -> Option<String> {
+    let millidegrees: i64 = fs::read_to_string("/sys/class/thermal/thermal_zone0/temp")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(format!("{:.1} °C", millidegrees as f64 / 1000.0))
+}
+
+/// What the firmware did to protect the board, now and since boot
+fn throttling() -> Option<String> {
+    /* Newer kernels have it in sysfs, otherwise the firmware is asked */
+    let state = fs::read_to_string("/sys/devices/platform/soc/soc:firmware/get_throttled")
+        .ok()
+        .and_then(|state| u32::from_str_radix(state.trim(), 16).ok())
+        .or_else(|| property(TAG_GET_THROTTLED, &[0]))?;
+    let flags = |shift: u32| {
+        THROTTLE_FLAGS
+            .iter()
+            .filter(|(bit, _)| state & (1 << (bit + shift)) != 0)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let (now, before) = (flags(0), flags(16));
+    let text = match (now.is_empty(), before.is_empty()) {
+        (true, true) => String::from("none"),
+        (false, true) => format!("{} now", now),
+        (true, false) => format!("{} since boot", before),
+        (false, false) => format!("{} now; {} since boot", now, before),
+    };
+    Some(format!("{} (0x{:x})", text, state))
+}
+
+fn voltage() -> Option<String> {
+    let microvolts = property(TAG_GET_VOLTAGE, &[VOLTAGE_CORE, 0])?;
+    Some(format!("{:.4} V", microvolts as f64 / 1_000_000.0))
+}
+
+fn clock() -> Option<String> {
+    let kilohertz: u64 =
+        fs::read_to_string("/sys/devices/system/cpu/cpu0/cpufreq/scaling_cur_freq")
+            .ok()?
+            .trim()
+            .parse()
+            .ok()?;
+    Some(format!("{} MHz", kilohertz / 1000))
+}
+
+fn memory() -> Option<String> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| -> Option<u64> {
+        let line = meminfo.lines().find_map(|line| line.strip_prefix(name))?;
+        let kilobytes: u64 = line
+            .trim_start_matches(':')
+            .trim()
+            .split(' ')
+            .next()?
+            .parse()
+            .ok()?;
+        Some(kilobytes * 1024)
+    };
+    let (total, available) = (field("MemTotal")?, field("MemAvailable")?);
+    let human = |bytes: u64| size::format(bytes, Units::Iec);
+    Some(format!(
+        "{} used of {}, {} available",
+        human(total - available),
+        human(total),
+        human(available)
+    ))
+}
+
+fn load() -> Option<String> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    let averages: Vec<&str> = loadavg.split(' ').take(3).collect();
+    Some(averages.join(" "))
+}
+
+pub(crate) fn uptime() -> Option<String> {
+    let uptime = fs::read_to_string("/proc/uptime").ok()?;
+    let seconds = uptime.split(['.', ' ']).next()?.parse::<u64>().ok()?;
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    Some(match days {
+        0 => format!("{}h {:02}m", hours, minutes),
+        _ => format!("{}d {}h {:02}m", days, hours, minutes),
+    })
+}
+
+/// Ask the firmware for a property through its mailbox, returning the last
+/// word of the answer. `values` is the request, which also sizes the answer.
+fn property(tag: u32, values: &[u32]) -> Option<u32> {
+    let mailbox = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(MAILBOX)
+        .ok()?;
+
+    /* The buffer size, a request code, the tag with the size of its value
+    buffer and of the request, the values and an end tag of 0 */
+    let mut buffer = vec![0u32; values.len() + 6];
+    buffer[0] = (buffer.len() * 4) as u32;
+    buffer[2] = tag;
+    buffer[3] = (values.len() * 4) as u32;
+    buffer[4] = buffer[3];
+    buffer[5..5 + values.len()].copy_from_slice(values);
+
+    /* _IOWR(100, 0, char *) */
+    let request = (3 << 30) | ((std::mem::size_of::<*mut u8>() as u64) << 16) | (100 << 8);
+    let result = unsafe { libc::ioctl(mailbox.as_raw_fd(), request as _, buffer.as_mut_ptr()) };
+    /* The firmware sets the top bit of the response code on success */
+    match result >= 0 && buffer[1] == 0x8000_0000 {
+        true => values.len().checked_sub(1).map(|last| buffer[5 + last]),
+        false => None,
+    }
+}
@@ -0,0 +1,382 @@
+//! Minimal telnet (RFC 854) support for network sessions. Incoming IAC
+//! sequences are answered and stripped from the input stream, and outgoing
+//! data is escaped so it is not mistaken for commands.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::pty::WindowSize;
+
+/* Commands */
+const SE: u8 = 240;
+const IP: u8 = 244;
+const EC: u8 = 247;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const IAC: u8 = 255;
+
+/* Options */
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+/// Negotiate About Window Size (RFC 1073)
+const NAWS: u8 = 31;
+
+/// Options the shell is willing to perform itself
+const LOCAL_OPTIONS: [u8; 2] = [ECHO, SUPPRESS_GO_AHEAD];
+/// Options the shell wants the client to perform
+const REMOTE_OPTIONS: [u8; 2] = [SUPPRESS_GO_AHEAD, NAWS];
+
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    /// Received a carriage return, which may be followed by NUL or LF
+    Cr,
+    Iac,
+    Negotiate(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// Reads data from a telnet client, handling protocol commands in between
+pub struct TelnetReader {
+    stream: TcpStream,
+    state: State,
+    /// Options currently enabled on our side and on the client's side
+    local: [bool; 256],
+    remote: [bool; 256],
+    /// Data of the subnegotiation being received
+    subnegotiation: Vec<u8>,
+    /// Size of the client's window, once it told
+    window_size: Option<WindowSize>,
+    /// Called when the client tells a new size
+    resize_callback: Option<ResizeCallback>,
+}
+
+/// Called with the new size of the client's window
+pub(crate) type ResizeCallback = Box<dyn Fn(WindowSize) + Send>;
+
+impl TelnetReader {
+    /// Start a telnet session by asking the client for character-at-a-time
+    /// mode with the shell doing the echo.
+    pub fn negotiate(stream: TcpStream) -> io::Result<TelnetReader> {
+        let mut reader = TelnetReader {
+            stream,
+            state: State::Data,
+            local: [false; 256],
+            remote: [false; 256],
+            subnegotiation: Vec::new(),
+            window_size: None,
+            resize_callback: None,
+        };
+
+        for option in LOCAL_OPTIONS {
+            reader.local[option as usize] = true;
+            reader.send(WILL, option)?;
+        }
+        for option in REMOTE_OPTIONS {
+            reader.remote[option as usize] = true;
+            reader.send(DO, option)?;
+        }
+
+        Ok(reader)
+    }
+
+    /// Size of the client's window, as last told with NAWS
+    pub(crate) fn window_size(&self) -> Option<WindowSize> {
+        self.window_size
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn set_resize_callback(&mut self, callback: ResizeCallback) {
+        self.resize_callback = Some(callback);
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn send(&mut self, command: u8, option: u8) -> io::Result<()> {
+        self.stream.write_all(&[IAC, command, option])
+    }
+
+    /// Answer a WILL/WONT/DO/DONT from the client. Replies are only sent when
+    /// the state of an option changes, which prevents negotiation loops.
+    fn handle_negotiation(&mut self, command: u8, option: u8) -> io::Result<()> {
+        let index = option as usize;
+        match command {
+            DO if !self.local[index] => {
+                if LOCAL_OPTIONS.contains(&option) {
+                    self.local[index] = true;
+                    self.send(WILL, option)
+                } else {
+                    self.send(WONT, option)
+                }
+            }
+            DONT if self.local[index] => {
+                self.local[index] = false;
+                self.send(WONT, option)
+            }
+            WILL if !self.remote[index] => {
+                if REMOTE_OPTIONS.contains(&option) {
+                    self.remote[index] = true;
+                    self.send(DO, option)
+                } else {
+                    self.send(DONT, option)
+                }
+            }
+            WONT if self.remote[index] => {
+                self.remote[index] = false;
+                self.send(DONT, option)
+            }
+            /* Already in the requested state */
+            _ => Ok(()),
+        }
+    }
+
+    /// Handle a finished subnegotiation. Only window sizes are of interest.
+    fn handle_subnegotiation(&mut self) {
+        let data = std::mem::take(&mut self.subnegotiation);
+        if let [NAWS, width_high, width_low, height_high, height_low] = data[..] {
+            let columns = u16::from_be_bytes([width_high, width_low]);
+            let rows = u16::from_be_bytes([height_high, height_low]);
+            /* Zero means the client doesn't know */
+            if columns > 0 && rows > 0 {
+                let size = WindowSize { rows, columns };
+                self.window_size = Some(size);
+                if let Some(callback) = &self.resize_callback {
+                    callback(size);
+                }
+            }
+        }
+    }
+
+    /// Feed one byte from the network through the protocol state machine,
+    /// returning the data byte it represents, if any.
+    fn process(&mut self, byte: u8) -> io::Result<Option<u8>> {
+        let (state, data) = match (self.state, byte) {
+            (State::Data, IAC) => (State::Iac, None),
+            (State::Data, b'\r') => (State::Cr, Some(b'\r')),
+            (State::Data, byte) => (State::Data, Some(byte)),
+            /* Enter is sent as CR LF or CR NUL, only pass on the CR */
+            (State::Cr, 0) | (State::Cr, b'\n') => (State::Data, None),
+            (State::Cr, IAC) => (State::Iac, None),
+            (State::Cr, byte) => (State::Data, Some(byte)),
+            /* Escaped 0xFF data byte */
+            (State::Iac, IAC) => (State::Data, Some(IAC)),
+            (State::Iac, WILL..=DONT) => (State::Negotiate(byte), None),
+            (State::Iac, SB) => (State::Subnegotiation, None),
+            /* Interrupt process and erase character map to the keys the
+            line editor already understands */
+            (State::Iac, IP) => (State::Data, Some(0x03)),
+            (State::Iac, EC) => (State::Data, Some(0x7f)),
+            (State::Iac, _) => (State::Data, None),
+            (State::Negotiate(command), option) => {
+                self.handle_negotiation(command, option)?;
+                (State::Data, None)
+            }
+            (State::Subnegotiation, IAC) => (State::SubnegotiationIac, None),
+            (State::Subnegotiation, byte) => {
+                self.subnegotiation.push(byte);
+                (State::Subnegotiation, None)
+            }
+            (State::SubnegotiationIac, SE) => {
+                self.handle_subnegotiation();
+                (State::Data, None)
+            }
+            /* Escaped 0xFF, e.g. in a width of 255 columns */
+            (State::SubnegotiationIac, IAC) => {
+                self.subnegotiation.push(IAC);
+                (State::Subnegotiation, None)
+            }
+            (State::SubnegotiationIac, _) => (State::Subnegotiation, None),
+        };
+
+        self.state = state;
+        Ok(data)
+    }
+}
+
+impl Read for TelnetReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        /* Keep reading until at least one data byte is available, as a read
+        of only protocol commands must not look like end of file */
+        let mut raw = vec![0u8; buf.len()];
+        loop {
+            let bytes_read = self.stream.read(&mut raw)?;
+            if bytes_read == 0 {
+                return Ok(0);
+            }
+
+            let mut length = 0;
+            for &byte in &raw[..bytes_read] {
+                if let Some(data) = self.process(byte)? {
+                    buf[length] = data;
+                    length += 1;
+                }
+            }
+            if length > 0 {
+                return Ok(length);
+            }
+        }
+    }
+}
+
+/// Escape outgoing data: IAC bytes are doubled and a bare carriage return is
+/// followed by NUL, as required by the protocol.
+pub fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for (i, &byte) in data.iter().enumerate() {
+        escaped.push(byte);
+        match byte {
+            IAC => escaped.push(IAC),
+            b'\r' if data.get(i + 1) != Some(&b'\n') => escaped.push(0),
+            _ => {}
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// A reader negotiating with a client on the other end of a loopback
+    /// connection
+    fn connect() -> (TelnetReader, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (TelnetReader::negotiate(server).unwrap(), client)
+    }
+
+    /// Send `input` as the client and read the data it carries
+    fn data(reader: &mut TelnetReader, client: &mut TcpStream, input: &[u8]) -> Vec<u8> {
+        client.write_all(input).unwrap();
+        let mut buf = [0u8; 64];
+        let length = reader.read(&mut buf).unwrap();
+        buf[..length].to_vec()
+    }
+
+    fn replies(client: &mut TcpStream, length: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; length];
+        client.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn asks_for_character_mode() {
+        let (_reader, mut client) = connect();
+        assert_eq!(
+            replies(&mut client, 12),
+            [
+                IAC,
+                WILL,
+                ECHO,
+                IAC,
+                WILL,
+                SUPPRESS_GO_AHEAD,
+                IAC,
+                DO,
+                SUPPRESS_GO_AHEAD,
+                IAC,
+                DO,
+                NAWS
+            ]
+        );
+    }
+
+    #[test]
+    fn strips_commands_from_the_data() {
+        let (mut reader, mut client) = connect();
+        replies(&mut client, 12);
+        assert_eq!(data(&mut reader, &mut client, b"ls\r\n"), b"ls\r");
+        assert_eq!(data(&mut reader, &mut client, b"a\r\0b"), b"a\rb");
+        assert_eq!(
+            data(&mut reader, &mut client, &[IAC, IAC, b'x']),
+            [IAC, b'x']
+        );
+        assert_eq!(
+            data(&mut reader, &mut client, &[IAC, IP, IAC, EC]),
+            [0x03, 0x7f]
+        );
+        /* Reads of nothing but commands wait for data */
+        assert_eq!(
+            data(&mut reader, &mut client, &[IAC, 241, IAC, 249, b'y']),
+            b"y"
+        );
+    }
+
+    #[test]
+    fn answers_only_changes_of_options() {
+        let (mut reader, mut client) = connect();
+        replies(&mut client, 12);
+        /* Agreeing to what was asked for needs no answer */
+        data(
+            &mut reader,
+            &mut client,
+            &[IAC, DO, ECHO, IAC, WILL, NAWS, b'.'],
+        );
+        data(
+            &mut reader,
+            &mut client,
+            &[IAC, DO, 99, IAC, WILL, 98, b'.'],
+        );
+        assert_eq!(replies(&mut client, 6), [IAC, WONT, 99, IAC, DONT, 98]);
+        data(
+            &mut reader,
+            &mut client,
+            &[IAC, DONT, ECHO, IAC, DONT, ECHO, b'.'],
+        );
+        assert_eq!(replies(&mut client, 3), [IAC, WONT, ECHO]);
+        data(&mut reader, &mut client, &[IAC, DO, ECHO, b'.']);
+        assert_eq!(replies(&mut client, 3), [IAC, WILL, ECHO]);
+    }
+
+    #[test]
+    fn reads_window_sizes() {
+        let (mut reader, mut client) = connect();
+        replies(&mut client, 12);
+        assert_eq!(reader.window_size(), None);
+        let size = [IAC, SB, NAWS, 0, 80, 0, 24, IAC, SE, b'.'];
+        data(&mut reader, &mut client, &size);
+        assert_eq!(
+            reader.window_size(),
+            Some(WindowSize {
+                rows: 24,
+                columns: 80
+            })
+        );
+        /* A width of 255 has its IAC escaped, and unknown sizes are ignored */
+        let size = [IAC, SB, NAWS, 0, IAC, IAC, 0, 50, IAC, SE, b'.'];
+        data(&mut reader, &mut client, &size);
+        let unknown = [IAC, SB, NAWS, 0, 0, 0, 0, IAC, SE, b'.'];
+        data(&mut reader, &mut client, &unknown);
+        assert_eq!(
+            reader.window_size(),
+            Some(WindowSize {
+                rows: 50,
+                columns: 255
+            })
+        );
+    }
+
+    #[test]
+    fn escapes_output() {
+        assert_eq!(escape(b"plain"), b"plain");
+        assert_eq!(escape(&[b'a', IAC, b'b']), [b'a', IAC, IAC, b'b']);
+        assert_eq!(escape(b"a\r\nb\rc\r"), b"a\r\nb\r\0c\r\0");
+    }
+}
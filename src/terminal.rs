@@ -0,0 +1,67 @@
+//! Raw mode of the terminal the shell runs in on stdio. While a line is
+//! edited, keys reach the line editor as they are typed, without echo or
+//! line buffering by the terminal, so editing works as it does over the
+//! UART. Commands get the terminal back as it was.
+
+use std::io;
+use std::panic;
+use std::sync::{Mutex, Once};
+
+use crate::pty;
+
+/// How the terminal was before raw mode, while it is in raw mode
+static SAVED: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// Put the terminal on stdin into raw mode, if it isn't already. Output is
+/// still processed, so lines written end as before.
+pub(crate) fn enter_raw_mode() -> io::Result<()> {
+    let mut saved = SAVED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if saved.is_some() {
+        return Ok(());
+    }
+    restore_on_panic();
+
+    let original = pty::attributes(libc::STDIN_FILENO)?;
+    let mut raw = original;
+    /* Ctrl-C, Ctrl-D and Enter are handled by the line editor */
+    raw.c_iflag &= !(libc::ICRNL | libc::INLCR | libc::IGNCR | libc::IXON | libc::ISTRIP);
+    raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ECHONL | libc::ISIG | libc::IEXTEN);
+    raw.c_cc[libc::VMIN] = 1;
+    raw.c_cc[libc::VTIME] = 0;
+    set_attributes(&raw)?;
+    *saved = Some(original);
+    Ok(())
+}
+
+/// Leave raw mode, bringing the terminal back as it was
+pub(crate) fn restore() {
+    let mut saved = SAVED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(original) = saved.take() {
+        let _ = set_attributes(&original);
+    }
+}
+
+/// Leave raw mode before a panic message is shown, so the terminal isn't
+/// left unusable
+fn restore_on_panic() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore();
+            previous(info);
+        }));
+    });
+}
+
+fn set_attributes(termios: &libc::termios) -> io::Result<()> {
+    /* Input typed before raw mode is kept */
+    match unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
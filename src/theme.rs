@@ -0,0 +1,150 @@
+use std::env;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// When the shell is allowed to emit ANSI color sequences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorPolicy {
+    Always,
+    Never,
+    /// Use colors when the transport is a terminal, honoring `NO_COLOR` and
+    /// `CLICOLOR_FORCE`.
+    Auto,
+}
+
+impl FromStr for ColorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(ColorPolicy::Always),
+            "never" => Ok(ColorPolicy::Never),
+            "auto" => Ok(ColorPolicy::Auto),
+            _ => Err(format!(
+                "invalid color policy '{}' (expected always, never or auto)",
+                s
+            )),
+        }
+    }
+}
+
+impl ColorPolicy {
+    /// Decide if colors should be used on a transport. `is_terminal` tells
+    /// whether the other end of the transport is believed to be a terminal.
+    pub fn resolve(self, is_terminal: bool) -> bool {
+        match self {
+            ColorPolicy::Always => true,
+            ColorPolicy::Never => false,
+            ColorPolicy::Auto => {
+                /* See https://no-color.org and https://bixense.com/clicolors */
+                if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+                    false
+                } else if env::var("CLICOLOR_FORCE").is_ok_and(|value| value != "0") {
+                    true
+                } else {
+                    is_terminal
+                }
+            }
+        }
+    }
+}
+
+/// Everything in the shell that can be colored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    PromptUser,
+    PromptPath,
+    PromptSymbol,
+    Error,
+    MenuItem,
+    MenuSelected,
+    SyntaxCommand,
+    SyntaxArgument,
+    SyntaxString,
+    SyntaxOperator,
+    SyntaxComment,
+}
+
+const ROLE_COUNT: usize = 11;
+
+/// A named table of SGR parameters, one entry per `Role`. An empty entry
+/// means the role is printed without any styling.
+#[derive(Debug)]
+pub struct Theme {
+    pub name: &'static str,
+    styles: [&'static str; ROLE_COUNT],
+}
+
+const THEMES: [Theme; 4] = [
+    Theme {
+        name: "default",
+        styles: [
+            "1;32", "1;34", "0", "1;31", "0", "7", "1", "0", "33", "36", "2",
+        ],
+    },
+    Theme {
+        name: "bright",
+        styles: [
+            "1;92", "1;94", "1;97", "1;91", "97", "30;107", "1;97", "0", "93", "96", "90",
+        ],
+    },
+    Theme {
+        name: "mono",
+        styles: ["1", "1", "0", "1", "0", "7", "1", "0", "4", "1", "2"],
+    },
+    Theme {
+        name: "none",
+        styles: ["", "", "", "", "", "", "", "", "", "", ""],
+    },
+];
+
+impl Theme {
+    pub fn default_theme() -> &'static Theme {
+        &THEMES[0]
+    }
+
+    pub fn by_name(name: &str) -> Option<&'static Theme> {
+        THEMES.iter().find(|theme| theme.name == name)
+    }
+
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        THEMES.iter().map(|theme| theme.name)
+    }
+
+    pub fn style(&self, role: Role) -> &'static str {
+        self.styles[role as usize]
+    }
+}
+
+/// A theme together with the outcome of the color policy for one transport.
+/// All colored output goes through `paint`.
+#[derive(Clone, Copy)]
+pub struct Colors {
+    theme: &'static Theme,
+    enabled: bool,
+}
+
+impl Colors {
+    pub fn new(theme: &'static Theme, enabled: bool) -> Colors {
+        Colors { theme, enabled }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn theme(&self) -> &'static Theme {
+        self.theme
+    }
+
+    pub fn paint(&self, role: Role, text: &str) -> String {
+        let style = self.theme.style(role);
+        if !self.enabled || style.is_empty() || text.is_empty() {
+            return text.to_owned();
+        }
+
+        format!("\x1b[{}m{}\x1b[0m", style, text)
+    }
+}
@@ -0,0 +1,249 @@
+//! Transcripts of sessions, recording everything read from and written to
+//! the transport with the time, so bring-up sessions over serial can be
+//! archived and attached to bug reports. Each line holds what went one way
+//! in a row, `<` for input and `>` for output, with control characters
+//! escaped.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::transport::{self, Reader};
+
+/// Traffic one way is put on a new line after this long
+const LINE_TIME: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Input,
+    Output,
+}
+
+/// Traffic one way not yet written, as more may follow
+struct Line {
+    time: SystemTime,
+    direction: Direction,
+    bytes: Vec<u8>,
+}
+
+pub(crate) struct Transcript {
+    path: PathBuf,
+    file: BufWriter<File>,
+    line: Option<Line>,
+    /// Input is masked, e.g. while a password is typed
+    hidden: bool,
+}
+
+/// The transcript a session writes, if any, shared by its reader and
+/// writer
+pub(crate) type SharedTranscript = Arc<Mutex<Option<Transcript>>>;
+
+impl Transcript {
+    /// Start a transcript, appending to `path`. Only the user can read it,
+    /// as it holds everything typed.
+    pub(crate) fn open(path: &Path, what: &str) -> io::Result<Transcript> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)?;
+        let mut transcript = Transcript {
+            path: path.to_owned(),
+            file: BufWriter::new(file),
+            line: None,
+            hidden: false,
+        };
+        writeln!(
+            transcript.file,
+            "# {} transcript of {}",
+            timestamp(SystemTime::now()),
+            what
+        )?;
+        transcript.file.flush()?;
+        Ok(transcript)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Mask input from now on, or stop masking it
+    pub(crate) fn set_hidden(&mut self, hidden: bool) {
+        self.hidden = hidden;
+    }
+
+    fn record(&mut self, direction: Direction, bytes: &[u8]) {
+        let now = SystemTime::now();
+        let continues = match &self.line {
+            Some(line) => {
+                line.direction == direction
+                    && now.duration_since(line.time).unwrap_or_default() < LINE_TIME
+            }
+            None => false,
+        };
+        if !continues {
+            self.write_line();
+            self.line = Some(Line {
+                time: now,
+                direction,
+                bytes: Vec::new(),
+            });
+        }
+        if let Some(line) = &mut self.line {
+            match direction == Direction::Input && self.hidden {
+                true => line.bytes.extend(bytes.iter().map(|byte| match byte {
+                    b'\r' | b'\n' => *byte,
+                    _ => b'*',
+                })),
+                false => line.bytes.extend_from_slice(bytes),
+            }
+            /* A line ends with the end of a line of text or of a command */
+            if line.bytes.ends_with(b"\n") || line.bytes.ends_with(b"\r") {
+                self.write_line();
+            }
+        }
+    }
+
+    /// Write the traffic since the last line. Failures are ignored, as the
+    /// session must go on without its transcript.
+    fn write_line(&mut self) {
+        if let Some(line) = self.line.take() {
+            let marker = match line.direction {
+                Direction::Input => '<',
+                Direction::Output => '>',
+            };
+            let _ = writeln!(
+                self.file,
+                "{} {} {}",
+                timestamp(line.time),
+                marker,
+                escape(&line.bytes)
+            );
+            let _ = self.file.flush();
+        }
+    }
+}
+
+impl Drop for Transcript {
+    fn drop(&mut self) {
+        self.write_line();
+    }
+}
+
+/// A reader or writer whose traffic goes into the transcript of its
+/// session, while there is one
+pub(crate) struct Recorded<T> {
+    link: T,
+    transcript: SharedTranscript,
+}
+
+impl<T> Recorded<T> {
+    pub(crate) fn new(link: T, transcript: SharedTranscript) -> Recorded<T> {
+        Recorded { link, transcript }
+    }
+
+    /// The reader or writer itself, for traffic left out of the transcript
+    pub(crate) fn unrecorded(&mut self) -> &mut T {
+        &mut self.link
+    }
+
+    fn record(&self, direction: Direction, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        if let Some(transcript) = lock(&self.transcript).as_mut() {
+            transcript.record(direction, bytes);
+        }
+    }
+}
+
+impl<T> Deref for Recorded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.link
+    }
+}
+
+impl<T> DerefMut for Recorded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.link
+    }
+}
+
+impl<T: Read> Read for Recorded<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.link.read(buf)?;
+        self.record(Direction::Input, &buf[..length]);
+        Ok(length)
+    }
+}
+
+impl<T: Write> Write for Recorded<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = self.link.write(buf)?;
+        self.record(Direction::Output, &buf[..length]);
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.link.flush()
+    }
+}
+
+impl Recorded<Reader> {
+    pub(crate) fn read_utf8_char(&mut self) -> io::Result<Option<char>> {
+        transport::read_utf8_char(self)
+    }
+}
+
+pub(crate) fn lock(transcript: &SharedTranscript) -> MutexGuard<'_, Option<Transcript>> {
+    transcript
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Local time with milliseconds, like `2024-05-01 14:03:22.120`
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() as libc::time_t;
+    let mut local: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&seconds, &mut local) }.is_null() {
+        return format!("{}.{:03}", seconds, since_epoch.subsec_millis());
+    }
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        local.tm_year + 1900,
+        local.tm_mon + 1,
+        local.tm_mday,
+        local.tm_hour,
+        local.tm_min,
+        local.tm_sec,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Text as it is, with control characters and invalid UTF-8 escaped
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\r' => escaped.push_str("\\r"),
+                '\n' => escaped.push_str("\\n"),
+                '\t' => escaped.push_str("\\t"),
+                '\\' => escaped.push_str("\\\\"),
+                c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+                c => escaped.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    escaped
+}
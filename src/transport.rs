@@ -0,0 +1,474 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Cursor, IsTerminal, Read, Stdin, Stdout, Write};
+use std::net::TcpStream;
+use std::ops::BitAnd;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use rppal::uart::{self, Parity, Uart};
+
+use crate::config::FlowControl;
+use crate::hardware::{self, Peripheral};
+use crate::pty::WindowSize;
+use crate::service;
+#[cfg(feature = "async")]
+use crate::telnet::ResizeCallback;
+use crate::telnet::{self, TelnetReader};
+
+/// The UART rppal opens for the console
+const UART_DEVICE: &str = "/dev/serial0";
+
+/// Bytes written to the UART at once. At 115200 baud this takes about 45 ms
+/// to send, so a single write of large output doesn't block for long.
+const UART_CHUNK_SIZE: usize = 512;
+
+/// Bytes read from the UART at once. A read returns whatever arrived so far,
+/// so a pasted line takes a few reads instead of one per byte.
+const UART_READ_BUFFER_SIZE: usize = 256;
+
+/// How long a polling read of a TCP connection waits for input. Timeouts of
+/// zero aren't allowed.
+const POLL_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// How long a read of the UART waits before checking the idle timeout and
+/// whether the shell is stopping. The driver can't wait longer than 25.5
+/// seconds at once.
+const UART_READ_ROUND: Duration = Duration::from_secs(1);
+
+/// The kind of link a session runs over, used to select its config profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportKind {
+    Stdio,
+    Uart,
+    Tcp,
+}
+
+pub enum Reader {
+    Stdin(BufReader<Stdin>),
+    Uart(BufReader<UartReader>),
+    Tcp(TcpStream),
+    Telnet(Box<TelnetReader>),
+    /// Scripted input, e.g. from the test harness
+    Memory(Cursor<Vec<u8>>),
+    /// Input handled elsewhere, e.g. by another thread. Always at end of file.
+    #[cfg(feature = "async")]
+    Closed,
+}
+
+pub enum Writer {
+    Stdout(BufWriter<Stdout>),
+    Uart(Uart),
+    Tcp(TcpStream),
+    Telnet(TcpStream),
+    /// Output collected in a buffer shared with whoever created the session
+    Memory(Arc<Mutex<Vec<u8>>>),
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::Stdout(stdout) => stdout.get_mut().write(buf),
+            Writer::Uart(uart) => {
+                let chunk = &buf[..buf.len().min(UART_CHUNK_SIZE)];
+                uart.write(chunk).map_err(uart_error)
+            }
+            Writer::Tcp(stream) => stream.write(buf),
+            Writer::Telnet(stream) => {
+                stream.write_all(&telnet::escape(buf))?;
+                Ok(buf.len())
+            }
+            Writer::Memory(output) => lock(output).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Stdout(stdout) => stdout.get_mut().flush(),
+            /* Writes go straight to the driver, flushing its queue would
+            discard what wasn't sent yet */
+            Writer::Uart(_) => Ok(()),
+            Writer::Tcp(stream) | Writer::Telnet(stream) => stream.flush(),
+            Writer::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl Writer {
+    /// Whether the other end of the writer is believed to be a terminal
+    pub fn is_terminal(&self) -> bool {
+        match self {
+            Writer::Stdout(stdout) => stdout.get_ref().is_terminal(),
+            /* Anything attached to the UART or connecting over TCP is assumed
+            to be a terminal */
+            Writer::Uart(_) | Writer::Tcp(_) | Writer::Telnet(_) => true,
+            Writer::Memory(_) => false,
+        }
+    }
+
+    /// Whether the other end is believed to show inline images. Network
+    /// clients are usually graphical terminals, serial consoles rarely are.
+    pub fn supports_images(&self) -> bool {
+        match self {
+            Writer::Stdout(stdout) => stdout.get_ref().is_terminal(),
+            Writer::Tcp(_) | Writer::Telnet(_) => true,
+            Writer::Uart(_) | Writer::Memory(_) => false,
+        }
+    }
+
+    /// Turn XON/XOFF flow control of the UART on or off, e.g. while sending
+    /// files in which those are just bytes
+    pub(crate) fn set_software_flow_control(&mut self, enabled: bool) -> io::Result<()> {
+        match self {
+            Writer::Uart(uart) => uart.set_software_flow_control(enabled).map_err(uart_error),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait until everything written has been sent
+    pub fn drain(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Uart(uart) => uart.drain().map_err(uart_error),
+            _ => self.flush(),
+        }
+    }
+
+    /// What a program replacing the shell with `exec` is connected to:
+    /// None for the stdio of the shell, which it inherits, or the UART
+    /// opened again. Network and memory sessions can't be handed over.
+    pub(crate) fn console(&self) -> io::Result<Option<File>> {
+        match self {
+            Writer::Stdout(_) => Ok(None),
+            Writer::Uart(_) => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(UART_DEVICE)
+                .map(Some),
+            Writer::Tcp(_) | Writer::Telnet(_) | Writer::Memory(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only sessions on stdio or the UART can be handed over",
+            )),
+        }
+    }
+
+    pub fn kind(&self) -> TransportKind {
+        match self {
+            /* Memory sessions stand in for stdio ones */
+            Writer::Stdout(_) | Writer::Memory(_) => TransportKind::Stdio,
+            Writer::Uart(_) => TransportKind::Uart,
+            Writer::Tcp(_) | Writer::Telnet(_) => TransportKind::Tcp,
+        }
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Reader::Stdin(stdin) => stdin.read(buf),
+            Reader::Uart(uart) => uart.read(buf),
+            Reader::Tcp(stream) => stream.read(buf),
+            Reader::Telnet(telnet) => telnet.read(buf),
+            Reader::Memory(input) => input.read(buf),
+            #[cfg(feature = "async")]
+            Reader::Closed => Ok(0),
+        }
+    }
+}
+
+impl Reader {
+    /// Make reads fail with `TimedOut`/`WouldBlock` when no input arrives
+    /// within `timeout`. Only supported on the UART and TCP connections,
+    /// other transports keep blocking.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Reader::Uart(uart) => {
+                let uart = uart.get_mut();
+                uart.idle_timeout = timeout;
+                uart.set_read_mode()
+            }
+            Reader::Tcp(stream) => stream.set_read_timeout(timeout),
+            Reader::Telnet(telnet) => telnet.set_read_timeout(timeout),
+            Reader::Stdin(_) | Reader::Memory(_) => Ok(()),
+            #[cfg(feature = "async")]
+            Reader::Closed => Ok(()),
+        }
+    }
+
+    /// Make reads of the UART and TCP connections return right away with
+    /// what has arrived, to check for input while doing something else.
+    /// TCP reads fail with `WouldBlock`/`TimedOut` if nothing arrived, and
+    /// block without a timeout again once polling is turned off. Other
+    /// transports keep blocking.
+    pub fn set_polling(&mut self, polling: bool) -> io::Result<()> {
+        let timeout = polling.then_some(POLL_TIMEOUT);
+        match self {
+            Reader::Uart(uart) => {
+                let uart = uart.get_mut();
+                uart.polling = polling;
+                uart.set_read_mode()
+            }
+            Reader::Tcp(stream) => stream.set_read_timeout(timeout),
+            Reader::Telnet(telnet) => telnet.set_read_timeout(timeout),
+            _ => Ok(()),
+        }
+    }
+
+    /// Size of the terminal at the other end, if the transport tells it
+    pub(crate) fn window_size(&self) -> Option<WindowSize> {
+        match self {
+            Reader::Telnet(telnet) => telnet.window_size(),
+            _ => None,
+        }
+    }
+
+    /// Have `callback` called whenever the terminal at the other end tells
+    /// its new size
+    #[cfg(feature = "async")]
+    pub(crate) fn set_resize_callback(&mut self, callback: ResizeCallback) {
+        if let Reader::Telnet(telnet) = self {
+            telnet.set_resize_callback(callback);
+        }
+    }
+
+    /// Whether the reader can be handed to commands as their terminal
+    pub fn is_terminal(&self) -> bool {
+        match self {
+            Reader::Stdin(stdin) => stdin.get_ref().is_terminal(),
+            _ => false,
+        }
+    }
+
+    pub fn read_utf8_char(&mut self) -> io::Result<Option<char>> {
+        read_utf8_char(self)
+    }
+}
+
+/// Read a character, byte by byte so nothing after it is read. Returns
+/// `None` at end of file. Bytes that aren't valid UTF-8, like line noise on
+/// a serial link, are read as U+FFFD instead of failing. A character cut
+/// short by the start of another is skipped, so the other one isn't lost.
+pub(crate) fn read_utf8_char(reader: &mut impl Read) -> io::Result<Option<char>> {
+    let mut read_buf = [0u8; 1];
+    let mut char_buf = [0u8; 4];
+
+    /* Read first byte */
+    if reader.read(&mut read_buf[..])? == 0 {
+        /* "End of file" reached */
+        return Ok(None);
+    }
+
+    'character: loop {
+        /* Find number of bytes of the UTF-8 character */
+        let first_byte = read_buf[0];
+        let bytes_in_char = if first_byte.bitand(0x80) == 0x00 {
+            1
+        } else if first_byte.bitand(0xE0) == 0xC0 {
+            2
+        } else if first_byte.bitand(0xF0) == 0xE0 {
+            3
+        } else if first_byte.bitand(0xF8) == 0xF0 {
+            4
+        } else {
+            /* A stray continuation byte, or one never used in UTF-8 */
+            return Ok(Some(char::REPLACEMENT_CHARACTER));
+        };
+
+        /* Read the remaining bytes */
+        char_buf[0] = first_byte;
+        for byte in &mut char_buf[1..bytes_in_char] {
+            if reader.read(&mut read_buf[..])? == 0 {
+                /* Nothing to read, but not end of valid UTF-8 character */
+                return Ok(Some(char::REPLACEMENT_CHARACTER));
+            }
+            if read_buf[0].bitand(0xC0) != 0x80 {
+                continue 'character;
+            }
+            *byte = read_buf[0];
+        }
+
+        /* Convert to char. Overlong forms and surrogates are invalid too. */
+        return match std::str::from_utf8(&char_buf[..bytes_in_char]) {
+            Ok(c) => Ok(c.chars().next()),
+            Err(_) => Ok(Some(char::REPLACEMENT_CHARACTER)),
+        };
+    }
+}
+
+/// The transport used when the shell is not listening on the network
+pub fn default_kind() -> TransportKind {
+    if cfg!(target_arch = "aarch64") {
+        TransportKind::Uart
+    } else {
+        TransportKind::Stdio
+    }
+}
+
+/// The UART read by a session. Reads wait for input in rounds of
+/// `UART_READ_ROUND`, failing once the idle timeout passed or the shell is
+/// stopping. Sessions read it through a buffer, so characters are taken one
+/// at a time from what arrived without a system call each.
+pub struct UartReader {
+    uart: Uart,
+    idle_timeout: Option<Duration>,
+    polling: bool,
+}
+
+impl UartReader {
+    fn new(uart: Uart) -> io::Result<UartReader> {
+        let mut reader = UartReader {
+            uart,
+            idle_timeout: None,
+            polling: false,
+        };
+        reader.set_read_mode()?;
+        Ok(reader)
+    }
+
+    fn set_read_mode(&mut self) -> io::Result<()> {
+        let timeout = match self.polling {
+            true => Duration::ZERO,
+            false => UART_READ_ROUND,
+        };
+        self.uart.set_read_mode(0, timeout).map_err(uart_error)
+    }
+}
+
+impl Read for UartReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        loop {
+            let round = Instant::now();
+            let length = self.uart.read(buf).map_err(uart_error)?;
+            if length > 0 || self.polling {
+                return Ok(length);
+            }
+            /* Reads only return nothing early once the other end hung up,
+            e.g. a USB adapter or gadget was disconnected */
+            if round.elapsed() < UART_READ_ROUND / 2 {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "the UART hung up",
+                ));
+            }
+            if service::terminating() {
+                return Err(io::Error::other("the shell is stopping"));
+            }
+            if self
+                .idle_timeout
+                .is_some_and(|timeout| started.elapsed() >= timeout)
+            {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no input"));
+            }
+        }
+    }
+}
+
+/// A serial device other than the console, like a modem or GPS module on a
+/// USB adapter, or a Pi attached to one
+pub(crate) struct Serial(Uart);
+
+impl Serial {
+    /// Open a serial device. Reads return no data if nothing arrived within
+    /// `timeout`, which is rounded down to tenths of a second.
+    pub(crate) fn open(path: &Path, baud: u32, timeout: Duration) -> io::Result<Serial> {
+        let mut uart = Uart::with_path(path, baud, Parity::None, 8, 1).map_err(uart_error)?;
+        uart.set_read_mode(0, timeout).map_err(uart_error)?;
+        uart.set_write_mode(true).map_err(uart_error)?;
+        Ok(Serial(uart))
+    }
+}
+
+impl Read for Serial {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(uart_error)
+    }
+}
+
+impl Write for Serial {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf).map_err(uart_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.drain().map_err(uart_error)
+    }
+}
+
+/// Open the UART. Errors tell how to make it available if it is missing or
+/// not accessible.
+pub fn uart_reader_writer(baud: u32, flow_control: FlowControl) -> io::Result<(Reader, Writer)> {
+    let open = || {
+        Uart::new(baud, Parity::None, 8, 1)
+            .map_err(|error| hardware::unavailable(Peripheral::Uart, uart_error(error)))
+    };
+    let mut uart_write = open()?;
+
+    /* Waiting for room in the transmit queue slows output down to the baud
+    rate, instead of failing writes once the queue is full */
+    uart_write.set_write_mode(true).map_err(uart_error)?;
+    /* Read must be last, as set_read_mode() is overwritten by calling
+    Uart::new again. */
+    let uart_read = BufReader::with_capacity(UART_READ_BUFFER_SIZE, UartReader::new(open()?)?);
+    /* Flow control is a setting of the device, so it applies to reads as
+    well. Once output is stopped, writes wait in the driver. */
+    match flow_control {
+        FlowControl::None => {}
+        FlowControl::RtsCts => uart_write
+            .set_hardware_flow_control(true)
+            .map_err(uart_error)?,
+        FlowControl::XonXoff => uart_write
+            .set_software_flow_control(true)
+            .map_err(uart_error)?,
+    }
+
+    Ok((Reader::Uart(uart_read), Writer::Uart(uart_write)))
+}
+
+pub fn stdio_reader_writer() -> (Reader, Writer) {
+    (
+        Reader::Stdin(BufReader::new(io::stdin())),
+        Writer::Stdout(BufWriter::new(io::stdout())),
+    )
+}
+
+/// Create a reader and writer for a connection accepted by the TCP listener,
+/// optionally speaking the telnet protocol on it
+pub fn tcp_reader_writer(stream: TcpStream, telnet: bool) -> io::Result<(Reader, Writer)> {
+    let read_stream = stream.try_clone()?;
+    if telnet {
+        let telnet_reader = TelnetReader::negotiate(read_stream)?;
+        Ok((
+            Reader::Telnet(Box::new(telnet_reader)),
+            Writer::Telnet(stream),
+        ))
+    } else {
+        Ok((Reader::Tcp(read_stream), Writer::Tcp(stream)))
+    }
+}
+
+/// Create a reader returning `input` and a writer collecting everything
+/// written into the returned buffer
+pub fn memory_reader_writer(input: Vec<u8>) -> (Reader, Writer, Arc<Mutex<Vec<u8>>>) {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    (
+        Reader::Memory(Cursor::new(input)),
+        Writer::Memory(Arc::clone(&output)),
+        output,
+    )
+}
+
+fn uart_error(error: uart::Error) -> io::Error {
+    match error {
+        uart::Error::Io(error) => error,
+        uart::Error::InvalidValue => io::Error::from(io::ErrorKind::InvalidData),
+        uart::Error::Gpio(error) => hardware::gpio_error(error),
+    }
+}
+
+/// Lock a shared buffer. A panic while holding the lock leaves the buffer
+/// itself intact, so poisoning is ignored.
+pub(crate) fn lock(buffer: &Mutex<Vec<u8>>) -> MutexGuard<'_, Vec<u8>> {
+    buffer
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
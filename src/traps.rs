@@ -0,0 +1,203 @@
+//! Commands run when the shell gets a signal, set with `trap`. Signals are
+//! counted by a handler and the sessions trapping them run their commands
+//! between commands. The shell process serves several sessions, so every
+//! session trapping a signal gets it, and signals no session traps keep
+//! their default action.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// The pseudo-signal of the session ending
+pub(crate) const EXIT: i32 = 0;
+
+/// Signals that can be trapped, by name without `SIG`
+pub(crate) const SIGNALS: [(&str, i32); 8] = [
+    ("EXIT", EXIT),
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+];
+
+/// Signals that `kill` sends besides the ones that can be trapped
+const UNTRAPPED: [(&str, i32); 6] = [
+    ("KILL", libc::SIGKILL),
+    ("PIPE", libc::SIGPIPE),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+];
+
+const SLOTS: usize = 32;
+
+/// How often each signal was received by the process
+static RECEIVED: [AtomicUsize; SLOTS] = [const { AtomicUsize::new(0) }; SLOTS];
+/// How many sessions trap each signal
+static TRAPPED: [AtomicUsize; SLOTS] = [const { AtomicUsize::new(0) }; SLOTS];
+static INSTALLED: [AtomicBool; SLOTS] = [const { AtomicBool::new(false) }; SLOTS];
+
+/// Find a signal by name, with or without `SIG`, or by number
+pub(crate) fn signal_by_name(name: &str) -> Option<i32> {
+    if let Ok(number) = name.parse::<i32>() {
+        return SIGNALS
+            .iter()
+            .find(|(_, signal)| *signal == number)
+            .map(|(_, signal)| *signal);
+    }
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNALS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, signal)| *signal)
+}
+
+/// Find any signal `kill` can send, by name or number. Numbers need not be
+/// in the tables.
+pub(crate) fn sendable_signal(name: &str) -> Option<i32> {
+    if let Ok(number) = name.parse::<i32>() {
+        return (0..SLOTS as i32).contains(&number).then_some(number);
+    }
+    let upper = name.to_ascii_uppercase();
+    let upper = upper.strip_prefix("SIG").unwrap_or(&upper);
+    UNTRAPPED
+        .iter()
+        .find(|(known, _)| *known == upper)
+        .map(|(_, signal)| *signal)
+        .or_else(|| signal_by_name(name).filter(|signal| *signal != EXIT))
+}
+
+/// Every signal `kill` can send by name, by number
+pub(crate) fn sendable_signals() -> Vec<(&'static str, i32)> {
+    let mut signals: Vec<(&str, i32)> = SIGNALS
+        .iter()
+        .chain(UNTRAPPED.iter())
+        .filter(|(_, signal)| *signal != EXIT)
+        .copied()
+        .collect();
+    signals.sort_by_key(|(_, signal)| *signal);
+    signals
+}
+
+pub(crate) fn signal_name(signal: i32) -> &'static str {
+    SIGNALS
+        .iter()
+        .find(|(_, known)| *known == signal)
+        .map_or("?", |(name, _)| name)
+}
+
+extern "C" fn handle(signal: libc::c_int) {
+    let slot = signal as usize;
+    /* Nobody traps it anymore, so do what would have happened without a
+    handler */
+    if TRAPPED[slot].load(Ordering::SeqCst) == 0 {
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+        return;
+    }
+    RECEIVED[slot].fetch_add(1, Ordering::SeqCst);
+}
+
+/// Count the signal from now on, once for the process
+fn install(signal: i32) {
+    let slot = signal as usize;
+    if INSTALLED[slot].swap(true, Ordering::SeqCst) {
+        return;
+    }
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+}
+
+/// The traps of a session
+#[derive(Default)]
+pub(crate) struct Traps {
+    /// Commands by signal. An empty command ignores the signal.
+    commands: BTreeMap<i32, String>,
+    /// How often each trapped signal had been received when it was last
+    /// handled
+    handled: BTreeMap<i32, usize>,
+    /// Signals the session got itself, like Ctrl-C over the transport
+    raised: BTreeSet<i32>,
+}
+
+impl Traps {
+    /// Run `command` when `signal` arrives, or stop trapping it for `None`
+    pub(crate) fn set(&mut self, signal: i32, command: Option<String>) {
+        let slot = signal as usize;
+        match command {
+            Some(command) => {
+                if signal != EXIT && !self.commands.contains_key(&signal) {
+                    self.handled
+                        .insert(signal, RECEIVED[slot].load(Ordering::SeqCst));
+                    TRAPPED[slot].fetch_add(1, Ordering::SeqCst);
+                    install(signal);
+                }
+                self.commands.insert(signal, command);
+            }
+            None => {
+                if self.commands.remove(&signal).is_some() && signal != EXIT {
+                    self.handled.remove(&signal);
+                    TRAPPED[slot].fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    /// The traps set, by signal
+    pub(crate) fn list(&self) -> impl Iterator<Item = (i32, &str)> {
+        self.commands
+            .iter()
+            .map(|(signal, command)| (*signal, command.as_str()))
+    }
+
+    /// Note a signal the session got without the process getting it
+    pub(crate) fn raise(&mut self, signal: i32) {
+        if self.commands.contains_key(&signal) {
+            self.raised.insert(signal);
+        }
+    }
+
+    /// The commands of trapped signals that arrived since the last call,
+    /// each once however often its signal arrived
+    pub(crate) fn take_pending(&mut self) -> Vec<String> {
+        let mut pending = std::mem::take(&mut self.raised);
+        for (signal, handled) in &mut self.handled {
+            let received = RECEIVED[*signal as usize].load(Ordering::SeqCst);
+            if received != *handled {
+                *handled = received;
+                pending.insert(*signal);
+            }
+        }
+        pending
+            .into_iter()
+            .filter_map(|signal| self.commands.get(&signal).cloned())
+            .filter(|command| !command.is_empty())
+            .collect()
+    }
+
+    /// The command to run as the session ends. It only runs once.
+    pub(crate) fn take_exit(&mut self) -> Option<String> {
+        self.commands
+            .remove(&EXIT)
+            .filter(|command| !command.is_empty())
+    }
+}
+
+impl Drop for Traps {
+    fn drop(&mut self) {
+        for signal in self.handled.keys() {
+            TRAPPED[*signal as usize].fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Setup wizard offered the first time the shell starts without a config
+//! file. It asks a few questions over the active transport and writes a
+//! commented `pieshell.toml`.
+
+use std::fs;
+use std::io;
+
+use crate::config::{self, Config};
+use crate::prompt::DEFAULT_PROMPT;
+use crate::session::Session;
+use crate::transport::TransportKind;
+
+const PROMPT_STYLES: [(&str, &str); 3] = [
+    ("user@host:dir$", DEFAULT_PROMPT),
+    ("dir$", "\\w\\$ "),
+    ("$", "\\$ "),
+];
+
+/// Run the wizard. Returns the newly written config, or `None` if the wizard
+/// was skipped.
+pub fn run(session: &mut Session) -> io::Result<Option<Config>> {
+    let path = match config::default_paths().into_iter().next() {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    say(session, "No configuration file was found.")?;
+    if !ask_yes_no(session, "Run the setup wizard now?", true)? {
+        say(
+            session,
+            &format!(
+                "Skipping setup. Create {} to configure pieshell.",
+                path.display()
+            ),
+        )?;
+        return Ok(None);
+    }
+
+    let newline = ask_choice(
+        session,
+        "Line endings sent to the serial console (lf/crlf)",
+        &["crlf", "lf"],
+    )?;
+    let baud = loop {
+        let answer = ask(session, "Serial console baud rate", "115200")?;
+        match answer.parse::<u32>() {
+            Ok(baud) if baud > 0 => break baud,
+            _ => say(session, "Please enter a number, e.g. 9600 or 115200.")?,
+        }
+    };
+    let telnet = ask_yes_no(
+        session,
+        "Speak the telnet protocol on network (--listen) sessions?",
+        true,
+    )?;
+
+    for (i, (example, _)) in PROMPT_STYLES.iter().enumerate() {
+        say(session, &format!("  {}) {}", i + 1, example))?;
+    }
+    let prompt = loop {
+        let answer = ask(session, "Prompt style", "1")?;
+        match answer.parse::<usize>() {
+            Ok(i) if (1..=PROMPT_STYLES.len()).contains(&i) => break PROMPT_STYLES[i - 1].1,
+            _ => say(session, "Please choose one of the listed styles.")?,
+        }
+    };
+    let history_size = loop {
+        let answer = ask(session, "Number of commands to keep in history", "500")?;
+        match answer.parse::<usize>() {
+            Ok(size) => break size,
+            _ => say(session, "Please enter a number.")?,
+        }
+    };
+    let hardware = ask_yes_no(
+        session,
+        "Enable the hardware builtins (gpio, i2c, spi, pwm)?",
+        true,
+    )?;
+
+    let contents = format!(
+        r#"# pieshell configuration, generated by the setup wizard.
+#
+# Settings at the top level apply to every transport. Settings in a
+# [transport.stdio], [transport.uart] or [transport.tcp] section only apply
+# to sessions on that transport and take precedence.
+
+# Prompt template: \u user, \h host name, \w working directory, \W last
+# directory component, \$ prompt symbol
+prompt = "{prompt}"
+
+# Prompt for more lines of an unfinished command, like PS2 in other shells
+#continuation_prompt = "> "
+
+# Number of commands kept in the history of each session
+history_size = {history_size}
+
+# Keep the history in a file so it survives restarts
+#history_file = "/var/lib/pieshell/history"
+# "file" keeps a line per command, "sqlite" a database that `history
+# --search` and `history --stats` don't read whole (needs the sqlite
+# feature), and "none" nothing
+#history_backend = "file"
+
+# Commands run when a session starts
+#rc_file = "~/.pieshellrc"
+
+# Key bindings and editor settings in the format of readline
+#inputrc = "~/.inputrc"
+# Edit lines with "emacs" or "vi" keys, and bind more keys as in the inputrc
+#editing_mode = "vi"
+#key_bindings = ['"\C-r": history-search-backward', '"\ep": "git pull\n"']
+
+# Have cd "correct" typos like /hoem/pi to /home/pi, or "suggest" the fix
+#cd_spell = "off"
+
+# Enable the gpio, i2c, spi and pwm builtins
+hardware = {hardware}
+
+# Color policy (always, never or auto) and theme (default, bright, mono, none)
+#color = "auto"
+#theme = "default"
+
+# Text printed when a session starts, /etc/motd unless a banner is given.
+# {{hostname}}, {{ip}}, {{model}}, {{temperature}} and {{uptime}} are filled in.
+#banner = "Welcome to {{hostname}} ({{ip}}), up {{uptime}}"
+#banner_file = "/etc/pieshell/banner"
+
+# Pause command output every N lines
+#pager = 24
+
+# Close sessions on the UART and TCP after N seconds without input, so a
+# console left plugged into a deployed unit doesn't stay logged in
+#idle_timeout = 600
+
+# Allow the clip builtin and Ctrl-X Ctrl-C to copy to the terminal's
+# clipboard (OSC 52)
+#clipboard = true
+
+# Inline images (sixel, iTerm) in command output: passthrough, strip, or
+# auto to only pass them to network sessions and terminals on stdio
+#images = "auto"
+
+# Output that isn't valid UTF-8, e.g. of binaries: raw passes it through,
+# lossy replaces invalid bytes for terminals that get confused by them
+#output_encoding = "raw"
+
+# Offer to run a command again with sudo when it failed for lack of
+# permissions
+#elevate = true
+
+# Memory caps in kilobytes for the history, the output of the last command
+# and the output buffered for each background job
+#history_memory = 64
+#scrollback_memory = 256
+#job_memory = 64
+
+# Kilobytes of the output of a command sent over the transport. The rest is
+# saved to /tmp/pieshell-out-N, up to 16 MiB, keeping runaway output off slow
+# serial links. Commands given the terminal on stdio aren't capped.
+#output_limit = 1024
+
+# Mask for files created in sessions, and variables passed to commands. Both
+# can differ per transport, e.g. for automation over TCP
+#umask = "022"
+#env = {{ LANG = "C.UTF-8" }}
+
+# Log in before the first prompt: "file" checks crypt(3) hashes in
+# /etc/pieshell/credentials, "totp" codes of an authenticator app with
+# secrets in /etc/pieshell/totp, and "pam" the PAM service "pieshell".
+# Lines of the files are "user:hash" or "user:secret".
+#auth = "file"
+#credentials = "/etc/pieshell/credentials"
+
+# No prompt or echo, and each line of input is followed by
+# <<<EXIT STATUS TIMEms>>>, for test rigs driving the shell
+#batch = true
+
+# Follow each command line with e.g. [exit 1, 3.2s], so a command printing
+# nothing still tells whether it worked
+#summary = true
+
+# Append everything read and written in sessions to a file, with the time,
+# to archive them. `log on FILE` and `log off` switch it in a session.
+#transcript = "/var/log/pieshell/transcript.log"
+
+# Log each command line with who ran it, when, for how long and its exit
+# status, to a file or with "syslog" to the authpriv facility
+#audit_log = "/var/log/pieshell/audit.log"
+
+[transport.uart]
+baud = {baud}
+# Hold output back while the terminal can't keep up: "rtscts" with the
+# RTS/CTS lines wired, or "xonxoff" with Ctrl-S/Ctrl-Q
+#flow_control = "none"
+newline = "{newline}"
+# What the terminal sends to end a line: "cr", "lf" or "any" of them
+#input_newline = "any"
+# Serial terminals don't echo what is typed, so the shell does it
+echo = true
+# Only run these builtins and programs, without redirections and inside
+# the directory, e.g. on a console for field technicians
+#restricted = {{ commands = ["cd", "ls", "cat", "gpio", "sysinfo"], directory = "/srv/field" }}
+
+[transport.tcp]
+telnet = {telnet}
+"#,
+        prompt = prompt.replace('\\', "\\\\"),
+    );
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, contents)?;
+    say(
+        session,
+        &format!("Configuration written to {}", path.display()),
+    )?;
+    if session.kind() == TransportKind::Uart {
+        say(
+            session,
+            "The baud rate takes effect the next time pieshell starts.",
+        )?;
+    }
+
+    Config::load(Some(&path))
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+fn say(session: &mut Session, text: &str) -> io::Result<()> {
+    session.write_output(format!("{}\n", text).as_bytes())
+}
+
+/// Print a question with a hint of the possible answers and read the answer
+fn read_answer(session: &mut Session, question: &str, hint: &str) -> io::Result<String> {
+    session.write_output(format!("{} [{}]: ", question, hint).as_bytes())?;
+    match session.read_line()? {
+        Some(answer) => Ok(answer.trim().to_owned()),
+        None => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+    }
+}
+
+/// Ask a question, returning the default if the answer is empty
+fn ask(session: &mut Session, question: &str, default: &str) -> io::Result<String> {
+    let answer = read_answer(session, question, default)?;
+    if answer.is_empty() {
+        Ok(default.to_owned())
+    } else {
+        Ok(answer)
+    }
+}
+
+fn ask_yes_no(session: &mut Session, question: &str, default: bool) -> io::Result<bool> {
+    let choices = if default { "Y/n" } else { "y/N" };
+    loop {
+        let answer = read_answer(session, question, choices)?;
+        match answer.to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => say(session, "Please answer yes or no.")?,
+        }
+    }
+}
+
+/// Ask for one of `choices`, the first being the default
+fn ask_choice(
+    session: &mut Session,
+    question: &str,
+    choices: &[&'static str],
+) -> io::Result<&'static str> {
+    loop {
+        let answer = ask(session, question, choices[0])?;
+        if let Some(choice) = choices.iter().find(|choice| **choice == answer) {
+            return Ok(choice);
+        }
+        say(
+            session,
+            &format!("Please answer one of: {}", choices.join(", ")),
+        )?;
+    }
+}
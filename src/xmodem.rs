@@ -0,0 +1,596 @@
+//! XMODEM and YMODEM file transfers over the UART, for pushing firmware and
+//! config files to a Pi without a network from the "Send File" of a
+//! terminal program, and fetching logs from it. The sender picks the
+//! protocol: YMODEM starts with a header block telling the name and size of
+//! the file, XMODEM with the data. The receiver picks between CRCs and
+//! checksums.
+
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::transport::{Reader, Writer};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// Asks the sender to start with 16-bit CRCs instead of checksums
+const CRC_START: u8 = b'C';
+/// Ctrl-C typed while waiting for the other end
+const INTERRUPT: u8 = 0x03;
+
+/// How often the sender is asked to start
+const START_INTERVAL: Duration = Duration::from_secs(3);
+/// How many times the sender is asked to start, about a minute to find the
+/// file to send
+const START_TRIES: usize = 20;
+/// Unanswered requests for CRCs before falling back to checksums, which
+/// old senders only know
+const CRC_TRIES: usize = 3;
+/// How long the next block may take to arrive
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the bytes of a block may be apart
+const BYTE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Bad or missing blocks in a row before giving up
+const RETRIES: usize = 10;
+/// Filler of the last block of a file
+const PADDING: u8 = 0x1a;
+
+/// What a finished transfer received
+pub(crate) struct Received {
+    pub(crate) bytes: u64,
+    pub(crate) ymodem: bool,
+    /// The sender had more files in its batch, which were refused
+    pub(crate) refused_more: bool,
+}
+
+/// The name, size and modification time of a file, which YMODEM sends
+/// ahead of it
+pub(crate) struct Header<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) size: u64,
+    pub(crate) modified: u64,
+}
+
+/// What a finished transfer sent
+pub(crate) struct Sent {
+    pub(crate) bytes: u64,
+    pub(crate) blocks: usize,
+    /// Blocks sent again as the receiver missed them
+    pub(crate) retries: usize,
+}
+
+enum Block {
+    Data(u8, Vec<u8>),
+    /// The sender has sent everything
+    End,
+    Cancel,
+    Timeout,
+    /// A block with a bad checksum or header
+    Bad,
+}
+
+/// Receive a file into `file`, telling the sender to start and
+/// acknowledging the blocks. The file may be partly written if this fails.
+pub(crate) fn receive(
+    reader: &mut Reader,
+    writer: &mut Writer,
+    file: &mut impl Write,
+) -> io::Result<Received> {
+    let mut crc = true;
+    let mut block = Block::Timeout;
+    for tries in 0..START_TRIES {
+        if tries == CRC_TRIES {
+            crc = false;
+        }
+        let start = match crc {
+            true => CRC_START,
+            false => NAK,
+        };
+        send(writer, &[start])?;
+        block = read_block(reader, crc, START_INTERVAL)?;
+        match block {
+            Block::Data(..) | Block::End | Block::Cancel => break,
+            Block::Bad => purge(reader)?,
+            Block::Timeout => {}
+        }
+    }
+    if let Block::Timeout | Block::Bad = block {
+        cancel(writer)?;
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no sender started the transfer",
+        ));
+    }
+
+    let mut received = Received {
+        bytes: 0,
+        ymodem: false,
+        refused_more: false,
+    };
+    let mut size = None;
+    let mut expected = 1u8;
+    let mut errors = 0;
+    loop {
+        match block {
+            /* YMODEM starts with block 0, which is sent again if the
+            acknowledgement got lost */
+            Block::Data(0, header) if expected == 1 => {
+                if received.ymodem {
+                    send(writer, &[ACK, CRC_START])?;
+                } else if header.first() == Some(&0) {
+                    /* An empty name means the batch has no files */
+                    send(writer, &[ACK])?;
+                    return Err(io::Error::other("the sender had no file to send"));
+                } else {
+                    received.ymodem = true;
+                    size = header_size(&header);
+                    send(writer, &[ACK, CRC_START])?;
+                }
+            }
+            Block::Data(number, data) if number == expected => {
+                let length = match size {
+                    Some(size) => data.len().min((size - received.bytes) as usize),
+                    None => data.len(),
+                };
+                if let Err(error) = file.write_all(&data[..length]) {
+                    cancel(writer)?;
+                    return Err(error);
+                }
+                received.bytes += length as u64;
+                expected = expected.wrapping_add(1);
+                errors = 0;
+                send(writer, &[ACK])?;
+            }
+            /* The acknowledgement of the last block got lost */
+            Block::Data(number, _) if number == expected.wrapping_sub(1) => send(writer, &[ACK])?,
+            Block::Data(..) => {
+                cancel(writer)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "blocks arrived out of order",
+                ));
+            }
+            Block::End => break,
+            Block::Cancel => return Err(cancelled()),
+            Block::Timeout | Block::Bad => {
+                errors += 1;
+                if errors > RETRIES {
+                    cancel(writer)?;
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "too many bad or missing blocks",
+                    ));
+                }
+                if matches!(block, Block::Bad) {
+                    purge(reader)?;
+                }
+                send(writer, &[NAK])?;
+            }
+        }
+        block = read_block(reader, crc, BLOCK_TIMEOUT)?;
+    }
+
+    match received.ymodem {
+        /* The end is confirmed by sending it twice, then an empty header
+        ends the batch */
+        true => {
+            send(writer, &[NAK])?;
+            if let Block::End = read_block(reader, crc, BLOCK_TIMEOUT)? {
+                send(writer, &[ACK, CRC_START])?;
+                match read_block(reader, crc, BLOCK_TIMEOUT)? {
+                    Block::Data(0, header) if header.first() != Some(&0) => {
+                        cancel(writer)?;
+                        received.refused_more = true;
+                    }
+                    Block::Data(0, _) => send(writer, &[ACK])?,
+                    _ => {}
+                }
+            }
+        }
+        false => send(writer, &[ACK])?,
+    }
+    Ok(received)
+}
+
+/// Send a file once the receiver asks for it, with a YMODEM header if one
+/// is given. `long_blocks` sends 1024 bytes at a time, as XMODEM-1K and
+/// YMODEM do, except for a last bit that fits 128.
+pub(crate) fn send_file(
+    reader: &mut Reader,
+    writer: &mut Writer,
+    file: &mut impl Read,
+    header: Option<Header>,
+    long_blocks: bool,
+) -> io::Result<Sent> {
+    let mut sent = Sent {
+        bytes: 0,
+        blocks: 0,
+        retries: 0,
+    };
+    let crc = wait_for_start(reader)?;
+    if let Some(header) = &header {
+        let mut block =
+            format!("{}\0{} {:o}", header.name, header.size, header.modified).into_bytes();
+        /* Long names need a long block, which the NUL must fit into */
+        let size = match block.len() < 128 {
+            true => 128,
+            false => 1024,
+        };
+        block.resize(size, 0);
+        send_block(reader, writer, 0, &block, crc, &mut sent)?;
+        /* The receiver asks for the data like for the header */
+        wait_for_start(reader)?;
+    }
+
+    let mut number = 1u8;
+    let mut buffer = match long_blocks {
+        true => vec![0; 1024],
+        false => vec![0; 128],
+    };
+    loop {
+        let length = fill(file, &mut buffer).inspect_err(|_| {
+            let _ = cancel(writer);
+        })?;
+        if length == 0 {
+            break;
+        }
+        let size = match length <= 128 {
+            true => 128,
+            false => buffer.len(),
+        };
+        let mut block = buffer[..length].to_vec();
+        block.resize(size, PADDING);
+        send_block(reader, writer, number, &block, crc, &mut sent)?;
+        sent.bytes += length as u64;
+        number = number.wrapping_add(1);
+    }
+
+    /* YMODEM receivers refuse the first end to make sure it is one */
+    let mut ended = false;
+    for _ in 0..=RETRIES {
+        send(writer, &[EOT])?;
+        match wait_reply(reader)? {
+            Some(ACK) => {
+                ended = true;
+                break;
+            }
+            Some(CAN | INTERRUPT) => return Err(cancelled()),
+            _ => {}
+        }
+    }
+    if !ended {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the receiver didn't confirm the end of the file",
+        ));
+    }
+    if header.is_some() {
+        /* An empty header ends the batch */
+        wait_for_start(reader)?;
+        send_block(reader, writer, 0, &[0; 128], crc, &mut sent)?;
+    }
+    Ok(sent)
+}
+
+/// Wait for the receiver to ask for the first block. Returns whether it
+/// wants CRCs rather than checksums.
+fn wait_for_start(reader: &mut Reader) -> io::Result<bool> {
+    let deadline = Instant::now() + START_INTERVAL * START_TRIES as u32;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match read_byte(reader, timeout)? {
+            Some(CRC_START) => return Ok(true),
+            Some(NAK) => return Ok(false),
+            Some(CAN | INTERRUPT) => return Err(cancelled()),
+            Some(_) => {}
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no receiver started the transfer",
+                ))
+            }
+        }
+    }
+}
+
+/// Send a block until the receiver acknowledges it
+fn send_block(
+    reader: &mut Reader,
+    writer: &mut Writer,
+    number: u8,
+    data: &[u8],
+    crc: bool,
+    sent: &mut Sent,
+) -> io::Result<()> {
+    let start = match data.len() {
+        128 => SOH,
+        _ => STX,
+    };
+    let mut block = vec![start, number, !number];
+    block.extend_from_slice(data);
+    match crc {
+        true => block.extend_from_slice(&crc16(data).to_be_bytes()),
+        false => block.push(checksum(data)),
+    }
+
+    for tries in 0..=RETRIES {
+        if tries > 0 {
+            sent.retries += 1;
+        }
+        send(writer, &block)?;
+        match wait_reply(reader)? {
+            Some(ACK) => {
+                sent.blocks += 1;
+                return Ok(());
+            }
+            Some(CAN | INTERRUPT) => return Err(cancelled()),
+            _ => {}
+        }
+    }
+    cancel(writer)?;
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "too many blocks were not acknowledged",
+    ))
+}
+
+/// Wait for the receiver to acknowledge or refuse a block, skipping
+/// anything else, e.g. a late request to start
+fn wait_reply(reader: &mut Reader) -> io::Result<Option<u8>> {
+    let deadline = Instant::now() + BLOCK_TIMEOUT;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match read_byte(reader, timeout)? {
+            Some(reply @ (ACK | NAK | CAN | INTERRUPT)) => return Ok(Some(reply)),
+            Some(_) => {}
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Read as much as fits into `buffer`, short only at the end of the file
+fn fill(file: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut length = 0;
+    while length < buffer.len() {
+        match file.read(&mut buffer[length..]) {
+            Ok(0) => break,
+            Ok(read) => length += read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(length)
+}
+
+fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "the transfer was cancelled")
+}
+
+/// The size in a YMODEM header, after the name and its NUL
+fn header_size(header: &[u8]) -> Option<u64> {
+    let start = header.iter().position(|byte| *byte == 0)? + 1;
+    let fields = String::from_utf8_lossy(&header[start..]);
+    fields
+        .trim_end_matches('\0')
+        .split([' ', '\0'])
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn read_block(reader: &mut Reader, crc: bool, timeout: Duration) -> io::Result<Block> {
+    let length = match read_byte(reader, timeout)? {
+        None => return Ok(Block::Timeout),
+        Some(SOH) => 128,
+        Some(STX) => 1024,
+        Some(EOT) => return Ok(Block::End),
+        Some(CAN | INTERRUPT) => return Ok(Block::Cancel),
+        Some(_) => return Ok(Block::Bad),
+    };
+
+    /* The number, its complement, the data and the check */
+    let check = match crc {
+        true => 2,
+        false => 1,
+    };
+    let mut block = vec![0; 2 + length + check];
+    for byte in block.iter_mut() {
+        match read_byte(reader, BYTE_TIMEOUT)? {
+            Some(value) => *byte = value,
+            None => return Ok(Block::Bad),
+        }
+    }
+    let (number, complement) = (block[0], block[1]);
+    let data = &block[2..2 + length];
+    let valid = match crc {
+        true => block[2 + length..] == crc16(data).to_be_bytes(),
+        false => block[2 + length] == checksum(data),
+    };
+    match valid && number == !complement {
+        true => Ok(Block::Data(number, data.to_vec())),
+        false => Ok(Block::Bad),
+    }
+}
+
+/// Read a byte, or `None` if none arrived within `timeout`. The reader
+/// returns right away while the UART is lent for a transfer.
+fn read_byte(reader: &mut Reader, timeout: Duration) -> io::Result<Option<u8>> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 1];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(1) => return Ok(Some(buf[0])),
+            Ok(_) => {}
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(error) => return Err(error),
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Drop what arrives until the line is quiet, the rest of a bad block
+fn purge(reader: &mut Reader) -> io::Result<()> {
+    while read_byte(reader, BYTE_TIMEOUT)?.is_some() {}
+    Ok(())
+}
+
+fn send(writer: &mut Writer, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(bytes)?;
+    writer.flush()
+}
+
+/// Tell the other end to stop
+fn cancel(writer: &mut Writer) -> io::Result<()> {
+    send(writer, &[CAN; 5])
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// The CRC-16 of XMODEM, with polynomial 0x1021 and no inversion
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, byte| {
+        let mut crc = crc ^ ((*byte as u16) << 8);
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport;
+
+    /// Send `data` to a receiver replying with `replies`, and return what
+    /// went over the line
+    fn sent(data: &[u8], header: Option<Header>, long_blocks: bool, replies: &[u8]) -> Vec<u8> {
+        let (mut reader, mut writer, output) = transport::memory_reader_writer(replies.to_vec());
+        send_file(
+            &mut reader,
+            &mut writer,
+            &mut &data[..],
+            header,
+            long_blocks,
+        )
+        .expect("the transfer should succeed");
+        let line = transport::lock(&output).clone();
+        line
+    }
+
+    /// Receive from a sender that sends `line`, returning the file and what
+    /// the receiver replied
+    fn received(line: Vec<u8>) -> (Received, Vec<u8>, Vec<u8>) {
+        let (mut reader, mut writer, output) = transport::memory_reader_writer(line);
+        let mut file = Vec::new();
+        let received =
+            receive(&mut reader, &mut writer, &mut file).expect("the transfer should succeed");
+        let replies = transport::lock(&output).clone();
+        (received, file, replies)
+    }
+
+    #[test]
+    fn computes_checks() {
+        /* The check value of CRC-16/XMODEM */
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc16(b""), 0);
+        assert_eq!(checksum(&[0xff, 0x02, 0x10]), 0x11);
+    }
+
+    #[test]
+    fn reads_the_size_of_ymodem_headers() {
+        assert_eq!(
+            header_size(b"config.txt\x001500 14370436770\0\0"),
+            Some(1500)
+        );
+        assert_eq!(header_size(b"config.txt\x0042\0\0\0"), Some(42));
+        assert_eq!(header_size(b"config.txt\0\0\0"), None);
+        assert_eq!(header_size(b"no terminator"), None);
+    }
+
+    #[test]
+    fn frames_blocks() {
+        let data = [0x55; 100];
+        let line = sent(&data, None, false, &[NAK, ACK, ACK]);
+        assert_eq!(line.len(), 3 + 128 + 1 + 1);
+        assert_eq!(line[..3], [SOH, 1, 0xfe]);
+        assert_eq!(line[3..103], data);
+        assert!(line[103..131].iter().all(|byte| *byte == PADDING));
+        assert_eq!(line[131], checksum(&line[3..131]));
+        assert_eq!(line[132], EOT);
+
+        let line = sent(&[0x55; 200], None, true, &[CRC_START, ACK, ACK]);
+        assert_eq!(line.len(), 3 + 1024 + 2 + 1);
+        assert_eq!(line[..3], [STX, 1, 0xfe]);
+        assert_eq!(line[1027..1029], crc16(&line[3..1027]).to_be_bytes());
+    }
+
+    #[test]
+    fn sends_blocks_again_until_acknowledged() {
+        let (mut reader, mut writer, output) =
+            transport::memory_reader_writer(vec![CRC_START, NAK, NAK, ACK, ACK]);
+        let sent = send_file(&mut reader, &mut writer, &mut &b"data"[..], None, false).unwrap();
+        assert_eq!((sent.bytes, sent.blocks, sent.retries), (4, 1, 2));
+        assert_eq!(transport::lock(&output).len(), 3 * (3 + 128 + 2) + 1);
+    }
+
+    #[test]
+    fn transfers_files_with_xmodem() {
+        let data: Vec<u8> = (0..=255).cycle().take(300).collect();
+        let line = sent(&data, None, false, &[CRC_START, ACK, ACK, ACK, ACK]);
+        let (received, file, replies) = received(line);
+        assert!(!received.ymodem);
+        /* XMODEM has no sizes, so the last block keeps its padding */
+        assert_eq!(received.bytes, 384);
+        assert_eq!(file[..300], data);
+        assert_eq!(replies, [CRC_START, ACK, ACK, ACK, ACK]);
+    }
+
+    #[test]
+    fn transfers_files_with_ymodem() {
+        let data: Vec<u8> = (0..=255).cycle().take(1500).collect();
+        let header = Header {
+            name: "firmware.bin",
+            size: data.len() as u64,
+            modified: 0,
+        };
+        let replies = [
+            CRC_START, ACK, CRC_START, ACK, ACK, NAK, ACK, CRC_START, ACK,
+        ];
+        let line = sent(&data, Some(header), true, &replies);
+        let (received, file, replies) = received(line);
+        assert!(received.ymodem);
+        assert!(!received.refused_more);
+        assert_eq!(received.bytes, 1500);
+        assert_eq!(file, data);
+        assert_eq!(
+            replies,
+            [CRC_START, ACK, CRC_START, ACK, ACK, NAK, ACK, CRC_START, ACK]
+        );
+    }
+
+    #[test]
+    fn refuses_blocks_with_bad_checks() {
+        let mut line = sent(b"data", None, false, &[CRC_START, ACK, ACK]);
+        line[10] ^= 1;
+        let (mut reader, _, _) = transport::memory_reader_writer(line);
+        let block = read_block(&mut reader, true, BYTE_TIMEOUT).unwrap();
+        assert!(matches!(block, Block::Bad));
+    }
+}
@@ -0,0 +1,243 @@
+//! Sessions driven through the harness: keys go through the line editor,
+//! lines through the parser and commands through the executor.
+
+use std::fs;
+use std::path::PathBuf;
+
+use pieshell::config::Restricted;
+use pieshell::encoding::OutputEncoding;
+use pieshell::harness::Harness;
+use pieshell::images::ImagePolicy;
+
+/// An empty directory for a test to work in
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pieshell-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("scratch directory should be created");
+    dir
+}
+
+#[test]
+fn runs_commands() {
+    let transcript = Harness::new()
+        .line("echo hello")
+        .line("echo a | tr a b")
+        .run();
+    assert!(transcript.result().is_ok());
+    assert_eq!(transcript.lines(), ["hello", "b"]);
+}
+
+#[test]
+fn edits_lines() {
+    let transcript = Harness::new()
+        .keys("echo hellx\u{7f}o\r")
+        .keys("echo lost\u{15}echo kept\r")
+        .keys("echo one two\u{17}three\r")
+        .run();
+    assert_eq!(transcript.lines(), ["hello", "kept", "one three"]);
+}
+
+#[test]
+fn recalls_history() {
+    let transcript = Harness::new()
+        .line("echo again")
+        .keys("\u{10}\r")
+        .keys("\u{1b}[A\r")
+        .run();
+    assert_eq!(transcript.lines(), ["again", "again", "again"]);
+}
+
+#[test]
+fn continues_unfinished_lines() {
+    let transcript = Harness::new()
+        .line("for word in a b; do")
+        .line("echo $word")
+        .line("done")
+        .line("if true")
+        .line("then echo yes; fi")
+        .run();
+    assert_eq!(transcript.lines(), ["> > a", "b", "> yes"]);
+}
+
+#[test]
+fn expands_braces_without_overflowing() {
+    let transcript = Harness::new()
+        .line("echo {1..3} x{a,b}")
+        .line("echo {9223372036854775806..9223372036854775807}")
+        .line("echo {1..9223372036854775807..9223372036854775807}")
+        .line("echo {-9223372036854775808..9223372036854775807}")
+        .line(&format!("echo {}", "{1..5}".repeat(12)))
+        .line(&format!("echo {}", "{a,b}".repeat(40)))
+        .line("echo survived")
+        .run();
+    assert!(transcript.result().is_ok());
+    let lines = transcript.lines();
+    assert_eq!(lines[0], "1 2 3 xa xb");
+    assert_eq!(lines[1], "9223372036854775806 9223372036854775807");
+    assert_eq!(lines[2], "1");
+    assert_eq!(lines[3], "{-9223372036854775808..9223372036854775807}");
+    assert_eq!(lines[4], "{1..5}".repeat(12));
+    assert_eq!(lines[5], "{a,b}".repeat(40));
+    assert_eq!(lines.last().map(String::as_str), Some("survived"));
+}
+
+#[test]
+fn runs_exec_in_a_subshell_as_a_child() {
+    let transcript = Harness::new().line("(exec echo inside); echo after").run();
+    assert_eq!(transcript.lines(), ["inside", "after"]);
+}
+
+#[test]
+fn gives_subshells_traps_of_their_own() {
+    let transcript = Harness::new()
+        .line("trap 'echo outer' EXIT")
+        .line("(trap 'echo inner' EXIT; echo body)")
+        .line("exit")
+        .run();
+    assert_eq!(transcript.lines(), ["body", "inner", "outer"]);
+}
+
+#[test]
+fn substitutes_lists_of_commands() {
+    let transcript = Harness::new()
+        .line("cat <(echo a; echo b)")
+        .line("paste <(echo c; echo d) <(echo e && echo f)")
+        .run();
+    assert_eq!(transcript.lines(), ["a", "b", "c\te", "d\tf"]);
+}
+
+#[test]
+fn redirects_compound_commands() {
+    let dir = scratch_dir("redirects");
+    fs::write(dir.join("input"), "first\nsecond\nlast").unwrap();
+    let transcript = Harness::new()
+        .current_dir(&dir)
+        .line("while read line; do echo \"<$line>\"; done < input; echo \"[$line]\"")
+        .line("for word in x y; do echo $word; done > output")
+        .line("if true; then echo yes; fi >> output")
+        .line("cat output")
+        .run();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        transcript.lines(),
+        ["<first>", "<second>", "[last]", "x", "y", "yes"]
+    );
+}
+
+#[test]
+fn runs_once_with_arguments_as_given() {
+    let key = format!("harness-{}", std::process::id());
+    let transcript = Harness::new()
+        .line(&format!("once {} echo 'a  b' '$HOME'", key))
+        .line(&format!("once {} echo ran again", key))
+        .run();
+    assert_eq!(transcript.lines(), ["a  b $HOME", "a  b $HOME"]);
+}
+
+#[test]
+fn keeps_receipts_across_sessions() {
+    let key = format!("harness-shared-{}", std::process::id());
+    let first = Harness::new()
+        .line(&format!("once {} sh -c 'echo first; exit 3'", key))
+        .line("echo $?")
+        .run();
+    let second = Harness::new()
+        .line(&format!("once {} echo second", key))
+        .line("echo $?")
+        .run();
+    assert_eq!(first.lines(), ["first", "3"]);
+    assert_eq!(second.lines(), ["first", "3"]);
+}
+
+#[test]
+fn resets_the_image_filter_after_each_command() {
+    let transcript = Harness::new()
+        .configure(|settings| settings.images = ImagePolicy::Strip)
+        .line("env printf 'text\\033]1337;File=name=x:'")
+        .line("echo after")
+        .run();
+    let output = transcript.output();
+    assert!(output.contains("text"));
+    assert!(output.contains("after"));
+}
+
+#[test]
+fn replaces_unfinished_utf8_when_a_command_ends() {
+    let transcript = Harness::new()
+        .configure(|settings| settings.output_encoding = OutputEncoding::Lossy)
+        .line("env printf 'a\\342\\202'")
+        .line("echo b")
+        .run();
+    assert!(transcript.contains("a\u{fffd}"));
+    assert!(transcript.contains("b"));
+    assert!(!transcript.contains("\u{fffd}\u{fffd}"));
+}
+
+#[test]
+fn refuses_timeouts_too_long_to_wait_for() {
+    let transcript = Harness::new()
+        .line("timeout 1e300 true; echo $?")
+        .line("timeout 1e18 echo waited")
+        .line("timeout 0.2 sleep 5; echo $?")
+        .run();
+    assert!(transcript.result().is_ok());
+    assert_eq!(
+        transcript.lines(),
+        [
+            "pieshell: timeout: '1e300' is not a positive number of seconds",
+            "2",
+            "waited",
+            "124"
+        ]
+    );
+}
+
+#[test]
+fn refuses_empty_expected_text_in_converse() {
+    let transcript = Harness::new()
+        .line("converse /dev/null --expect ''; echo $?")
+        .line("converse /dev/null --timeout 1e300 --expect x; echo $?")
+        .run();
+    assert!(transcript.result().is_ok());
+    let lines = transcript.lines();
+    assert!(lines[0].starts_with("pieshell: converse: usage:"));
+    assert_eq!(
+        lines[1..],
+        [
+            "2",
+            "pieshell: converse: '1e300' is not a positive number of seconds",
+            "2"
+        ]
+    );
+}
+
+#[test]
+fn keeps_restricted_sessions_from_changing_what_programs_do() {
+    let transcript = Harness::new()
+        .configure(|settings| {
+            settings.restricted = Some(Restricted {
+                commands: vec![String::from("echo"), String::from("true")],
+                directory: None,
+            })
+        })
+        .line("true && echo allowed")
+        .line("LD_PRELOAD=/x.so true; echo $?")
+        .line("greet() { echo hi; }; GREETING=x greet")
+        .line("PATH=/tmp; LD_LIBRARY_PATH=/tmp")
+        .line("name=kept; echo $name")
+        .line("ls")
+        .run();
+    assert_eq!(
+        transcript.lines(),
+        [
+            "allowed",
+            "pieshell: LD_PRELOAD: restricted",
+            "1",
+            "pieshell: GREETING: restricted",
+            "pieshell: PATH: restricted",
+            "pieshell: LD_LIBRARY_PATH: restricted",
+            "kept",
+            "pieshell: ls: restricted"
+        ]
+    );
+}